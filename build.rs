@@ -0,0 +1,20 @@
+//! Record the version of LLVM that bfc links against, so `--version`
+//! can show it.
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    // llvm-sys tells crates that depend on it where it found
+    // llvm-config.
+    println!("cargo:rerun-if-env-changed=DEP_LLVM_14_CONFIG_PATH");
+
+    let version = env::var("DEP_LLVM_14_CONFIG_PATH")
+        .ok()
+        .and_then(|llvm_config| Command::new(llvm_config).arg("--version").output().ok())
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=BFC_LLVM_VERSION={}", version);
+}
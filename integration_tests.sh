@@ -56,6 +56,29 @@ function compile_and_run {
     fi
 }
 
+# Check that interpreting a program gives the same output as
+# compiling it.
+function compare_interpreted {
+    local test_program=$1
+    local executable="${test_program%.*}"
+    local input=sample_programs/${test_program}.in
+    if [ ! -f $input ]; then
+        input=/dev/null
+    fi
+
+    ./target/release/bfc sample_programs/$test_program && \
+        ./$executable < $input > compiled_output.txt
+    ./target/release/bfc --interpret sample_programs/$test_program < $input > interpreted_output.txt
+
+    diff compiled_output.txt interpreted_output.txt > /dev/null
+    if [[ $? -ne 0 ]]; then
+        echo "Interpreted output differs!"
+        failed=1
+    fi
+
+    rm -f $executable compiled_output.txt interpreted_output.txt
+}
+
 function check_program {
     summary "Testing $1"
     compile_and_run $1
@@ -71,4 +94,27 @@ check_program factor.bf
 check_program mandelbrot.bf
 check_program life.bf
 
+summary "Testing reading source from stdin"
+./target/release/bfc - < sample_programs/hello_world.bf && ./a.out > output.txt
+diff output.txt sample_programs/hello_world.bf.out > /dev/null
+if [[ $? -ne 0 ]]; then
+    echo "Output differs when reading source from stdin!"
+    failed=1
+fi
+rm -f a.out output.txt
+
+summary "Testing interpreting with --bang-input"
+printf ',.,.!AB' > bang_input.bf
+echo XY | ./target/release/bfc --interpret --bang-input bang_input.bf > output.txt
+if [[ "$(cat output.txt)" != "AB" ]]; then
+    echo "Interpreted output differs with --bang-input!"
+    failed=1
+fi
+rm -f bang_input.bf output.txt
+
+summary "Comparing interpreted and compiled output"
+compare_interpreted hello_world.bf
+compare_interpreted factor.bf
+compare_interpreted bottles.bf
+
 exit $failed
@@ -0,0 +1,89 @@
+//! Summary statistics about a BF program, for `--analyze`.
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{count_instrs, AstNode};
+use crate::bounds::highest_cell_index;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    /// The number of BF commands in the source.
+    pub bf_commands: u64,
+    /// The number of instructions after optimisation, including
+    /// instructions inside loop bodies.
+    pub optimized_instrs: u64,
+    /// The deepest loop nesting in the source. 0 if there are no
+    /// loops.
+    pub max_loop_depth: usize,
+    /// The highest cell index that the optimised program can reach.
+    pub highest_cell_index: usize,
+}
+
+/// Analyse a program, given its instructions straight from the parser
+/// and after optimisation.
+pub fn analyze(parsed: &[AstNode], optimized: &[AstNode]) -> Analysis {
+    Analysis {
+        bf_commands: count_bf_commands(parsed),
+        optimized_instrs: count_instrs(optimized),
+        max_loop_depth: max_loop_depth(parsed),
+        highest_cell_index: highest_cell_index(optimized),
+    }
+}
+
+/// The number of BF commands in a program, given its instructions
+/// straight from the parser. The parser creates one instruction for
+/// each command, except for loops, which are `[` and `]`.
+fn count_bf_commands(instrs: &[AstNode]) -> u64 {
+    instrs
+        .iter()
+        .map(|instr| match instr {
+            Loop { body, .. } => 2 + count_bf_commands(body),
+            _ => 1,
+        })
+        .sum()
+}
+
+fn max_loop_depth(instrs: &[AstNode]) -> usize {
+    instrs
+        .iter()
+        .map(|instr| match instr {
+            Loop { body, .. } => 1 + max_loop_depth(body),
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::peephole::{optimize, OptimizeOptions};
+
+    #[test]
+    fn analyze_nested_loops() {
+        let instrs = parse("+[>[-]<[>+<-]]>>.").unwrap();
+        let analysis = analyze(&instrs, &instrs);
+        assert_eq!(analysis.bf_commands, 17);
+        assert_eq!(analysis.optimized_instrs, 14);
+        assert_eq!(analysis.max_loop_depth, 2);
+        assert_eq!(analysis.highest_cell_index, 2);
+    }
+
+    #[test]
+    fn analyze_hello_world() {
+        let src = include_str!("../sample_programs/hello_world.bf");
+        let instrs = parse(src).unwrap();
+        let (optimized, _) = optimize(instrs.clone(), OptimizeOptions::default());
+
+        let analysis = analyze(&instrs, &optimized);
+        assert_eq!(
+            analysis,
+            Analysis {
+                bf_commands: 111,
+                optimized_instrs: 33,
+                max_loop_depth: 1,
+                highest_cell_index: 4,
+            }
+        );
+    }
+}
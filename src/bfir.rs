@@ -7,7 +7,10 @@
 
 use std::collections::HashMap;
 use std::fmt;
+use std::io::{self, BufReader, Bytes};
+use std::iter::Peekable;
 use std::num::Wrapping;
+use std::slice;
 
 use self::AstNode::*;
 
@@ -16,6 +19,12 @@ use self::AstNode::*;
 /// byte.
 pub type BfValue = Wrapping<i8>;
 
+/// The deepest loop nesting we accept. The optimiser and code
+/// generator recurse into loop bodies, so deeper nesting could
+/// overflow the stack. Generated programs can nest loops a few
+/// hundred deep, so only pathological programs should reach this.
+pub const MAX_LOOP_DEPTH: usize = 1024;
+
 /// An inclusive range used for tracking positions in source code.
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub struct Position {
@@ -88,12 +97,31 @@ pub enum AstNode {
         position: Option<Position>,
     },
     /// The `,` instruction in BF.
-    Read { position: Option<Position> },
+    Read {
+        /// The offset of the BF cell to read into, relative to the
+        /// current BF cell pointer.
+        ///
+        /// For example, `>,<` is a read at offset 1.
+        offset: isize,
+        position: Option<Position>,
+    },
     /// The `.` instruction in BF.
-    Write { position: Option<Position> },
+    Write {
+        /// The offset of the BF cell to write, relative to the current
+        /// BF cell pointer.
+        offset: isize,
+        position: Option<Position>,
+    },
     /// A loop in BF, such as `[>]`.
     Loop {
         body: Vec<AstNode>,
+        /// The offset of the BF cell tested by the loop condition,
+        /// relative to the current BF cell pointer.
+        ///
+        /// This is zero unless the loop has been simplified. For
+        /// example, `>[+<+>]` is equivalent to a loop at offset 1 with
+        /// a body of `>+<+`, followed by `>`.
+        offset: isize,
         position: Option<Position>,
     },
     /// Set the current BF cell to a value.
@@ -114,10 +142,56 @@ pub enum AstNode {
     /// to zero.
     ///
     /// For example, `[>+++<-]` is `MultiplyMove { changes: { 1: 3 }}`.
+    ///
+    /// The current BF cell is zeroed before the changes are applied,
+    /// so a change at offset 0 preserves the current BF cell. For
+    /// example, copying the current cell to the next cell is
+    /// `MultiplyMove { changes: { 0: 1, 1: 1 }}`.
     MultiplyMove {
         changes: HashMap<isize, BfValue>,
         position: Option<Position>,
     },
+    /// Copy the value of one BF cell to another, leaving the source
+    /// cell unchanged. If `overwrite` is false, add the value to the
+    /// destination cell instead.
+    ///
+    /// This is only emitted during simplification. For example,
+    /// `MultiplyMove { changes: { 0: 1, 1: 1 }}` is
+    /// `CellCopy { src_offset: 0, dst_offset: 1, overwrite: false }`.
+    ///
+    /// The source and destination may be the same cell. Overwriting
+    /// then does nothing, and adding doubles the cell.
+    CellCopy {
+        /// The offset of the BF cell to read, relative to the current
+        /// BF cell pointer.
+        src_offset: isize,
+        /// The offset of the BF cell to write, relative to the
+        /// current BF cell pointer.
+        dst_offset: isize,
+        overwrite: bool,
+        position: Option<Position>,
+    },
+    /// Move the BF cell pointer by `step` until the current cell is
+    /// zero.
+    ///
+    /// This is only emitted during simplification. For example, `[>]`
+    /// is `ScanLoop { step: 1 }` and `[<<]` is `ScanLoop { step: -2 }`.
+    ScanLoop {
+        step: isize,
+        position: Option<Position>,
+    },
+    /// A loop that never terminates if the tested BF cell is
+    /// non-zero, because the loop body never changes it.
+    ///
+    /// This is only emitted during simplification. For example, `[]`
+    /// and `[>+<]` are both `InfiniteLoop { offset: 0 }`. The body
+    /// is discarded, as we can never observe its effects.
+    InfiniteLoop {
+        /// The offset of the BF cell tested by the loop condition,
+        /// relative to the current BF cell pointer.
+        offset: isize,
+        position: Option<Position>,
+    },
 }
 
 fn fmt_with_indent(instr: &AstNode, indent: i32, f: &mut fmt::Formatter) {
@@ -128,10 +202,14 @@ fn fmt_with_indent(instr: &AstNode, indent: i32, f: &mut fmt::Formatter) {
     match instr {
         &Loop {
             body: ref loop_body,
+            offset,
             position,
-            ..
         } => {
-            let _ = write!(f, "Loop position: {:?}", position);
+            if offset == 0 {
+                let _ = write!(f, "Loop position: {:?}", position);
+            } else {
+                let _ = write!(f, "Loop offset: {} position: {:?}", offset, position);
+            }
 
             for loop_instr in loop_body {
                 let _ = writeln!(f);
@@ -155,31 +233,200 @@ pub fn get_position(instr: &AstNode) -> Option<Position> {
     match *instr {
         Increment { position, .. } => position,
         PointerIncrement { position, .. } => position,
-        Read { position } => position,
-        Write { position } => position,
+        Read { position, .. } => position,
+        Write { position, .. } => position,
         Loop { position, .. } => position,
         Set { position, .. } => position,
         MultiplyMove { position, .. } => position,
+        CellCopy { position, .. } => position,
+        ScanLoop { position, .. } => position,
+        InfiniteLoop { position, .. } => position,
     }
 }
 
+/// The number of instructions in `instrs`, including instructions
+/// inside loop bodies.
+pub fn count_instrs(instrs: &[AstNode]) -> u64 {
+    let mut count = 0;
+    for instr in instrs {
+        if let Loop { ref body, .. } = *instr {
+            count += count_instrs(body);
+        }
+        count += 1;
+    }
+    count
+}
+
 #[derive(Debug)]
 pub struct ParseError {
     pub message: String,
     pub position: Position,
 }
 
+/// A run of characters in BF source that aren't BF commands, such as
+/// an explanation of the code or the whitespace between commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    pub text: String,
+    pub position: Position,
+}
+
 /// Given a string of BF source code, parse and return our BF IR
 /// representation. If parsing fails, return a position and message
 /// describing what went wrong.
+///
+/// Characters that aren't BF commands are treated as comments.
 pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
+    parse_chars(source.chars(), false, None)
+}
+
+/// Parse BF source code, but return an error for any characters
+/// that aren't BF commands or whitespace. This catches typos in
+/// programs.
+pub fn parse_strict(source: &str) -> Result<Vec<AstNode>, ParseError> {
+    parse_chars(source.chars(), true, None)
+}
+
+/// Parse BF source code, and also return the comments in the source,
+/// in order. This lets us write comments back out with the
+/// instructions they were next to.
+pub fn parse_with_comments(
+    source: &str,
+    strict: bool,
+) -> Result<(Vec<AstNode>, Vec<Comment>), ParseError> {
+    let mut comments = vec![];
+    let instrs = parse_chars(source.chars(), strict, Some(&mut comments))?;
+    Ok((instrs, comments))
+}
+
+/// Parse BF source code from `reader`, decoding it as UTF-8 as we
+/// go. This avoids holding the whole source in memory, which matters
+/// for very large generated programs.
+///
+/// The outer result is an error if we couldn't read the source, or
+/// it wasn't valid UTF-8.
+pub fn parse_reader<R: io::Read>(
+    reader: R,
+    strict: bool,
+) -> io::Result<Result<Vec<AstNode>, ParseError>> {
+    let mut chars = Utf8Chars {
+        bytes: io::Read::bytes(BufReader::new(reader)),
+        error: None,
+    };
+    let result = parse_chars(&mut chars, strict, None);
+
+    match chars.error {
+        Some(e) => Err(e),
+        None => Ok(result),
+    }
+}
+
+/// An iterator of the characters in a UTF-8 byte stream. If reading
+/// fails, we stop iterating and store the error.
+struct Utf8Chars<R: io::Read> {
+    bytes: Bytes<BufReader<R>>,
+    error: Option<io::Error>,
+}
+
+impl<R: io::Read> Utf8Chars<R> {
+    fn next_byte(&mut self) -> Option<u8> {
+        match self.bytes.next()? {
+            Ok(byte) => Some(byte),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn invalid_utf8(&mut self) -> Option<char> {
+        self.error = Some(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        ));
+        None
+    }
+}
+
+impl<R: io::Read> Iterator for Utf8Chars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        let first_byte = self.next_byte()?;
+        let char_len = match first_byte.leading_ones() {
+            0 => 1,
+            2 => 2,
+            3 => 3,
+            4 => 4,
+            _ => return self.invalid_utf8(),
+        };
+
+        let mut buf = [first_byte, 0, 0, 0];
+        for byte in buf.iter_mut().take(char_len).skip(1) {
+            match self.next_byte() {
+                Some(next_byte) => *byte = next_byte,
+                None if self.error.is_some() => return None,
+                None => return self.invalid_utf8(),
+            }
+        }
+
+        match std::str::from_utf8(&buf[..char_len]) {
+            Ok(s) => s.chars().next(),
+            Err(_) => self.invalid_utf8(),
+        }
+    }
+}
+
+fn is_bf_command(c: char) -> bool {
+    matches!(c, '+' | '-' | '>' | '<' | ',' | '.' | '[' | ']')
+}
+
+fn parse_chars<I: Iterator<Item = char>>(
+    chars: I,
+    strict: bool,
+    mut comments: Option<&mut Vec<Comment>>,
+) -> Result<Vec<AstNode>, ParseError> {
     // AstNodes in the current loop (or toplevel).
     let mut instructions = vec![];
     // Contains the instructions of open parent loops (or toplevel),
     // and the starting indices of the loops.
     let mut stack = vec![];
 
-    for (index, c) in source.chars().enumerate() {
+    // A `#!` line at the start is a comment, even if it contains BF
+    // commands. This lets users write BF programs that are
+    // executable scripts.
+    let mut chars = chars;
+    let prefix: Vec<char> = chars.by_ref().take(2).collect();
+    let mut in_bang_header = prefix == ['#', '!'];
+    // The comment we're currently reading, if we're collecting them.
+    let mut comment: Option<Comment> = None;
+
+    for (index, c) in prefix.into_iter().chain(chars).enumerate() {
+        if let Some(ref mut comments) = comments {
+            if in_bang_header || !is_bf_command(c) {
+                let comment = comment.get_or_insert_with(|| Comment {
+                    text: String::new(),
+                    position: Position {
+                        start: index,
+                        end: index,
+                    },
+                });
+                comment.text.push(c);
+                comment.position.end = index;
+            } else if let Some(comment) = comment.take() {
+                comments.push(comment);
+            }
+        }
+
+        if in_bang_header {
+            in_bang_header = c != '\n';
+            continue;
+        }
+
         match c {
             '+' => instructions.push(Increment {
                 amount: Wrapping(1),
@@ -212,18 +459,32 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
                 }),
             }),
             ',' => instructions.push(Read {
+                offset: 0,
                 position: Some(Position {
                     start: index,
                     end: index,
                 }),
             }),
             '.' => instructions.push(Write {
+                offset: 0,
                 position: Some(Position {
                     start: index,
                     end: index,
                 }),
             }),
             '[' => {
+                if stack.len() == MAX_LOOP_DEPTH {
+                    return Err(ParseError {
+                        message: format!(
+                            "This [ is nested more than {} loops deep",
+                            MAX_LOOP_DEPTH
+                        ),
+                        position: Position {
+                            start: index,
+                            end: index,
+                        },
+                    });
+                }
                 stack.push((instructions, index));
                 instructions = vec![];
             }
@@ -231,6 +492,7 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
                 if let Some((mut parent_instr, open_index)) = stack.pop() {
                     parent_instr.push(Loop {
                         body: instructions,
+                        offset: 0,
                         position: Some(Position {
                             start: open_index,
                             end: index,
@@ -247,10 +509,24 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
                     });
                 }
             }
-            _ => (),
+            _ => {
+                if strict && !c.is_whitespace() {
+                    return Err(ParseError {
+                        message: format!("{:?} is not a BF command", c),
+                        position: Position {
+                            start: index,
+                            end: index,
+                        },
+                    });
+                }
+            }
         }
     }
 
+    if let (Some(comments), Some(comment)) = (comments, comment) {
+        comments.push(comment);
+    }
+
     if !stack.is_empty() {
         let pos = stack.last().unwrap().1;
         return Err(ParseError {
@@ -265,10 +541,154 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
     Ok(instructions)
 }
 
+/// Convert instructions back to BF source, using only the eight BF
+/// commands. Parsing the result gives an equivalent program.
+///
+/// A `MultiplyMove` with a change at offset 0 or a `CellCopy` can't
+/// be expressed without a temporary cell, so we return an error for
+/// those.
+pub fn to_bf_source(instrs: &[AstNode]) -> Result<String, String> {
+    to_bf_source_with_comments(instrs, &[])
+}
+
+/// Convert instructions back to BF source as `to_bf_source` does,
+/// writing each comment before the first instruction that started
+/// after it in the original source.
+pub fn to_bf_source_with_comments(
+    instrs: &[AstNode],
+    comments: &[Comment],
+) -> Result<String, String> {
+    let mut src = String::new();
+    let mut comments = comments.iter().peekable();
+    for instr in instrs {
+        push_bf_source(instr, &mut comments, &mut src)?;
+    }
+    for comment in comments {
+        src.push_str(&comment.text);
+    }
+    Ok(src)
+}
+
+/// Append the text of the comments that start before `index`.
+fn push_comments_before(
+    index: usize,
+    comments: &mut Peekable<slice::Iter<Comment>>,
+    src: &mut String,
+) {
+    while let Some(comment) = comments.next_if(|comment| comment.position.start < index) {
+        src.push_str(&comment.text);
+    }
+}
+
+/// Append `<` or `>` commands to move the cell pointer by `amount`.
+fn push_pointer_moves(amount: isize, src: &mut String) {
+    let command = if amount < 0 { "<" } else { ">" };
+    src.push_str(&command.repeat(amount.unsigned_abs()));
+}
+
+/// Append `+` or `-` commands to add `amount` to the current cell.
+fn push_cell_changes(amount: BfValue, src: &mut String) {
+    let amount = amount.0 as i16;
+    let command = if amount < 0 { "-" } else { "+" };
+    src.push_str(&command.repeat(amount.unsigned_abs() as usize));
+}
+
+fn push_bf_source(
+    instr: &AstNode,
+    comments: &mut Peekable<slice::Iter<Comment>>,
+    src: &mut String,
+) -> Result<(), String> {
+    if let Some(position) = get_position(instr) {
+        push_comments_before(position.start, comments, src);
+    }
+
+    match instr {
+        Increment { amount, offset, .. } => {
+            push_pointer_moves(*offset, src);
+            push_cell_changes(*amount, src);
+            push_pointer_moves(-offset, src);
+        }
+        Set { amount, offset, .. } => {
+            push_pointer_moves(*offset, src);
+            src.push_str("[-]");
+            push_cell_changes(*amount, src);
+            push_pointer_moves(-offset, src);
+        }
+        MultiplyMove { changes, .. } => {
+            if changes.contains_key(&0) {
+                return Err(
+                    "Can't convert a MultiplyMove that preserves the current cell to BF".to_owned(),
+                );
+            }
+
+            // Apply changes in offset order, so output is deterministic.
+            let mut changes: Vec<_> = changes.iter().collect();
+            changes.sort();
+
+            src.push_str("[-");
+            for (offset, factor) in changes {
+                push_pointer_moves(*offset, src);
+                push_cell_changes(*factor, src);
+                push_pointer_moves(-offset, src);
+            }
+            src.push(']');
+        }
+        CellCopy { .. } => {
+            return Err("Can't convert a CellCopy to BF".to_owned());
+        }
+        PointerIncrement { amount, .. } => push_pointer_moves(*amount, src),
+        Read { offset, .. } => {
+            push_pointer_moves(*offset, src);
+            src.push(',');
+            push_pointer_moves(-offset, src);
+        }
+        Write { offset, .. } => {
+            push_pointer_moves(*offset, src);
+            src.push('.');
+            push_pointer_moves(-offset, src);
+        }
+        Loop {
+            body,
+            offset,
+            position,
+        } => {
+            // Test the cell at the offset, but run the body from the
+            // current cell.
+            push_pointer_moves(*offset, src);
+            src.push('[');
+            push_pointer_moves(-offset, src);
+            for body_instr in body {
+                push_bf_source(body_instr, comments, src)?;
+            }
+            if let Some(position) = position {
+                push_comments_before(position.end, comments, src);
+            }
+            push_pointer_moves(*offset, src);
+            src.push(']');
+            push_pointer_moves(-offset, src);
+        }
+        ScanLoop { step, .. } => {
+            src.push('[');
+            push_pointer_moves(*step, src);
+            src.push(']');
+        }
+        InfiniteLoop { offset, .. } => {
+            push_pointer_moves(*offset, src);
+            src.push_str("[]");
+            push_pointer_moves(-offset, src);
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
+    use quickcheck::{quickcheck, TestResult};
+
+    use crate::execution::Outcome::*;
+    use crate::execution::{execute_with_state, ExecutionState};
 
     #[test]
     fn parse_increment() {
@@ -336,6 +756,7 @@ mod tests {
         assert_eq!(
             parse(",").unwrap(),
             [Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 })
             }]
         );
@@ -346,6 +767,7 @@ mod tests {
         assert_eq!(
             parse(".").unwrap(),
             [Write {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 })
             }]
         );
@@ -355,6 +777,7 @@ mod tests {
     fn parse_empty_loop() {
         let expected = [Loop {
             body: vec![],
+            offset: 0,
             position: Some(Position { start: 0, end: 1 }),
         }];
         assert_eq!(parse("[]").unwrap(), expected);
@@ -369,6 +792,7 @@ mod tests {
         }];
         let expected = [Loop {
             body: loop_body,
+            offset: 0,
             position: Some(Position { start: 0, end: 2 }),
         }];
         assert_eq!(parse("[+]").unwrap(), expected);
@@ -378,6 +802,7 @@ mod tests {
     fn parse_complex_loop() {
         let loop_body = vec![
             Read {
+                offset: 0,
                 position: Some(Position { start: 2, end: 2 }),
             },
             Increment {
@@ -388,10 +813,12 @@ mod tests {
         ];
         let expected = [
             Write {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Loop {
                 body: loop_body,
+                offset: 0,
                 position: Some(Position { start: 1, end: 4 }),
             },
             Increment {
@@ -411,11 +838,107 @@ mod tests {
         assert!(parse("[][").is_err());
     }
 
+    #[test]
+    fn parse_nesting_limit() {
+        let src = format!(
+            "{}{}",
+            "[".repeat(MAX_LOOP_DEPTH),
+            "]".repeat(MAX_LOOP_DEPTH)
+        );
+        assert!(parse(&src).is_ok());
+
+        let src = format!("+{}", "[".repeat(MAX_LOOP_DEPTH + 1));
+        let error = parse(&src).unwrap_err();
+        assert_eq!(
+            error.message,
+            format!("This [ is nested more than {} loops deep", MAX_LOOP_DEPTH)
+        );
+        assert_eq!(
+            error.position,
+            Position {
+                start: MAX_LOOP_DEPTH + 1,
+                end: MAX_LOOP_DEPTH + 1
+            }
+        );
+    }
+
     #[test]
     fn parse_comment() {
         assert_eq!(parse("foo! ").unwrap(), []);
     }
 
+    #[test]
+    fn parse_lenient_ignores_comments() {
+        let instrs = parse("+a+").unwrap();
+        assert_eq!(instrs.len(), 2);
+    }
+
+    #[test]
+    fn parse_strict_rejects_comments() {
+        let err = parse_strict("+a+").unwrap_err();
+        assert_eq!(err.message, "'a' is not a BF command");
+        assert_eq!(err.position, Position { start: 1, end: 1 });
+    }
+
+    #[test]
+    fn parse_strict_allows_whitespace() {
+        let instrs = parse_strict("+ +\n\t.").unwrap();
+        assert_eq!(instrs.len(), 3);
+    }
+
+    #[test]
+    fn parse_reader_matches_parse() {
+        // Read a couple of bytes at a time, so multi-byte characters
+        // are split across reads.
+        struct ChunkedReader<'a> {
+            remaining: &'a [u8],
+        }
+
+        impl io::Read for ChunkedReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(self.remaining.len()).min(2);
+                buf[..len].copy_from_slice(&self.remaining[..len]);
+                self.remaining = &self.remaining[len..];
+                Ok(len)
+            }
+        }
+
+        let source = "#!/usr/bin/env bfc\n+[->+<] λ comment ×2\n>.";
+        let reader = ChunkedReader {
+            remaining: source.as_bytes(),
+        };
+
+        assert_eq!(
+            parse_reader(reader, false).unwrap().unwrap(),
+            parse(source).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_reader_strict_error() {
+        let result = parse_reader("+ x".as_bytes(), true).unwrap();
+        assert_eq!(result.unwrap_err().position, Position { start: 2, end: 2 });
+    }
+
+    #[test]
+    fn parse_reader_invalid_utf8() {
+        let result = parse_reader(&b"+\xff."[..], false);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parse_bang_header() {
+        // The header is a comment, so the - is ignored.
+        let source = "#!/usr/bin/env -S bfc --run\n+";
+        let expected = [Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 28, end: 28 }),
+        }];
+        assert_eq!(parse(source).unwrap(), expected);
+        assert_eq!(parse_strict(source).unwrap(), expected);
+    }
+
     #[test]
     fn test_combine_pos() {
         let pos1 = Some(Position { start: 1, end: 2 });
@@ -447,4 +970,173 @@ mod tests {
 
         assert_eq!(pos1.combine(pos2), Some(Position { start: 1, end: 3 }));
     }
+
+    #[test]
+    fn to_bf_source_roundtrip() {
+        let src = "+>-<[,.]";
+        assert_eq!(to_bf_source(&parse(src).unwrap()).unwrap(), src);
+    }
+
+    #[test]
+    fn to_bf_source_strips_comments() {
+        let instrs = parse("#!/usr/bin/env bfc\n+ add one\n.").unwrap();
+        assert_eq!(to_bf_source(&instrs).unwrap(), "+.");
+    }
+
+    #[test]
+    fn parse_with_comments_positions() {
+        let (instrs, comments) = parse_with_comments("+ add one\n[-] clear", false).unwrap();
+        assert_eq!(instrs.len(), 2);
+        assert_eq!(
+            comments,
+            vec![
+                Comment {
+                    text: " add one\n".to_owned(),
+                    position: Position { start: 1, end: 9 },
+                },
+                Comment {
+                    text: " clear".to_owned(),
+                    position: Position { start: 13, end: 18 },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_with_comments_bang_header() {
+        let (_, comments) = parse_with_comments("#!/usr/bin/env bfc --run\n+", false).unwrap();
+        assert_eq!(
+            comments,
+            vec![Comment {
+                text: "#!/usr/bin/env bfc --run\n".to_owned(),
+                position: Position { start: 0, end: 24 },
+            }]
+        );
+    }
+
+    #[test]
+    fn to_bf_source_with_comments_roundtrip() {
+        let src = "#!/usr/bin/env bfc\n+ add one\n[ loop - decrement ] done\n.";
+        let (instrs, comments) = parse_with_comments(src, false).unwrap();
+        assert_eq!(to_bf_source_with_comments(&instrs, &comments).unwrap(), src);
+    }
+
+    #[test]
+    fn to_bf_source_offsets() {
+        let instrs = vec![
+            Increment {
+                amount: Wrapping(-2),
+                offset: 2,
+                position: None,
+            },
+            Set {
+                amount: Wrapping(1),
+                offset: -1,
+                position: None,
+            },
+        ];
+        assert_eq!(to_bf_source(&instrs).unwrap(), ">>--<<<[-]+>");
+    }
+
+    #[test]
+    fn to_bf_source_read_and_write_offsets() {
+        let instrs = vec![
+            Read {
+                offset: 1,
+                position: None,
+            },
+            Write {
+                offset: -2,
+                position: None,
+            },
+        ];
+        assert_eq!(to_bf_source(&instrs).unwrap(), ">,<<<.>>");
+    }
+
+    #[test]
+    fn to_bf_source_multiply_move() {
+        let instrs = vec![MultiplyMove {
+            changes: HashMap::from([(2, Wrapping(3)), (-1, Wrapping(-1))]),
+            position: None,
+        }];
+        assert_eq!(to_bf_source(&instrs).unwrap(), "[-<->>>+++<<]");
+    }
+
+    #[test]
+    fn to_bf_source_copy_is_error() {
+        let instrs = vec![MultiplyMove {
+            changes: HashMap::from([(0, Wrapping(1)), (1, Wrapping(1))]),
+            position: None,
+        }];
+        assert!(to_bf_source(&instrs).is_err());
+    }
+
+    #[test]
+    fn to_bf_source_loop_offset_and_scan() {
+        let instrs = vec![
+            Loop {
+                body: vec![Write {
+                    offset: 0,
+                    position: None,
+                }],
+                offset: 1,
+                position: None,
+            },
+            ScanLoop {
+                step: -2,
+                position: None,
+            },
+        ];
+        assert_eq!(to_bf_source(&instrs).unwrap(), ">[<.>]<[<<]");
+    }
+
+    #[test]
+    fn to_bf_source_infinite_loop() {
+        let instrs = vec![InfiniteLoop {
+            offset: -1,
+            position: None,
+        }];
+        assert_eq!(to_bf_source(&instrs).unwrap(), "<[]>");
+    }
+
+    fn to_bf_source_is_equivalent(instrs: Vec<AstNode>) -> TestResult {
+        let src = match to_bf_source(&instrs) {
+            Ok(src) => src,
+            Err(_) => return TestResult::discard(),
+        };
+        let roundtrip_instrs = parse(&src).unwrap();
+
+        let max_steps = 1000;
+        let mut state = ExecutionState::initial(&instrs);
+        let result = execute_with_state(&instrs, &mut state, max_steps, &mut std::iter::empty());
+        match result {
+            Completed(_) | ReachedRuntimeValue => (),
+            RuntimeError(_) | OutOfSteps => return TestResult::discard(),
+        }
+
+        // Every optimised instruction may expand to hundreds of BF
+        // commands, e.g. a Set is a [-] loop followed by increments.
+        let mut roundtrip_state = ExecutionState::initial(&instrs);
+        let roundtrip_result = execute_with_state(
+            &roundtrip_instrs,
+            &mut roundtrip_state,
+            max_steps * 1000,
+            &mut std::iter::empty(),
+        );
+
+        let same_outcome = matches!(
+            (result, roundtrip_result),
+            (Completed(_), Completed(_)) | (ReachedRuntimeValue, ReachedRuntimeValue)
+        );
+        TestResult::from_bool(
+            same_outcome
+                && state.outputs == roundtrip_state.outputs
+                && state.cells == roundtrip_state.cells,
+        )
+    }
+
+    #[test]
+    fn quickcheck_to_bf_source_is_equivalent() {
+        quickcheck(to_bf_source_is_equivalent as fn(Vec<AstNode>) -> TestResult);
+    }
 }
@@ -1,8 +1,8 @@
 #![warn(trivial_numeric_casts)]
 
-//! Calculate the maximum cell accessed by a BF program.
+//! Calculate the range of cells accessed by a BF program.
 
-use std::cmp::{max, Ord, Ordering};
+use std::cmp::{max, min, Ord, Ordering};
 use std::ops::Add;
 
 use crate::bfir::AstNode;
@@ -11,6 +11,10 @@ use crate::bfir::AstNode::*;
 // 100,000 cells, zero-indexed.
 pub const MAX_CELL_INDEX: usize = 99999;
 
+/// The number of cells when the tape wraps around, matching the
+/// classic BF implementation.
+pub const WRAPPING_TAPE_LEN: usize = 30_000;
+
 /// Return the highest cell index that can be reached during program
 /// execution. Zero-indexed.
 pub fn highest_cell_index(instrs: &[AstNode]) -> usize {
@@ -29,6 +33,83 @@ pub fn highest_cell_index(instrs: &[AstNode]) -> usize {
     }
 }
 
+/// Return the lowest and highest cell indexes that can be reached
+/// during program execution. Zero-indexed.
+///
+/// The pointer starts at cell #0, so the lowest index is never
+/// positive, and the tape always starts at cell #0. A negative lowest
+/// index means the program moves before the first cell, which is an
+/// error at runtime.
+pub fn cell_range(instrs: &[AstNode]) -> (isize, isize) {
+    (
+        lowest_cell_index(instrs),
+        highest_cell_index(instrs) as isize,
+    )
+}
+
+/// Return the lowest cell index that the program reaches whenever
+/// the code containing it runs. This is never positive.
+///
+/// A negative index is always an error, but we only know it's
+/// reached if the enclosing loops run, so e.g. `,[<]` may be fine.
+pub fn lowest_cell_index(instrs: &[AstNode]) -> isize {
+    let (lowest_index, _) = lowest_movement(instrs);
+    max(lowest_index, -(MAX_CELL_INDEX as i64)) as isize
+}
+
+/// A top-level instruction that accesses a cell before cell #0.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NegativeAccess {
+    /// The index of the instruction in the top-level instructions.
+    pub index: usize,
+    /// The lowest cell index that the instruction reaches.
+    pub cell_index: isize,
+}
+
+/// Find the first top-level instruction that always accesses a cell
+/// before cell #0, when the program starts at `start_cell`. Loop
+/// bodies may not run, so we only consider the cell that each loop
+/// tests.
+pub fn first_negative_access(instrs: &[AstNode], start_cell: usize) -> Option<NegativeAccess> {
+    find_negative_access(instrs, start_cell, false)
+}
+
+/// Find the first top-level instruction that accesses a cell before
+/// cell #0 whenever it runs, including loops whose bodies do so.
+pub fn first_possible_negative_access(
+    instrs: &[AstNode],
+    start_cell: usize,
+) -> Option<NegativeAccess> {
+    find_negative_access(instrs, start_cell, true)
+}
+
+fn find_negative_access(
+    instrs: &[AstNode],
+    start_cell: usize,
+    include_loop_bodies: bool,
+) -> Option<NegativeAccess> {
+    let mut cell_index = start_cell as i64;
+    for (index, instr) in instrs.iter().enumerate() {
+        let (lowest_offset, net_movement) = lowest_instr_movement(instr);
+        let lowest_offset = match *instr {
+            Loop { offset, .. } if !include_loop_bodies => min(offset as i64, 0),
+            _ => lowest_offset,
+        };
+
+        if cell_index + lowest_offset < 0 {
+            return Some(NegativeAccess {
+                index,
+                cell_index: max(cell_index + lowest_offset, -(MAX_CELL_INDEX as i64)) as isize,
+            });
+        }
+
+        // Once we've lost track of the cell pointer, we can't say
+        // anything about later instructions.
+        cell_index += net_movement?;
+    }
+    None
+}
+
 /// Saturating arithmetic: we have normal integers that work as
 /// expected, but Max is bigger than any Number.
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
@@ -41,7 +122,9 @@ impl Add for SaturatingInt {
     type Output = SaturatingInt;
     fn add(self, rhs: SaturatingInt) -> SaturatingInt {
         if let (&SaturatingInt::Number(x), &SaturatingInt::Number(y)) = (&self, &rhs) {
-            SaturatingInt::Number(x + y)
+            // Offsets can be huge, so don't overflow.
+            x.checked_add(y)
+                .map_or(SaturatingInt::Max, SaturatingInt::Number)
         } else {
             SaturatingInt::Max
         }
@@ -98,7 +181,11 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
                 )
             }
         }
-        Increment { offset, .. } | Set { offset, .. } => (
+        Increment { offset, .. }
+        | Set { offset, .. }
+        | Read { offset, .. }
+        | Write { offset, .. }
+        | InfiniteLoop { offset, .. } => (
             SaturatingInt::Number(offset as i64),
             SaturatingInt::Number(0),
         ),
@@ -114,8 +201,20 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
                 SaturatingInt::Number(0),
             )
         }
-        Loop { ref body, .. } => {
+        CellCopy {
+            src_offset,
+            dst_offset,
+            ..
+        } => (
+            SaturatingInt::Number(max(src_offset, dst_offset) as i64),
+            SaturatingInt::Number(0),
+        ),
+        Loop {
+            ref body, offset, ..
+        } => {
             let (max_in_body, net_in_body) = overall_movement(body);
+            // The loop condition may test a cell at an offset.
+            let max_in_body = max(max_in_body, SaturatingInt::Number(offset as i64));
 
             match net_in_body {
                 SaturatingInt::Number(net_loop_movement) => {
@@ -138,7 +237,74 @@ fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
                 }
             }
         }
-        Read { .. } | Write { .. } => (SaturatingInt::Number(0), SaturatingInt::Number(0)),
+        ScanLoop { step, .. } => {
+            if step > 0 {
+                // We can't know how far we will scan, so we can't
+                // assume any bounds.
+                (SaturatingInt::Max, SaturatingInt::Max)
+            } else {
+                // Scanning backwards never reaches a higher cell, and
+                // we conservatively assume the scan stops immediately.
+                (SaturatingInt::Number(0), SaturatingInt::Number(0))
+            }
+        }
+    }
+}
+
+/// Return a tuple (lowest cell index reached, cell index at end).
+///
+/// Unlike the highest index, we only consider positions that we can
+/// track statically, so a negative index is always reached when this
+/// code runs. If we can't track the position at the end, return None.
+fn lowest_movement(instrs: &[AstNode]) -> (i64, Option<i64>) {
+    let mut net_movement = 0;
+    let mut min_index = 0;
+
+    for instr in instrs {
+        let (instr_lowest_offset, instr_net_movement) = lowest_instr_movement(instr);
+        min_index = min(min_index, net_movement + instr_lowest_offset);
+        match instr_net_movement {
+            Some(instr_net_movement) => net_movement += instr_net_movement,
+            None => return (min_index, None),
+        }
+    }
+    (min_index, Some(net_movement))
+}
+
+/// Return a tuple (lowest cell index reached, cell index at end) for
+/// a single instruction.
+fn lowest_instr_movement(instr: &AstNode) -> (i64, Option<i64>) {
+    match *instr {
+        PointerIncrement { amount, .. } => (min(amount as i64, 0), Some(amount as i64)),
+        Increment { offset, .. }
+        | Set { offset, .. }
+        | Read { offset, .. }
+        | Write { offset, .. }
+        | InfiniteLoop { offset, .. } => (min(offset as i64, 0), Some(0)),
+        MultiplyMove { ref changes, .. } => {
+            let lowest_affected = changes.keys().copied().min().unwrap_or(0);
+            (min(lowest_affected as i64, 0), Some(0))
+        }
+        CellCopy {
+            src_offset,
+            dst_offset,
+            ..
+        } => (min(min(src_offset, dst_offset) as i64, 0), Some(0)),
+        Loop {
+            ref body, offset, ..
+        } => {
+            let (min_in_body, net_in_body) = lowest_movement(body);
+            let min_in_body = min(min_in_body, offset as i64);
+
+            if net_in_body == Some(0) {
+                (min_in_body, Some(0))
+            } else {
+                // We don't know how many times the loop runs, so we
+                // don't know where we finish.
+                (min_in_body, None)
+            }
+        }
+        ScanLoop { .. } => (0, None),
     }
 }
 
@@ -204,6 +370,18 @@ mod tests {
         assert_eq!(highest_cell_index(&instrs), 4);
     }
 
+    #[test]
+    fn cell_copy_bounds() {
+        let instrs = vec![CellCopy {
+            src_offset: 3,
+            dst_offset: -1,
+            overwrite: false,
+            position: None,
+        }];
+        assert_eq!(highest_cell_index(&instrs), 3);
+        assert_eq!(lowest_cell_index(&instrs), -1);
+    }
+
     /// Multiply move uses offsets to the current pointer value.
     /// Verify we add to the current pointer value.
     #[test]
@@ -253,6 +431,37 @@ mod tests {
         assert_eq!(highest_cell_index(&instrs), 1);
     }
 
+    #[test]
+    fn scan_loop_bounds() {
+        let instrs = vec![ScanLoop {
+            step: 1,
+            position: Some(Position { start: 0, end: 2 }),
+        }];
+        assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
+
+        let instrs = vec![
+            PointerIncrement {
+                amount: 2,
+                position: Some(Position { start: 0, end: 1 }),
+            },
+            ScanLoop {
+                step: -1,
+                position: Some(Position { start: 2, end: 4 }),
+            },
+        ];
+        assert_eq!(highest_cell_index(&instrs), 2);
+    }
+
+    #[test]
+    fn loop_offset_bounds() {
+        let instrs = vec![Loop {
+            body: vec![],
+            offset: 3,
+            position: Some(Position { start: 0, end: 1 }),
+        }];
+        assert_eq!(highest_cell_index(&instrs), 3);
+    }
+
     #[test]
     fn excessive_bounds_truncated() {
         // TODO: we should generate a warning in this situation.
@@ -287,6 +496,126 @@ mod tests {
         quickcheck(highest_cell_index_in_bounds as fn(Vec<AstNode>) -> bool);
     }
 
+    #[test]
+    fn cell_range_one_cell() {
+        let instrs = parse("+-.,").unwrap();
+        assert_eq!(cell_range(&instrs), (0, 0));
+    }
+
+    #[test]
+    fn cell_range_negative() {
+        let instrs = parse("<+>").unwrap();
+        assert_eq!(cell_range(&instrs), (-1, 0));
+
+        // The loop body reaches cell #-2 whenever it runs.
+        let instrs = parse(",[<<+>>-]").unwrap();
+        assert_eq!(cell_range(&instrs), (-2, 0));
+    }
+
+    #[test]
+    fn lowest_cell_index_immediate() {
+        let instrs = parse("<+").unwrap();
+        assert_eq!(lowest_cell_index(&instrs), -1);
+    }
+
+    #[test]
+    fn lowest_cell_index_after_moving_right() {
+        let instrs = parse(">+<<+").unwrap();
+        assert_eq!(lowest_cell_index(&instrs), -1);
+        assert_eq!(highest_cell_index(&instrs), 1);
+
+        let instrs = parse(">+<+").unwrap();
+        assert_eq!(lowest_cell_index(&instrs), 0);
+    }
+
+    #[test]
+    fn first_negative_access_immediate() {
+        let instrs = parse("<+").unwrap();
+        assert_eq!(
+            first_negative_access(&instrs, 0),
+            Some(NegativeAccess {
+                index: 0,
+                cell_index: -1
+            })
+        );
+        assert_eq!(first_negative_access(&instrs, 1), None);
+    }
+
+    #[test]
+    fn first_negative_access_after_moving_right() {
+        let instrs = parse(">+<<+").unwrap();
+        assert_eq!(
+            first_negative_access(&instrs, 0),
+            Some(NegativeAccess {
+                index: 3,
+                cell_index: -1
+            })
+        );
+    }
+
+    #[test]
+    fn first_negative_access_in_loop() {
+        // The loop body may never run.
+        let instrs = parse(",[<<+>>-]").unwrap();
+        assert_eq!(first_negative_access(&instrs, 0), None);
+        assert_eq!(
+            first_possible_negative_access(&instrs, 0),
+            Some(NegativeAccess {
+                index: 1,
+                cell_index: -2
+            })
+        );
+    }
+
+    #[test]
+    fn first_negative_access_after_unknown_movement() {
+        let instrs = parse(",[>,]<<+").unwrap();
+        assert_eq!(first_negative_access(&instrs, 0), None);
+    }
+
+    #[test]
+    fn cell_range_negative_offset() {
+        let instrs = [Increment {
+            amount: Wrapping(1),
+            offset: -3,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        assert_eq!(cell_range(&instrs), (-3, 0));
+    }
+
+    #[test]
+    fn cell_range_high() {
+        let instrs = parse(">>>>>+<<.").unwrap();
+        assert_eq!(cell_range(&instrs), (0, 5));
+
+        let instrs = [Set {
+            amount: Wrapping(2),
+            offset: 1000,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        assert_eq!(cell_range(&instrs), (0, 1000));
+    }
+
+    #[test]
+    fn cell_range_unknown_position() {
+        // We don't know where the loops finish, so we can't tell
+        // whether the `<` moves before the first cell.
+        let instrs = parse(",[>,]<<.").unwrap();
+        assert_eq!(cell_range(&instrs), (0, MAX_CELL_INDEX as isize));
+
+        let instrs = parse(">>[<]<").unwrap();
+        assert_eq!(cell_range(&instrs), (0, 2));
+    }
+
+    #[test]
+    fn quickcheck_cell_range_in_bounds() {
+        fn cell_range_in_bounds(instrs: Vec<AstNode>) -> bool {
+            let (lowest, highest) = cell_range(&instrs);
+            lowest <= 0 && 0 <= highest && highest <= MAX_CELL_INDEX as isize
+        }
+        quickcheck(cell_range_in_bounds as fn(Vec<AstNode>) -> bool);
+    }
+
     #[test]
     fn increment_offset_bounds() {
         let instrs = [Increment {
@@ -313,4 +642,19 @@ mod tests {
         ];
         assert_eq!(highest_cell_index(&instrs), 11);
     }
+
+    #[test]
+    fn read_and_write_offset_bounds() {
+        let instrs = [
+            Read {
+                offset: 3,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Write {
+                offset: 4,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(highest_cell_index(&instrs), 4);
+    }
 }
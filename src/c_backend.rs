@@ -0,0 +1,248 @@
+//! Convert a BF AST to C source code.
+
+use std::fmt::Write as _;
+
+use crate::bfir::AstNode;
+use crate::bfir::AstNode::*;
+use crate::execution::ExecutionState;
+
+/// Return a C program equivalent to these instructions, starting
+/// from the state given by compile time execution.
+///
+/// As with LLVM codegen, runtime execution begins at
+/// `initial_state.start_instr`, which may be inside a loop. We use
+/// `goto` to jump there.
+pub fn compile_to_c(instrs: &[AstNode], initial_state: &ExecutionState) -> String {
+    let mut src = String::new();
+    src.push_str("#include <stdio.h>\n\n");
+
+    if !initial_state.outputs.is_empty() {
+        let outputs: Vec<String> = initial_state
+            .outputs
+            .iter()
+            .map(|value| (*value as u8).to_string())
+            .collect();
+        writeln!(
+            src,
+            "static const unsigned char known_outputs[{}] = {{{}}};\n",
+            outputs.len(),
+            outputs.join(", ")
+        )
+        .unwrap();
+    }
+
+    if let Some(start_instr) = initial_state.start_instr {
+        // C zero-initialises any cells after the last one we specify.
+        let initialised_len = initial_state
+            .cells
+            .iter()
+            .rposition(|value| value.0 != 0)
+            .map_or(1, |index| index + 1);
+        let cells: Vec<String> = initial_state.cells[..initialised_len]
+            .iter()
+            .map(|value| (value.0 as u8).to_string())
+            .collect();
+        writeln!(
+            src,
+            "static unsigned char cells[{}] = {{{}}};\n",
+            initial_state.cells.len(),
+            cells.join(", ")
+        )
+        .unwrap();
+
+        src.push_str("int main(void) {\n");
+        if !initial_state.outputs.is_empty() {
+            src.push_str("    fwrite(known_outputs, 1, sizeof(known_outputs), stdout);\n");
+        }
+        writeln!(src, "    long ptr = {};\n", initial_state.cell_ptr).unwrap();
+        src.push_str("    goto start;\n\n");
+
+        for instr in instrs {
+            compile_instr(instr, start_instr, 1, &mut src);
+        }
+    } else {
+        src.push_str("int main(void) {\n");
+        if !initial_state.outputs.is_empty() {
+            src.push_str("    fwrite(known_outputs, 1, sizeof(known_outputs), stdout);\n");
+        }
+    }
+
+    src.push_str("    return 0;\n}\n");
+    src
+}
+
+/// The C expression for the cell at `offset` from the current cell.
+fn cell(offset: isize) -> String {
+    if offset == 0 {
+        "cells[ptr]".to_owned()
+    } else if offset < 0 {
+        format!("cells[ptr - {}]", -offset)
+    } else {
+        format!("cells[ptr + {}]", offset)
+    }
+}
+
+fn compile_instr(instr: &AstNode, start_instr: &AstNode, depth: usize, src: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    if std::ptr::eq(instr, start_instr) {
+        // A label must be followed by a statement, so add an empty one.
+        writeln!(src, "{}start:;", indent).unwrap();
+    }
+
+    match instr {
+        Increment { amount, offset, .. } => {
+            writeln!(src, "{}{} += {};", indent, cell(*offset), amount.0 as u8).unwrap();
+        }
+        Set { amount, offset, .. } => {
+            writeln!(src, "{}{} = {};", indent, cell(*offset), amount.0 as u8).unwrap();
+        }
+        MultiplyMove { changes, .. } => {
+            // Apply changes in offset order, so output is deterministic.
+            let mut changes: Vec<_> = changes.iter().collect();
+            changes.sort();
+
+            writeln!(src, "{}if (cells[ptr]) {{", indent).unwrap();
+            writeln!(src, "{}    unsigned char value = cells[ptr];", indent).unwrap();
+            writeln!(src, "{}    cells[ptr] = 0;", indent).unwrap();
+            for (offset, factor) in changes {
+                writeln!(
+                    src,
+                    "{}    {} += value * {};",
+                    indent,
+                    cell(*offset),
+                    factor.0 as u8
+                )
+                .unwrap();
+            }
+            writeln!(src, "{}}}", indent).unwrap();
+        }
+        CellCopy {
+            src_offset,
+            dst_offset,
+            overwrite,
+            ..
+        } => {
+            let op = if *overwrite { "=" } else { "+=" };
+            writeln!(
+                src,
+                "{}{} {} {};",
+                indent,
+                cell(*dst_offset),
+                op,
+                cell(*src_offset)
+            )
+            .unwrap();
+        }
+        PointerIncrement { amount, .. } => {
+            writeln!(src, "{}ptr += {};", indent, amount).unwrap();
+        }
+        Read { offset, .. } => {
+            writeln!(src, "{}{} = getchar();", indent, cell(*offset)).unwrap();
+        }
+        Write { offset, .. } => {
+            writeln!(src, "{}putchar({});", indent, cell(*offset)).unwrap();
+        }
+        Loop { body, offset, .. } => {
+            writeln!(src, "{}while ({}) {{", indent, cell(*offset)).unwrap();
+            for body_instr in body {
+                compile_instr(body_instr, start_instr, depth + 1, src);
+            }
+            writeln!(src, "{}}}", indent).unwrap();
+        }
+        ScanLoop { step, .. } => {
+            writeln!(src, "{}while (cells[ptr]) {{", indent).unwrap();
+            writeln!(src, "{}    ptr += {};", indent, step).unwrap();
+            writeln!(src, "{}}}", indent).unwrap();
+        }
+        InfiniteLoop { offset, .. } => {
+            // C compilers may assume that a loop with a non-constant
+            // condition and no side effects terminates, so use for (;;).
+            writeln!(src, "{}if ({}) {{", indent, cell(*offset)).unwrap();
+            writeln!(src, "{}    fflush(stdout);", indent).unwrap();
+            writeln!(src, "{}    for (;;) {{}}", indent).unwrap();
+            writeln!(src, "{}}}", indent).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::num::Wrapping;
+
+    use crate::bfir::parse;
+
+    #[test]
+    fn compile_loop_to_c() {
+        let instrs = parse(",[>+<-]>.").unwrap();
+        let mut state = ExecutionState::initial(&instrs);
+        state.start_instr = instrs.first();
+
+        let expected = "#include <stdio.h>
+
+static unsigned char cells[2] = {0};
+
+int main(void) {
+    long ptr = 0;
+
+    goto start;
+
+    start:;
+    cells[ptr] = getchar();
+    while (cells[ptr]) {
+        ptr += 1;
+        cells[ptr] += 1;
+        ptr += -1;
+        cells[ptr] += 255;
+    }
+    ptr += 1;
+    putchar(cells[ptr]);
+    return 0;
+}
+";
+        assert_eq!(compile_to_c(&instrs, &state), expected);
+    }
+
+    #[test]
+    fn compile_read_and_write_offsets_to_c() {
+        let instrs = vec![
+            Read {
+                offset: 1,
+                position: None,
+            },
+            Write {
+                offset: 1,
+                position: None,
+            },
+        ];
+        let mut state = ExecutionState::initial(&instrs);
+        state.start_instr = instrs.first();
+
+        let c_src = compile_to_c(&instrs, &state);
+        assert!(c_src.contains("cells[ptr + 1] = getchar();"), "{}", c_src);
+        assert!(c_src.contains("putchar(cells[ptr + 1]);"), "{}", c_src);
+    }
+
+    #[test]
+    fn compile_static_outputs_to_c() {
+        let state = ExecutionState {
+            start_instr: None,
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![72, 105],
+        };
+
+        let expected = "#include <stdio.h>
+
+static const unsigned char known_outputs[2] = {72, 105};
+
+int main(void) {
+    fwrite(known_outputs, 1, sizeof(known_outputs), stdout);
+    return 0;
+}
+";
+        assert_eq!(compile_to_c(&[], &state), expected);
+    }
+}
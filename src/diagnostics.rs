@@ -1,9 +1,317 @@
-//! Human-readable warnings and errors for the CLI.
+//! Warnings and errors reported by the CLI.
 
-use crate::bfir::Position;
+use std::fmt::Write as _;
+use std::num::Wrapping;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{AstNode, BfValue, Position};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Warning {
     pub message: String,
     pub position: Option<Position>,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// An error or warning to report to the user, either with ariadne or
+/// as JSON for editors and other tools.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A summary of the kind of problem, e.g. "Parse error".
+    pub title: String,
+    /// Details of this specific problem.
+    pub message: String,
+    pub position: Option<Position>,
+}
+
+impl Diagnostic {
+    /// Serialise this diagnostic as a single line JSON object, e.g.
+    ///
+    /// ```text
+    /// {"file":"foo.bf","severity":"error","title":"Parse error","message":"This [ has no matching ]","start":0,"end":0}
+    /// ```
+    ///
+    /// `start` and `end` are inclusive character offsets into the
+    /// source file, or `null` if we don't know the position.
+    pub fn to_json(&self, path: &str) -> String {
+        let (start, end) = match self.position {
+            Some(Position { start, end }) => (start.to_string(), end.to_string()),
+            None => ("null".to_owned(), "null".to_owned()),
+        };
+
+        format!(
+            "{{\"file\":{},\"severity\":\"{}\",\"title\":{},\"message\":{},\"start\":{},\"end\":{}}}",
+            json_string(path),
+            self.severity.as_str(),
+            json_string(&self.title),
+            json_string(&self.message),
+            start,
+            end
+        )
+    }
+}
+
+/// Warn about loops whose body never decreases the cell the loop
+/// tests, such as `[+]` or `[>+<]`. These loops either rely on the
+/// cell wrapping around to zero or never terminate, so they're
+/// probably mistakes.
+///
+/// We only warn when we can see every change to the loop cell, so the
+/// body may only contain increments, writes, and reads or sets of
+/// other cells, with no net pointer movement.
+///
+/// Loops at the start of the program or straight after another loop
+/// never run, so they're often used for comments, e.g.
+/// `[-][ clear the cell. ]`. We don't warn about those.
+pub fn non_decreasing_loop_warnings(instrs: &[AstNode]) -> Vec<Warning> {
+    non_decreasing_loop_warnings_inner(instrs, true)
+}
+
+fn non_decreasing_loop_warnings_inner(instrs: &[AstNode], top_level: bool) -> Vec<Warning> {
+    let mut warnings = vec![];
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Loop {
+            body,
+            offset,
+            position,
+        } = instr
+        {
+            let is_dead = match index.checked_sub(1).map(|i| &instrs[i]) {
+                None => top_level,
+                Some(Loop {
+                    offset: prev_offset,
+                    ..
+                })
+                | Some(InfiniteLoop {
+                    offset: prev_offset,
+                    ..
+                }) => prev_offset == offset,
+                Some(ScanLoop { .. }) => *offset == 0,
+                Some(_) => false,
+            };
+
+            if let Some(delta) = loop_cell_delta(body, *offset).filter(|_| !is_dead) {
+                let message = if delta.0 == 0 {
+                    "This loop never changes its cell, so it never terminates once entered."
+                } else {
+                    "This loop only increases its cell, so it relies on the cell wrapping around to zero."
+                };
+                if delta.0 >= 0 {
+                    warnings.push(Warning {
+                        message: message.to_owned(),
+                        position: *position,
+                    });
+                }
+            }
+            warnings.extend(non_decreasing_loop_warnings_inner(body, false));
+        }
+    }
+    warnings
+}
+
+/// Remove warnings about code that a warning in `reported` already
+/// covers. The optimiser warns about loops that it knows never
+/// terminate, so this stops us reporting those loops twice.
+pub fn without_reported(warnings: Vec<Warning>, reported: &[Warning]) -> Vec<Warning> {
+    let overlaps = |pos1: Option<Position>, pos2: Option<Position>| match (pos1, pos2) {
+        (Some(pos1), Some(pos2)) => pos1.start <= pos2.end && pos2.start <= pos1.end,
+        _ => false,
+    };
+    warnings
+        .into_iter()
+        .filter(|warning| {
+            !reported
+                .iter()
+                .any(|other| overlaps(warning.position, other.position))
+        })
+        .collect()
+}
+
+/// How much `body` changes the cell at `loop_offset` on every
+/// iteration, or None if we can't tell.
+fn loop_cell_delta(body: &[AstNode], loop_offset: isize) -> Option<BfValue> {
+    let mut ptr_offset = 0;
+    let mut delta = Wrapping(0);
+    for instr in body {
+        match instr {
+            Increment { amount, offset, .. } => {
+                if ptr_offset + offset == loop_offset {
+                    delta += *amount;
+                }
+            }
+            Read { offset, .. } | Set { offset, .. } => {
+                if ptr_offset + offset == loop_offset {
+                    return None;
+                }
+            }
+            PointerIncrement { amount, .. } => ptr_offset += amount,
+            Write { .. } => {}
+            Loop { .. }
+            | MultiplyMove { .. }
+            | CellCopy { .. }
+            | ScanLoop { .. }
+            | InfiniteLoop { .. } => return None,
+        }
+    }
+
+    if ptr_offset == 0 {
+        Some(delta)
+    } else {
+        None
+    }
+}
+
+/// Quote and escape `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => {
+                write!(result, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    use crate::bfir::parse;
+
+    #[test]
+    fn diagnostic_to_json() {
+        let diagnostic = Diagnostic {
+            severity: Severity::Warning,
+            title: "Suspicious code".to_owned(),
+            message: "This loop is dead".to_owned(),
+            position: Some(Position { start: 2, end: 5 }),
+        };
+        assert_eq!(
+            diagnostic.to_json("foo.bf"),
+            r#"{"file":"foo.bf","severity":"warning","title":"Suspicious code","message":"This loop is dead","start":2,"end":5}"#
+        );
+    }
+
+    #[test]
+    fn diagnostic_to_json_no_position() {
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            title: "Parse error".to_owned(),
+            message: "".to_owned(),
+            position: None,
+        };
+        assert_eq!(
+            diagnostic.to_json("foo.bf"),
+            r#"{"file":"foo.bf","severity":"error","title":"Parse error","message":"","start":null,"end":null}"#
+        );
+    }
+
+    fn loop_warnings(src: &str) -> Vec<String> {
+        let instrs = parse(src).unwrap();
+        non_decreasing_loop_warnings(&instrs)
+            .into_iter()
+            .map(|warning| warning.message)
+            .collect()
+    }
+
+    #[test]
+    fn warn_on_incrementing_loop() {
+        let instrs = parse("+[+]").unwrap();
+        assert_eq!(
+            non_decreasing_loop_warnings(&instrs),
+            vec![Warning {
+                message: "This loop only increases its cell, so it relies on the cell wrapping around to zero.".to_owned(),
+                position: Some(Position { start: 1, end: 3 }),
+            }]
+        );
+    }
+
+    #[test]
+    fn warn_on_loop_that_never_changes_its_cell() {
+        assert_eq!(
+            loop_warnings("+[>+<]"),
+            vec!["This loop never changes its cell, so it never terminates once entered."]
+        );
+    }
+
+    #[test]
+    fn no_warning_on_decrementing_loop() {
+        assert!(loop_warnings("+[-]").is_empty());
+        assert!(loop_warnings("+[->+<]").is_empty());
+        assert!(loop_warnings("+[+>+<--]").is_empty());
+    }
+
+    #[test]
+    fn no_warning_on_loop_that_moves() {
+        assert!(loop_warnings("+[>]").is_empty());
+        assert!(loop_warnings("+[>+]").is_empty());
+    }
+
+    /// Reads and inner loops may change the cell, so we can't tell.
+    #[test]
+    fn no_warning_on_unknown_loop_body() {
+        assert!(loop_warnings("+[+,]").is_empty());
+        assert!(loop_warnings("+[+>[-]<]").is_empty());
+    }
+
+    #[test]
+    fn no_warning_on_comment_loops() {
+        assert!(loop_warnings("[ comment ]+").is_empty());
+        assert!(loop_warnings("+[-][ comment ]").is_empty());
+        assert!(loop_warnings("+[>][ comment ]").is_empty());
+    }
+
+    #[test]
+    fn warn_on_nested_incrementing_loop() {
+        assert_eq!(
+            loop_warnings("+[>+[+]<-]"),
+            vec!["This loop only increases its cell, so it relies on the cell wrapping around to zero."]
+        );
+    }
+
+    #[test]
+    fn no_warning_on_loops_the_optimiser_reports() {
+        let instrs = parse(",+[-]+[]").unwrap();
+        let (_, reported) =
+            crate::peephole::optimize(instrs.clone(), crate::peephole::OptimizeOptions::default());
+        assert!(!reported.is_empty());
+
+        let warnings = non_decreasing_loop_warnings(&instrs);
+        assert_eq!(warnings.len(), 1);
+        assert!(without_reported(warnings, &reported).is_empty());
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(
+            json_string("a \"b\" \\ c\n\u{1}é"),
+            r#""a \"b\" \\ c\n\u0001é""#
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Errors that stop bfc from compiling a program.
+
+use std::fmt;
+use std::io;
+
+use crate::bfir::ParseError;
+use crate::diagnostics::Warning;
+
+#[derive(Debug)]
+pub enum CompileError {
+    /// Reading or writing `path` failed.
+    Io { path: String, error: io::Error },
+    /// The BF source could not be parsed.
+    Parse(ParseError),
+    /// The program always moves before the first cell.
+    CellOutOfRange(Warning),
+    /// Compile time execution stopped after reading `read` of the
+    /// `len` bytes of known input, so the rest would be lost.
+    UnreadInput { read: usize, len: usize },
+    /// Compile time execution wrote too much output, so the program
+    /// would run at runtime and lose its known input.
+    DiscardedInput,
+    /// Linking failed. `status` is the linker's exit code, or None if
+    /// it was killed by a signal.
+    Link {
+        status: Option<i32>,
+        stdout: String,
+        stderr: String,
+    },
+    /// We couldn't start the linker `command`.
+    Spawn { command: String, error: io::Error },
+    /// The linker can't link the executable we've been asked for.
+    UnsupportedLinker(String),
+    /// LLVM could not generate code for the program.
+    Llvm(String),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Io { path, error } => write!(f, "{}: {}", path, error),
+            CompileError::Parse(ParseError { message, position }) => {
+                write!(f, "Parse error at offset {}: {}", position.start, message)
+            }
+            CompileError::CellOutOfRange(Warning { message, position }) => match position {
+                Some(position) => write!(
+                    f,
+                    "Out of range cell access at offset {}: {}",
+                    position.start, message
+                ),
+                None => write!(f, "Out of range cell access: {}", message),
+            },
+            CompileError::UnreadInput { read, len } => write!(
+                f,
+                "Compile time execution only read {} of the {} bytes of input, \
+                 but the compiled program reads its remaining input from stdin.",
+                read, len
+            ),
+            CompileError::DiscardedInput => write!(
+                f,
+                "Compile time execution wrote more than the maximum static output, \
+                 so the program's input would be lost at runtime."
+            ),
+            CompileError::Link {
+                status,
+                stdout,
+                stderr,
+            } => {
+                match status {
+                    Some(status) => write!(f, "Linking failed with exit code {}.", status)?,
+                    None => write!(
+                        f,
+                        "Linking failed because the linker was killed by a signal."
+                    )?,
+                }
+                // Linkers write most errors to stderr, but some
+                // (e.g. MSVC-style drivers) use stdout.
+                for output in [stdout, stderr] {
+                    if !output.trim().is_empty() {
+                        write!(f, "\n{}", output.trim_end())?;
+                    }
+                }
+                Ok(())
+            }
+            CompileError::Spawn { command, error } => {
+                if error.kind() == io::ErrorKind::NotFound {
+                    write!(f, "Could not execute '{}'. Is it on $PATH?", command)
+                } else {
+                    write!(f, "Could not execute '{}': {}", command, error)
+                }
+            }
+            CompileError::UnsupportedLinker(message) => write!(f, "{}", message),
+            CompileError::Llvm(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::Io { error, .. } | CompileError::Spawn { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::Position;
+
+    #[test]
+    fn display_parse_error() {
+        let error = CompileError::Parse(ParseError {
+            message: "This [ has no matching ]".to_owned(),
+            position: Position { start: 3, end: 3 },
+        });
+        assert_eq!(
+            error.to_string(),
+            "Parse error at offset 3: This [ has no matching ]"
+        );
+    }
+
+    #[test]
+    fn display_cell_out_of_range() {
+        let error = CompileError::CellOutOfRange(Warning {
+            message: "This instruction moves to cell #-1, but the first cell is #0.".to_owned(),
+            position: Some(Position { start: 1, end: 1 }),
+        });
+        assert_eq!(
+            error.to_string(),
+            "Out of range cell access at offset 1: This instruction moves to cell #-1, but the first cell is #0."
+        );
+    }
+
+    #[test]
+    fn display_unread_input() {
+        let error = CompileError::UnreadInput { read: 1, len: 2 };
+        assert_eq!(
+            error.to_string(),
+            "Compile time execution only read 1 of the 2 bytes of input, \
+             but the compiled program reads its remaining input from stdin."
+        );
+    }
+
+    #[test]
+    fn display_link_error_without_output() {
+        let error = CompileError::Link {
+            status: Some(1),
+            stdout: "".to_owned(),
+            stderr: "".to_owned(),
+        };
+        assert_eq!(error.to_string(), "Linking failed with exit code 1.");
+    }
+
+    #[test]
+    fn display_link_error_with_output() {
+        let error = CompileError::Link {
+            status: Some(1),
+            stdout: "linking foo.o\n".to_owned(),
+            stderr: "ld: cannot find -lc\n".to_owned(),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Linking failed with exit code 1.\nlinking foo.o\nld: cannot find -lc"
+        );
+    }
+
+    #[test]
+    fn display_link_error_killed_by_signal() {
+        let error = CompileError::Link {
+            status: None,
+            stdout: "".to_owned(),
+            stderr: "".to_owned(),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Linking failed because the linker was killed by a signal."
+        );
+    }
+
+    #[test]
+    fn display_spawn_error_not_found() {
+        let error = CompileError::Spawn {
+            command: "clang".to_owned(),
+            error: io::Error::from(io::ErrorKind::NotFound),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Could not execute 'clang'. Is it on $PATH?"
+        );
+    }
+}
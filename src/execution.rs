@@ -3,14 +3,15 @@
 //! Compile time execution of BF programs.
 
 use std::env;
+use std::io;
 use std::num::Wrapping;
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{AstNode, BfValue};
+use crate::bfir::{get_position, AstNode, BfValue};
 
 use crate::diagnostics::Warning;
 
-use crate::bounds::highest_cell_index;
+use crate::bounds::{highest_cell_index, WRAPPING_TAPE_LEN};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ExecutionState<'a> {
@@ -22,15 +23,70 @@ pub struct ExecutionState<'a> {
 
 impl<'a> ExecutionState<'a> {
     pub fn initial(instrs: &[AstNode]) -> Self {
+        Self::initial_on_tape(instrs, Tape::Bounded, 0)
+    }
+
+    /// The state before execution, with the cell pointer at
+    /// `start_cell`. On a bounded tape, we allocate enough cells
+    /// after `start_cell` for the program, so it can move left of its
+    /// starting cell by up to `start_cell` cells.
+    pub fn initial_on_tape(instrs: &[AstNode], tape: Tape, start_cell: usize) -> Self {
+        let (num_cells, cell_ptr) = match tape {
+            Tape::Bounded => (start_cell + highest_cell_index(instrs) + 1, start_cell),
+            Tape::Wrapping => (WRAPPING_TAPE_LEN, start_cell % WRAPPING_TAPE_LEN),
+        };
         ExecutionState {
             start_instr: None,
-            cells: vec![Wrapping(0); highest_cell_index(instrs) + 1],
-            cell_ptr: 0,
+            cells: vec![Wrapping(0); num_cells],
+            cell_ptr: cell_ptr as isize,
             outputs: vec![],
         }
     }
 }
 
+/// What happens when the pointer moves outside the cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Tape {
+    /// We allocate enough cells for the highest cell the program
+    /// can reach, and moving before the first cell is an error.
+    #[default]
+    Bounded,
+    /// The cells form a circle of `WRAPPING_TAPE_LEN` cells, so
+    /// moving left from the first cell reaches the last cell.
+    Wrapping,
+}
+
+/// The index of the cell `offset` cells after `cell_ptr`. On a
+/// wrapping tape, this is always a valid index.
+///
+/// Offsets may be arbitrarily large, so this never overflows. On a
+/// bounded tape, the result is out of range instead.
+fn offset_cell_ptr(cell_ptr: isize, offset: isize, num_cells: usize, tape: Tape) -> isize {
+    match tape {
+        Tape::Bounded => cell_ptr.saturating_add(offset),
+        Tape::Wrapping => {
+            let num_cells = num_cells as isize;
+            (cell_ptr.rem_euclid(num_cells) + offset.rem_euclid(num_cells)).rem_euclid(num_cells)
+        }
+    }
+}
+
+/// The index of the cell `offset` cells after `cell_ptr`, or the
+/// out-of-range cell number if there's no such cell.
+fn cell_address(
+    cell_ptr: isize,
+    offset: isize,
+    num_cells: usize,
+    tape: Tape,
+) -> Result<usize, isize> {
+    let target_cell_ptr = offset_cell_ptr(cell_ptr, offset, num_cells, tape);
+    if target_cell_ptr < 0 || target_cell_ptr >= num_cells as isize {
+        Err(target_cell_ptr)
+    } else {
+        Ok(target_cell_ptr as usize)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Outcome {
     // Return the number of steps remaining at completion.
@@ -45,7 +101,27 @@ pub fn max_steps() -> u64 {
     // It takes around 1 million steps to finish executing bottles.bf
     // at compile time. This is intolerably slow for debug builds of
     // bfc, but instant on a release build.
-    let mut steps = 10_000_000;
+    steps_from_env(10_000_000)
+}
+
+/// The maximum number of steps we should execute at compile time
+/// for this program.
+///
+/// Programs that never read input are deterministic, so compile time
+/// execution can always run them to completion given enough
+/// steps. We allow more steps for these programs, so we can compile
+/// them to their output where feasible.
+pub fn max_steps_for(instrs: &[AstNode]) -> u64 {
+    if reads_input(instrs) {
+        max_steps()
+    } else {
+        steps_from_env(100_000_000)
+    }
+}
+
+/// Return the number of steps specified by the user, or `default`.
+fn steps_from_env(default: u64) -> u64 {
+    let mut steps = default;
 
     if let Some(val) = env::var_os("BFC_MAX_STEPS") {
         steps = val.to_str().unwrap().parse::<u64>().unwrap_or(steps);
@@ -53,12 +129,78 @@ pub fn max_steps() -> u64 {
     steps
 }
 
+/// Does this program contain any read instructions?
+pub fn reads_input(instrs: &[AstNode]) -> bool {
+    instrs.iter().any(|instr| match instr {
+        Read { .. } => true,
+        Loop { body, .. } => reads_input(body),
+        _ => false,
+    })
+}
+
 /// Compile time speculative execution of instructions. We return the
 /// final state of the cells, any print side effects, and the point in
 /// the code we reached.
 pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warning>) {
-    let mut state = ExecutionState::initial(instrs);
-    let outcome = execute_with_state(instrs, &mut state, steps, None);
+    execute_with_input(instrs, steps, &[])
+}
+
+/// Compile time speculative execution, where we know the first bytes
+/// the program will read. Once we've used all of `input`, reads
+/// happen at runtime as usual.
+pub fn execute_with_input<'a>(
+    instrs: &'a [AstNode],
+    steps: u64,
+    input: &[u8],
+) -> (ExecutionState<'a>, Option<Warning>) {
+    execute_with_input_on_tape(instrs, steps, &mut input.iter().copied(), Tape::Bounded, 0)
+}
+
+/// Compile time speculative execution with known input, as
+/// `execute_with_input`, on this kind of tape, starting at
+/// `start_cell`. Reads consume bytes from `input`, so afterwards it
+/// only has the bytes the program didn't read.
+pub fn execute_with_input_on_tape<'a>(
+    instrs: &'a [AstNode],
+    steps: u64,
+    input: &mut dyn Iterator<Item = u8>,
+    tape: Tape,
+    start_cell: usize,
+) -> (ExecutionState<'a>, Option<Warning>) {
+    execute_with_writes(instrs, steps, input, tape, start_cell, &mut Writes::Collect)
+}
+
+/// Compile time speculative execution, as
+/// `execute_with_input_on_tape`, but stop before the first write. The
+/// resulting state never has any outputs, so every write happens at
+/// runtime.
+pub fn execute_until_write<'a>(
+    instrs: &'a [AstNode],
+    steps: u64,
+    input: &mut dyn Iterator<Item = u8>,
+    tape: Tape,
+    start_cell: usize,
+) -> (ExecutionState<'a>, Option<Warning>) {
+    execute_with_writes(
+        instrs,
+        steps,
+        input,
+        tape,
+        start_cell,
+        &mut Writes::StopBefore,
+    )
+}
+
+fn execute_with_writes<'a>(
+    instrs: &'a [AstNode],
+    steps: u64,
+    input: &mut dyn Iterator<Item = u8>,
+    tape: Tape,
+    start_cell: usize,
+    writes: &mut Writes,
+) -> (ExecutionState<'a>, Option<Warning>) {
+    let mut state = ExecutionState::initial_on_tape(instrs, tape, start_cell);
+    let outcome = execute_with_output(instrs, &mut state, steps, input, tape, writes);
 
     // Sanity check: if we have a start instruction we
     // can't have executed the entire program at compile time.
@@ -76,34 +218,144 @@ pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warnin
 /// Execute the instructions given, updating the state as we go.
 /// To avoid infinite loops, stop execution after `steps` steps.
 ///
-/// Execution also stops if we encounter a read instruction.  Users may
-/// alternatively pass in a dummy value for the read (used in testing).
+/// Read instructions consume bytes from `input`. Execution stops if
+/// we encounter a read instruction after `input` is exhausted.
 pub fn execute_with_state<'a>(
     instrs: &'a [AstNode],
     state: &mut ExecutionState<'a>,
     steps: u64,
-    dummy_read_value: Option<i8>,
+    input: &mut dyn Iterator<Item = u8>,
+) -> Outcome {
+    execute_with_state_on_tape(instrs, state, steps, input, Tape::Bounded)
+}
+
+/// Execute the instructions given, as `execute_with_state`. On a
+/// wrapping tape, `state` must have `WRAPPING_TAPE_LEN` cells.
+pub fn execute_with_state_on_tape<'a>(
+    instrs: &'a [AstNode],
+    state: &mut ExecutionState<'a>,
+    steps: u64,
+    input: &mut dyn Iterator<Item = u8>,
+    tape: Tape,
 ) -> Outcome {
+    execute_with_output(instrs, state, steps, input, tape, &mut Writes::Collect)
+}
+
+/// Run the whole program at runtime, rather than at compile time.
+///
+/// Output is written to `output` as soon as the program writes it,
+/// instead of collecting it in `ExecutionState::outputs`. It's an
+/// error if the program reads after `input` is exhausted, or reaches
+/// a loop that never terminates.
+pub fn interpret(
+    instrs: &[AstNode],
+    tape: Tape,
+    start_cell: usize,
+    input: &mut dyn Iterator<Item = u8>,
+    output: &mut dyn io::Write,
+) -> Result<(), Warning> {
+    let mut state = ExecutionState::initial_on_tape(instrs, tape, start_cell);
+    let outcome = execute_with_output(
+        instrs,
+        &mut state,
+        u64::MAX,
+        input,
+        tape,
+        &mut Writes::Stream(output),
+    );
+
+    let position = state.start_instr.and_then(get_position);
+    match outcome {
+        Outcome::Completed(_) => Ok(()),
+        Outcome::RuntimeError(warning) => Err(warning),
+        // We have unlimited steps, so we only stop early when we
+        // know a loop runs forever.
+        Outcome::OutOfSteps => Err(Warning {
+            message: "This loop never terminates.".to_owned(),
+            position,
+        }),
+        Outcome::ReachedRuntimeValue => Err(Warning {
+            message: "This read happens after the end of the input.".to_owned(),
+            position,
+        }),
+    }
+}
+
+/// What to do when the program writes a cell.
+enum Writes<'w> {
+    /// Collect the output in `ExecutionState::outputs`.
+    Collect,
+    /// Stop execution, so the write happens at runtime.
+    StopBefore,
+    /// Write the output immediately.
+    Stream(&'w mut dyn io::Write),
+}
+
+/// Execute the instructions given, as `execute_with_state_on_tape`,
+/// handling writes as `writes` says.
+fn execute_with_output<'a>(
+    instrs: &'a [AstNode],
+    state: &mut ExecutionState<'a>,
+    steps: u64,
+    input: &mut dyn Iterator<Item = u8>,
+    tape: Tape,
+    writes: &mut Writes,
+) -> Outcome {
+    let num_cells = state.cells.len();
     let mut steps_left = steps;
     let mut instr_idx = 0;
     while instr_idx < instrs.len() && steps_left > 0 {
         let cell_ptr = state.cell_ptr as usize;
 
         match instrs[instr_idx] {
-            Increment { amount, offset, .. } => {
-                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
+            Increment {
+                amount,
+                offset,
+                position,
+            } => {
+                let target_cell_ptr = match cell_address(state.cell_ptr, offset, num_cells, tape) {
+                    Ok(target_cell_ptr) => target_cell_ptr,
+                    Err(target_cell_ptr) => {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!(
+                                "This increment uses cell {}, which is out of range.",
+                                target_cell_ptr
+                            ),
+                            position,
+                        });
+                    }
+                };
+
                 state.cells[target_cell_ptr] += amount;
                 instr_idx += 1;
             }
-            Set { amount, offset, .. } => {
-                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
+            Set {
+                amount,
+                offset,
+                position,
+            } => {
+                let target_cell_ptr = match cell_address(state.cell_ptr, offset, num_cells, tape) {
+                    Ok(target_cell_ptr) => target_cell_ptr,
+                    Err(target_cell_ptr) => {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!(
+                                "This set uses cell {}, which is out of range.",
+                                target_cell_ptr
+                            ),
+                            position,
+                        });
+                    }
+                };
+
                 state.cells[target_cell_ptr] = amount;
                 instr_idx += 1;
             }
             PointerIncrement {
                 amount, position, ..
             } => {
-                let new_cell_ptr = state.cell_ptr + amount;
+                let new_cell_ptr = offset_cell_ptr(state.cell_ptr, amount, num_cells, tape);
                 if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
                     // We can't execute this instruction, so we'll
                     // execute it at runtime (it'll probably be an
@@ -137,56 +389,170 @@ pub fn execute_with_state<'a>(
                 let cell_value = state.cells[cell_ptr];
 
                 if cell_value.0 != 0 {
-                    // We will multiply by the current cell value.
-
-                    for (cell_offset, factor) in changes {
-                        let dest_ptr = cell_ptr as isize + *cell_offset;
-                        if dest_ptr < 0 {
-                            // Tried to access a cell before cell #0.
+                    // We will multiply by the current cell value. Check
+                    // that all the cells are in range before modifying
+                    // any, so runtime execution can start from this
+                    // instruction.
+                    for cell_offset in changes.keys() {
+                        if let Err(dest_ptr) =
+                            cell_address(state.cell_ptr, *cell_offset, num_cells, tape)
+                        {
                             state.start_instr = Some(&instrs[instr_idx]);
 
-                            // TODO: would be nice to have a Hint: message too in compiler warnings.
-                            let message = format!(
-                                "This multiply loop tried to access cell {} \
-                                 (offset {} from current cell {})",
-                                dest_ptr, *cell_offset, cell_ptr
-                            );
-
-                            return Outcome::RuntimeError(Warning { message, position });
-                        }
-                        if dest_ptr as usize >= state.cells.len() {
-                            state.start_instr = Some(&instrs[instr_idx]);
-                            return Outcome::RuntimeError(Warning {
-                                message: format!(
+                            let message = if dest_ptr < 0 {
+                                // Tried to access a cell before cell #0.
+                                // TODO: would be nice to have a Hint: message too in compiler warnings.
+                                format!(
+                                    "This multiply loop tried to access cell {} \
+                                     (offset {} from current cell {})",
+                                    dest_ptr, *cell_offset, cell_ptr
+                                )
+                            } else {
+                                format!(
                                     "This multiply loop tried to access cell {} (the \
                                      highest cell is {})",
                                     dest_ptr,
                                     state.cells.len() - 1
-                                ),
-                                position,
-                            });
+                                )
+                            };
+                            return Outcome::RuntimeError(Warning { message, position });
                         }
-
-                        let current_val = state.cells[dest_ptr as usize];
-                        state.cells[dest_ptr as usize] = current_val + cell_value * (*factor);
                     }
 
-                    // Finally, zero the cell we used.
+                    // Zero the cell we used. We do this first, so
+                    // changes with an offset of zero are preserved.
                     state.cells[cell_ptr] = Wrapping(0);
+
+                    for (cell_offset, factor) in changes {
+                        if let Ok(dest_ptr) =
+                            cell_address(state.cell_ptr, *cell_offset, num_cells, tape)
+                        {
+                            state.cells[dest_ptr] += cell_value * (*factor);
+                        }
+                    }
+                }
+
+                instr_idx += 1;
+            }
+            CellCopy {
+                src_offset,
+                dst_offset,
+                overwrite,
+                position,
+            } => {
+                // Check both cells before modifying either, so
+                // runtime execution can start from this instruction.
+                let (src_ptr, dst_ptr) = match (
+                    cell_address(state.cell_ptr, src_offset, num_cells, tape),
+                    cell_address(state.cell_ptr, dst_offset, num_cells, tape),
+                ) {
+                    (Ok(src_ptr), Ok(dst_ptr)) => (src_ptr, dst_ptr),
+                    (Err(target_cell_ptr), _) | (_, Err(target_cell_ptr)) => {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!(
+                                "This copy uses cell {}, which is out of range.",
+                                target_cell_ptr
+                            ),
+                            position,
+                        });
+                    }
+                };
+
+                // Read the source first, so copying a cell to itself
+                // works.
+                let value = state.cells[src_ptr];
+                if overwrite {
+                    state.cells[dst_ptr] = value;
+                } else {
+                    state.cells[dst_ptr] += value;
+                }
+                instr_idx += 1;
+            }
+            ScanLoop { step, position } => {
+                let mut new_cell_ptr = state.cell_ptr;
+                let mut cells_scanned = 0;
+                while state.cells[new_cell_ptr as usize].0 != 0 {
+                    new_cell_ptr = offset_cell_ptr(new_cell_ptr, step, num_cells, tape);
+
+                    // On a wrapping tape with no zero cells in
+                    // reach, we'd scan forever.
+                    cells_scanned += 1;
+                    if cells_scanned > num_cells {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::OutOfSteps;
+                    }
+                    if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
+                        // We can't execute this instruction, so we'll
+                        // execute it at runtime (it'll probably be an
+                        // error).
+                        state.start_instr = Some(&instrs[instr_idx]);
+
+                        let message = if new_cell_ptr < 0 {
+                            format!("This scan loop moves the pointer to cell {}.", new_cell_ptr)
+                        } else {
+                            format!(
+                                "This scan loop moves the pointer after the last cell ({}), to \
+                                 cell {}.",
+                                state.cells.len() - 1,
+                                new_cell_ptr
+                            )
+                        };
+                        return Outcome::RuntimeError(Warning { message, position });
+                    }
                 }
 
+                state.cell_ptr = new_cell_ptr;
                 instr_idx += 1;
             }
-            Write { .. } => {
-                let cell_value = state.cells[state.cell_ptr as usize];
-                state.outputs.push(cell_value.0);
+            Write { offset, position } => {
+                let target_cell_ptr = match cell_address(state.cell_ptr, offset, num_cells, tape) {
+                    Ok(target_cell_ptr) => target_cell_ptr,
+                    Err(target_cell_ptr) => {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!(
+                                "This write uses cell {}, which is out of range.",
+                                target_cell_ptr
+                            ),
+                            position,
+                        });
+                    }
+                };
+
+                let cell_value = state.cells[target_cell_ptr];
+                match writes {
+                    Writes::Collect => state.outputs.push(cell_value.0),
+                    Writes::StopBefore => {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::ReachedRuntimeValue;
+                    }
+                    // Like compiled programs, we ignore write errors.
+                    Writes::Stream(output) => {
+                        let _ = output.write_all(&[cell_value.0 as u8]);
+                    }
+                }
                 instr_idx += 1;
             }
-            Read { .. } => {
-                if let Some(read_value) = dummy_read_value {
-                    // If we're given a dummy value to use for the
-                    // read, pretend that we've read that value.
-                    state.cells[state.cell_ptr as usize] = Wrapping(read_value);
+            Read { offset, position } => {
+                let target_cell_ptr = match cell_address(state.cell_ptr, offset, num_cells, tape) {
+                    Ok(target_cell_ptr) => target_cell_ptr,
+                    Err(target_cell_ptr) => {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!(
+                                "This read uses cell {}, which is out of range.",
+                                target_cell_ptr
+                            ),
+                            position,
+                        });
+                    }
+                };
+
+                if let Some(read_value) = input.next() {
+                    // We know what the program will read, so we can
+                    // continue.
+                    state.cells[target_cell_ptr] = Wrapping(read_value as i8);
                     instr_idx += 1
                 } else {
                     // Otherwise, we cannot proceed at compile time,
@@ -195,15 +561,57 @@ pub fn execute_with_state<'a>(
                     return Outcome::ReachedRuntimeValue;
                 }
             }
-            Loop { ref body, .. } => {
-                if state.cells[state.cell_ptr as usize].0 == 0 {
+            InfiniteLoop { offset, position } => {
+                let test_cell_ptr = match cell_address(state.cell_ptr, offset, num_cells, tape) {
+                    Ok(test_cell_ptr) => test_cell_ptr,
+                    Err(test_cell_ptr) => {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!(
+                                "This loop tests cell {}, which is out of range.",
+                                test_cell_ptr
+                            ),
+                            position,
+                        });
+                    }
+                };
+
+                if state.cells[test_cell_ptr].0 == 0 {
+                    instr_idx += 1;
+                } else {
+                    // This loop never terminates, so there's no point
+                    // running it until we're out of steps.
+                    state.start_instr = Some(&instrs[instr_idx]);
+                    return Outcome::OutOfSteps;
+                }
+            }
+            Loop {
+                ref body,
+                offset,
+                position,
+            } => {
+                let test_cell_ptr = match cell_address(state.cell_ptr, offset, num_cells, tape) {
+                    Ok(test_cell_ptr) => test_cell_ptr,
+                    Err(test_cell_ptr) => {
+                        state.start_instr = Some(&instrs[instr_idx]);
+                        return Outcome::RuntimeError(Warning {
+                            message: format!(
+                                "This loop tests cell {}, which is out of range.",
+                                test_cell_ptr
+                            ),
+                            position,
+                        });
+                    }
+                };
+
+                if state.cells[test_cell_ptr].0 == 0 {
                     // Step over the loop because the current cell is
                     // zero.
                     instr_idx += 1;
                 } else {
                     // Execute the loop body.
                     let loop_outcome =
-                        execute_with_state(body, state, steps_left, dummy_read_value);
+                        execute_with_output(body, state, steps_left, input, tape, writes);
                     match loop_outcome {
                         Outcome::Completed(remaining_steps) => {
                             // We've run several steps during the loop
@@ -335,6 +743,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn multiply_move_keeps_current_cell() {
+        // A change at offset 0 is applied after zeroing the current
+        // cell, so this copies the current cell to the next cell.
+        let mut changes = HashMap::new();
+        changes.insert(0, Wrapping(1));
+        changes.insert(1, Wrapping(1));
+
+        let instrs = [
+            Increment {
+                amount: Wrapping(3),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(3), Wrapping(3)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+    }
+
     /// When the current cell is zero, we shouldn't execute a multiply move instruction.
     /// Otherwise, the BF program [-<+>] (which is well formed and does nothing) becomes
     /// undefined behaviour when we have a multiply move instruction.
@@ -448,6 +888,115 @@ mod tests {
         );
     }
 
+    #[test]
+    fn cell_copy_adds() {
+        let instrs = [
+            Set {
+                amount: Wrapping(3),
+                offset: 0,
+                position: None,
+            },
+            Set {
+                amount: Wrapping(4),
+                offset: 1,
+                position: None,
+            },
+            CellCopy {
+                src_offset: 0,
+                dst_offset: 1,
+                overwrite: false,
+                position: None,
+            },
+        ];
+
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.cells, vec![Wrapping(3), Wrapping(7)]);
+    }
+
+    #[test]
+    fn cell_copy_overwrites() {
+        let instrs = [
+            Set {
+                amount: Wrapping(3),
+                offset: 1,
+                position: None,
+            },
+            Set {
+                amount: Wrapping(4),
+                offset: 0,
+                position: None,
+            },
+            CellCopy {
+                src_offset: 1,
+                dst_offset: 0,
+                overwrite: true,
+                position: None,
+            },
+        ];
+
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.cells, vec![Wrapping(3), Wrapping(3)]);
+    }
+
+    /// Adding a cell to itself doubles it, and overwriting a cell with
+    /// itself does nothing.
+    #[test]
+    fn cell_copy_same_cell() {
+        let copy = |overwrite| CellCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            overwrite,
+            position: None,
+        };
+        let instrs = [
+            Set {
+                amount: Wrapping(100),
+                offset: 0,
+                position: None,
+            },
+            copy(true),
+            copy(false),
+        ];
+
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.cells, vec![Wrapping(-56)]);
+    }
+
+    #[test]
+    fn cell_copy_offset_too_low() {
+        let instrs = [
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            CellCopy {
+                src_offset: 0,
+                dst_offset: -1,
+                overwrite: false,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+        ];
+
+        let (final_state, warning) = execute(&instrs, max_steps());
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[1]),
+                cells: vec![Wrapping(1)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+        assert_eq!(
+            warning,
+            Some(Warning {
+                message: "This copy uses cell -1, which is out of range.".to_owned(),
+                position: Some(Position { start: 1, end: 1 }),
+            })
+        );
+    }
+
     #[test]
     fn set_executed() {
         let instrs = [Set {
@@ -565,6 +1114,173 @@ mod tests {
         assert!(warning.is_some());
     }
 
+    #[test]
+    fn scan_loop_executed() {
+        let instrs = [
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            ScanLoop {
+                step: 1,
+                position: Some(Position { start: 2, end: 4 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 5, end: 5 }),
+            },
+        ];
+        let final_state = execute(&instrs, max_steps()).0;
+
+        // We can't bound a forward scan, so we have the maximum
+        // number of cells.
+        assert_eq!(final_state.cells.len(), MAX_CELL_INDEX + 1);
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.cell_ptr, 3);
+    }
+
+    #[test]
+    fn scan_loop_out_of_range() {
+        let instrs = [
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            ScanLoop {
+                step: -1,
+                position: Some(Position { start: 1, end: 3 }),
+            },
+        ];
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[1]),
+                cells: vec![Wrapping(1)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+        assert_eq!(
+            warning,
+            Some(Warning {
+                message: "This scan loop moves the pointer to cell -1.".to_owned(),
+                position: Some(Position { start: 1, end: 3 }),
+            })
+        );
+    }
+
+    #[test]
+    fn loop_offset_out_of_range() {
+        let instrs = [Loop {
+            body: vec![],
+            offset: -1,
+            position: Some(Position { start: 0, end: 1 }),
+        }];
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[0]),
+                cells: vec![Wrapping(0)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+        assert_eq!(
+            warning,
+            Some(Warning {
+                message: "This loop tests cell -1, which is out of range.".to_owned(),
+                position: Some(Position { start: 0, end: 1 }),
+            })
+        );
+    }
+
+    #[test]
+    fn increment_offset_out_of_range() {
+        let instrs = [
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: isize::MAX - 1,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        // Runtime execution continues from the out of range
+        // instruction.
+        assert_eq!(final_state.start_instr, Some(&instrs[2]));
+        assert_eq!(final_state.cells[1], Wrapping(1));
+        assert_eq!(
+            warning,
+            Some(Warning {
+                message: format!(
+                    "This increment uses cell {}, which is out of range.",
+                    isize::MAX
+                ),
+                position: Some(Position { start: 2, end: 2 }),
+            })
+        );
+    }
+
+    #[test]
+    fn set_offset_out_of_range() {
+        let instrs = [Set {
+            amount: Wrapping(1),
+            offset: -1,
+            position: Some(Position { start: 0, end: 2 }),
+        }];
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(final_state.start_instr, Some(&instrs[0]));
+        assert_eq!(
+            warning,
+            Some(Warning {
+                message: "This set uses cell -1, which is out of range.".to_owned(),
+                position: Some(Position { start: 0, end: 2 }),
+            })
+        );
+    }
+
+    #[test]
+    fn increment_huge_offset_on_wrapping_tape() {
+        let instrs = [Increment {
+            amount: Wrapping(1),
+            offset: isize::MAX,
+            position: None,
+        }];
+        let mut state = ExecutionState::initial_on_tape(&instrs, Tape::Wrapping, 0);
+        let outcome = execute_with_state_on_tape(
+            &instrs,
+            &mut state,
+            max_steps(),
+            &mut std::iter::empty(),
+            Tape::Wrapping,
+        );
+
+        assert!(matches!(outcome, Outcome::Completed(_)));
+        let index = (isize::MAX as usize) % WRAPPING_TAPE_LEN;
+        assert_eq!(state.cells[index], Wrapping(1));
+    }
+
     #[test]
     fn limit_to_steps_specified() {
         let instrs = parse("++++").unwrap();
@@ -633,6 +1349,7 @@ mod tests {
         assert_eq!(
             *start_instr,
             Read {
+                offset: 0,
                 position: Some(Position { start: 3, end: 3 })
             }
         );
@@ -653,18 +1370,86 @@ mod tests {
         let instrs = parse(",").unwrap();
 
         let mut state = ExecutionState::initial(&instrs[..]);
-        execute_with_state(&instrs[..], &mut state, 5, Some(1));
+        execute_with_state(&instrs[..], &mut state, 5, &mut std::iter::repeat(1));
 
         assert_eq!(state.cells[0], Wrapping(1));
     }
 
+    #[test]
+    fn execute_write_high_value() {
+        // Cells are signed bytes, but we should write byte 200.
+        let instrs = parse(&format!("{}.", "+".repeat(200))).unwrap();
+
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.outputs, vec![-56]);
+        assert_eq!(final_state.outputs[0] as u8, 200);
+    }
+
+    #[test]
+    fn execute_with_known_input() {
+        let instrs = parse(",[.,]").unwrap();
+        let (final_state, warning) = execute_with_input(&instrs, max_steps(), b"hi\0");
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.outputs, vec![104, 105]);
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn execute_with_too_little_input() {
+        let instrs = parse(",.,.").unwrap();
+        let (final_state, _) = execute_with_input(&instrs, max_steps(), b"h");
+
+        // We should start runtime execution at the second read.
+        assert_eq!(final_state.start_instr, Some(&instrs[2]));
+        assert_eq!(final_state.outputs, vec![104]);
+    }
+
+    #[test]
+    fn execute_until_write_stops_before_write() {
+        let instrs = parse("++.").unwrap();
+        let (final_state, warning) = execute_until_write(
+            &instrs,
+            max_steps(),
+            &mut std::iter::empty(),
+            Tape::Bounded,
+            0,
+        );
+
+        assert_eq!(final_state.start_instr, Some(&instrs[2]));
+        assert_eq!(final_state.cells, vec![Wrapping(2)]);
+        assert_eq!(final_state.outputs, vec![]);
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn execute_until_write_in_loop() {
+        let instrs = parse("+[>+.<-]").unwrap();
+        let (final_state, _) = execute_until_write(
+            &instrs,
+            max_steps(),
+            &mut std::iter::empty(),
+            Tape::Bounded,
+            0,
+        );
+
+        // We stop at the write inside the loop body.
+        let body = match &instrs[1] {
+            Loop { body, .. } => body,
+            _ => unreachable!(),
+        };
+        assert_eq!(final_state.start_instr, Some(&body[2]));
+        assert_eq!(final_state.cells, vec![Wrapping(1), Wrapping(1)]);
+        assert_eq!(final_state.outputs, vec![]);
+    }
+
     #[test]
     fn execute_read_with_dummy_value_nested_loop() {
         // Regression test.
         let instrs = parse("+[[,]]").unwrap();
 
         let mut state = ExecutionState::initial(&instrs[..]);
-        let outcome = execute_with_state(&instrs[..], &mut state, 20, Some(0));
+        let outcome = execute_with_state(&instrs[..], &mut state, 20, &mut std::iter::repeat(0));
 
         assert!(matches!(outcome, Outcome::Completed(_)));
     }
@@ -741,6 +1526,7 @@ mod tests {
         assert_eq!(
             *start_instr,
             Read {
+                offset: 0,
                 position: Some(Position { start: 3, end: 3 })
             }
         );
@@ -788,6 +1574,58 @@ mod tests {
         );
     }
 
+    #[test]
+    fn infinite_loop_executed() {
+        let instrs = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            InfiniteLoop {
+                offset: 0,
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, 20).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[1]),
+                cells: vec![Wrapping(1)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn infinite_loop_on_zero_cell_skipped() {
+        let instrs = vec![
+            InfiniteLoop {
+                offset: 0,
+                position: None,
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, 20).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(1)],
+                cell_ptr: 0,
+                outputs: vec![],
+            }
+        );
+    }
+
     #[test]
     fn quickcheck_cell_ptr_in_bounds() {
         fn cell_ptr_in_bounds(instrs: Vec<AstNode>) -> bool {
@@ -797,6 +1635,229 @@ mod tests {
         quickcheck(cell_ptr_in_bounds as fn(Vec<AstNode>) -> bool);
     }
 
+    #[test]
+    fn reads_input_nested() {
+        assert!(reads_input(&parse("+[>[,]<]").unwrap()));
+        assert!(!reads_input(&parse("+[>[.]<]").unwrap()));
+    }
+
+    #[test]
+    fn read_free_programs_get_more_steps() {
+        let read_free = parse("+.").unwrap();
+        let reads = parse(",.").unwrap();
+
+        let read_free_steps = max_steps_for(&read_free);
+        let reads_steps = max_steps_for(&reads);
+        assert_eq!(read_free_steps, 100_000_000);
+        assert_eq!(reads_steps, 10_000_000);
+        assert!(read_free_steps > reads_steps);
+    }
+
+    #[test]
+    fn execute_read_and_write_offsets() {
+        let instrs = vec![
+            Read {
+                offset: 2,
+                position: None,
+            },
+            Write {
+                offset: 2,
+                position: None,
+            },
+        ];
+        let (state, warning) = execute_with_input(&instrs, 10, &[7]);
+
+        assert_eq!(warning, None);
+        assert_eq!(state.cells, vec![Wrapping(0), Wrapping(0), Wrapping(7)]);
+        assert_eq!(state.cell_ptr, 0);
+        assert_eq!(state.outputs, vec![7]);
+    }
+
+    #[test]
+    fn execute_write_offset_out_of_range() {
+        let instrs = vec![Write {
+            offset: -1,
+            position: None,
+        }];
+        let (_, warning) = execute(&instrs, 10);
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn wrapping_tape_move_below_first_cell() {
+        let instrs = parse("<+").unwrap();
+        let (state, warning) =
+            execute_with_input_on_tape(&instrs, 10, &mut std::iter::empty(), Tape::Wrapping, 0);
+
+        assert_eq!(warning, None);
+        assert_eq!(state.cells.len(), WRAPPING_TAPE_LEN);
+        assert_eq!(state.cell_ptr, WRAPPING_TAPE_LEN as isize - 1);
+        assert_eq!(state.cells[WRAPPING_TAPE_LEN - 1], Wrapping(1));
+    }
+
+    #[test]
+    fn wrapping_tape_move_above_last_cell() {
+        let instrs = vec![
+            PointerIncrement {
+                amount: WRAPPING_TAPE_LEN as isize + 2,
+                position: None,
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: -3,
+                position: None,
+            },
+        ];
+        let (state, warning) =
+            execute_with_input_on_tape(&instrs, 10, &mut std::iter::empty(), Tape::Wrapping, 0);
+
+        assert_eq!(warning, None);
+        assert_eq!(state.cell_ptr, 2);
+        assert_eq!(state.cells[WRAPPING_TAPE_LEN - 1], Wrapping(1));
+    }
+
+    #[test]
+    fn wrapping_tape_scan_past_first_cell() {
+        // Cells 0 and -1 are non-zero, so we stop at cell -2.
+        let instrs = parse("<+>+[<]").unwrap();
+        let (state, warning) =
+            execute_with_input_on_tape(&instrs, 100, &mut std::iter::empty(), Tape::Wrapping, 0);
+
+        assert_eq!(warning, None);
+        assert_eq!(state.start_instr, None);
+        assert_eq!(state.cell_ptr, WRAPPING_TAPE_LEN as isize - 2);
+    }
+
+    #[test]
+    fn bounded_tape_move_below_first_cell() {
+        let instrs = parse("<+").unwrap();
+        let (_, warning) =
+            execute_with_input_on_tape(&instrs, 10, &mut std::iter::empty(), Tape::Bounded, 0);
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn bounded_tape_move_left_of_start_cell() {
+        let instrs = parse("<+>>+").unwrap();
+        let (state, warning) =
+            execute_with_input_on_tape(&instrs, 10, &mut std::iter::empty(), Tape::Bounded, 1);
+        assert_eq!(warning, None);
+        assert_eq!(state.cell_ptr, 2);
+        assert_eq!(state.cells, vec![Wrapping(1), Wrapping(0), Wrapping(1)]);
+    }
+
+    #[test]
+    fn bounded_tape_move_below_start_cell() {
+        let instrs = parse("<<+").unwrap();
+        let (_, warning) =
+            execute_with_input_on_tape(&instrs, 10, &mut std::iter::empty(), Tape::Bounded, 1);
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn interpret_from_start_cell() {
+        let instrs = parse("<+.").unwrap();
+        let mut output = vec![];
+        let result = interpret(
+            &instrs,
+            Tape::Bounded,
+            1,
+            &mut std::iter::empty(),
+            &mut output,
+        );
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, [1]);
+    }
+
+    #[test]
+    fn interpret_writes_output() {
+        let instrs = parse(",[.,]").unwrap();
+        let mut output = vec![];
+        let result = interpret(
+            &instrs,
+            Tape::Bounded,
+            0,
+            &mut b"bf\0".iter().copied(),
+            &mut output,
+        );
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, b"bf");
+    }
+
+    #[test]
+    fn interpret_runs_beyond_step_limit() {
+        // This takes more than `max_steps()` steps.
+        let instrs = parse("-[>-[>-[-]<-]<-]+.").unwrap();
+        let mut output = vec![];
+        let result = interpret(
+            &instrs,
+            Tape::Bounded,
+            0,
+            &mut std::iter::empty(),
+            &mut output,
+        );
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, vec![1]);
+    }
+
+    #[test]
+    fn interpret_runtime_error() {
+        let instrs = parse(",<.").unwrap();
+        let mut output = vec![];
+        let result = interpret(&instrs, Tape::Bounded, 0, &mut [1].into_iter(), &mut output);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn interpret_infinite_loop() {
+        let instrs = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            InfiniteLoop {
+                offset: 0,
+                position: Some(Position { start: 1, end: 2 }),
+            },
+        ];
+        let mut output = vec![];
+        let result = interpret(
+            &instrs,
+            Tape::Bounded,
+            0,
+            &mut std::iter::empty(),
+            &mut output,
+        );
+
+        assert_eq!(
+            result,
+            Err(Warning {
+                message: "This loop never terminates.".to_owned(),
+                position: Some(Position { start: 1, end: 2 }),
+            })
+        );
+    }
+
+    #[test]
+    fn interpret_read_after_input() {
+        let instrs = parse(",.,.").unwrap();
+        let mut output = vec![];
+        let result = interpret(&instrs, Tape::Bounded, 0, &mut [1].into_iter(), &mut output);
+
+        assert_eq!(
+            result,
+            Err(Warning {
+                message: "This read happens after the end of the input.".to_owned(),
+                position: Some(Position { start: 2, end: 2 }),
+            })
+        );
+        assert_eq!(output, [1]);
+    }
+
     #[test]
     fn arithmetic_error_nested_loops() {
         // Regression test, based on a snippet from
@@ -0,0 +1,885 @@
+#![warn(trivial_numeric_casts)]
+
+//! bfc is a highly optimising compiler for BF.
+//!
+//! This crate exposes the parser, optimiser and code generator, so
+//! BF programs can be compiled without shelling out to the bfc
+//! binary.
+
+pub mod analysis;
+pub mod bfir;
+pub mod bounds;
+pub mod c_backend;
+pub mod diagnostics;
+pub mod error;
+pub mod execution;
+pub mod llvm;
+pub mod peephole;
+pub mod source_map;
+
+#[cfg(test)]
+mod llvm_tests;
+
+pub use bfir::parse;
+pub use error::CompileError;
+pub use execution::execute;
+pub use peephole::{optimize, OptimizeOptions};
+
+/// How much of the program to run at compile time
+/// (`--compile-time-exec`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompileTimeExec {
+    /// Run the whole program at runtime.
+    Off,
+    /// Compute the initial cells, but stop before the first write, so
+    /// the program doesn't print a fixed string.
+    Cells,
+    /// Run as much as possible, including writes.
+    #[default]
+    Full,
+}
+
+/// Options for compiling BF source, equivalent to the bfc CLI
+/// flags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileOptions {
+    /// The bfc optimisation level, from 0 to 2 (`--opt`).
+    pub opt_level: u8,
+    /// The LLVM optimisation level, from 0 to 3 (`--llvm-opt`).
+    pub llvm_opt_level: i64,
+    /// The LLVM target triple (`--target`). If None, we use the
+    /// default triple for this machine.
+    pub target_triple: Option<String>,
+    /// The CPU name and features to generate code for (`--mcpu` and
+    /// `--mattr`).
+    pub target_cpu: llvm::TargetCpu,
+    /// The relocation model (`--reloc`).
+    pub reloc_mode: llvm::RelocMode,
+    /// The code model (`--code-model`).
+    pub code_model: llvm::CodeModel,
+    /// Limit bfc optimisations to those specified (`--passes`).
+    pub passes: Option<String>,
+    /// Unroll loops that run at most this many times
+    /// (`--unroll-limit`).
+    pub unroll_limit: u64,
+    /// Stop optimising after this many iterations of the passes
+    /// (`--max-opt-iterations`).
+    pub max_opt_iterations: u64,
+    /// How much of the program to run at compile time with
+    /// `opt_level` 2 (`--compile-time-exec`).
+    pub compile_time_exec: CompileTimeExec,
+    /// The maximum steps of compile time execution (`--max-steps`).
+    /// If None, we use `execution::max_steps_for`.
+    pub max_steps: Option<u64>,
+    /// Run the whole program at runtime if compile time execution
+    /// writes more than this many bytes (`--max-static-output`).
+    pub max_static_output: Option<u64>,
+    /// Don't warn when compile time execution reaches an error
+    /// (`--keep-going`).
+    pub keep_going: bool,
+    /// How the compiled program buffers stdout
+    /// (`--stdout-buffering`). If None, we use libc's default.
+    pub stdout_buffering: Option<llvm::StdoutBuffering>,
+    /// Write each output byte with putchar, rather than buffering
+    /// output (`--unbuffered`).
+    pub unbuffered: bool,
+    /// Exit with an error if the program moves outside its cells
+    /// (`--debug-bounds`).
+    pub debug_bounds: bool,
+    /// The first bytes the program reads, which we use during compile
+    /// time execution (`--stdin-file` or `--bang-input`). It's an
+    /// error if compile time execution doesn't read all of them.
+    pub known_input: Vec<u8>,
+    /// Write the number of iterations of each loop to stderr when the
+    /// program exits (`--count-loops`).
+    pub count_loop_iterations: bool,
+    /// Emit DWARF line tables, using this as the path of the source
+    /// file (`-g`).
+    pub debug_info: Option<String>,
+    /// Use a circular tape of `bounds::WRAPPING_TAPE_LEN` cells
+    /// (`--wrapping-tape`).
+    pub wrapping_tape: bool,
+    /// Call `bf_getchar` and `bf_putchar`, which the caller provides
+    /// at link time, rather than libc's `getchar` and `putchar`
+    /// (`--no-builtin-io`).
+    pub no_builtin_io: bool,
+    /// The cell that the pointer starts at, so the program can move
+    /// left of it (`--start-cell`).
+    pub start_cell: usize,
+    /// The name of the function that runs the program, rather than
+    /// `main` (`--entry`). Other names give a function returning void.
+    pub entry_name: Option<String>,
+    /// Instrument the program with AddressSanitizer (`--sanitize`).
+    pub sanitize_address: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            opt_level: 2,
+            llvm_opt_level: 3,
+            target_triple: None,
+            target_cpu: llvm::TargetCpu::default(),
+            reloc_mode: llvm::RelocMode::default(),
+            code_model: llvm::CodeModel::default(),
+            passes: None,
+            unroll_limit: peephole::DEFAULT_UNROLL_LIMIT,
+            max_opt_iterations: peephole::DEFAULT_MAX_ITERATIONS,
+            compile_time_exec: CompileTimeExec::default(),
+            max_steps: None,
+            max_static_output: None,
+            keep_going: false,
+            stdout_buffering: None,
+            unbuffered: false,
+            debug_bounds: false,
+            known_input: vec![],
+            count_loop_iterations: false,
+            debug_info: None,
+            wrapping_tape: false,
+            no_builtin_io: false,
+            start_cell: 0,
+            entry_name: None,
+            sanitize_address: false,
+        }
+    }
+}
+
+impl CompileOptions {
+    /// The tape that the compiled program uses.
+    pub fn tape(&self) -> execution::Tape {
+        if self.wrapping_tape {
+            execution::Tape::Wrapping
+        } else {
+            execution::Tape::Bounded
+        }
+    }
+
+    /// The peephole optimisation options for these options.
+    pub fn optimize_options<'a>(&self) -> OptimizeOptions<'a> {
+        OptimizeOptions {
+            passes: self.passes.clone(),
+            unroll_limit: self.unroll_limit,
+            max_iterations: self.max_opt_iterations,
+            ..OptimizeOptions::default()
+        }
+    }
+
+    /// The code generation options for these options. `src` is only
+    /// used for debug info.
+    pub fn codegen_options(&self, src: &str) -> llvm::CodegenOptions {
+        llvm::CodegenOptions {
+            stdout_buffering: self.stdout_buffering,
+            unbuffered_writes: self.unbuffered,
+            debug_bounds: self.debug_bounds,
+            count_loop_iterations: self.count_loop_iterations,
+            debug_info: self
+                .debug_info
+                .as_ref()
+                .map(|path| llvm::DebugSource::new(path, src)),
+            wrapping_tape: self.wrapping_tape,
+            custom_io: self.no_builtin_io,
+            sanitize_address: self.sanitize_address,
+            entry_name: self.entry_name.clone(),
+        }
+    }
+}
+
+/// Parse BF source and apply peephole optimisations, returning the
+/// optimised instructions. This doesn't panic on any input, so it's
+/// safe to use on untrusted source.
+pub fn try_compile(src: &str) -> Result<Vec<bfir::AstNode>, bfir::ParseError> {
+    let instrs = parse(src)?;
+    let (instrs, _) = optimize(instrs, OptimizeOptions::default());
+    Ok(instrs)
+}
+
+/// Check that `instrs` can run when starting at `opts.start_cell`.
+///
+/// It's an error if the program always moves before the first
+/// cell. If a loop moves before the first cell whenever its body
+/// runs, we return a warning.
+pub fn check_cell_bounds(
+    instrs: &[bfir::AstNode],
+    opts: &CompileOptions,
+) -> Result<Option<diagnostics::Warning>, CompileError> {
+    // A wrapping tape has no out of range cells.
+    if opts.wrapping_tape {
+        return Ok(None);
+    }
+
+    match negative_cell_error(instrs, opts.start_cell) {
+        Some(warning) => Err(CompileError::CellOutOfRange(warning)),
+        None => Ok(negative_cell_warning(instrs, opts.start_cell)),
+    }
+}
+
+/// Run as much of `instrs` at compile time as `opts` allows, and
+/// return the state that runtime execution starts from.
+///
+/// If compile time execution stops due to an error (e.g. the pointer
+/// moves out of range), we also return a warning, unless
+/// `opts.keep_going` is set.
+pub fn execute_at_compile_time<'a>(
+    instrs: &'a [bfir::AstNode],
+    opts: &CompileOptions,
+) -> Result<(execution::ExecutionState<'a>, Option<diagnostics::Warning>), CompileError> {
+    let tape = opts.tape();
+    let mut remaining_input = opts.known_input.iter().copied();
+    let (state, warning) = if opts.opt_level >= 2 {
+        compile_time_execute(
+            instrs,
+            &mut remaining_input,
+            opts.keep_going,
+            tape,
+            opts.start_cell,
+            opts.max_steps,
+            opts.compile_time_exec,
+        )
+    } else {
+        (runtime_only_state(instrs, tape, opts.start_cell), None)
+    };
+
+    // The compiled program reads from stdin, so any input left over
+    // when compile time execution stops would be lost.
+    if state.start_instr.is_some() && remaining_input.len() > 0 {
+        return Err(CompileError::UnreadInput {
+            read: opts.known_input.len() - remaining_input.len(),
+            len: opts.known_input.len(),
+        });
+    }
+
+    // Running the whole program at runtime would read the known
+    // input from stdin instead.
+    let over_static_output = opts
+        .max_static_output
+        .map_or(false, |max| state.outputs.len() as u64 > max);
+    if over_static_output && !opts.known_input.is_empty() {
+        return Err(CompileError::DiscardedInput);
+    }
+
+    let state = limit_static_output(state, instrs, tape, opts.start_cell, opts.max_static_output);
+    Ok((state, warning))
+}
+
+/// Compile BF source to an object file, and return its contents.
+///
+/// Warnings from optimisation and compile time execution are
+/// ignored.
+pub fn compile_to_object(src: &str, opts: CompileOptions) -> Result<Vec<u8>, CompileError> {
+    let mut instrs = parse(src).map_err(CompileError::Parse)?;
+
+    if opts.opt_level > 0 {
+        let (opt_instrs, _) = optimize(instrs, opts.optimize_options());
+        instrs = opt_instrs;
+    }
+
+    check_cell_bounds(&instrs, &opts)?;
+    let (state, _) = execute_at_compile_time(&instrs, &opts)?;
+
+    llvm::init_llvm();
+    let mut llvm_module = llvm::compile_to_module_with_options(
+        "bfc",
+        opts.target_triple.clone(),
+        &instrs,
+        &state,
+        &opts.codegen_options(src),
+    );
+    llvm::verify_module(&llvm_module)?;
+    llvm::optimise_ir(&mut llvm_module, opts.llvm_opt_level);
+
+    llvm::emit_object_to_memory(
+        &mut llvm_module,
+        &opts.target_cpu,
+        opts.reloc_mode,
+        opts.code_model,
+    )
+}
+
+/// Execute as much of the program as possible at compile time.
+/// Reads consume bytes from `input`, and happen at runtime once
+/// `input` is exhausted. Afterwards, `input` only has the bytes the
+/// program didn't read.
+///
+/// If compile time execution stops due to an error (e.g. the pointer
+/// moves out of range), we return a warning. When `keep_going` is
+/// set, we treat this as a normal transition to runtime execution.
+fn compile_time_execute<'a>(
+    instrs: &'a [bfir::AstNode],
+    input: &mut dyn Iterator<Item = u8>,
+    keep_going: bool,
+    tape: execution::Tape,
+    start_cell: usize,
+    max_steps: Option<u64>,
+    mode: CompileTimeExec,
+) -> (execution::ExecutionState<'a>, Option<diagnostics::Warning>) {
+    let max_steps = max_steps.unwrap_or_else(|| execution::max_steps_for(instrs));
+    if max_steps == 0 || mode == CompileTimeExec::Off {
+        return (runtime_only_state(instrs, tape, start_cell), None);
+    }
+
+    let (state, warning) = if mode == CompileTimeExec::Cells {
+        execution::execute_until_write(instrs, max_steps, input, tape, start_cell)
+    } else {
+        execution::execute_with_input_on_tape(instrs, max_steps, input, tape, start_cell)
+    };
+    if keep_going {
+        (state, None)
+    } else {
+        (state, warning)
+    }
+}
+
+/// If compile time execution wrote more than `max_static_output`
+/// bytes, discard it and run the whole program at runtime instead, so
+/// we don't embed huge outputs in the executable.
+fn limit_static_output<'a>(
+    state: execution::ExecutionState<'a>,
+    instrs: &'a [bfir::AstNode],
+    tape: execution::Tape,
+    start_cell: usize,
+    max_static_output: Option<u64>,
+) -> execution::ExecutionState<'a> {
+    match max_static_output {
+        Some(max) if state.outputs.len() as u64 > max => {
+            runtime_only_state(instrs, tape, start_cell)
+        }
+        _ => state,
+    }
+}
+
+/// The initial state for a program that does all its work at
+/// runtime.
+fn runtime_only_state(
+    instrs: &[bfir::AstNode],
+    tape: execution::Tape,
+    start_cell: usize,
+) -> execution::ExecutionState<'_> {
+    let mut state = execution::ExecutionState::initial_on_tape(instrs, tape, start_cell);
+    state.start_instr = instrs.first();
+    state
+}
+
+/// Return a warning if the program always moves before the first
+/// cell when it starts at `start_cell`, pointing at the instruction
+/// that does so.
+fn negative_cell_error(
+    instrs: &[bfir::AstNode],
+    start_cell: usize,
+) -> Option<diagnostics::Warning> {
+    bounds::first_negative_access(instrs, start_cell).map(|access| diagnostics::Warning {
+        message: format!(
+            "This instruction moves to cell #{}, but the first cell is #0.",
+            access.cell_index
+        ),
+        position: bfir::get_position(&instrs[access.index]),
+    })
+}
+
+/// Return a warning if a loop in the program moves before the first
+/// cell whenever its body runs, when the program starts at
+/// `start_cell`.
+fn negative_cell_warning(
+    instrs: &[bfir::AstNode],
+    start_cell: usize,
+) -> Option<diagnostics::Warning> {
+    bounds::first_possible_negative_access(instrs, start_cell).map(|access| diagnostics::Warning {
+        message: format!(
+            "This code moves to cell #{} when it runs, but the first cell is #0.",
+            access.cell_index
+        ),
+        position: bfir::get_position(&instrs[access.index]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    #[test]
+    fn try_compile_empty() {
+        assert_eq!(try_compile("").unwrap(), vec![]);
+        assert_eq!(try_compile("no commands").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn try_compile_parse_error() {
+        assert!(try_compile("[").is_err());
+        assert!(try_compile("]").is_err());
+    }
+
+    #[test]
+    fn try_compile_deep_nesting() {
+        // Debug builds need more stack for each loop than test
+        // threads have by default.
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(|| {
+                let depth = bfir::MAX_LOOP_DEPTH;
+                let src = format!(",{}>.<-{}", "[".repeat(depth), "]".repeat(depth));
+                assert!(try_compile(&src).is_ok());
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let depth = 100_000;
+        let src = format!(",{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(try_compile(&src).is_err());
+    }
+
+    #[test]
+    fn try_compile_never_panics() {
+        fn no_panic(commands: Vec<u8>) -> bool {
+            // Random strings rarely contain BF commands, or have
+            // balanced brackets, so build source from bytes.
+            let mut src = String::new();
+            let mut depth = 0;
+            for byte in commands {
+                let c = b"+-<>[].,"[usize::from(byte % 8)] as char;
+                match c {
+                    '[' => depth += 1,
+                    ']' if depth == 0 => continue,
+                    ']' => depth -= 1,
+                    _ => {}
+                }
+                src.push(c);
+            }
+            src.push_str(&"]".repeat(depth));
+
+            try_compile(&src).is_ok()
+        }
+        quickcheck(no_panic as fn(Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn compile_time_execute_warns() {
+        let instrs = bfir::parse("<").unwrap();
+        let (_, warning) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            false,
+            execution::Tape::Bounded,
+            0,
+            None,
+            CompileTimeExec::Full,
+        );
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn compile_time_execute_keep_going() {
+        let instrs = bfir::parse("<").unwrap();
+        let (state, warning) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            true,
+            execution::Tape::Bounded,
+            0,
+            None,
+            CompileTimeExec::Full,
+        );
+
+        assert_eq!(warning, None);
+        // We should still start runtime execution from the `<`.
+        assert_eq!(state.start_instr, Some(&instrs[0]));
+    }
+
+    #[test]
+    fn compile_time_execute_with_input() {
+        let instrs = bfir::parse(",[.,]").unwrap();
+        let (state, warning) = compile_time_execute(
+            &instrs,
+            &mut b"bf\0".iter().copied(),
+            false,
+            execution::Tape::Bounded,
+            0,
+            None,
+            CompileTimeExec::Full,
+        );
+
+        assert_eq!(warning, None);
+        assert_eq!(state.start_instr, None);
+        assert_eq!(state.outputs, vec![98, 102]);
+    }
+
+    #[test]
+    fn compile_time_execute_read_free() {
+        // Programs without reads are deterministic, so we should
+        // compile them to their output. This program takes around 21
+        // million steps, more than programs with reads are given.
+        let instrs = bfir::parse(
+            "++++++++++[>++++++++++++++++<-]>[>-[>-[-]<-]<-]
+             ++++++++++++++++++++++++++++++++++++++++++++++++++.",
+        )
+        .unwrap();
+
+        let (state, _) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            false,
+            execution::Tape::Bounded,
+            0,
+            Some(execution::max_steps()),
+            CompileTimeExec::Full,
+        );
+        assert!(state.start_instr.is_some());
+
+        let (state, warning) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            false,
+            execution::Tape::Bounded,
+            0,
+            None,
+            CompileTimeExec::Full,
+        );
+
+        assert_eq!(warning, None);
+        assert_eq!(state.start_instr, None);
+        assert_eq!(state.outputs, vec![50]);
+    }
+
+    #[test]
+    fn compile_time_execute_max_steps() {
+        let instrs = bfir::parse("++++++[>++++++++<-]>+.").unwrap();
+        let (state, warning) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            false,
+            execution::Tape::Bounded,
+            0,
+            Some(10),
+            CompileTimeExec::Full,
+        );
+
+        assert_eq!(warning, None);
+        assert!(state.start_instr.is_some());
+    }
+
+    #[test]
+    fn compile_time_execute_zero_max_steps() {
+        // Zero steps disables compile time execution, so we start
+        // from the first instruction.
+        let instrs = bfir::parse("+.").unwrap();
+        let (state, warning) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            false,
+            execution::Tape::Bounded,
+            0,
+            Some(0),
+            CompileTimeExec::Full,
+        );
+
+        assert_eq!(warning, None);
+        assert_eq!(state.start_instr, Some(&instrs[0]));
+        assert_eq!(state.outputs, vec![]);
+    }
+
+    #[test]
+    fn compile_time_exec_modes_write() {
+        let instrs = bfir::parse("++.").unwrap();
+        let execute = |mode| {
+            compile_time_execute(
+                &instrs,
+                &mut std::iter::empty(),
+                false,
+                execution::Tape::Bounded,
+                0,
+                None,
+                mode,
+            )
+            .0
+        };
+
+        let state = execute(CompileTimeExec::Off);
+        assert_eq!(state.start_instr, Some(&instrs[0]));
+        assert_eq!(state.cells, vec![std::num::Wrapping(0)]);
+
+        // Cells are computed, but the write happens at runtime.
+        let state = execute(CompileTimeExec::Cells);
+        assert_eq!(state.start_instr, Some(&instrs[2]));
+        assert_eq!(state.cells, vec![std::num::Wrapping(2)]);
+        assert_eq!(state.outputs, vec![]);
+
+        let state = execute(CompileTimeExec::Full);
+        assert_eq!(state.start_instr, None);
+        assert_eq!(state.outputs, vec![2]);
+    }
+
+    #[test]
+    fn compile_time_exec_modes_read() {
+        // Every mode stops at the read.
+        let instrs = bfir::parse(",.").unwrap();
+        for mode in [
+            CompileTimeExec::Off,
+            CompileTimeExec::Cells,
+            CompileTimeExec::Full,
+        ] {
+            let (state, warning) = compile_time_execute(
+                &instrs,
+                &mut std::iter::empty(),
+                false,
+                execution::Tape::Bounded,
+                0,
+                None,
+                mode,
+            );
+            assert_eq!(warning, None);
+            assert_eq!(state.start_instr, Some(&instrs[0]));
+            assert_eq!(state.outputs, vec![]);
+        }
+    }
+
+    #[test]
+    fn limit_static_output_at_threshold() {
+        let instrs = bfir::parse("+..").unwrap();
+        let (state, _) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            false,
+            execution::Tape::Bounded,
+            0,
+            None,
+            CompileTimeExec::Full,
+        );
+        let state = limit_static_output(state, &instrs, execution::Tape::Bounded, 0, Some(2));
+
+        assert_eq!(state.start_instr, None);
+        assert_eq!(state.outputs, vec![1, 1]);
+    }
+
+    #[test]
+    fn limit_static_output_over_threshold() {
+        let instrs = bfir::parse("+..").unwrap();
+        let (state, _) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            false,
+            execution::Tape::Bounded,
+            0,
+            None,
+            CompileTimeExec::Full,
+        );
+        let state = limit_static_output(state, &instrs, execution::Tape::Bounded, 0, Some(1));
+
+        assert_eq!(state.start_instr, Some(&instrs[0]));
+        assert_eq!(state.outputs, vec![]);
+    }
+
+    #[test]
+    fn negative_cell_error_after_read() {
+        // Compile time execution stops at the read, but we can still
+        // see that the `<` moves before the first cell.
+        let instrs = bfir::parse(",<.").unwrap();
+        let error = negative_cell_error(&instrs, 0).unwrap();
+        assert!(error.message.contains("#-1"));
+        assert_eq!(error.position, Some(bfir::Position { start: 1, end: 1 }));
+    }
+
+    #[test]
+    fn negative_cell_error_after_moving_right() {
+        let instrs = bfir::parse(">+<<+").unwrap();
+        let error = negative_cell_error(&instrs, 0).unwrap();
+        assert!(error.message.contains("#-1"));
+        assert_eq!(error.position, Some(bfir::Position { start: 3, end: 3 }));
+    }
+
+    #[test]
+    fn negative_cell_error_with_start_cell() {
+        let instrs = bfir::parse(",<<.").unwrap();
+        assert_eq!(negative_cell_error(&instrs, 2), None);
+
+        let error = negative_cell_error(&instrs, 1).unwrap();
+        assert!(error.message.contains("#-1"));
+    }
+
+    #[test]
+    fn negative_cell_warning_in_loop() {
+        let instrs = bfir::parse(",[<<+>>-]").unwrap();
+        assert_eq!(negative_cell_error(&instrs, 0), None);
+
+        let warning = negative_cell_warning(&instrs, 0).unwrap();
+        assert!(warning.message.contains("#-2"));
+        assert_eq!(warning.position, Some(bfir::Position { start: 1, end: 8 }));
+    }
+
+    #[test]
+    fn compile_time_execute_start_cell() {
+        let instrs = bfir::parse("<+.").unwrap();
+        let (state, warning) = compile_time_execute(
+            &instrs,
+            &mut std::iter::empty(),
+            false,
+            execution::Tape::Bounded,
+            1,
+            None,
+            CompileTimeExec::Full,
+        );
+        assert_eq!(warning, None);
+        assert_eq!(state.outputs, vec![1]);
+    }
+
+    #[test]
+    fn negative_cell_warning_in_range() {
+        let instrs = bfir::parse(",>+<.").unwrap();
+        assert_eq!(negative_cell_error(&instrs, 0), None);
+        assert_eq!(negative_cell_warning(&instrs, 0), None);
+    }
+
+    #[test]
+    fn compile_to_object_elf() {
+        let opts = CompileOptions {
+            target_triple: Some("x86_64-pc-linux-gnu".to_owned()),
+            ..CompileOptions::default()
+        };
+        let object = compile_to_object(",[.,]", opts).unwrap();
+        assert!(object.starts_with(b"\x7fELF"));
+    }
+
+    #[test]
+    fn compile_to_object_deep_nesting() {
+        let depth = 300;
+        let src = format!(",{}>.<-{}", "[".repeat(depth), "]".repeat(depth));
+        let opts = CompileOptions {
+            target_triple: Some("x86_64-pc-linux-gnu".to_owned()),
+            ..CompileOptions::default()
+        };
+        assert!(compile_to_object(&src, opts).is_ok());
+    }
+
+    #[test]
+    fn compile_to_object_unoptimised() {
+        let opts = CompileOptions {
+            opt_level: 0,
+            llvm_opt_level: 0,
+            target_triple: Some("x86_64-pc-linux-gnu".to_owned()),
+            target_cpu: llvm::TargetCpu::default(),
+            reloc_mode: llvm::RelocMode::default(),
+            code_model: llvm::CodeModel::default(),
+            passes: None,
+            unroll_limit: 0,
+            max_opt_iterations: 1,
+            compile_time_exec: CompileTimeExec::Off,
+            max_steps: None,
+            max_static_output: None,
+            keep_going: false,
+            stdout_buffering: None,
+            unbuffered: true,
+            debug_bounds: true,
+            known_input: vec![],
+            count_loop_iterations: true,
+            debug_info: Some("foo.bf".to_owned()),
+            wrapping_tape: false,
+            no_builtin_io: false,
+            start_cell: 1,
+            entry_name: None,
+            sanitize_address: false,
+        };
+        let object = compile_to_object("+>+.", opts).unwrap();
+        assert!(object.starts_with(b"\x7fELF"));
+    }
+
+    #[test]
+    fn compile_to_object_target_cpu() {
+        let opts = CompileOptions {
+            target_triple: Some("x86_64-pc-linux-gnu".to_owned()),
+            target_cpu: llvm::TargetCpu {
+                name: "skylake".to_owned(),
+                features: "+avx2".to_owned(),
+            },
+            ..CompileOptions::default()
+        };
+        let object = compile_to_object(",[.,]", opts).unwrap();
+        assert!(object.starts_with(b"\x7fELF"));
+    }
+
+    #[test]
+    fn compile_to_object_static_large() {
+        let opts = CompileOptions {
+            target_triple: Some("x86_64-pc-linux-gnu".to_owned()),
+            reloc_mode: llvm::RelocMode::Static,
+            code_model: llvm::CodeModel::Large,
+            ..CompileOptions::default()
+        };
+        let object = compile_to_object(",[.,]", opts).unwrap();
+        assert!(object.starts_with(b"\x7fELF"));
+    }
+
+    #[test]
+    fn compile_to_object_negative_cell() {
+        let result = compile_to_object(",<.", CompileOptions::default());
+        assert!(matches!(result, Err(CompileError::CellOutOfRange(_))));
+
+        let opts = CompileOptions {
+            target_triple: Some("x86_64-pc-linux-gnu".to_owned()),
+            wrapping_tape: true,
+            ..CompileOptions::default()
+        };
+        assert!(compile_to_object(",<.", opts).is_ok());
+    }
+
+    #[test]
+    fn compile_to_object_unread_input() {
+        // Compile time execution stops before the second read.
+        let opts = CompileOptions {
+            known_input: b"AB".to_vec(),
+            max_steps: Some(2),
+            ..CompileOptions::default()
+        };
+        let result = compile_to_object("+>+<,.>,.", opts);
+        assert!(matches!(
+            result,
+            Err(CompileError::UnreadInput { read: 1, len: 2 })
+        ));
+
+        let opts = CompileOptions {
+            known_input: b"AB".to_vec(),
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let result = compile_to_object(",.,.", opts);
+        assert!(matches!(
+            result,
+            Err(CompileError::UnreadInput { read: 0, len: 2 })
+        ));
+    }
+
+    #[test]
+    fn execute_at_compile_time_max_static_output() {
+        let instrs = parse(",.,.").unwrap();
+        let opts = CompileOptions {
+            known_input: b"AB".to_vec(),
+            max_static_output: Some(2),
+            ..CompileOptions::default()
+        };
+        let (state, _) = execute_at_compile_time(&instrs, &opts).unwrap();
+        assert_eq!(state.outputs, vec![b'A' as i8, b'B' as i8]);
+
+        let opts = CompileOptions {
+            max_static_output: Some(1),
+            ..opts
+        };
+        assert!(matches!(
+            execute_at_compile_time(&instrs, &opts),
+            Err(CompileError::DiscardedInput)
+        ));
+    }
+
+    #[test]
+    fn compile_to_object_parse_error() {
+        let result = compile_to_object("[", CompileOptions::default());
+        assert!(matches!(result, Err(CompileError::Parse(_))));
+    }
+
+    #[test]
+    fn compile_to_object_invalid_target() {
+        let opts = CompileOptions {
+            target_triple: Some("not-a-target".to_owned()),
+            ..CompileOptions::default()
+        };
+        let result = compile_to_object("+.", opts);
+        assert!(matches!(result, Err(CompileError::Llvm(_))));
+    }
+}
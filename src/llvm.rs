@@ -1,24 +1,41 @@
 //! The LLVM module handles converting a BF AST to LLVM IR.
 
 use itertools::Itertools;
+use llvm_sys::analysis::{LLVMVerifierFailureAction, LLVMVerifyModule};
+use llvm_sys::bit_writer::LLVMWriteBitcodeToFile;
 use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
+#[cfg(not(feature = "legacy-pass-manager"))]
+use llvm_sys::error::{LLVMDisposeErrorMessage, LLVMGetErrorMessage};
+use llvm_sys::execution_engine::*;
 use llvm_sys::prelude::*;
 use llvm_sys::target::*;
 use llvm_sys::target_machine::*;
+#[cfg(not(feature = "legacy-pass-manager"))]
+use llvm_sys::transforms::pass_builder::*;
+#[cfg(feature = "legacy-pass-manager")]
 use llvm_sys::transforms::pass_manager_builder::*;
-use llvm_sys::{LLVMBuilder, LLVMIntPredicate, LLVMModule};
+use llvm_sys::{
+    LLVMAttributeFunctionIndex, LLVMBuilder, LLVMIntPredicate, LLVMLinkage, LLVMModule,
+    LLVMModuleFlagBehavior,
+};
 
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_uint, c_ulonglong};
+use std::path::Path;
 use std::ptr::null_mut;
 use std::str;
 
+use std::cell::Cell;
+use std::cmp::min;
 use std::collections::HashMap;
 use std::num::Wrapping;
+use std::rc::Rc;
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{AstNode, BfValue};
+use crate::bfir::{get_position, AstNode, BfValue, Position};
 
+use crate::error::CompileError;
 use crate::execution::ExecutionState;
 
 const LLVM_FALSE: LLVMBool = 0;
@@ -29,6 +46,104 @@ const LLVM_TRUE: LLVMBool = 1;
 pub struct Module {
     module: *mut LLVMModule,
     strings: Vec<CString>,
+    /// The source position of each loop we're counting iterations
+    /// of, and the name of the global holding its count.
+    loop_counters: Vec<(usize, String)>,
+    /// How the compiled program does I/O and allocates memory.
+    runtime: Runtime,
+    /// If set, we attach source locations to the instructions we
+    /// generate.
+    debug_scope: Option<DebugScope>,
+}
+
+/// The BF source that we're compiling, so we can emit DWARF line
+/// tables that map machine code back to BF commands.
+#[derive(Debug, Clone)]
+pub struct DebugSource {
+    /// The path of the BF source file.
+    pub path: String,
+    /// The character offset of the start of each line.
+    line_starts: Vec<usize>,
+}
+
+impl DebugSource {
+    pub fn new(path: &str, src: &str) -> Self {
+        let mut line_starts = vec![0];
+        for (index, c) in src.chars().enumerate() {
+            if c == '\n' {
+                line_starts.push(index + 1);
+            }
+        }
+
+        DebugSource {
+            path: path.to_owned(),
+            line_starts,
+        }
+    }
+
+    /// The line and column, both starting from 1, of the character
+    /// at `offset`.
+    fn line_and_column(&self, offset: usize) -> (c_uint, c_uint) {
+        let line_index = match self.line_starts.binary_search(&offset) {
+            Ok(line_index) => line_index,
+            Err(next_line_index) => next_line_index - 1,
+        };
+        let column = offset - self.line_starts[line_index];
+
+        (line_index as c_uint + 1, column as c_uint + 1)
+    }
+}
+
+/// The DWARF subprogram for `main`, which we use as the scope of
+/// every source location.
+struct DebugScope {
+    source: DebugSource,
+    subprogram: LLVMMetadataRef,
+}
+
+/// The functions that the compiled program calls for I/O and memory
+/// allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Runtime {
+    /// Call libc functions, and allocate cells with malloc. This
+    /// requires linking against libc.
+    Libc,
+    /// Allocate cells with libc, but call `bf_getchar` and
+    /// `bf_putchar` for I/O. The user provides these at link time.
+    CustomIo,
+    /// Call WASI functions directly, and store cells in linear
+    /// memory. This works on wasm32 targets without a libc.
+    Wasi,
+}
+
+impl Runtime {
+    fn for_target(target_triple: &CStr, custom_io: bool) -> Self {
+        if target_triple.to_bytes().starts_with(b"wasm32") {
+            Runtime::Wasi
+        } else if custom_io {
+            Runtime::CustomIo
+        } else {
+            Runtime::Libc
+        }
+    }
+
+    /// The function we call to read a byte, with the same signature
+    /// as `getchar`.
+    fn getchar_name(self) -> &'static str {
+        match self {
+            Runtime::CustomIo => "bf_getchar",
+            Runtime::Libc | Runtime::Wasi => "getchar",
+        }
+    }
+
+    /// The function we call to write a byte, with the same signature
+    /// as `putchar`.
+    fn putchar_name(self) -> &'static str {
+        match self {
+            Runtime::CustomIo => "bf_putchar",
+            Runtime::Libc | Runtime::Wasi => "putchar",
+        }
+    }
 }
 
 impl Module {
@@ -67,6 +182,12 @@ impl Module {
 
 impl Drop for Module {
     fn drop(&mut self) {
+        // If we've handed the module to an execution engine, the
+        // engine owns it and will dispose it.
+        if self.module.is_null() {
+            return;
+        }
+
         // Rust requires that drop() is a safe function.
         unsafe {
             LLVMDisposeModule(self.module);
@@ -111,6 +232,32 @@ struct CompileContext {
     cells: LLVMValueRef,
     cell_index_ptr: LLVMValueRef,
     main_fn: LLVMValueRef,
+    /// If set, writes go to this buffer rather than calling putchar.
+    output_buffer: Option<OutputBuffer>,
+    /// If true, call `check_cell_index` before accessing cells and
+    /// after moving the cell pointer.
+    bounds_check: bool,
+    /// If true, count how many times each loop body runs.
+    count_loop_iterations: bool,
+    /// If set, the cells form a circle of this length, so cell
+    /// indexes wrap around.
+    wrapping_tape_len: Option<usize>,
+    /// The last cell index we loaded or stored, and the basic block
+    /// we did it in. Straight-line code only needs to load the cell
+    /// index once.
+    cell_index_cache: Rc<Cell<Option<(LLVMBasicBlockRef, LLVMValueRef)>>>,
+}
+
+/// The number of bytes we buffer before writing to stdout.
+const OUTPUT_BUFFER_SIZE: c_ulonglong = 4096;
+
+/// Globals for buffering output, so we can write many bytes with a
+/// single `write` call. The buffer is flushed by the `flush_output`
+/// function.
+#[derive(Clone, Copy)]
+struct OutputBuffer {
+    buffer: LLVMValueRef,
+    len: LLVMValueRef,
 }
 
 /// Convert this integer to LLVM's representation of a constant
@@ -125,6 +272,10 @@ fn int32(val: c_ulonglong) -> LLVMValueRef {
     unsafe { LLVMConstInt(LLVMInt32Type(), val, LLVM_FALSE) }
 }
 
+fn int64(val: c_ulonglong) -> LLVMValueRef {
+    unsafe { LLVMConstInt(LLVMInt64Type(), val, LLVM_FALSE) }
+}
+
 fn int1_type() -> LLVMTypeRef {
     unsafe { LLVMInt1Type() }
 }
@@ -162,19 +313,40 @@ fn add_c_declarations(module: &mut Module) {
     add_function(
         module,
         "llvm.memset.p0i8.i32",
-        &mut [
-            int8_ptr_type(),
-            int8_type(),
-            int32_type(),
-            int32_type(),
-            int1_type(),
-        ],
+        &mut [int8_ptr_type(), int8_type(), int32_type(), int1_type()],
         void,
     );
 
+    match module.runtime {
+        Runtime::Libc => add_libc_declarations(module),
+        Runtime::CustomIo => {
+            add_libc_memory_declarations(module);
+            unsafe { add_custom_io_definitions(module) }
+        }
+        Runtime::Wasi => unsafe { add_wasi_definitions(module) },
+    }
+}
+
+fn add_libc_memory_declarations(module: &mut Module) {
+    let void;
+    unsafe {
+        void = LLVMVoidType();
+    }
+
     add_function(module, "malloc", &mut [int32_type()], int8_ptr_type());
 
+    add_function(
+        module,
+        "calloc",
+        &mut [int32_type(), int32_type()],
+        int8_ptr_type(),
+    );
+
     add_function(module, "free", &mut [int8_ptr_type()], void);
+}
+
+fn add_libc_declarations(module: &mut Module) {
+    add_libc_memory_declarations(module);
 
     add_function(
         module,
@@ -186,6 +358,318 @@ fn add_c_declarations(module: &mut Module) {
     add_function(module, "putchar", &mut [int32_type()], int32_type());
 
     add_function(module, "getchar", &mut [], int32_type());
+
+    add_function(
+        module,
+        "setvbuf",
+        &mut [int8_ptr_type(), int8_ptr_type(), int32_type(), int32_type()],
+        int32_type(),
+    );
+}
+
+/// Declare `bf_getchar` and `bf_putchar`, which the user provides,
+/// and define `write` in terms of `bf_putchar`. This lets us buffer
+/// output and write compile time outputs the same way as with libc.
+unsafe fn add_custom_io_definitions(module: &mut Module) {
+    add_function(module, "bf_putchar", &mut [int32_type()], int32_type());
+    add_function(module, "bf_getchar", &mut [], int32_type());
+
+    // int write(int fd, char *buf, int len) {
+    //   for (int i = 0; i < len; i++) {
+    //     bf_putchar((unsigned char)buf[i]);
+    //   }
+    //   return len;
+    // }
+    let (write_fn, builder) = add_private_function(
+        module,
+        "write",
+        &mut [int32_type(), int8_ptr_type(), int32_type()],
+        int32_type(),
+    );
+    let buf = LLVMGetParam(write_fn, 1);
+    let len = LLVMGetParam(write_fn, 2);
+
+    let index_ptr = LLVMBuildAlloca(
+        builder.builder,
+        int32_type(),
+        module.new_string_ptr("index_ptr"),
+    );
+    LLVMBuildStore(builder.builder, int32(0), index_ptr);
+
+    let header_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("write_header"));
+    let body_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("write_body"));
+    let after_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("write_after"));
+    LLVMBuildBr(builder.builder, header_bb);
+
+    builder.position_at_end(header_bb);
+    let index = LLVMBuildLoad(builder.builder, index_ptr, module.new_string_ptr("index"));
+    let is_done = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntSGE,
+        index,
+        len,
+        module.new_string_ptr("is_done"),
+    );
+    LLVMBuildCondBr(builder.builder, is_done, after_bb, body_bb);
+
+    builder.position_at_end(body_bb);
+    let mut indices = vec![index];
+    let byte_ptr = LLVMBuildGEP(
+        builder.builder,
+        buf,
+        indices.as_mut_ptr(),
+        indices.len() as c_uint,
+        module.new_string_ptr("byte_ptr"),
+    );
+    let byte = LLVMBuildLoad(builder.builder, byte_ptr, module.new_string_ptr("byte"));
+    let char_value = LLVMBuildZExt(
+        builder.builder,
+        byte,
+        int32_type(),
+        module.new_string_ptr("char_value"),
+    );
+    add_function_call(module, body_bb, "bf_putchar", &mut [char_value], "");
+    let next_index = LLVMBuildAdd(
+        builder.builder,
+        index,
+        int32(1),
+        module.new_string_ptr("next_index"),
+    );
+    LLVMBuildStore(builder.builder, next_index, index_ptr);
+    LLVMBuildBr(builder.builder, header_bb);
+
+    builder.position_at_end(after_bb);
+    LLVMBuildRet(builder.builder, len);
+}
+
+/// The WASI `iovec` struct: a pointer to a buffer and its length.
+fn iovec_type() -> LLVMTypeRef {
+    let mut fields = [int8_ptr_type(), int32_type()];
+    unsafe { LLVMStructType(fields.as_mut_ptr(), fields.len() as c_uint, LLVM_FALSE) }
+}
+
+/// Declare a function that is imported from the host's
+/// `wasi_snapshot_preview1` module.
+unsafe fn add_wasi_import(
+    module: &mut Module,
+    fn_name: &str,
+    args: &mut [LLVMTypeRef],
+    ret_type: LLVMTypeRef,
+) {
+    add_function(module, fn_name, args, ret_type);
+    let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+
+    for (key, value) in [
+        ("wasm-import-module", "wasi_snapshot_preview1"),
+        ("wasm-import-name", fn_name),
+    ] {
+        let attribute = LLVMCreateStringAttribute(
+            LLVMGetGlobalContext(),
+            module.new_string_ptr(key),
+            key.len() as c_uint,
+            module.new_string_ptr(value),
+            value.len() as c_uint,
+        );
+        LLVMAddAttributeAtIndex(function, LLVMAttributeFunctionIndex, attribute);
+    }
+}
+
+/// Define a private function in this module, and return it along
+/// with a builder positioned at its entry block.
+unsafe fn add_private_function(
+    module: &mut Module,
+    fn_name: &str,
+    args: &mut [LLVMTypeRef],
+    ret_type: LLVMTypeRef,
+) -> (LLVMValueRef, Builder) {
+    add_function(module, fn_name, args, ret_type);
+    let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+    LLVMSetLinkage(function, LLVMLinkage::LLVMPrivateLinkage);
+
+    let bb = LLVMAppendBasicBlock(function, module.new_string_ptr("entry"));
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    (function, builder)
+}
+
+/// Allocate an iovec on the stack that refers to `len` bytes at `buf`.
+unsafe fn build_iovec(
+    module: &mut Module,
+    builder: &Builder,
+    buf: LLVMValueRef,
+    len: LLVMValueRef,
+) -> LLVMValueRef {
+    let iovec = LLVMBuildAlloca(
+        builder.builder,
+        iovec_type(),
+        module.new_string_ptr("iovec"),
+    );
+    let buf_ptr = LLVMBuildStructGEP2(
+        builder.builder,
+        iovec_type(),
+        iovec,
+        0,
+        module.new_string_ptr("buf_ptr"),
+    );
+    LLVMBuildStore(builder.builder, buf, buf_ptr);
+    let len_ptr = LLVMBuildStructGEP2(
+        builder.builder,
+        iovec_type(),
+        iovec,
+        1,
+        module.new_string_ptr("len_ptr"),
+    );
+    LLVMBuildStore(builder.builder, len, len_ptr);
+
+    iovec
+}
+
+/// Import `fd_write` and `fd_read` from WASI, and define `write`,
+/// `putchar` and `getchar` in terms of them. This lets us compile
+/// I/O the same way on every target.
+unsafe fn add_wasi_definitions(module: &mut Module) {
+    let iovec_ptr_type = LLVMPointerType(iovec_type(), 0);
+    let int32_ptr_type = LLVMPointerType(int32_type(), 0);
+    for fn_name in ["fd_write", "fd_read"] {
+        add_wasi_import(
+            module,
+            fn_name,
+            &mut [int32_type(), iovec_ptr_type, int32_type(), int32_ptr_type],
+            int32_type(),
+        );
+    }
+
+    // int write(int fd, char *buf, int len) {
+    //   size_t nwritten = 0;
+    //   fd_write(fd, &(iovec){buf, len}, 1, &nwritten);
+    //   return nwritten;
+    // }
+    let (write_fn, builder) = add_private_function(
+        module,
+        "write",
+        &mut [int32_type(), int8_ptr_type(), int32_type()],
+        int32_type(),
+    );
+    let iovec = build_iovec(
+        module,
+        &builder,
+        LLVMGetParam(write_fn, 1),
+        LLVMGetParam(write_fn, 2),
+    );
+    let nwritten_ptr = LLVMBuildAlloca(
+        builder.builder,
+        int32_type(),
+        module.new_string_ptr("nwritten_ptr"),
+    );
+    LLVMBuildStore(builder.builder, int32(0), nwritten_ptr);
+    let write_bb = LLVMGetInsertBlock(builder.builder);
+    add_function_call(
+        module,
+        write_bb,
+        "fd_write",
+        &mut [LLVMGetParam(write_fn, 0), iovec, int32(1), nwritten_ptr],
+        "",
+    );
+    let nwritten = LLVMBuildLoad(
+        builder.builder,
+        nwritten_ptr,
+        module.new_string_ptr("nwritten"),
+    );
+    LLVMBuildRet(builder.builder, nwritten);
+
+    // int putchar(int c) {
+    //   char byte = c;
+    //   write(1, &byte, 1);
+    //   return c;
+    // }
+    let (putchar_fn, builder) =
+        add_private_function(module, "putchar", &mut [int32_type()], int32_type());
+    let byte_ptr = LLVMBuildAlloca(
+        builder.builder,
+        int8_type(),
+        module.new_string_ptr("byte_ptr"),
+    );
+    let byte = LLVMBuildTrunc(
+        builder.builder,
+        LLVMGetParam(putchar_fn, 0),
+        int8_type(),
+        module.new_string_ptr("byte"),
+    );
+    LLVMBuildStore(builder.builder, byte, byte_ptr);
+    let putchar_bb = LLVMGetInsertBlock(builder.builder);
+    add_function_call(
+        module,
+        putchar_bb,
+        "write",
+        &mut [int32(1), byte_ptr, int32(1)],
+        "",
+    );
+    LLVMBuildRet(builder.builder, LLVMGetParam(putchar_fn, 0));
+
+    // int getchar() {
+    //   unsigned char byte;
+    //   size_t nread = 0;
+    //   fd_read(0, &(iovec){&byte, 1}, 1, &nread);
+    //   return nread == 0 ? EOF : byte;
+    // }
+    let (_, builder) = add_private_function(module, "getchar", &mut [], int32_type());
+    let byte_ptr = LLVMBuildAlloca(
+        builder.builder,
+        int8_type(),
+        module.new_string_ptr("byte_ptr"),
+    );
+    let iovec = build_iovec(module, &builder, byte_ptr, int32(1));
+    let nread_ptr = LLVMBuildAlloca(
+        builder.builder,
+        int32_type(),
+        module.new_string_ptr("nread_ptr"),
+    );
+    LLVMBuildStore(builder.builder, int32(0), nread_ptr);
+    let getchar_bb = LLVMGetInsertBlock(builder.builder);
+    add_function_call(
+        module,
+        getchar_bb,
+        "fd_read",
+        &mut [int32(0), iovec, int32(1), nread_ptr],
+        "",
+    );
+    let nread = LLVMBuildLoad(builder.builder, nread_ptr, module.new_string_ptr("nread"));
+    let is_eof = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        nread,
+        int32(0),
+        module.new_string_ptr("is_eof"),
+    );
+    let byte = LLVMBuildLoad(builder.builder, byte_ptr, module.new_string_ptr("byte"));
+    let char_value = LLVMBuildZExt(
+        builder.builder,
+        byte,
+        int32_type(),
+        module.new_string_ptr("char_value"),
+    );
+    let result = LLVMBuildSelect(
+        builder.builder,
+        is_eof,
+        LLVMConstInt(int32_type(), -1_i64 as c_ulonglong, LLVM_TRUE),
+        char_value,
+        module.new_string_ptr("result"),
+    );
+    LLVMBuildRet(builder.builder, result);
+}
+
+/// Define `_start`, which WASI runtimes call to run the program.
+unsafe fn add_wasi_entry_point(module: &mut Module) {
+    add_function(module, "_start", &mut [], LLVMVoidType());
+    let start_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("_start"));
+    let bb = LLVMAppendBasicBlock(start_fn, module.new_string_ptr("entry"));
+
+    add_function_call(module, bb, "main", &mut [], "");
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    LLVMBuildRetVoid(builder.builder);
 }
 
 unsafe fn add_function_call(
@@ -237,19 +721,26 @@ fn add_cells_init(
     builder.position_at_end(bb);
 
     unsafe {
-        // char* cells = malloc(num_cells);
+        if module.runtime == Runtime::Wasi {
+            return add_static_cells_init(init_values, module, &builder);
+        }
+
         let num_cells = int32(init_values.len() as c_ulonglong);
+
+        // If no cells have been modified at compile time, calloc is
+        // cheaper than malloc and memset.
+        if init_values.iter().all(|cell_val| cell_val.0 == 0) {
+            // char* cells = calloc(num_cells, 1);
+            let mut calloc_args = vec![num_cells, int32(1)];
+            return add_function_call(module, bb, "calloc", &mut calloc_args, "cells");
+        }
+
+        // char* cells = malloc(num_cells);
         let mut malloc_args = vec![num_cells];
         let cells_ptr = add_function_call(module, bb, "malloc", &mut malloc_args, "cells");
 
-        let one = int32(1);
-        let false_ = LLVMConstInt(int1_type(), 1, LLVM_FALSE);
-
         let mut offset = 0;
         for (cell_val, cell_count) in run_length_encode(init_values) {
-            let llvm_cell_val = int8(cell_val.0 as c_ulonglong);
-            let llvm_cell_count = int32(cell_count as c_ulonglong);
-
             // TODO: factor out a build_gep function.
             let mut offset_vec = vec![int32(offset as c_ulonglong)];
             let offset_cell_ptr = LLVMBuildGEP(
@@ -260,9 +751,7 @@ fn add_cells_init(
                 module.new_string_ptr("offset_cell_ptr"),
             );
 
-            let mut memset_args =
-                vec![offset_cell_ptr, llvm_cell_val, llvm_cell_count, one, false_];
-            add_function_call(module, bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
+            add_memset(module, bb, offset_cell_ptr, cell_val, cell_count);
 
             offset += cell_count;
         }
@@ -271,7 +760,72 @@ fn add_cells_init(
     }
 }
 
+/// Set `count` cells from `cell_ptr` onwards to `value`.
+unsafe fn add_memset(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    cell_ptr: LLVMValueRef,
+    value: BfValue,
+    count: usize,
+) {
+    add_memset_value(module, bb, cell_ptr, int8(value.0 as c_ulonglong), count);
+}
+
+/// As `add_memset`, but `value` is an i8 that may only be known at
+/// runtime.
+unsafe fn add_memset_value(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ptr: LLVMValueRef,
+    value: LLVMValueRef,
+    count: usize,
+) {
+    let is_volatile = LLVMConstInt(int1_type(), 0, LLVM_FALSE);
+
+    let mut memset_args = vec![ptr, value, int32(count as c_ulonglong), is_volatile];
+    add_function_call(module, bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
+}
+
+/// Store the cells in a global in linear memory, initialised to
+/// `init_values`. We don't use memset, as wasm32 lowers it to a
+/// libc call.
+unsafe fn add_static_cells_init(
+    init_values: &[Wrapping<i8>],
+    module: &mut Module,
+    builder: &Builder,
+) -> LLVMValueRef {
+    // static char cells[num_cells] = {...};
+    let mut llvm_init_values: Vec<_> = init_values
+        .iter()
+        .map(|cell_val| int8(cell_val.0 as c_ulonglong))
+        .collect();
+    let cells_init = LLVMConstArray(
+        int8_type(),
+        llvm_init_values.as_mut_ptr(),
+        llvm_init_values.len() as c_uint,
+    );
+    let cells = LLVMAddGlobal(
+        module.module,
+        LLVMTypeOf(cells_init),
+        module.new_string_ptr("cells"),
+    );
+    LLVMSetInitializer(cells, cells_init);
+    LLVMSetLinkage(cells, LLVMLinkage::LLVMPrivateLinkage);
+
+    LLVMBuildPointerCast(
+        builder.builder,
+        cells,
+        int8_ptr_type(),
+        module.new_string_ptr("cells"),
+    )
+}
+
 fn add_cells_cleanup(module: &mut Module, bb: LLVMBasicBlockRef, cells: LLVMValueRef) {
+    // Cells in linear memory are never freed.
+    if module.runtime == Runtime::Wasi {
+        return;
+    }
+
     let builder = Builder::new();
     builder.position_at_end(bb);
 
@@ -282,7 +836,7 @@ fn add_cells_cleanup(module: &mut Module, bb: LLVMBasicBlockRef, cells: LLVMValu
     }
 }
 
-fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
+fn create_module(module_name: &str, target_triple: Option<String>, custom_io: bool) -> Module {
     let c_module_name = CString::new(module_name).unwrap();
     let module_name_char_ptr = c_module_name.to_bytes_with_nul().as_ptr() as *const _;
 
@@ -290,10 +844,6 @@ fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
     unsafe {
         llvm_module = LLVMModuleCreateWithName(module_name_char_ptr);
     }
-    let mut module = Module {
-        module: llvm_module,
-        strings: vec![c_module_name],
-    };
 
     let target_triple_cstring = if let Some(target_triple) = target_triple {
         CString::new(target_triple).unwrap()
@@ -301,6 +851,14 @@ fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
         get_default_target_triple()
     };
 
+    let mut module = Module {
+        module: llvm_module,
+        strings: vec![c_module_name],
+        loop_counters: vec![],
+        runtime: Runtime::for_target(&target_triple_cstring, custom_io),
+        debug_scope: None,
+    };
+
     // This is necessary for maximum LLVM performance, see
     // http://llvm.org/docs/Frontend/PerformanceTips.html
     unsafe {
@@ -313,12 +871,56 @@ fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
     module
 }
 
-fn add_main_fn(module: &mut Module) -> LLVMValueRef {
+/// Functions and globals that generated modules may declare or
+/// define, other than `main`.
+const RESERVED_SYMBOLS: &[&str] = &[
+    "_start",
+    "__stdoutp",
+    "bf_getchar",
+    "bf_putchar",
+    "calloc",
+    "cells",
+    "check_cell_index",
+    "dprintf",
+    "exit",
+    "fd_read",
+    "fd_write",
+    "flush_output",
+    "free",
+    "getchar",
+    "known_outputs",
+    "malloc",
+    "output_buffer",
+    "output_len",
+    "putchar",
+    "setvbuf",
+    "stdout",
+    "write",
+];
+
+/// Would an entry point called `name` clash with a symbol in the
+/// generated module? LLVM would rename one of them, so the entry
+/// point wouldn't have the name the user asked for.
+pub fn is_reserved_symbol(name: &str) -> bool {
+    RESERVED_SYMBOLS.contains(&name)
+        || name.starts_with("llvm.")
+        || name.starts_with("loop_iterations")
+}
+
+/// Add the function that runs the program. `main` returns an exit
+/// code for libc, but other entry points return nothing, so other
+/// code can call them.
+fn add_main_fn(module: &mut Module, name: &str) -> LLVMValueRef {
     let mut main_args = vec![];
     unsafe {
-        let main_type = LLVMFunctionType(int32_type(), main_args.as_mut_ptr(), 0, LLVM_FALSE);
+        let return_type = if name == "main" {
+            int32_type()
+        } else {
+            LLVMVoidType()
+        };
+        let main_type = LLVMFunctionType(return_type, main_args.as_mut_ptr(), 0, LLVM_FALSE);
         // TODO: use add_function() here instead.
-        LLVMAddFunction(module.module, module.new_string_ptr("main"), main_type)
+        LLVMAddFunction(module.module, module.new_string_ptr(name), main_type)
     }
 }
 
@@ -362,13 +964,17 @@ unsafe fn add_cell_index_init(
     cell_index_ptr
 }
 
-/// Add prologue to main function.
-unsafe fn add_main_cleanup(bb: LLVMBasicBlockRef) {
+/// Add prologue to main function. Only `main` returns an exit code.
+unsafe fn add_main_cleanup(bb: LLVMBasicBlockRef, entry_name: &str) {
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let zero = int32(0);
-    LLVMBuildRet(builder.builder, zero);
+    if entry_name == "main" {
+        let zero = int32(0);
+        LLVMBuildRet(builder.builder, zero);
+    } else {
+        LLVMBuildRetVoid(builder.builder);
+    }
 }
 
 /// Add LLVM IR instructions for accessing the current cell, and
@@ -376,26 +982,12 @@ unsafe fn add_main_cleanup(bb: LLVMBasicBlockRef) {
 unsafe fn add_current_cell_access(
     module: &mut Module,
     bb: LLVMBasicBlockRef,
-    cells: LLVMValueRef,
-    cell_index_ptr: LLVMValueRef,
+    ctx: &CompileContext,
 ) -> (LLVMValueRef, LLVMValueRef) {
+    let current_cell_ptr = add_offset_cell_ptr(module, bb, ctx, 0);
+
     let builder = Builder::new();
     builder.position_at_end(bb);
-
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
-
-    let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        cells,
-        indices.as_mut_ptr(),
-        indices.len() as u32,
-        module.new_string_ptr("current_cell_ptr"),
-    );
     let current_cell = LLVMBuildLoad(
         builder.builder,
         current_cell_ptr,
@@ -405,37 +997,91 @@ unsafe fn add_current_cell_access(
     (current_cell, current_cell_ptr)
 }
 
-unsafe fn compile_increment(
-    amount: BfValue,
-    offset: isize,
+/// Return the current cell index, loading it at the end of `bb` unless
+/// we already know its value there.
+unsafe fn load_cell_index(
     module: &mut Module,
+    builder: &Builder,
     bb: LLVMBasicBlockRef,
-    ctx: CompileContext,
-) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
-    builder.position_at_end(bb);
+    ctx: &CompileContext,
+    name: &str,
+) -> LLVMValueRef {
+    if let Some((cached_bb, cell_index)) = ctx.cell_index_cache.get() {
+        if cached_bb == bb {
+            return cell_index;
+        }
+    }
 
     let cell_index = LLVMBuildLoad(
         builder.builder,
         ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
+        module.new_string_ptr(name),
     );
+    ctx.cell_index_cache.set(Some((bb, cell_index)));
+    cell_index
+}
 
-    let offset_cell_index = LLVMBuildAdd(
-        builder.builder,
-        cell_index,
-        int32(offset as c_ulonglong),
-        module.new_string_ptr("offset_cell_index"),
-    );
+/// Store `cell_index` at the end of `bb`, and remember it so later
+/// instructions in `bb` don't need to load it.
+unsafe fn store_cell_index(
+    builder: &Builder,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+    cell_index: LLVMValueRef,
+) {
+    LLVMBuildStore(builder.builder, cell_index, ctx.cell_index_ptr);
+    ctx.cell_index_cache.set(Some((bb, cell_index)));
+}
 
-    let mut indices = vec![offset_cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as c_uint,
-        module.new_string_ptr("current_cell_ptr"),
-    );
+/// Add `offset` to `cell_index`. On a wrapping tape, we take the
+/// result modulo the tape length, so it's always a valid index.
+unsafe fn build_offset_cell_index(
+    module: &mut Module,
+    builder: &Builder,
+    ctx: &CompileContext,
+    cell_index: LLVMValueRef,
+    offset: isize,
+    name: &str,
+) -> LLVMValueRef {
+    match ctx.wrapping_tape_len {
+        Some(tape_len) => {
+            // Use an offset in 0..tape_len, so we can use unsigned
+            // remainder. The cell index is in 0..tape_len, so the sum
+            // can't overflow.
+            let offset = offset.rem_euclid(tape_len as isize);
+            let unwrapped_index = LLVMBuildAdd(
+                builder.builder,
+                cell_index,
+                int32(offset as c_ulonglong),
+                module.new_string_ptr(&format!("{}_unwrapped", name)),
+            );
+            LLVMBuildURem(
+                builder.builder,
+                unwrapped_index,
+                int32(tape_len as c_ulonglong),
+                module.new_string_ptr(name),
+            )
+        }
+        None => LLVMBuildAdd(
+            builder.builder,
+            cell_index,
+            int32(offset as c_ulonglong),
+            module.new_string_ptr(name),
+        ),
+    }
+}
+
+unsafe fn compile_increment(
+    amount: BfValue,
+    offset: isize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let current_cell_ptr = add_offset_cell_ptr(module, bb, &ctx, offset);
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
 
     let cell_val = LLVMBuildLoad(
         builder.builder,
@@ -462,36 +1108,58 @@ unsafe fn compile_set(
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
+    let current_cell_ptr = add_offset_cell_ptr(module, bb, &ctx, offset);
+
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
+    LLVMBuildStore(
         builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
+        int8(amount.0 as c_ulonglong),
+        current_cell_ptr,
     );
+    bb
+}
 
-    let offset_cell_index = LLVMBuildAdd(
-        builder.builder,
-        cell_index,
-        int32(offset as c_ulonglong),
-        module.new_string_ptr("offset_cell_index"),
-    );
+/// Copy the cell at `src_offset` to the cell at `dst_offset`, adding
+/// to it unless `overwrite` is set. We load the source before
+/// touching the destination, so the cells may be the same.
+unsafe fn compile_cell_copy(
+    src_offset: isize,
+    dst_offset: isize,
+    overwrite: bool,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let src_cell_ptr = add_offset_cell_ptr(module, bb, &ctx, src_offset);
+    let dst_cell_ptr = add_offset_cell_ptr(module, bb, &ctx, dst_offset);
 
-    let mut indices = vec![offset_cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as c_uint,
-        module.new_string_ptr("current_cell_ptr"),
-    );
+    let builder = Builder::new();
+    builder.position_at_end(bb);
 
-    LLVMBuildStore(
+    let src_cell_val = LLVMBuildLoad(
         builder.builder,
-        int8(amount.0 as c_ulonglong),
-        current_cell_ptr,
+        src_cell_ptr,
+        module.new_string_ptr("src_cell_val"),
     );
+    let new_cell_val = if overwrite {
+        src_cell_val
+    } else {
+        let dst_cell_val = LLVMBuildLoad(
+            builder.builder,
+            dst_cell_ptr,
+            module.new_string_ptr("dst_cell_val"),
+        );
+        LLVMBuildAdd(
+            builder.builder,
+            dst_cell_val,
+            src_cell_val,
+            module.new_string_ptr("new_cell_value"),
+        )
+    };
+
+    LLVMBuildStore(builder.builder, new_cell_val, dst_cell_ptr);
     bb
 }
 
@@ -508,8 +1176,7 @@ unsafe fn compile_multiply_move(
     builder.position_at_end(bb);
 
     // First, get the current cell value.
-    let (cell_val, cell_val_ptr) =
-        add_current_cell_access(module, bb, ctx.cells, ctx.cell_index_ptr);
+    let (cell_val, cell_val_ptr) = add_current_cell_access(module, bb, &ctx);
 
     // Check if the current cell is zero, as we only do the multiply
     // if it's non-zero.
@@ -531,24 +1198,53 @@ unsafe fn compile_multiply_move(
     // In the multiply body, do the multiply
     builder.position_at_end(multiply_body);
 
-    // Zero the current cell.
-    LLVMBuildStore(builder.builder, int8(0), cell_val_ptr);
+    // Update the current cell. Copies that don't need a temporary
+    // cell, e.g. {0: 1, 1: 1}, leave the current cell unchanged.
+    match changes.get(&0) {
+        Some(factor) if factor.0 == 1 => {}
+        Some(factor) => {
+            let new_cell_val = LLVMBuildMul(
+                builder.builder,
+                cell_val,
+                int8(factor.0 as c_ulonglong),
+                module.new_string_ptr("new_cell_value"),
+            );
+            LLVMBuildStore(builder.builder, new_cell_val, cell_val_ptr);
+        }
+        None => {
+            LLVMBuildStore(builder.builder, int8(0), cell_val_ptr);
+        }
+    }
 
-    let mut targets: Vec<_> = changes.keys().collect();
+    let mut targets: Vec<_> = changes.keys().filter(|offset| **offset != 0).collect();
     targets.sort();
 
     // For each cell that we should change, multiply the current cell
     // value then add it.
     for target in targets {
-        // Calculate the position of this target cell.
-        let mut indices = vec![int32(*target as c_ulonglong)];
-        let target_cell_ptr = LLVMBuildGEP(
-            builder.builder,
-            cell_val_ptr,
-            indices.as_mut_ptr(),
-            indices.len() as c_uint,
-            module.new_string_ptr("target_cell_ptr"),
-        );
+        // Calculate the position of this target cell. On a wrapping
+        // tape, the target may be on the other side of the tape.
+        let target_cell_ptr = if ctx.bounds_check || ctx.wrapping_tape_len.is_some() {
+            let cell_index = load_cell_index(module, &builder, multiply_body, &ctx, "cell_index");
+            cell_address(
+                module,
+                &builder,
+                multiply_body,
+                &ctx,
+                cell_index,
+                *target,
+                "target_cell",
+            )
+        } else {
+            let mut indices = vec![int32(*target as c_ulonglong)];
+            LLVMBuildGEP(
+                builder.builder,
+                cell_val_ptr,
+                indices.as_mut_ptr(),
+                indices.len() as c_uint,
+                module.new_string_ptr("target_cell_ptr"),
+            )
+        };
 
         // Get the current value of the target cell.
         let target_cell_val = LLVMBuildLoad(
@@ -557,14 +1253,19 @@ unsafe fn compile_multiply_move(
             module.new_string_ptr("target_cell_val"),
         );
 
-        // Calculate the new value.
+        // Calculate the new value. Moves with a factor of 1 are very
+        // common, so don't emit a multiply for them.
         let factor = *changes.get(target).unwrap();
-        let additional_val = LLVMBuildMul(
-            builder.builder,
-            cell_val,
-            int8(factor.0 as c_ulonglong),
-            module.new_string_ptr("additional_val"),
-        );
+        let additional_val = if factor.0 == 1 {
+            cell_val
+        } else {
+            LLVMBuildMul(
+                builder.builder,
+                cell_val,
+                int8(factor.0 as c_ulonglong),
+                module.new_string_ptr("additional_val"),
+            )
+        };
         let new_target_val = LLVMBuildAdd(
             builder.builder,
             target_cell_val,
@@ -589,150 +1290,1054 @@ unsafe fn compile_ptr_increment(
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
+    let cell_index = load_cell_index(module, &builder, bb, &ctx, "cell_index");
+
+    let new_cell_index =
+        build_offset_cell_index(module, &builder, &ctx, cell_index, amount, "new_cell_index");
+    add_bounds_check(module, bb, &ctx, new_cell_index);
+
+    store_cell_index(&builder, bb, &ctx, new_cell_index);
+    bb
+}
+
+/// Move the cell index by `step` until we reach a zero cell. We keep
+/// the cell index in a register during the scan, and only store it
+/// once we're done.
+unsafe fn compile_scan_loop(
+    step: isize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let scan_header = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("scan_header"));
+    let scan_body = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("scan_body"));
+    let scan_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("scan_after"));
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let start_cell_index = load_cell_index(module, &builder, bb, &ctx, "scan_start_index");
+    LLVMBuildBr(builder.builder, scan_header);
+
+    // scan_header:
+    //   %scan_index = phi ...
+    //   %scan_cell_value = ...
+    //   %scan_cell_value_is_zero = icmp ...
+    //   br %scan_cell_value_is_zero, %scan_after, %scan_body
+    builder.position_at_end(scan_header);
+    let scan_index = LLVMBuildPhi(
         builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
+        int32_type(),
+        module.new_string_ptr("scan_index"),
+    );
+
+    let scan_cell_ptr = cell_address(
+        module,
+        &builder,
+        scan_header,
+        &ctx,
+        scan_index,
+        0,
+        "scan_cell",
+    );
+    let scan_cell_val = LLVMBuildLoad(
+        builder.builder,
+        scan_cell_ptr,
+        module.new_string_ptr("scan_cell_value"),
+    );
+
+    let zero = int8(0);
+    let scan_cell_val_is_zero = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        zero,
+        scan_cell_val,
+        module.new_string_ptr("scan_cell_value_is_zero"),
+    );
+    LLVMBuildCondBr(
+        builder.builder,
+        scan_cell_val_is_zero,
+        scan_after,
+        scan_body,
+    );
+
+    // scan_body:
+    //   %scan_next_index = add %scan_index, step
+    //   br %scan_header
+    builder.position_at_end(scan_body);
+    let next_index =
+        build_offset_cell_index(module, &builder, &ctx, scan_index, step, "scan_next_index");
+    LLVMBuildBr(builder.builder, scan_header);
+
+    let mut incoming_values = vec![start_cell_index, next_index];
+    let mut incoming_blocks = vec![bb, scan_body];
+    LLVMAddIncoming(
+        scan_index,
+        incoming_values.as_mut_ptr(),
+        incoming_blocks.as_mut_ptr(),
+        incoming_values.len() as c_uint,
+    );
+
+    // Once we've found a zero cell, update the cell index.
+    builder.position_at_end(scan_after);
+    store_cell_index(&builder, scan_after, &ctx, scan_index);
+
+    scan_after
+}
+
+/// If the cell at `offset` is non-zero, loop forever. We don't need
+/// to re-check the cell, as nothing can change it.
+unsafe fn compile_infinite_loop(
+    offset: isize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let infinite_entry = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("infinite_entry"));
+    let infinite_loop = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("infinite_loop"));
+    let infinite_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("infinite_after"));
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cell_val = add_offset_cell_access(module, bb, &ctx, offset);
+
+    let zero = int8(0);
+    let cell_val_is_zero = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        zero,
+        cell_val,
+        module.new_string_ptr("cell_value_is_zero"),
+    );
+    LLVMBuildCondBr(
+        builder.builder,
+        cell_val_is_zero,
+        infinite_after,
+        infinite_entry,
+    );
+
+    // We never exit the loop, so flush any buffered output now.
+    if ctx.output_buffer.is_some() {
+        add_function_call(module, infinite_entry, "flush_output", &mut [], "");
+    }
+    builder.position_at_end(infinite_entry);
+    LLVMBuildBr(builder.builder, infinite_loop);
+
+    // infinite_loop:
+    //   br %infinite_loop
+    builder.position_at_end(infinite_loop);
+    LLVMBuildBr(builder.builder, infinite_loop);
+
+    infinite_after
+}
+
+unsafe fn compile_read(
+    offset: isize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    // Flush any buffered output first, so the user sees any prompt
+    // before we wait for input.
+    if ctx.output_buffer.is_some() {
+        add_function_call(module, bb, "flush_output", &mut [], "");
+    }
+
+    let current_cell_ptr = add_offset_cell_ptr(module, bb, &ctx, offset);
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let mut getchar_args = vec![];
+    let input_char = add_function_call(
+        module,
+        bb,
+        module.runtime.getchar_name(),
+        &mut getchar_args,
+        "input_char",
+    );
+    let input_byte = LLVMBuildTrunc(
+        builder.builder,
+        input_char,
+        int8_type(),
+        module.new_string_ptr("input_byte"),
+    );
+
+    LLVMBuildStore(builder.builder, input_byte, current_cell_ptr);
+    bb
+}
+
+unsafe fn compile_write(
+    offset: isize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    if let Some(output_buffer) = ctx.output_buffer {
+        return compile_buffered_write(offset, module, bb, ctx, output_buffer);
+    }
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // putchar writes the low byte of its argument. Zero extend, so
+    // the argument is the byte we're writing, e.g. a cell containing
+    // 200 is putchar(200) rather than putchar(-56).
+    let cell_val = add_offset_cell_access(module, bb, &ctx, offset);
+    let cell_val_as_char = LLVMBuildZExt(
+        builder.builder,
+        cell_val,
+        int32_type(),
+        module.new_string_ptr("cell_val_as_char"),
+    );
+
+    let mut putchar_args = vec![cell_val_as_char];
+    add_function_call(
+        module,
+        bb,
+        module.runtime.putchar_name(),
+        &mut putchar_args,
+        "",
+    );
+    bb
+}
+
+/// Append the current cell to the output buffer, and flush the
+/// buffer if it's full.
+unsafe fn compile_buffered_write(
+    offset: isize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+    output_buffer: OutputBuffer,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cell_val = add_offset_cell_access(module, bb, &ctx, offset);
+
+    // output_buffer[output_len] = cell_val;
+    let output_len = LLVMBuildLoad(
+        builder.builder,
+        output_buffer.len,
+        module.new_string_ptr("output_len"),
+    );
+    let mut indices = vec![int32(0), output_len];
+    let output_ptr = LLVMBuildGEP(
+        builder.builder,
+        output_buffer.buffer,
+        indices.as_mut_ptr(),
+        indices.len() as c_uint,
+        module.new_string_ptr("output_ptr"),
+    );
+    LLVMBuildStore(builder.builder, cell_val, output_ptr);
+
+    // output_len++;
+    let new_output_len = LLVMBuildAdd(
+        builder.builder,
+        output_len,
+        int32(1),
+        module.new_string_ptr("new_output_len"),
+    );
+    LLVMBuildStore(builder.builder, new_output_len, output_buffer.len);
+
+    add_flush_if_full(new_output_len, module, bb, &ctx)
+}
+
+/// Flush the output buffer if `new_output_len` shows it's full, so
+/// there's always room for another byte. Returns the basic block
+/// after flushing.
+unsafe fn add_flush_if_full(
+    new_output_len: LLVMValueRef,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // if (output_len == OUTPUT_BUFFER_SIZE) { flush_output(); }
+    let flush_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("flush_full_output"));
+    let after_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("after_write"));
+
+    let output_is_full = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        new_output_len,
+        int32(OUTPUT_BUFFER_SIZE),
+        module.new_string_ptr("output_is_full"),
+    );
+    LLVMBuildCondBr(builder.builder, output_is_full, flush_bb, after_bb);
+
+    add_function_call(module, flush_bb, "flush_output", &mut [], "");
+    builder.position_at_end(flush_bb);
+    LLVMBuildBr(builder.builder, after_bb);
+
+    after_bb
+}
+
+/// Write the cell at `offset` `len` times. The cell doesn't change
+/// between writes, so we only load it once. With an output buffer, we
+/// fill the buffer with memset rather than appending a byte at a
+/// time, otherwise we call putchar in a loop.
+unsafe fn compile_write_run(
+    offset: isize,
+    len: usize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let cell_val = add_offset_cell_access(module, bb, &ctx, offset);
+
+    match ctx.output_buffer {
+        Some(output_buffer) => {
+            let mut bb = bb;
+            let mut remaining = len;
+            while remaining > 0 {
+                let fill_len = min(remaining, OUTPUT_BUFFER_SIZE as usize);
+                bb = add_output_fill(cell_val, fill_len, module, bb, &ctx, output_buffer);
+                remaining -= fill_len;
+            }
+            bb
+        }
+        None => add_putchar_loop(cell_val, len, module, bb, &ctx),
+    }
+}
+
+/// Append `len` copies of `value` to the output buffer, flushing the
+/// buffer first if there isn't room. `len` must not be larger than
+/// the buffer.
+unsafe fn add_output_fill(
+    value: LLVMValueRef,
+    len: usize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+    output_buffer: OutputBuffer,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // if (output_len > OUTPUT_BUFFER_SIZE - len) { flush_output(); }
+    let output_len = LLVMBuildLoad(
+        builder.builder,
+        output_buffer.len,
+        module.new_string_ptr("output_len"),
+    );
+    let output_lacks_room = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntUGT,
+        output_len,
+        int32(OUTPUT_BUFFER_SIZE - len as c_ulonglong),
+        module.new_string_ptr("output_lacks_room"),
+    );
+    let flush_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("flush_before_fill"));
+    let fill_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("fill_output"));
+    LLVMBuildCondBr(builder.builder, output_lacks_room, flush_bb, fill_bb);
+
+    add_function_call(module, flush_bb, "flush_output", &mut [], "");
+    builder.position_at_end(flush_bb);
+    LLVMBuildBr(builder.builder, fill_bb);
+
+    // memset(&output_buffer[output_len], value, len);
+    builder.position_at_end(fill_bb);
+    let output_len = LLVMBuildLoad(
+        builder.builder,
+        output_buffer.len,
+        module.new_string_ptr("output_len"),
+    );
+    let mut indices = vec![int32(0), output_len];
+    let output_ptr = LLVMBuildGEP(
+        builder.builder,
+        output_buffer.buffer,
+        indices.as_mut_ptr(),
+        indices.len() as c_uint,
+        module.new_string_ptr("output_ptr"),
+    );
+    add_memset_value(module, fill_bb, output_ptr, value, len);
+
+    // output_len += len;
+    builder.position_at_end(fill_bb);
+    let new_output_len = LLVMBuildAdd(
+        builder.builder,
+        output_len,
+        int32(len as c_ulonglong),
+        module.new_string_ptr("new_output_len"),
+    );
+    LLVMBuildStore(builder.builder, new_output_len, output_buffer.len);
+
+    add_flush_if_full(new_output_len, module, fill_bb, ctx)
+}
+
+/// Call putchar with `value` `len` times.
+unsafe fn add_putchar_loop(
+    value: LLVMValueRef,
+    len: usize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMBasicBlockRef {
+    let write_loop = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("write_loop"));
+    let write_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("write_after"));
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let value_as_char = LLVMBuildZExt(
+        builder.builder,
+        value,
+        int32_type(),
+        module.new_string_ptr("cell_val_as_char"),
+    );
+    LLVMBuildBr(builder.builder, write_loop);
+
+    // write_loop:
+    //   %writes = phi ...
+    //   putchar(%cell_val_as_char)
+    //   %next_writes = add %writes, 1
+    //   br %next_writes == len, %write_after, %write_loop
+    builder.position_at_end(write_loop);
+    let writes = LLVMBuildPhi(
+        builder.builder,
+        int32_type(),
+        module.new_string_ptr("writes"),
+    );
+    add_function_call(
+        module,
+        write_loop,
+        module.runtime.putchar_name(),
+        &mut [value_as_char],
+        "",
+    );
+    builder.position_at_end(write_loop);
+    let next_writes = LLVMBuildAdd(
+        builder.builder,
+        writes,
+        int32(1),
+        module.new_string_ptr("next_writes"),
+    );
+    let writes_done = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        next_writes,
+        int32(len as c_ulonglong),
+        module.new_string_ptr("writes_done"),
+    );
+    LLVMBuildCondBr(builder.builder, writes_done, write_after, write_loop);
+
+    let mut incoming_values = vec![int32(0), next_writes];
+    let mut incoming_blocks = vec![bb, write_loop];
+    LLVMAddIncoming(
+        writes,
+        incoming_values.as_mut_ptr(),
+        incoming_blocks.as_mut_ptr(),
+        incoming_values.len() as c_uint,
+    );
+
+    write_after
+}
+
+/// Define the output buffer globals, and a `flush_output` function
+/// that writes the buffer to stdout.
+unsafe fn add_output_buffer(module: &mut Module) -> OutputBuffer {
+    let buffer_type = LLVMArrayType(int8_type(), OUTPUT_BUFFER_SIZE as c_uint);
+    let buffer = LLVMAddGlobal(
+        module.module,
+        buffer_type,
+        module.new_string_ptr("output_buffer"),
+    );
+    LLVMSetInitializer(buffer, LLVMConstNull(buffer_type));
+    LLVMSetLinkage(buffer, LLVMLinkage::LLVMPrivateLinkage);
+
+    let len = LLVMAddGlobal(
+        module.module,
+        int32_type(),
+        module.new_string_ptr("output_len"),
+    );
+    LLVMSetInitializer(len, int32(0));
+    LLVMSetLinkage(len, LLVMLinkage::LLVMPrivateLinkage);
+
+    add_function(module, "flush_output", &mut [], LLVMVoidType());
+    let flush_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("flush_output"));
+    LLVMSetLinkage(flush_fn, LLVMLinkage::LLVMPrivateLinkage);
+
+    let bb = LLVMAppendBasicBlock(flush_fn, module.new_string_ptr("entry"));
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // write(1, output_buffer, output_len);
+    let output_len = LLVMBuildLoad(builder.builder, len, module.new_string_ptr("output_len"));
+    let buffer_ptr = LLVMBuildPointerCast(
+        builder.builder,
+        buffer,
+        int8_ptr_type(),
+        module.new_string_ptr("output_buffer_ptr"),
+    );
+    add_function_call(
+        module,
+        bb,
+        "write",
+        &mut [int32(1), buffer_ptr, output_len],
+        "",
+    );
+
+    // output_len = 0;
+    LLVMBuildStore(builder.builder, int32(0), len);
+    LLVMBuildRetVoid(builder.builder);
+
+    OutputBuffer { buffer, len }
+}
+
+/// Declare `dprintf`, which takes a variable number of arguments, if
+/// we haven't already.
+unsafe fn add_dprintf(module: &mut Module) {
+    if !LLVMGetNamedFunction(module.module, module.new_string_ptr("dprintf")).is_null() {
+        return;
+    }
+
+    let mut dprintf_args = vec![int32_type(), int8_ptr_type()];
+    let dprintf_type = LLVMFunctionType(
+        int32_type(),
+        dprintf_args.as_mut_ptr(),
+        dprintf_args.len() as c_uint,
+        LLVM_TRUE,
+    );
+    LLVMAddFunction(
+        module.module,
+        module.new_string_ptr("dprintf"),
+        dprintf_type,
+    );
+}
+
+/// Add a global counter for the loop at `position`, and increment it
+/// at the end of `bb`.
+unsafe fn add_loop_iteration_count(module: &mut Module, bb: LLVMBasicBlockRef, position: Position) {
+    let counter_name = format!("loop_iterations.{}", module.loop_counters.len());
+    let counter = LLVMAddGlobal(
+        module.module,
+        LLVMInt64Type(),
+        module.new_string_ptr(&counter_name),
+    );
+    LLVMSetInitializer(counter, int64(0));
+    module.loop_counters.push((position.start, counter_name));
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let count = LLVMBuildLoad(
+        builder.builder,
+        counter,
+        module.new_string_ptr("loop_iterations"),
+    );
+    let new_count = LLVMBuildAdd(
+        builder.builder,
+        count,
+        int64(1),
+        module.new_string_ptr("new_loop_iterations"),
     );
+    LLVMBuildStore(builder.builder, new_count, counter);
+}
 
-    let new_cell_index = LLVMBuildAdd(
+/// Write the iteration count of every loop to stderr.
+unsafe fn add_loop_iteration_report(module: &mut Module, bb: LLVMBasicBlockRef) {
+    add_dprintf(module);
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let message = LLVMBuildGlobalStringPtr(
         builder.builder,
+        module.new_string_ptr("Loop at offset %d: %llu iterations\n"),
+        module.new_string_ptr("loop_iterations_message"),
+    );
+
+    for (loop_start, counter_name) in module.loop_counters.clone() {
+        let counter = LLVMGetNamedGlobal(module.module, module.new_string_ptr(&counter_name));
+        let count = LLVMBuildLoad(
+            builder.builder,
+            counter,
+            module.new_string_ptr("loop_iterations"),
+        );
+        add_function_call(
+            module,
+            bb,
+            "dprintf",
+            &mut [int32(2), message, int32(loop_start as c_ulonglong), count],
+            "",
+        );
+    }
+}
+
+/// Define a `check_cell_index` function that exits with an error if
+/// its argument isn't a valid index into the `num_cells` cells.
+unsafe fn add_cell_index_check(module: &mut Module, num_cells: usize, output_buffer: bool) {
+    add_dprintf(module);
+    add_function(module, "exit", &mut [int32_type()], LLVMVoidType());
+
+    add_function(
+        module,
+        "check_cell_index",
+        &mut [int32_type()],
+        LLVMVoidType(),
+    );
+    let check_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("check_cell_index"));
+    LLVMSetLinkage(check_fn, LLVMLinkage::LLVMPrivateLinkage);
+    let cell_index = LLVMGetParam(check_fn, 0);
+
+    let entry_bb = LLVMAppendBasicBlock(check_fn, module.new_string_ptr("entry"));
+    let out_of_range_bb = LLVMAppendBasicBlock(check_fn, module.new_string_ptr("out_of_range"));
+    let in_range_bb = LLVMAppendBasicBlock(check_fn, module.new_string_ptr("in_range"));
+
+    // Negative indices are large when treated as unsigned, so a
+    // single comparison checks both bounds.
+    let builder = Builder::new();
+    builder.position_at_end(entry_bb);
+    let is_in_range = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntULT,
         cell_index,
-        int32(amount as c_ulonglong),
-        module.new_string_ptr("new_cell_index"),
+        int32(num_cells as c_ulonglong),
+        module.new_string_ptr("is_in_range"),
+    );
+    LLVMBuildCondBr(builder.builder, is_in_range, in_range_bb, out_of_range_bb);
+
+    // Show any output so far, so the user can see how far the
+    // program got.
+    if output_buffer {
+        add_function_call(module, out_of_range_bb, "flush_output", &mut [], "");
+    }
+    builder.position_at_end(out_of_range_bb);
+    let message = LLVMBuildGlobalStringPtr(
+        builder.builder,
+        module.new_string_ptr(&format!(
+            "Error: cell index %d is out of range (the program has {} cells).\n",
+            num_cells
+        )),
+        module.new_string_ptr("out_of_range_message"),
+    );
+    add_function_call(
+        module,
+        out_of_range_bb,
+        "dprintf",
+        &mut [int32(2), message, cell_index],
+        "",
     );
+    add_function_call(module, out_of_range_bb, "exit", &mut [int32(1)], "");
+    builder.position_at_end(out_of_range_bb);
+    LLVMBuildUnreachable(builder.builder);
 
-    LLVMBuildStore(builder.builder, new_cell_index, ctx.cell_index_ptr);
+    builder.position_at_end(in_range_bb);
+    LLVMBuildRetVoid(builder.builder);
+}
+
+/// If bounds checking is enabled, check that `cell_index` is a valid
+/// cell index before we use it.
+unsafe fn add_bounds_check(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+    cell_index: LLVMValueRef,
+) {
+    if ctx.bounds_check {
+        add_function_call(module, bb, "check_cell_index", &mut [cell_index], "");
+    }
+}
+
+/// Does this program contain any `.` instructions?
+fn contains_write(instrs: &[AstNode]) -> bool {
+    instrs.iter().any(|instr| match instr {
+        Write { .. } => true,
+        Loop { body, .. } => contains_write(body),
+        _ => false,
+    })
+}
+
+fn ptr_equal<T>(a: *const T, b: *const T) -> bool {
+    a == b
+}
+
+/// Add LLVM IR instructions for accessing the cell at `offset` from
+/// the current cell, and return a reference to its value.
+unsafe fn add_offset_cell_access(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+    offset: isize,
+) -> LLVMValueRef {
+    if offset == 0 {
+        return add_current_cell_access(module, bb, ctx).0;
+    }
+
+    let offset_cell_ptr = add_offset_cell_ptr(module, bb, ctx, offset);
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    LLVMBuildLoad(
+        builder.builder,
+        offset_cell_ptr,
+        module.new_string_ptr("cell_value"),
+    )
+}
+
+/// Add LLVM IR instructions for finding the cell at `offset` from
+/// the current cell, and return a pointer to it.
+unsafe fn add_offset_cell_ptr(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+    offset: isize,
+) -> LLVMValueRef {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cell_index = load_cell_index(module, &builder, bb, ctx, "cell_index");
+
+    let name = if offset == 0 {
+        "current_cell"
+    } else {
+        "offset_cell"
+    };
+    cell_address(module, &builder, bb, ctx, cell_index, offset, name)
+}
+
+/// Add LLVM IR instructions for finding the cell at `offset` from
+/// `cell_index`, bounds checking it if requested, and return a
+/// pointer to it. Intermediate values are named after `name`.
+unsafe fn cell_address(
+    module: &mut Module,
+    builder: &Builder,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+    cell_index: LLVMValueRef,
+    offset: isize,
+    name: &str,
+) -> LLVMValueRef {
+    let cell_index = if offset == 0 {
+        cell_index
+    } else {
+        build_offset_cell_index(
+            module,
+            builder,
+            ctx,
+            cell_index,
+            offset,
+            &format!("{}_index", name),
+        )
+    };
+    add_bounds_check(module, bb, ctx, cell_index);
+
+    let mut indices = vec![cell_index];
+    LLVMBuildGEP(
+        builder.builder,
+        ctx.cells,
+        indices.as_mut_ptr(),
+        indices.len() as c_uint,
+        module.new_string_ptr(&format!("{}_ptr", name)),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe fn compile_loop(
+    loop_body: &[AstNode],
+    offset: isize,
+    position: Option<Position>,
+    start_instr: &AstNode,
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new();
+
+    // First, we branch into the loop header from the previous basic
+    // block.
+    let loop_header_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_header"));
+    builder.position_at_end(bb);
+    LLVMBuildBr(builder.builder, loop_header_bb);
+
+    let mut loop_body_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_body"));
+    let loop_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_after"));
+
+    // loop_header:
+    //   %cell_value = ...
+    //   %cell_value_is_zero = icmp ...
+    //   br %cell_value_is_zero, %loop_after, %loop_body
+    builder.position_at_end(loop_header_bb);
+
+    let cell_val = add_offset_cell_access(module, &mut *loop_header_bb, &ctx, offset);
+
+    let zero = int8(0);
+    let cell_val_is_zero = LLVMBuildICmp(
+        builder.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        zero,
+        cell_val,
+        module.new_string_ptr("cell_value_is_zero"),
+    );
+    LLVMBuildCondBr(builder.builder, cell_val_is_zero, loop_after, loop_body_bb);
+
+    if ctx.count_loop_iterations {
+        if let Some(position) = position {
+            add_loop_iteration_count(module, loop_body_bb, position);
+        }
+    }
+
+    // Recursively compile instructions in the loop body.
+    loop_body_bb = compile_instrs(
+        loop_body,
+        start_instr,
+        module,
+        main_fn,
+        loop_body_bb,
+        ctx.clone(),
+    );
+
+    // When the loop is finished, jump back to the beginning of the
+    // loop.
+    builder.position_at_end(loop_body_bb);
+    LLVMBuildBr(builder.builder, loop_header_bb);
+
+    &mut *loop_after
+}
+
+/// Append LLVM IR instructions to bb for each of `instrs`, and return
+/// the basic block to append any following instructions to.
+unsafe fn compile_instrs(
+    instrs: &[AstNode],
+    start_instr: &AstNode,
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    mut bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    // Each cell in a run of sets or increments must be checked
+    // separately, and may wrap around the tape.
+    let can_combine_cell_runs = !ctx.bounds_check && ctx.wrapping_tape_len.is_none();
+
+    let mut index = 0;
+    while index < instrs.len() {
+        let instr = &instrs[index];
+        if ptr_equal(instr, start_instr) {
+            // This is the point we want to start execution from.
+            bb = set_entry_point_after(module, main_fn, bb);
+        }
+
+        let run_len = match *instr {
+            // Debuggers should be able to step through each BF command.
+            _ if module.debug_scope.is_some() => 1,
+            Write { .. } => write_run_len(&instrs[index..], start_instr),
+            _ if can_combine_cell_runs => cell_run_len(&instrs[index..], start_instr),
+            _ => 1,
+        };
+        if run_len > 1 {
+            match *instr {
+                Set { amount, offset, .. } => {
+                    bb = compile_set_run(amount, offset, run_len, module, bb, ctx.clone());
+                }
+                Increment { amount, offset, .. } => {
+                    bb = compile_increment_run(amount, offset, run_len, module, bb, ctx.clone());
+                }
+                Write { offset, .. } => {
+                    bb = compile_write_run(offset, run_len, module, bb, ctx.clone());
+                }
+                _ => unreachable!("Only sets, increments and writes form runs"),
+            }
+        } else {
+            bb = compile_instr(instr, start_instr, module, main_fn, bb, ctx.clone());
+        }
+        index += run_len;
+    }
+
+    bb
+}
+
+/// The number of instructions at the start of `instrs` that set
+/// consecutive cells to the same value, e.g. `[-]>[-]>[-]`, or
+/// increment consecutive cells by the same amount, e.g. `+>+>+`. Runs
+/// stop before `start_instr`, so we can start execution there.
+fn cell_run_len(instrs: &[AstNode], start_instr: &AstNode) -> usize {
+    let (run_amount, run_offset) = match instrs.first() {
+        Some(Set { amount, offset, .. }) | Some(Increment { amount, offset, .. }) => {
+            (*amount, *offset)
+        }
+        _ => return 1,
+    };
+
+    let mut run_len = 1;
+    for instr in &instrs[1..] {
+        let same_kind = std::mem::discriminant(instr) == std::mem::discriminant(&instrs[0]);
+        match *instr {
+            Set { amount, offset, .. } | Increment { amount, offset, .. }
+                if same_kind
+                    && amount == run_amount
+                    && offset == run_offset + run_len as isize
+                    && !ptr_equal(instr, start_instr) =>
+            {
+                run_len += 1;
+            }
+            _ => break,
+        }
+    }
+    run_len
+}
+
+/// The number of writes of the same cell at the start of `instrs`,
+/// e.g. `...`. Runs stop before `start_instr`.
+fn write_run_len(instrs: &[AstNode], start_instr: &AstNode) -> usize {
+    let run_offset = match instrs.first() {
+        Some(Write { offset, .. }) => *offset,
+        _ => return 1,
+    };
+
+    let mut run_len = 1;
+    for instr in &instrs[1..] {
+        match *instr {
+            Write { offset, .. } if offset == run_offset && !ptr_equal(instr, start_instr) => {
+                run_len += 1;
+            }
+            _ => break,
+        }
+    }
+    run_len
+}
+
+/// Set `len` cells, starting at `offset` from the current cell, to
+/// `amount` with a single memset.
+unsafe fn compile_set_run(
+    amount: BfValue,
+    offset: isize,
+    len: usize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let offset_cell_ptr = add_offset_cell_ptr(module, bb, &ctx, offset);
+    add_memset(module, bb, offset_cell_ptr, amount, len);
     bb
 }
 
-unsafe fn compile_read(
+/// Increment `len` cells, starting at `offset` from the current cell,
+/// by `amount`. We treat the cells as a single vector, so this is one
+/// load, add and store, which LLVM can lower to SIMD instructions.
+unsafe fn compile_increment_run(
+    amount: BfValue,
+    offset: isize,
+    len: usize,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
+    let offset_cell_ptr = add_offset_cell_ptr(module, bb, &ctx, offset);
+
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
-
-    let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
+    let vector_type = LLVMVectorType(int8_type(), len as c_uint);
+    let cells_ptr = LLVMBuildBitCast(
         builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as u32,
-        module.new_string_ptr("current_cell_ptr"),
+        offset_cell_ptr,
+        LLVMPointerType(vector_type, 0),
+        module.new_string_ptr("cells_ptr"),
     );
 
-    let mut getchar_args = vec![];
-    let input_char = add_function_call(module, bb, "getchar", &mut getchar_args, "input_char");
-    let input_byte = LLVMBuildTrunc(
+    // Cells have no alignment, so we can't use the vector's alignment.
+    let cell_vals = LLVMBuildLoad(
         builder.builder,
-        input_char,
-        int8_type(),
-        module.new_string_ptr("input_byte"),
+        cells_ptr,
+        module.new_string_ptr("cell_values"),
     );
+    LLVMSetAlignment(cell_vals, 1);
 
-    LLVMBuildStore(builder.builder, input_byte, current_cell_ptr);
-    bb
-}
-
-unsafe fn compile_write(
-    module: &mut Module,
-    bb: LLVMBasicBlockRef,
-    ctx: CompileContext,
-) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
-    builder.position_at_end(bb);
-
-    let cell_val = add_current_cell_access(module, bb, ctx.cells, ctx.cell_index_ptr).0;
-    let cell_val_as_char = LLVMBuildSExt(
+    let mut amounts = vec![int8(amount.0 as c_ulonglong); len];
+    let increment_amounts = LLVMConstVector(amounts.as_mut_ptr(), len as c_uint);
+    let new_cell_vals = LLVMBuildAdd(
         builder.builder,
-        cell_val,
-        int32_type(),
-        module.new_string_ptr("cell_val_as_char"),
+        cell_vals,
+        increment_amounts,
+        module.new_string_ptr("new_cell_values"),
     );
 
-    let mut putchar_args = vec![cell_val_as_char];
-    add_function_call(module, bb, "putchar", &mut putchar_args, "");
+    let store = LLVMBuildStore(builder.builder, new_cell_vals, cells_ptr);
+    LLVMSetAlignment(store, 1);
     bb
 }
 
-fn ptr_equal<T>(a: *const T, b: *const T) -> bool {
-    a == b
-}
-
-unsafe fn compile_loop(
-    loop_body: &[AstNode],
+/// Append LLVM IR instructions to bb according to the BF instruction
+/// passed in.
+unsafe fn compile_instr(
+    instr: &AstNode,
     start_instr: &AstNode,
     module: &mut Module,
     main_fn: LLVMValueRef,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
-    let builder = Builder::new();
-
-    // First, we branch into the loop header from the previous basic
-    // block.
-    let loop_header_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_header"));
-    builder.position_at_end(bb);
-    LLVMBuildBr(builder.builder, loop_header_bb);
+    if module.debug_scope.is_none() {
+        return compile_instr_inner(instr, start_instr, module, main_fn, bb, ctx);
+    }
 
-    let mut loop_body_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_body"));
-    let loop_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_after"));
+    // Note where we are, so we can find the LLVM instructions we
+    // generate for this BF instruction.
+    let prev_instr = LLVMGetLastInstruction(bb);
+    let prev_last_bb = LLVMGetLastBasicBlock(main_fn);
+
+    let after_bb = compile_instr_inner(instr, start_instr, module, main_fn, bb, ctx);
+
+    if let Some(position) = get_position(instr) {
+        let first_instr = if prev_instr.is_null() {
+            LLVMGetFirstInstruction(bb)
+        } else {
+            LLVMGetNextInstruction(prev_instr)
+        };
+        let mut new_bbs = vec![];
+        let mut new_bb = LLVMGetNextBasicBlock(prev_last_bb);
+        while !new_bb.is_null() {
+            new_bbs.push(new_bb);
+            new_bb = LLVMGetNextBasicBlock(new_bb);
+        }
 
-    // loop_header:
-    //   %cell_value = ...
-    //   %cell_value_is_zero = icmp ...
-    //   br %cell_value_is_zero, %loop_after, %loop_body
-    builder.position_at_end(loop_header_bb);
+        set_debug_locations(module, first_instr, &new_bbs, position);
+    }
 
-    let cell_val =
-        add_current_cell_access(module, &mut *loop_header_bb, ctx.cells, ctx.cell_index_ptr).0;
+    after_bb
+}
 
-    let zero = int8(0);
-    let cell_val_is_zero = LLVMBuildICmp(
-        builder.builder,
-        LLVMIntPredicate::LLVMIntEQ,
-        zero,
-        cell_val,
-        module.new_string_ptr("cell_value_is_zero"),
+/// Set the source location of every LLVM instruction from
+/// `first_instr` to the end of its basic block, and every
+/// instruction in `bbs`, unless it already has a location. Loop
+/// bodies are compiled first, so their instructions keep their own
+/// locations.
+unsafe fn set_debug_locations(
+    module: &Module,
+    first_instr: LLVMValueRef,
+    bbs: &[LLVMBasicBlockRef],
+    position: Position,
+) {
+    let debug_scope = match module.debug_scope {
+        Some(ref debug_scope) => debug_scope,
+        None => return,
+    };
+    let (line, column) = debug_scope.source.line_and_column(position.start);
+    let location = LLVMDIBuilderCreateDebugLocation(
+        LLVMGetGlobalContext(),
+        line,
+        column,
+        debug_scope.subprogram,
+        null_mut(),
     );
-    LLVMBuildCondBr(builder.builder, cell_val_is_zero, loop_after, loop_body_bb);
 
-    // Recursively compile instructions in the loop body.
-    for instr in loop_body {
-        if ptr_equal(instr, start_instr) {
-            // This is the point we want to start execution from.
-            loop_body_bb = set_entry_point_after(module, main_fn, loop_body_bb);
+    let first_instrs =
+        std::iter::once(first_instr).chain(bbs.iter().map(|bb| LLVMGetFirstInstruction(*bb)));
+    for first_instr in first_instrs {
+        let mut instr = first_instr;
+        while !instr.is_null() {
+            if LLVMInstructionGetDebugLoc(instr).is_null() {
+                LLVMInstructionSetDebugLoc(instr, location);
+            }
+            instr = LLVMGetNextInstruction(instr);
         }
-
-        loop_body_bb = compile_instr(
-            instr,
-            start_instr,
-            module,
-            main_fn,
-            loop_body_bb,
-            ctx.clone(),
-        );
     }
-
-    // When the loop is finished, jump back to the beginning of the
-    // loop.
-    builder.position_at_end(loop_body_bb);
-    LLVMBuildBr(builder.builder, loop_header_bb);
-
-    &mut *loop_after
 }
 
-/// Append LLVM IR instructions to bb according to the BF instruction
-/// passed in.
-unsafe fn compile_instr(
+unsafe fn compile_instr_inner(
     instr: &AstNode,
     start_instr: &AstNode,
     module: &mut Module,
@@ -744,10 +2349,31 @@ unsafe fn compile_instr(
         Increment { amount, offset, .. } => compile_increment(amount, offset, module, bb, ctx),
         Set { amount, offset, .. } => compile_set(amount, offset, module, bb, ctx),
         MultiplyMove { ref changes, .. } => compile_multiply_move(changes, module, bb, ctx),
+        CellCopy {
+            src_offset,
+            dst_offset,
+            overwrite,
+            ..
+        } => compile_cell_copy(src_offset, dst_offset, overwrite, module, bb, ctx),
         PointerIncrement { amount, .. } => compile_ptr_increment(amount, module, bb, ctx),
-        Read { .. } => compile_read(module, bb, ctx),
-        Write { .. } => compile_write(module, bb, ctx),
-        Loop { ref body, .. } => compile_loop(body, start_instr, module, main_fn, bb, ctx),
+        Read { offset, .. } => compile_read(offset, module, bb, ctx),
+        Write { offset, .. } => compile_write(offset, module, bb, ctx),
+        Loop {
+            ref body,
+            offset,
+            position,
+        } => compile_loop(
+            body,
+            offset,
+            position,
+            start_instr,
+            module,
+            main_fn,
+            bb,
+            ctx,
+        ),
+        ScanLoop { step, .. } => compile_scan_loop(step, module, bb, ctx),
+        InfiniteLoop { offset, .. } => compile_infinite_loop(offset, module, bb, ctx),
     }
 }
 
@@ -817,6 +2443,218 @@ unsafe fn set_entry_point_after(
     after_init_bb
 }
 
+/// How stdout should be buffered in the compiled program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdoutBuffering {
+    Full,
+    Line,
+    Unbuffered,
+}
+
+impl StdoutBuffering {
+    /// The `mode` argument to setvbuf. These values are the same
+    /// on glibc, musl and macOS.
+    fn setvbuf_mode(self) -> c_ulonglong {
+        match self {
+            // _IOFBF
+            StdoutBuffering::Full => 0,
+            // _IOLBF
+            StdoutBuffering::Line => 1,
+            // _IONBF
+            StdoutBuffering::Unbuffered => 2,
+        }
+    }
+}
+
+/// Options that affect the code we generate.
+#[derive(Debug, Clone, Default)]
+pub struct CodegenOptions {
+    /// If set, call setvbuf on stdout at startup. Otherwise, use
+    /// libc's default buffering.
+    ///
+    /// This only applies to output written with putchar, so setting
+    /// it implies `unbuffered_writes`. Not supported on wasm32.
+    pub stdout_buffering: Option<StdoutBuffering>,
+    /// Call putchar for every `.`. Otherwise, we buffer output
+    /// ourselves and write it in chunks.
+    pub unbuffered_writes: bool,
+    /// Exit with an error if the program accesses a cell, or moves
+    /// the cell pointer, outside the cells we allocated. Otherwise,
+    /// this is undefined behaviour. Not supported on wasm32.
+    pub debug_bounds: bool,
+    /// Count how many times each loop body runs, and write the counts
+    /// to stderr when the program finishes. This is useful for
+    /// checking that optimisations preserve loop behaviour. Not
+    /// supported on wasm32.
+    pub count_loop_iterations: bool,
+    /// If set, emit DWARF line tables that map the generated code to
+    /// positions in this source file.
+    pub debug_info: Option<DebugSource>,
+    /// Treat the cells in the initial state as a circle, so moving
+    /// left from the first cell reaches the last cell. The initial
+    /// state should have `WRAPPING_TAPE_LEN` cells.
+    pub wrapping_tape: bool,
+    /// Call `bf_getchar` and `bf_putchar` for I/O, rather than libc's
+    /// `getchar` and `putchar`. These have the same signatures as
+    /// their libc equivalents, and the user must provide them at link
+    /// time. Not supported on wasm32.
+    pub custom_io: bool,
+    /// Mark our functions with `sanitize_address`, so AddressSanitizer
+    /// instruments them. LLVM's C API doesn't expose the sanitizer
+    /// passes, so this only has an effect when clang compiles the IR
+    /// with `-fsanitize=address`.
+    pub sanitize_address: bool,
+    /// The name of the function that runs the program, or `main` if
+    /// not set. Any other name gives a function that returns void
+    /// rather than an exit code, so the program can be called from
+    /// other code. Not supported on wasm32, where `_start` calls
+    /// `main`. This must not be a reserved symbol (see
+    /// `is_reserved_symbol`).
+    pub entry_name: Option<String>,
+}
+
+/// Call setvbuf at the start of the program, to set how stdout is
+/// buffered.
+unsafe fn add_stdout_buffering(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    buffering: StdoutBuffering,
+) {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // stdout is a macro in C, which refers to a global whose name
+    // depends on the libc.
+    let target_triple = CStr::from_ptr(LLVMGetTarget(module.module)).to_string_lossy();
+    let stdout_name = if target_triple.contains("apple") {
+        "__stdoutp"
+    } else {
+        "stdout"
+    };
+
+    let stdout_global = LLVMAddGlobal(
+        module.module,
+        int8_ptr_type(),
+        module.new_string_ptr(stdout_name),
+    );
+    let stdout_ptr = LLVMBuildLoad(
+        builder.builder,
+        stdout_global,
+        module.new_string_ptr("stdout_ptr"),
+    );
+
+    add_function_call(
+        module,
+        bb,
+        "setvbuf",
+        &mut [
+            stdout_ptr,
+            LLVMConstNull(int8_ptr_type()),
+            int32(buffering.setvbuf_mode()),
+            int32(0),
+        ],
+        "",
+    );
+}
+
+/// Create a DWARF compile unit for `source`, and a subprogram for
+/// `main_fn`, which is called `main_name`. The caller must finalize
+/// and dispose of the returned DIBuilder.
+unsafe fn add_debug_info(
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    main_name: &str,
+    source: &DebugSource,
+) -> LLVMDIBuilderRef {
+    // Without these flags, LLVM discards the debug info.
+    let flags = [
+        (
+            "Debug Info Version",
+            LLVMDebugMetadataVersion() as c_ulonglong,
+        ),
+        ("Dwarf Version", 4),
+    ];
+    for (key, value) in flags {
+        LLVMAddModuleFlag(
+            module.module,
+            LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+            module.new_string_ptr(key),
+            key.len(),
+            LLVMValueAsMetadata(int32(value)),
+        );
+    }
+
+    let path = Path::new(&source.path);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let directory = path
+        .parent()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let di_builder = LLVMCreateDIBuilder(module.module);
+    let file = LLVMDIBuilderCreateFile(
+        di_builder,
+        module.new_string_ptr(&file_name),
+        file_name.len(),
+        module.new_string_ptr(&directory),
+        directory.len(),
+    );
+
+    // DWARF doesn't have a language code for BF, so we use C.
+    let producer = "bfc";
+    let compile_unit = LLVMDIBuilderCreateCompileUnit(
+        di_builder,
+        LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+        file,
+        module.new_string_ptr(producer),
+        producer.len(),
+        LLVM_FALSE,
+        module.new_string_ptr(""),
+        0,
+        0,
+        module.new_string_ptr(""),
+        0,
+        LLVMDWARFEmissionKind::LLVMDWARFEmissionKindLineTablesOnly,
+        0,
+        LLVM_FALSE,
+        LLVM_FALSE,
+        module.new_string_ptr(""),
+        0,
+        module.new_string_ptr(""),
+        0,
+    );
+
+    let main_type =
+        LLVMDIBuilderCreateSubroutineType(di_builder, file, null_mut(), 0, LLVMDIFlagZero);
+    let subprogram = LLVMDIBuilderCreateFunction(
+        di_builder,
+        compile_unit,
+        module.new_string_ptr(main_name),
+        main_name.len(),
+        module.new_string_ptr(main_name),
+        main_name.len(),
+        file,
+        1,
+        main_type,
+        LLVM_FALSE,
+        LLVM_TRUE,
+        1,
+        LLVMDIFlagZero,
+        LLVM_FALSE,
+    );
+    LLVMSetSubprogram(main_fn, subprogram);
+
+    module.debug_scope = Some(DebugScope {
+        source: source.clone(),
+        subprogram,
+    });
+
+    di_builder
+}
+
 // TODO: use init_values terminology consistently for names here.
 pub fn compile_to_module(
     module_name: &str,
@@ -824,11 +2662,48 @@ pub fn compile_to_module(
     instrs: &[AstNode],
     initial_state: &ExecutionState,
 ) -> Module {
-    let mut module = create_module(module_name, target_triple);
-    let main_fn = add_main_fn(&mut module);
+    compile_to_module_with_options(
+        module_name,
+        target_triple,
+        instrs,
+        initial_state,
+        &CodegenOptions::default(),
+    )
+}
+
+pub fn compile_to_module_with_options(
+    module_name: &str,
+    target_triple: Option<String>,
+    instrs: &[AstNode],
+    initial_state: &ExecutionState,
+    options: &CodegenOptions,
+) -> Module {
+    let mut module = create_module(module_name, target_triple, options.custom_io);
+    let entry_name = options.entry_name.as_deref().unwrap_or("main");
+    assert!(
+        !is_reserved_symbol(entry_name),
+        "Entry point {} clashes with a runtime symbol",
+        entry_name
+    );
+    let main_fn = add_main_fn(&mut module, entry_name);
+    let di_builder = options
+        .debug_info
+        .as_ref()
+        .map(|source| unsafe { add_debug_info(&mut module, main_fn, entry_name, source) });
+    if module.runtime == Runtime::Wasi {
+        unsafe {
+            add_wasi_entry_point(&mut module);
+        }
+    }
 
     let (init_bb, mut bb) = add_initial_bbs(&mut module, main_fn);
 
+    if let Some(buffering) = options.stdout_buffering {
+        unsafe {
+            add_stdout_buffering(&mut module, init_bb, buffering);
+        }
+    }
+
     if !initial_state.outputs.is_empty() {
         compile_static_outputs(&mut module, init_bb, &initial_state.outputs);
     }
@@ -844,21 +2719,46 @@ pub fn compile_to_module(
                 let llvm_cell_index =
                     add_cell_index_init(initial_state.cell_ptr, init_bb, &mut module);
 
+                let buffer_writes = !options.unbuffered_writes
+                    && options.stdout_buffering.is_none()
+                    && contains_write(instrs);
+                let output_buffer = if buffer_writes {
+                    Some(add_output_buffer(&mut module))
+                } else {
+                    None
+                };
+
+                if options.debug_bounds {
+                    add_cell_index_check(
+                        &mut module,
+                        initial_state.cells.len(),
+                        output_buffer.is_some(),
+                    );
+                }
+
                 let ctx = CompileContext {
                     cells: llvm_cells,
                     cell_index_ptr: llvm_cell_index,
                     main_fn,
+                    output_buffer,
+                    bounds_check: options.debug_bounds,
+                    count_loop_iterations: options.count_loop_iterations,
+                    wrapping_tape_len: if options.wrapping_tape {
+                        Some(initial_state.cells.len())
+                    } else {
+                        None
+                    },
+                    cell_index_cache: Rc::new(Cell::new(None)),
                 };
 
-                for instr in instrs {
-                    if ptr_equal(instr, start_instr) {
-                        // This is the point we want to start execution from.
-                        bb = set_entry_point_after(&mut module, main_fn, bb);
-                    }
+                bb = compile_instrs(instrs, start_instr, &mut module, main_fn, bb, ctx);
 
-                    bb = compile_instr(instr, start_instr, &mut module, main_fn, bb, ctx.clone());
+                if output_buffer.is_some() {
+                    add_function_call(&mut module, bb, "flush_output", &mut [], "");
+                }
+                if options.count_loop_iterations {
+                    add_loop_iteration_report(&mut module, bb);
                 }
-
                 add_cells_cleanup(&mut module, bb, llvm_cells);
             }
             None => {
@@ -870,14 +2770,86 @@ pub fn compile_to_module(
             }
         }
 
-        add_main_cleanup(bb);
+        add_main_cleanup(bb, entry_name);
+
+        if options.sanitize_address {
+            add_sanitize_address(&mut module);
+        }
+
+        if let Some(di_builder) = di_builder {
+            LLVMDIBuilderFinalize(di_builder);
+            LLVMDisposeDIBuilder(di_builder);
+        }
 
         module
     }
 }
 
+/// Add the `sanitize_address` attribute to every function defined in
+/// `module`.
+unsafe fn add_sanitize_address(module: &mut Module) {
+    let name = "sanitize_address";
+    let kind = LLVMGetEnumAttributeKindForName(module.new_string_ptr(name), name.len());
+    let attribute = LLVMCreateEnumAttribute(LLVMGetGlobalContext(), kind, 0);
+
+    let mut function = LLVMGetFirstFunction(module.module);
+    while !function.is_null() {
+        if LLVMIsDeclaration(function) == LLVM_FALSE {
+            LLVMAddAttributeAtIndex(function, LLVMAttributeFunctionIndex, attribute);
+        }
+        function = LLVMGetNextFunction(function);
+    }
+}
+
+/// Check that `module` is well-formed IR, so bugs in code generation
+/// are reported rather than producing a broken executable.
+pub fn verify_module(module: &Module) -> Result<(), CompileError> {
+    unsafe {
+        let mut err_msg_ptr = null_mut();
+        let result = LLVMVerifyModule(
+            module.module,
+            LLVMVerifierFailureAction::LLVMReturnStatusAction,
+            &mut err_msg_ptr,
+        );
+
+        let err_msg = CStr::from_ptr(err_msg_ptr as *const _)
+            .to_string_lossy()
+            .into_owned();
+        LLVMDisposeMessage(err_msg_ptr);
+
+        if result != 0 {
+            return Err(CompileError::Llvm(format!(
+                "LLVM module is invalid: {}",
+                err_msg.trim_end()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Optimise the module with LLVM's new pass manager, running the same
+/// pipeline as `opt -O<llvm_opt>`.
+#[cfg(not(feature = "legacy-pass-manager"))]
+pub fn optimise_ir(module: &mut Module, llvm_opt: i64) {
+    let passes = CString::new(format!("default<O{}>", llvm_opt)).unwrap();
+    unsafe {
+        let options = LLVMCreatePassBuilderOptions();
+        let error = LLVMRunPasses(module.module, passes.as_ptr(), null_mut(), options);
+        LLVMDisposePassBuilderOptions(options);
+
+        if !error.is_null() {
+            let message_ptr = LLVMGetErrorMessage(error);
+            let message = CStr::from_ptr(message_ptr).to_string_lossy().into_owned();
+            LLVMDisposeErrorMessage(message_ptr);
+            panic!("Invalid pass pipeline {:?}: {}", passes, message);
+        }
+    }
+}
+
+/// Optimise the module with LLVM's legacy pass manager, for LLVM
+/// releases without `LLVMRunPasses`.
+#[cfg(feature = "legacy-pass-manager")]
 pub fn optimise_ir(module: &mut Module, llvm_opt: i64) {
-    // TODO: add a verifier pass too.
     unsafe {
         let builder = LLVMPassManagerBuilderCreate();
         // E.g. if llvm_opt is 3, we want a pass equivalent to -O3.
@@ -898,6 +2870,12 @@ pub fn optimise_ir(module: &mut Module, llvm_opt: i64) {
     }
 }
 
+/// The version of LLVM that bfc is linked against, e.g. "14.0.6".
+///
+/// The C API only gained `LLVMGetVersion` in LLVM 16, so build.rs
+/// asks llvm-config when we build bfc.
+pub const LLVM_VERSION: &str = env!("BFC_LLVM_VERSION");
+
 pub fn get_default_target_triple() -> CString {
     let target_triple;
     unsafe {
@@ -909,12 +2887,99 @@ pub fn get_default_target_triple() -> CString {
     target_triple
 }
 
-struct TargetMachine {
+/// The CPU we generate code for, equivalent to llc's `-mcpu` and
+/// `-mattr` options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetCpu {
+    /// The CPU name, e.g. "skylake". "native" means the CPU of the
+    /// machine running bfc.
+    ///
+    /// See http://llvm.org/docs/CommandGuide/llc.html#cmdoption-mcpu
+    pub name: String,
+    /// Features to enable or disable, e.g. "+avx2,-sse4.1".
+    ///
+    /// See http://llvm.org/docs/CommandGuide/llc.html#cmdoption-mattr
+    pub features: String,
+}
+
+impl Default for TargetCpu {
+    fn default() -> Self {
+        TargetCpu {
+            name: "generic".to_owned(),
+            features: "".to_owned(),
+        }
+    }
+}
+
+/// Take ownership of a string allocated by LLVM.
+unsafe fn take_llvm_message(message: *mut i8) -> String {
+    let result = CStr::from_ptr(message as *const _)
+        .to_string_lossy()
+        .into_owned();
+    LLVMDisposeMessage(message);
+    result
+}
+
+/// How generated code refers to addresses, equivalent to llc's
+/// `-relocation-model` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelocMode {
+    /// Position independent code, which works in PIE executables.
+    #[default]
+    Pic,
+    /// Absolute addresses, for executables loaded at a fixed address.
+    Static,
+    /// Absolute addresses for our own code, but external symbols are
+    /// accessed indirectly.
+    DynamicNoPic,
+}
+
+impl RelocMode {
+    fn to_llvm(self) -> LLVMRelocMode {
+        match self {
+            RelocMode::Pic => LLVMRelocMode::LLVMRelocPIC,
+            RelocMode::Static => LLVMRelocMode::LLVMRelocStatic,
+            RelocMode::DynamicNoPic => LLVMRelocMode::LLVMRelocDynamicNoPic,
+        }
+    }
+}
+
+/// Which addresses generated code can reach, equivalent to llc's
+/// `-code-model` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodeModel {
+    /// Let LLVM choose, which is usually `Small`.
+    #[default]
+    Default,
+    Small,
+    Medium,
+    Large,
+    Kernel,
+}
+
+impl CodeModel {
+    fn to_llvm(self) -> LLVMCodeModel {
+        match self {
+            CodeModel::Default => LLVMCodeModel::LLVMCodeModelDefault,
+            CodeModel::Small => LLVMCodeModel::LLVMCodeModelSmall,
+            CodeModel::Medium => LLVMCodeModel::LLVMCodeModelMedium,
+            CodeModel::Large => LLVMCodeModel::LLVMCodeModelLarge,
+            CodeModel::Kernel => LLVMCodeModel::LLVMCodeModelKernel,
+        }
+    }
+}
+
+pub(crate) struct TargetMachine {
     tm: LLVMTargetMachineRef,
 }
 
 impl TargetMachine {
-    fn new(target_triple: *const i8) -> Result<Self, String> {
+    pub(crate) fn new(
+        target_triple: *const i8,
+        target_cpu: &TargetCpu,
+        reloc_mode: RelocMode,
+        code_model: CodeModel,
+    ) -> Result<Self, String> {
         let mut target = null_mut();
         let mut err_msg_ptr = null_mut();
         unsafe {
@@ -930,11 +2995,23 @@ impl TargetMachine {
             }
         }
 
-        // TODO: do these strings live long enough?
-        // cpu is documented: http://llvm.org/docs/CommandGuide/llc.html#cmdoption-mcpu
-        let cpu = CString::new("generic").unwrap();
-        // features are documented: http://llvm.org/docs/CommandGuide/llc.html#cmdoption-mattr
-        let features = CString::new("").unwrap();
+        // Like clang's -march=native, use the host's features too,
+        // unless the user specified features.
+        let (cpu_name, features) = if target_cpu.name == "native" {
+            unsafe {
+                let host_features = if target_cpu.features.is_empty() {
+                    take_llvm_message(LLVMGetHostCPUFeatures())
+                } else {
+                    target_cpu.features.clone()
+                };
+                (take_llvm_message(LLVMGetHostCPUName()), host_features)
+            }
+        } else {
+            (target_cpu.name.clone(), target_cpu.features.clone())
+        };
+
+        let cpu = CString::new(cpu_name).map_err(|_| "CPU name contains a NUL byte.")?;
+        let features = CString::new(features).map_err(|_| "CPU features contain a NUL byte.")?;
 
         let target_machine;
         unsafe {
@@ -944,13 +3021,23 @@ impl TargetMachine {
                 cpu.as_ptr() as *const _,
                 features.as_ptr() as *const _,
                 LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
-                LLVMRelocMode::LLVMRelocPIC,
-                LLVMCodeModel::LLVMCodeModelDefault,
+                reloc_mode.to_llvm(),
+                code_model.to_llvm(),
             );
         }
 
         Ok(TargetMachine { tm: target_machine })
     }
+
+    #[cfg(test)]
+    pub(crate) fn cpu(&self) -> String {
+        unsafe { take_llvm_message(LLVMGetTargetMachineCPU(self.tm)) }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn features(&self) -> String {
+        unsafe { take_llvm_message(LLVMGetTargetMachineFeatureString(self.tm)) }
+    }
 }
 
 impl Drop for TargetMachine {
@@ -972,22 +3059,155 @@ pub fn init_llvm() {
     }
 }
 
-pub fn write_object_file(module: &mut Module, path: &str) -> Result<(), String> {
+/// Write the module to `path` as an object file or as textual
+/// assembly, according to `file_type`.
+pub fn write_object_file(
+    module: &mut Module,
+    path: &str,
+    file_type: LLVMCodeGenFileType,
+    target_cpu: &TargetCpu,
+    reloc_mode: RelocMode,
+    code_model: CodeModel,
+) -> Result<(), CompileError> {
     unsafe {
         let target_triple = LLVMGetTarget(module.module);
-        let target_machine = TargetMachine::new(target_triple)?;
+        let target_machine = TargetMachine::new(target_triple, target_cpu, reloc_mode, code_model)
+            .map_err(CompileError::Llvm)?;
 
-        let mut obj_error = module.new_mut_string_ptr("Writing object file failed.");
+        let mut err_msg_ptr = null_mut();
         let result = LLVMTargetMachineEmitToFile(
             target_machine.tm,
             module.module,
             module.new_string_ptr(path) as *mut i8,
+            file_type,
+            &mut err_msg_ptr,
+        );
+
+        if result != 0 {
+            let err_msg = CStr::from_ptr(err_msg_ptr as *const _)
+                .to_string_lossy()
+                .into_owned();
+            LLVMDisposeMessage(err_msg_ptr);
+            return Err(CompileError::Llvm(format!("{}: {}", path, err_msg)));
+        }
+    }
+    Ok(())
+}
+
+/// Compile the module to an object file, and return its contents.
+pub fn emit_object_to_memory(
+    module: &mut Module,
+    target_cpu: &TargetCpu,
+    reloc_mode: RelocMode,
+    code_model: CodeModel,
+) -> Result<Vec<u8>, CompileError> {
+    unsafe {
+        let target_triple = LLVMGetTarget(module.module);
+        let target_machine = TargetMachine::new(target_triple, target_cpu, reloc_mode, code_model)
+            .map_err(CompileError::Llvm)?;
+
+        let mut err_msg_ptr = null_mut();
+        let mut buffer = null_mut();
+        let result = LLVMTargetMachineEmitToMemoryBuffer(
+            target_machine.tm,
+            module.module,
             LLVMCodeGenFileType::LLVMObjectFile,
-            &mut obj_error,
+            &mut err_msg_ptr,
+            &mut buffer,
         );
 
         if result != 0 {
-            panic!("obj_error: {:?}", CStr::from_ptr(obj_error as *const _));
+            let err_msg = CStr::from_ptr(err_msg_ptr as *const _)
+                .to_string_lossy()
+                .into_owned();
+            LLVMDisposeMessage(err_msg_ptr);
+            return Err(CompileError::Llvm(err_msg));
+        }
+
+        let start = LLVMGetBufferStart(buffer) as *const u8;
+        let size = LLVMGetBufferSize(buffer);
+        let bytes = std::slice::from_raw_parts(start, size).to_vec();
+        LLVMDisposeMemoryBuffer(buffer);
+
+        Ok(bytes)
+    }
+}
+
+/// JIT compile the module with MCJIT and call its `main` function in
+/// this process, returning the exit code. Reads and writes use the
+/// stdin and stdout of the current process.
+pub fn run_jit(module: Module) -> Result<i32, String> {
+    run_jit_with(module, |_, _| ()).map(|(exit_code, _)| exit_code)
+}
+
+/// JIT compile and run the module, as with `run_jit`, and return
+/// the iteration count of each loop as (source offset, count)
+/// pairs. The module should be compiled with `count_loop_iterations`.
+#[cfg(test)]
+pub(crate) fn run_jit_counting_loops(module: Module) -> Result<(i32, Vec<(usize, u64)>), String> {
+    run_jit_with(module, |engine, module| unsafe {
+        module
+            .loop_counters
+            .clone()
+            .into_iter()
+            .map(|(loop_start, counter_name)| {
+                let counter_addr =
+                    LLVMGetGlobalValueAddress(engine, module.new_string_ptr(&counter_name));
+                (loop_start, *(counter_addr as *const u64))
+            })
+            .collect()
+    })
+}
+
+/// JIT compile the module and call its `main` function. Once `main`
+/// returns, call `after_main` so we can inspect the state of the
+/// program.
+fn run_jit_with<T, F>(mut module: Module, after_main: F) -> Result<(i32, T), String>
+where
+    F: FnOnce(LLVMExecutionEngineRef, &mut Module) -> T,
+{
+    unsafe {
+        LLVMLinkInMCJIT();
+
+        // The execution engine takes ownership of the LLVM module
+        // (even if creating the engine fails), so ensure that Module
+        // doesn't dispose it too.
+        let llvm_module = std::mem::replace(&mut module.module, null_mut());
+
+        let mut engine = null_mut();
+        let mut err_msg_ptr = null_mut();
+        if LLVMCreateExecutionEngineForModule(&mut engine, llvm_module, &mut err_msg_ptr) != 0 {
+            let err_msg = CStr::from_ptr(err_msg_ptr as *const _)
+                .to_string_lossy()
+                .into_owned();
+            LLVMDisposeMessage(err_msg_ptr);
+            return Err(err_msg);
+        }
+
+        let main_addr = LLVMGetFunctionAddress(engine, module.new_string_ptr("main"));
+        if main_addr == 0 {
+            LLVMDisposeExecutionEngine(engine);
+            return Err("Could not find main function.".to_owned());
+        }
+
+        let main_fn: extern "C" fn() -> i32 = std::mem::transmute(main_addr as usize);
+        let exit_code = main_fn();
+        let result = after_main(engine, &mut module);
+
+        LLVMDisposeExecutionEngine(engine);
+        Ok((exit_code, result))
+    }
+}
+
+/// Write the module to `path` as LLVM bitcode.
+pub fn write_bitcode_file(module: &mut Module, path: &str) -> Result<(), CompileError> {
+    unsafe {
+        let result = LLVMWriteBitcodeToFile(module.module, module.new_string_ptr(path));
+        if result != 0 {
+            return Err(CompileError::Llvm(format!(
+                "{}: Writing bitcode failed.",
+                path
+            )));
         }
     }
     Ok(())
@@ -2,10 +2,16 @@ use std::collections::HashMap;
 use std::ffi::CString;
 use std::num::Wrapping;
 
+use crate::bfir::parse;
 use crate::bfir::AstNode::*;
 use crate::bfir::Position;
 use crate::execution::ExecutionState;
-use crate::llvm::compile_to_module;
+use crate::llvm::{
+    compile_to_module, compile_to_module_with_options, get_default_target_triple, init_llvm,
+    optimise_ir, run_jit, run_jit_counting_loops, verify_module, CodeModel, CodegenOptions,
+    DebugSource, RelocMode, StdoutBuffering, TargetCpu, TargetMachine,
+};
+use crate::peephole::{optimize, OptimizeOptions};
 
 use pretty_assertions::assert_eq;
 
@@ -32,6 +38,7 @@ fn compile_loop() {
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         }],
+        offset: 0,
         position: Some(Position { start: 0, end: 0 }),
     }];
 
@@ -51,10 +58,12 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -63,11 +72,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 1)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 1, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
@@ -87,8 +96,7 @@ loop_header:                                      ; preds = %loop_body, %after_i
 
 loop_body:                                        ; preds = %loop_header
   %cell_index1 = load i32, i32* %cell_index_ptr, align 4
-  %offset_cell_index = add i32 %cell_index1, 0
-  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %cell_index1
   %cell_value3 = load i8, i8* %current_cell_ptr2, align 1
   %new_cell_value = add i8 %cell_value3, 1
   store i8 %new_cell_value, i8* %current_cell_ptr2, align 1
@@ -122,10 +130,12 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -134,6 +144,8 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
   br label %beginning
@@ -170,10 +182,12 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -182,11 +196,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 50)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 50, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 50, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
@@ -197,8 +211,8 @@ beginning:                                        ; No predecessors!
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr, align 4
   %offset_cell_index = add i32 %cell_index, 42
-  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
-  store i8 1, i8* %current_cell_ptr, align 1
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  store i8 1, i8* %offset_cell_ptr, align 1
   call void @free(i8* %cells)
   ret i32 0
 }
@@ -211,7 +225,10 @@ attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 
 #[test]
 fn compile_read() {
-    let instrs = vec![Read { position: None }];
+    let instrs = vec![Read {
+        offset: 0,
+        position: None,
+    }];
 
     let result = compile_to_module(
         "foo",
@@ -230,10 +247,12 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -242,11 +261,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 1)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 1, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
@@ -273,7 +292,10 @@ attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 
 #[test]
 fn compile_write() {
-    let instrs = vec![Write { position: None }];
+    let instrs = vec![Write {
+        offset: 0,
+        position: None,
+    }];
 
     let result = compile_to_module(
         "foo",
@@ -287,15 +309,20 @@ fn compile_write() {
         },
     );
 
-    let expected = "; ModuleID = 'foo'
+    let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
+@output_buffer = private global [4096 x i8] zeroinitializer
+@output_len = private global i32 0
+
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -304,11 +331,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 1)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 1, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
@@ -320,12 +347,32 @@ after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr, align 4
   %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
   %cell_value = load i8, i8* %current_cell_ptr, align 1
-  %cell_val_as_char = sext i8 %cell_value to i32
-  %0 = call i32 @putchar(i32 %cell_val_as_char)
+  %output_len = load i32, i32* @output_len, align 4
+  %output_ptr = getelementptr [4096 x i8], [4096 x i8]* @output_buffer, i32 0, i32 %output_len
+  store i8 %cell_value, i8* %output_ptr, align 1
+  %new_output_len = add i32 %output_len, 1
+  store i32 %new_output_len, i32* @output_len, align 4
+  %output_is_full = icmp eq i32 %new_output_len, 4096
+  br i1 %output_is_full, label %flush_full_output, label %after_write
+
+flush_full_output:                                ; preds = %after_init
+  call void @flush_output()
+  br label %after_write
+
+after_write:                                      ; preds = %flush_full_output, %after_init
+  call void @flush_output()
   call void @free(i8* %cells)
   ret i32 0
 }
 
+define private void @flush_output() {
+entry:
+  %output_len = load i32, i32* @output_len, align 4
+  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([4096 x i8], [4096 x i8]* @output_buffer, i32 0, i32 0), i32 %output_len)
+  store i32 0, i32* @output_len, align 4
+  ret void
+}
+
 attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 ";
 
@@ -333,31 +380,96 @@ attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 }
 
 #[test]
-fn respect_initial_cell_ptr() {
-    let instrs = vec![PointerIncrement {
-        amount: 1,
-        position: Some(Position { start: 0, end: 0 }),
-    }];
-    let result = compile_to_module(
+fn compile_read_flushes_output() {
+    let instrs = parse(".,.").unwrap();
+    let mut state = ExecutionState::initial(&instrs);
+    state.start_instr = instrs.first();
+
+    let result = compile_to_module("foo", Some("i686-pc-linux-gnu".to_owned()), &instrs, &state);
+    let ir = result.to_cstring().into_string().unwrap();
+
+    // Any prompt should be written before we wait for input.
+    let flush_index = ir.find("call void @flush_output()").unwrap();
+    let getchar_index = ir.find("call i32 @getchar()").unwrap();
+    assert!(flush_index < getchar_index);
+}
+
+#[test]
+fn compile_static_outputs_before_buffered_writes() {
+    let instrs = parse(".").unwrap();
+    let state = ExecutionState {
+        start_instr: instrs.first(),
+        cells: vec![Wrapping(0)],
+        cell_ptr: 0,
+        outputs: vec![72, 105],
+    };
+
+    let result = compile_to_module("foo", Some("i686-pc-linux-gnu".to_owned()), &instrs, &state);
+    let ir = result.to_cstring().into_string().unwrap();
+
+    // Static outputs are written directly in the init block, before
+    // anything is added to the output buffer.
+    let static_write_index = ir.find("@known_outputs, i32 0, i32 0), i32 2)").unwrap();
+    let buffered_write_index = ir.find("store i8 %cell_value, i8* %output_ptr").unwrap();
+    assert!(static_write_index < buffered_write_index);
+}
+
+#[test]
+fn compile_static_outputs_high_byte() {
+    // A cell containing 200 is -56 as an i8, but we should write
+    // byte 200.
+    let state = ExecutionState {
+        start_instr: None,
+        cells: vec![],
+        cell_ptr: 0,
+        outputs: vec![-56],
+    };
+
+    let result = compile_to_module("foo", Some("i686-pc-linux-gnu".to_owned()), &[], &state);
+    let ir = result.to_cstring().into_string().unwrap();
+
+    assert!(ir.contains("@known_outputs = constant [1 x i8] c\"\\C8\""));
+}
+
+#[test]
+fn compile_read_and_write_offsets() {
+    let instrs = vec![
+        Read {
+            offset: 1,
+            position: None,
+        },
+        Write {
+            offset: 1,
+            position: None,
+        },
+    ];
+
+    let result = compile_to_module_with_options(
         "foo",
         Some("i686-pc-linux-gnu".to_owned()),
         &instrs,
         &ExecutionState {
             start_instr: Some(&instrs[0]),
-            cells: vec![Wrapping(0); 10],
-            cell_ptr: 8,
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
             outputs: vec![],
         },
+        &CodegenOptions {
+            unbuffered_writes: true,
+            ..CodegenOptions::default()
+        },
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -366,13 +478,13 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 10)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 10, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 2, i32 1)
   %cell_index_ptr = alloca i32, align 4
-  store i32 8, i32* %cell_index_ptr, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
 
 beginning:                                        ; No predecessors!
@@ -380,48 +492,69 @@ beginning:                                        ; No predecessors!
 
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr, align 4
-  %new_cell_index = add i32 %cell_index, 1
-  store i32 %new_cell_index, i32* %cell_index_ptr, align 4
+  %offset_cell_index = add i32 %cell_index, 1
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %offset_cell_ptr, align 1
+  %offset_cell_index1 = add i32 %cell_index, 1
+  %offset_cell_ptr2 = getelementptr i8, i8* %cells, i32 %offset_cell_index1
+  %cell_value = load i8, i8* %offset_cell_ptr2, align 1
+  %cell_val_as_char = zext i8 %cell_value to i32
+  %0 = call i32 @putchar(i32 %cell_val_as_char)
   call void @free(i8* %cells)
   ret i32 0
 }
 
 attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 ";
-
-    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+    assert_eq!(result.to_cstring().to_str().unwrap(), expected);
 }
 
 #[test]
-fn compile_multiply_move() {
-    let mut changes = HashMap::new();
-    changes.insert(1, Wrapping(2));
-    changes.insert(2, Wrapping(3));
-    let instrs = vec![MultiplyMove {
-        changes,
-        position: Some(Position { start: 0, end: 0 }),
-    }];
+fn compile_wrapping_tape() {
+    let instrs = vec![
+        Read {
+            offset: 0,
+            position: None,
+        },
+        PointerIncrement {
+            amount: -1,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 1,
+            position: None,
+        },
+    ];
 
-    let result = compile_to_module(
+    let result = compile_to_module_with_options(
         "foo",
         Some("i686-pc-linux-gnu".to_owned()),
         &instrs,
         &ExecutionState {
             start_instr: Some(&instrs[0]),
-            cells: vec![Wrapping(0); 3],
+            cells: vec![Wrapping(0); 30_000],
             cell_ptr: 0,
             outputs: vec![],
         },
+        &CodegenOptions {
+            wrapping_tape: true,
+            ..CodegenOptions::default()
+        },
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -430,11 +563,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 3)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 3, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 30000, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
@@ -445,68 +578,68 @@ beginning:                                        ; No predecessors!
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr, align 4
   %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
-  %cell_value = load i8, i8* %current_cell_ptr, align 1
-  %cell_value_is_zero = icmp eq i8 0, %cell_value
-  br i1 %cell_value_is_zero, label %multiply_after, label %multiply_body
-
-multiply_body:                                    ; preds = %after_init
-  store i8 0, i8* %current_cell_ptr, align 1
-  %target_cell_ptr = getelementptr i8, i8* %current_cell_ptr, i32 1
-  %target_cell_val = load i8, i8* %target_cell_ptr, align 1
-  %additional_val = mul i8 %cell_value, 2
-  %new_target_val = add i8 %target_cell_val, %additional_val
-  store i8 %new_target_val, i8* %target_cell_ptr, align 1
-  %target_cell_ptr1 = getelementptr i8, i8* %current_cell_ptr, i32 2
-  %target_cell_val2 = load i8, i8* %target_cell_ptr1, align 1
-  %additional_val3 = mul i8 %cell_value, 3
-  %new_target_val4 = add i8 %target_cell_val2, %additional_val3
-  store i8 %new_target_val4, i8* %target_cell_ptr1, align 1
-  br label %multiply_after
-
-multiply_after:                                   ; preds = %multiply_body, %after_init
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
+  %new_cell_index_unwrapped = add i32 %cell_index, 29999
+  %new_cell_index = urem i32 %new_cell_index_unwrapped, 30000
+  store i32 %new_cell_index, i32* %cell_index_ptr, align 4
+  %offset_cell_index_unwrapped = add i32 %new_cell_index, 1
+  %offset_cell_index = urem i32 %offset_cell_index_unwrapped, 30000
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %cell_value = load i8, i8* %offset_cell_ptr, align 1
+  %new_cell_value = add i8 %cell_value, 1
+  store i8 %new_cell_value, i8* %offset_cell_ptr, align 1
   call void @free(i8* %cells)
   ret i32 0
 }
 
 attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 ";
-
-    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+    assert_eq!(result.to_cstring().to_str().unwrap(), expected);
 }
 
 #[test]
-fn set_initial_cell_values() {
-    let instrs = vec![PointerIncrement {
-        amount: 1,
-        position: Some(Position { start: 0, end: 0 }),
-    }];
-    let result = compile_to_module(
+fn compile_debug_bounds() {
+    let instrs = vec![
+        Read {
+            offset: 0,
+            position: None,
+        },
+        PointerIncrement {
+            amount: 1,
+            position: None,
+        },
+    ];
+
+    let result = compile_to_module_with_options(
         "foo",
         Some("i686-pc-linux-gnu".to_owned()),
         &instrs,
         &ExecutionState {
             start_instr: Some(&instrs[0]),
-            cells: vec![
-                Wrapping(1),
-                Wrapping(1),
-                Wrapping(2),
-                Wrapping(0),
-                Wrapping(0),
-                Wrapping(0),
-            ],
+            cells: vec![Wrapping(0); 2],
             cell_ptr: 0,
             outputs: vec![],
         },
+        &CodegenOptions {
+            debug_bounds: true,
+            ..CodegenOptions::default()
+        },
     );
     let expected = "; ModuleID = \'foo\'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
+@out_of_range_message = private unnamed_addr constant [65 x i8] c\"Error: cell index %d is out of range (the program has 2 cells).\\0A\\00\", align 1
+
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -515,15 +648,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 6)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 1, i32 2, i32 1, i1 true)
-  %offset_cell_ptr1 = getelementptr i8, i8* %cells, i32 2
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr1, i8 2, i32 1, i32 1, i1 true)
-  %offset_cell_ptr2 = getelementptr i8, i8* %cells, i32 3
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr2, i8 0, i32 3, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 2, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
@@ -533,12 +662,36 @@ beginning:                                        ; No predecessors!
 
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr, align 4
+  call void @check_cell_index(i32 %cell_index)
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
   %new_cell_index = add i32 %cell_index, 1
+  call void @check_cell_index(i32 %new_cell_index)
   store i32 %new_cell_index, i32* %cell_index_ptr, align 4
   call void @free(i8* %cells)
   ret i32 0
 }
 
+declare i32 @dprintf(i32, i8*, ...)
+
+declare void @exit(i32)
+
+define private void @check_cell_index(i32 %0) {
+entry:
+  %is_in_range = icmp ult i32 %0, 2
+  br i1 %is_in_range, label %in_range, label %out_of_range
+
+out_of_range:                                     ; preds = %entry
+  %1 = call i32 (i32, i8*, ...) @dprintf(i32 2, i8* getelementptr inbounds ([65 x i8], [65 x i8]* @out_of_range_message, i32 0, i32 0), i32 %0)
+  call void @exit(i32 1)
+  unreachable
+
+in_range:                                         ; preds = %entry
+  ret void
+}
+
 attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 ";
 
@@ -546,29 +699,39 @@ attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 }
 
 #[test]
-fn compile_static_outputs() {
-    let result = compile_to_module(
+fn compile_unbuffered_write() {
+    let instrs = vec![Write {
+        offset: 0,
+        position: None,
+    }];
+
+    let result = compile_to_module_with_options(
         "foo",
         Some("i686-pc-linux-gnu".to_owned()),
-        &[],
+        &instrs,
         &ExecutionState {
-            start_instr: None,
-            cells: vec![],
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
             cell_ptr: 0,
-            outputs: vec![5, 10],
+            outputs: vec![],
+        },
+        &CodegenOptions {
+            unbuffered_writes: true,
+            ..CodegenOptions::default()
         },
     );
-    let expected = "; ModuleID = \'foo\'
+
+    let expected = "; ModuleID = 'foo'
 source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
-@known_outputs = constant [2 x i8] c\"\\05\\0A\"
-
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -577,12 +740,25 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([2 x i8], [2 x i8]* @known_outputs, i32 0, i32 0), i32 2)
-  br label %beginning
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
 
-beginning:                                        ; preds = %init
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr, align 1
+  %cell_val_as_char = zext i8 %cell_value to i32
+  %0 = call i32 @putchar(i32 %cell_val_as_char)
+  call void @free(i8* %cells)
   ret i32 0
 }
 
@@ -593,7 +769,7 @@ attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 }
 
 #[test]
-fn compile_ptr_increment() {
+fn respect_initial_cell_ptr() {
     let instrs = vec![PointerIncrement {
         amount: 1,
         position: Some(Position { start: 0, end: 0 }),
@@ -604,8 +780,8 @@ fn compile_ptr_increment() {
         &instrs,
         &ExecutionState {
             start_instr: Some(&instrs[0]),
-            cells: vec![Wrapping(0); 2],
-            cell_ptr: 0,
+            cells: vec![Wrapping(0); 10],
+            cell_ptr: 8,
             outputs: vec![],
         },
     );
@@ -614,10 +790,12 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -626,13 +804,13 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 2)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 2, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 10, i32 1)
   %cell_index_ptr = alloca i32, align 4
-  store i32 0, i32* %cell_index_ptr, align 4
+  store i32 8, i32* %cell_index_ptr, align 4
   br label %after_init
 
 beginning:                                        ; No predecessors!
@@ -653,19 +831,22 @@ attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 }
 
 #[test]
-fn compile_increment() {
-    let instrs = vec![Increment {
-        amount: Wrapping(1),
-        offset: 0,
+fn compile_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(2));
+    changes.insert(2, Wrapping(3));
+    let instrs = vec![MultiplyMove {
+        changes,
         position: Some(Position { start: 0, end: 0 }),
     }];
+
     let result = compile_to_module(
         "foo",
         Some("i686-pc-linux-gnu".to_owned()),
         &instrs,
         &ExecutionState {
             start_instr: Some(&instrs[0]),
-            cells: vec![Wrapping(0)],
+            cells: vec![Wrapping(0); 3],
             cell_ptr: 0,
             outputs: vec![],
         },
@@ -675,10 +856,12 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -687,11 +870,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 1)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 3, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
@@ -701,11 +884,26 @@ beginning:                                        ; No predecessors!
 
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr, align 4
-  %offset_cell_index = add i32 %cell_index, 0
-  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
   %cell_value = load i8, i8* %current_cell_ptr, align 1
-  %new_cell_value = add i8 %cell_value, 1
-  store i8 %new_cell_value, i8* %current_cell_ptr, align 1
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %multiply_after, label %multiply_body
+
+multiply_body:                                    ; preds = %after_init
+  store i8 0, i8* %current_cell_ptr, align 1
+  %target_cell_ptr = getelementptr i8, i8* %current_cell_ptr, i32 1
+  %target_cell_val = load i8, i8* %target_cell_ptr, align 1
+  %additional_val = mul i8 %cell_value, 2
+  %new_target_val = add i8 %target_cell_val, %additional_val
+  store i8 %new_target_val, i8* %target_cell_ptr, align 1
+  %target_cell_ptr1 = getelementptr i8, i8* %current_cell_ptr, i32 2
+  %target_cell_val2 = load i8, i8* %target_cell_ptr1, align 1
+  %additional_val3 = mul i8 %cell_value, 3
+  %new_target_val4 = add i8 %target_cell_val2, %additional_val3
+  store i8 %new_target_val4, i8* %target_cell_ptr1, align 1
+  br label %multiply_after
+
+multiply_after:                                   ; preds = %multiply_body, %after_init
   call void @free(i8* %cells)
   ret i32 0
 }
@@ -717,19 +915,24 @@ attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 }
 
 #[test]
-fn compile_increment_with_offset() {
-    let instrs = vec![Increment {
-        amount: Wrapping(1),
-        offset: 3,
+fn compile_copy_keeps_current_cell() {
+    // A copy without a temporary cell, e.g. `[->+>+<<]>>[-<<+>>]`
+    // after the copy_loop pass.
+    let mut changes = HashMap::new();
+    changes.insert(0, Wrapping(1));
+    changes.insert(1, Wrapping(1));
+    let instrs = vec![MultiplyMove {
+        changes,
         position: Some(Position { start: 0, end: 0 }),
     }];
+
     let result = compile_to_module(
         "foo",
         Some("i686-pc-linux-gnu".to_owned()),
         &instrs,
         &ExecutionState {
             start_instr: Some(&instrs[0]),
-            cells: vec![Wrapping(0); 4],
+            cells: vec![Wrapping(0); 3],
             cell_ptr: 0,
             outputs: vec![],
         },
@@ -739,10 +942,12 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -751,11 +956,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 4)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 4, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 3, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
@@ -765,41 +970,1980 @@ beginning:                                        ; No predecessors!
 
 after_init:                                       ; preds = %init, %beginning
   %cell_index = load i32, i32* %cell_index_ptr, align 4
-  %offset_cell_index = add i32 %cell_index, 3
-  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
   %cell_value = load i8, i8* %current_cell_ptr, align 1
-  %new_cell_value = add i8 %cell_value, 1
-  store i8 %new_cell_value, i8* %current_cell_ptr, align 1
-  call void @free(i8* %cells)
-  ret i32 0
-}
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %multiply_after, label %multiply_body
 
-attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+multiply_body:                                    ; preds = %after_init
+  %target_cell_ptr = getelementptr i8, i8* %current_cell_ptr, i32 1
+  %target_cell_val = load i8, i8* %target_cell_ptr, align 1
+  %new_target_val = add i8 %target_cell_val, %cell_value
+  store i8 %new_target_val, i8* %target_cell_ptr, align 1
+  br label %multiply_after
+
+multiply_after:                                   ; preds = %multiply_body, %after_init
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+/// A CellCopy loads the source before the destination, so it needs no
+/// check of the current cell.
+#[test]
+fn compile_cell_copy() {
+    let instrs = vec![
+        CellCopy {
+            src_offset: 0,
+            dst_offset: 1,
+            overwrite: false,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        CellCopy {
+            src_offset: 1,
+            dst_offset: 0,
+            overwrite: true,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 2, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %offset_cell_index = add i32 %cell_index, 1
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %src_cell_val = load i8, i8* %current_cell_ptr, align 1
+  %dst_cell_val = load i8, i8* %offset_cell_ptr, align 1
+  %new_cell_value = add i8 %dst_cell_val, %src_cell_val
+  store i8 %new_cell_value, i8* %offset_cell_ptr, align 1
+  %offset_cell_index1 = add i32 %cell_index, 1
+  %offset_cell_ptr2 = getelementptr i8, i8* %cells, i32 %offset_cell_index1
+  %current_cell_ptr3 = getelementptr i8, i8* %cells, i32 %cell_index
+  %src_cell_val4 = load i8, i8* %offset_cell_ptr2, align 1
+  store i8 %src_cell_val4, i8* %current_cell_ptr3, align 1
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_multiply_move_factor_one() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let instrs = vec![MultiplyMove {
+        changes,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 2, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr, align 1
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %multiply_after, label %multiply_body
+
+multiply_body:                                    ; preds = %after_init
+  store i8 0, i8* %current_cell_ptr, align 1
+  %target_cell_ptr = getelementptr i8, i8* %current_cell_ptr, i32 1
+  %target_cell_val = load i8, i8* %target_cell_ptr, align 1
+  %new_target_val = add i8 %target_cell_val, %cell_value
+  store i8 %new_target_val, i8* %target_cell_ptr, align 1
+  br label %multiply_after
+
+multiply_after:                                   ; preds = %multiply_body, %after_init
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn set_initial_cell_values() {
+    let instrs = vec![PointerIncrement {
+        amount: 1,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![
+                Wrapping(1),
+                Wrapping(1),
+                Wrapping(2),
+                Wrapping(0),
+                Wrapping(0),
+                Wrapping(0),
+            ],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @malloc(i32 6)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 1, i32 2, i1 false)
+  %offset_cell_ptr1 = getelementptr i8, i8* %cells, i32 2
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr1, i8 2, i32 1, i1 false)
+  %offset_cell_ptr2 = getelementptr i8, i8* %cells, i32 3
+  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr2, i8 0, i32 3, i1 false)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %new_cell_index = add i32 %cell_index, 1
+  store i32 %new_cell_index, i32* %cell_index_ptr, align 4
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_static_outputs() {
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &[],
+        &ExecutionState {
+            start_instr: None,
+            cells: vec![],
+            cell_ptr: 0,
+            outputs: vec![5, 10],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@known_outputs = constant [2 x i8] c\"\\05\\0A\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([2 x i8], [2 x i8]* @known_outputs, i32 0, i32 0), i32 2)
+  br label %beginning
+
+beginning:                                        ; preds = %init
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_ptr_increment() {
+    let instrs = vec![PointerIncrement {
+        amount: 1,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 2, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %new_cell_index = add i32 %cell_index, 1
+  store i32 %new_cell_index, i32* %cell_index_ptr, align 4
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_increment() {
+    let instrs = vec![Increment {
+        amount: Wrapping(1),
+        offset: 0,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr, align 1
+  %new_cell_value = add i8 %cell_value, 1
+  store i8 %new_cell_value, i8* %current_cell_ptr, align 1
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_increment_with_offset() {
+    let instrs = vec![Increment {
+        amount: Wrapping(1),
+        offset: 3,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 4],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 4, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %offset_cell_index = add i32 %cell_index, 3
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %cell_value = load i8, i8* %offset_cell_ptr, align 1
+  %new_cell_value = add i8 %cell_value, 1
+  store i8 %new_cell_value, i8* %offset_cell_ptr, align 1
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_sorted_increments_load_cell_index_once() {
+    let (instrs, _) = optimize(
+        parse("+>-").unwrap(),
+        OptimizeOptions {
+            passes: Some("offset_sort".to_owned()),
+            ..OptimizeOptions::default()
+        },
+    );
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 2, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr, align 1
+  %new_cell_value = add i8 %cell_value, 1
+  store i8 %new_cell_value, i8* %current_cell_ptr, align 1
+  %offset_cell_index = add i32 %cell_index, 1
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %cell_value1 = load i8, i8* %offset_cell_ptr, align 1
+  %new_cell_value2 = add i8 %cell_value1, -1
+  store i8 %new_cell_value2, i8* %offset_cell_ptr, align 1
+  %new_cell_index = add i32 %cell_index, 1
+  store i32 %new_cell_index, i32* %cell_index_ptr, align 4
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_increment_run() {
+    let (instrs, _) = optimize(
+        parse("+>+>+>+").unwrap(),
+        OptimizeOptions {
+            passes: Some("offset_sort".to_owned()),
+            ..OptimizeOptions::default()
+        },
+    );
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 4],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 4, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cells_ptr = bitcast i8* %current_cell_ptr to <4 x i8>*
+  %cell_values = load <4 x i8>, <4 x i8>* %cells_ptr, align 1
+  %new_cell_values = add <4 x i8> %cell_values, <i8 1, i8 1, i8 1, i8 1>
+  store <4 x i8> %new_cell_values, <4 x i8>* %cells_ptr, align 1
+  %new_cell_index = add i32 %cell_index, 3
+  store i32 %new_cell_index, i32* %cell_index_ptr, align 4
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_write_run_of_known_value() {
+    let instrs = parse("+++...").unwrap();
+    let (instrs, _) = optimize(instrs, OptimizeOptions::default());
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@output_buffer = private global [4096 x i8] zeroinitializer
+@output_len = private global i32 0
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  store i8 3, i8* %current_cell_ptr, align 1
+  %current_cell_ptr1 = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr1, align 1
+  %output_len = load i32, i32* @output_len, align 4
+  %output_lacks_room = icmp ugt i32 %output_len, 4093
+  br i1 %output_lacks_room, label %flush_before_fill, label %fill_output
+
+flush_before_fill:                                ; preds = %after_init
+  call void @flush_output()
+  br label %fill_output
+
+fill_output:                                      ; preds = %flush_before_fill, %after_init
+  %output_len2 = load i32, i32* @output_len, align 4
+  %output_ptr = getelementptr [4096 x i8], [4096 x i8]* @output_buffer, i32 0, i32 %output_len2
+  call void @llvm.memset.p0i8.i32(i8* %output_ptr, i8 %cell_value, i32 3, i1 false)
+  %new_output_len = add i32 %output_len2, 3
+  store i32 %new_output_len, i32* @output_len, align 4
+  %output_is_full = icmp eq i32 %new_output_len, 4096
+  br i1 %output_is_full, label %flush_full_output, label %after_write
+
+flush_full_output:                                ; preds = %fill_output
+  call void @flush_output()
+  br label %after_write
+
+after_write:                                      ; preds = %flush_full_output, %fill_output
+  call void @flush_output()
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+define private void @flush_output() {
+entry:
+  %output_len = load i32, i32* @output_len, align 4
+  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([4096 x i8], [4096 x i8]* @output_buffer, i32 0, i32 0), i32 %output_len)
+  store i32 0, i32* @output_len, align 4
+  ret void
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_write_run_of_unknown_value() {
+    let instrs = parse(",...").unwrap();
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@output_buffer = private global [4096 x i8] zeroinitializer
+@output_len = private global i32 0
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  call void @flush_output()
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
+  %current_cell_ptr1 = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr1, align 1
+  %output_len = load i32, i32* @output_len, align 4
+  %output_lacks_room = icmp ugt i32 %output_len, 4093
+  br i1 %output_lacks_room, label %flush_before_fill, label %fill_output
+
+flush_before_fill:                                ; preds = %after_init
+  call void @flush_output()
+  br label %fill_output
+
+fill_output:                                      ; preds = %flush_before_fill, %after_init
+  %output_len2 = load i32, i32* @output_len, align 4
+  %output_ptr = getelementptr [4096 x i8], [4096 x i8]* @output_buffer, i32 0, i32 %output_len2
+  call void @llvm.memset.p0i8.i32(i8* %output_ptr, i8 %cell_value, i32 3, i1 false)
+  %new_output_len = add i32 %output_len2, 3
+  store i32 %new_output_len, i32* @output_len, align 4
+  %output_is_full = icmp eq i32 %new_output_len, 4096
+  br i1 %output_is_full, label %flush_full_output, label %after_write
+
+flush_full_output:                                ; preds = %fill_output
+  call void @flush_output()
+  br label %after_write
+
+after_write:                                      ; preds = %flush_full_output, %fill_output
+  call void @flush_output()
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+define private void @flush_output() {
+entry:
+  %output_len = load i32, i32* @output_len, align 4
+  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([4096 x i8], [4096 x i8]* @output_buffer, i32 0, i32 0), i32 %output_len)
+  store i32 0, i32* @output_len, align 4
+  ret void
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_write_run_unbuffered() {
+    let instrs = parse(",...").unwrap();
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+        &CodegenOptions {
+            unbuffered_writes: true,
+            ..CodegenOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
+  %current_cell_ptr1 = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr1, align 1
+  %cell_val_as_char = zext i8 %cell_value to i32
+  br label %write_loop
+
+write_loop:                                       ; preds = %write_loop, %after_init
+  %writes = phi i32 [ 0, %after_init ], [ %next_writes, %write_loop ]
+  %0 = call i32 @putchar(i32 %cell_val_as_char)
+  %next_writes = add i32 %writes, 1
+  %writes_done = icmp eq i32 %next_writes, 3
+  br i1 %writes_done, label %write_after, label %write_loop
+
+write_after:                                      ; preds = %write_loop
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_write_run_longer_than_output_buffer() {
+    let src = format!(",{}", ".".repeat(5000));
+    let instrs = parse(&src).unwrap();
+    let state = ExecutionState {
+        start_instr: Some(&instrs[0]),
+        cells: vec![Wrapping(0)],
+        cell_ptr: 0,
+        outputs: vec![],
+    };
+    let module = compile_to_module("foo", None, &instrs, &state);
+    assert!(verify_module(&module).is_ok(), "{:?}", module.to_cstring());
+
+    // We fill the whole buffer, then the remaining bytes.
+    let ir = module.to_cstring().to_string_lossy().into_owned();
+    assert!(ir.contains("i32 4096, i1 false)"), "{}", ir);
+    assert!(ir.contains("i32 904, i1 false)"), "{}", ir);
+}
+
+#[test]
+fn compile_start_instr_midway() {
+    let instrs = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[1]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  store i8 1, i8* %current_cell_ptr, align 1
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index1 = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %cell_index1
+  store i8 2, i8* %current_cell_ptr2, align 1
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_scan_loop() {
+    let instrs = vec![ScanLoop {
+        step: -1,
+        position: Some(Position { start: 0, end: 2 }),
+    }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 3],
+            cell_ptr: 2,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 3, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 2, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %scan_start_index = load i32, i32* %cell_index_ptr, align 4
+  br label %scan_header
+
+scan_header:                                      ; preds = %scan_body, %after_init
+  %scan_index = phi i32 [ %scan_start_index, %after_init ], [ %scan_next_index, %scan_body ]
+  %scan_cell_ptr = getelementptr i8, i8* %cells, i32 %scan_index
+  %scan_cell_value = load i8, i8* %scan_cell_ptr, align 1
+  %scan_cell_value_is_zero = icmp eq i8 0, %scan_cell_value
+  br i1 %scan_cell_value_is_zero, label %scan_after, label %scan_body
+
+scan_body:                                        ; preds = %scan_header
+  %scan_next_index = add i32 %scan_index, -1
+  br label %scan_header
+
+scan_after:                                       ; preds = %scan_header
+  store i32 %scan_index, i32* %cell_index_ptr, align 4
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_loop_with_offset() {
+    let instrs = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![Increment {
+                amount: Wrapping(-1),
+                offset: 1,
+                position: Some(Position { start: 3, end: 3 }),
+            }],
+            offset: 1,
+            position: Some(Position { start: 2, end: 4 }),
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 2, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
+  br label %loop_header
+
+loop_header:                                      ; preds = %loop_body, %after_init
+  %cell_index1 = load i32, i32* %cell_index_ptr, align 4
+  %offset_cell_index = add i32 %cell_index1, 1
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %cell_value = load i8, i8* %offset_cell_ptr, align 1
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %loop_after, label %loop_body
+
+loop_body:                                        ; preds = %loop_header
+  %cell_index2 = load i32, i32* %cell_index_ptr, align 4
+  %offset_cell_index3 = add i32 %cell_index2, 1
+  %offset_cell_ptr4 = getelementptr i8, i8* %cells, i32 %offset_cell_index3
+  %cell_value5 = load i8, i8* %offset_cell_ptr4, align 1
+  %new_cell_value = add i8 %cell_value5, -1
+  store i8 %new_cell_value, i8* %offset_cell_ptr4, align 1
+  br label %loop_header
+
+loop_after:                                       ; preds = %loop_header
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_infinite_loop() {
+    let instrs = vec![
+        Read {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        InfiniteLoop {
+            offset: 1,
+            position: Some(Position { start: 1, end: 4 }),
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 2],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 2, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
+  %offset_cell_index = add i32 %cell_index, 1
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  %cell_value = load i8, i8* %offset_cell_ptr, align 1
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %infinite_after, label %infinite_entry
+
+infinite_entry:                                   ; preds = %after_init
+  br label %infinite_loop
+
+infinite_loop:                                    ; preds = %infinite_loop, %infinite_entry
+  br label %infinite_loop
+
+infinite_after:                                   ; preds = %after_init
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn run_jit_without_io() {
+    init_llvm();
+
+    let instrs = parse("+[->+<]").unwrap();
+    let mut state = ExecutionState::initial(&instrs);
+    state.start_instr = instrs.first();
+
+    let module = compile_to_module("foo", None, &instrs, &state);
+    assert_eq!(run_jit(module), Ok(0));
+}
+
+#[test]
+fn compile_count_loop_iterations() {
+    let instrs = parse("[-]").unwrap();
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+        &CodegenOptions {
+            count_loop_iterations: true,
+            ..CodegenOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@loop_iterations.0 = global i64 0
+@loop_iterations_message = private unnamed_addr constant [36 x i8] c\"Loop at offset %d: %llu iterations\\0A\\00\", align 1
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  br label %loop_header
+
+loop_header:                                      ; preds = %loop_body, %after_init
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr, align 1
+  %cell_value_is_zero = icmp eq i8 0, %cell_value
+  br i1 %cell_value_is_zero, label %loop_after, label %loop_body
+
+loop_body:                                        ; preds = %loop_header
+  %loop_iterations = load i64, i64* @loop_iterations.0, align 4
+  %new_loop_iterations = add i64 %loop_iterations, 1
+  store i64 %new_loop_iterations, i64* @loop_iterations.0, align 4
+  %cell_index1 = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr2 = getelementptr i8, i8* %cells, i32 %cell_index1
+  %cell_value3 = load i8, i8* %current_cell_ptr2, align 1
+  %new_cell_value = add i8 %cell_value3, -1
+  store i8 %new_cell_value, i8* %current_cell_ptr2, align 1
+  br label %loop_header
+
+loop_after:                                       ; preds = %loop_header
+  %loop_iterations4 = load i64, i64* @loop_iterations.0, align 4
+  %0 = call i32 (i32, i8*, ...) @dprintf(i32 2, i8* getelementptr inbounds ([36 x i8], [36 x i8]* @loop_iterations_message, i32 0, i32 0), i32 0, i64 %loop_iterations4)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+declare i32 @dprintf(i32, i8*, ...)
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn run_jit_counting_loops_after_optimisation() {
+    init_llvm();
+
+    let src = "++[>+++<-]>[<++>-]";
+
+    let instrs = parse(src).unwrap();
+    let mut state = ExecutionState::initial(&instrs);
+    state.start_instr = instrs.first();
+    let options = CodegenOptions {
+        count_loop_iterations: true,
+        ..CodegenOptions::default()
+    };
+    let module = compile_to_module_with_options("foo", None, &instrs, &state, &options);
+    let unoptimised_counts = run_jit_counting_loops(module);
+    assert_eq!(unoptimised_counts, Ok((0, vec![(2, 2), (11, 6)])));
+
+    // These passes preserve both loops.
+    let (opt_instrs, _) = optimize(
+        parse(src).unwrap(),
+        OptimizeOptions {
+            passes: Some("combine_inc,combine_ptr".to_owned()),
+            ..OptimizeOptions::default()
+        },
+    );
+    let mut state = ExecutionState::initial(&opt_instrs);
+    state.start_instr = opt_instrs.first();
+    let module = compile_to_module_with_options("foo", None, &opt_instrs, &state, &options);
+    assert_eq!(run_jit_counting_loops(module), unoptimised_counts);
+}
+
+#[test]
+fn compile_wasm() {
+    let instrs = parse(",.").unwrap();
+
+    let result = compile_to_module(
+        "foo",
+        Some("wasm32-unknown-unknown".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0), Wrapping(5)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"wasm32-unknown-unknown\"
+
+@cells = private global [2 x i8] c\"\\00\\05\"
+@output_buffer = private global [4096 x i8] zeroinitializer
+@output_len = private global i32 0
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i32 @fd_write(i32, { i8*, i32 }*, i32, i32*) #1
+
+declare i32 @fd_read(i32, { i8*, i32 }*, i32, i32*) #2
+
+define private i32 @write(i32 %0, i8* %1, i32 %2) {
+entry:
+  %iovec = alloca { i8*, i32 }, align 8
+  %buf_ptr = getelementptr inbounds { i8*, i32 }, { i8*, i32 }* %iovec, i32 0, i32 0
+  store i8* %1, i8** %buf_ptr, align 8
+  %len_ptr = getelementptr inbounds { i8*, i32 }, { i8*, i32 }* %iovec, i32 0, i32 1
+  store i32 %2, i32* %len_ptr, align 4
+  %nwritten_ptr = alloca i32, align 4
+  store i32 0, i32* %nwritten_ptr, align 4
+  %3 = call i32 @fd_write(i32 %0, { i8*, i32 }* %iovec, i32 1, i32* %nwritten_ptr)
+  %nwritten = load i32, i32* %nwritten_ptr, align 4
+  ret i32 %nwritten
+}
+
+define private i32 @putchar(i32 %0) {
+entry:
+  %byte_ptr = alloca i8, align 1
+  %byte = trunc i32 %0 to i8
+  store i8 %byte, i8* %byte_ptr, align 1
+  %1 = call i32 @write(i32 1, i8* %byte_ptr, i32 1)
+  ret i32 %0
+}
+
+define private i32 @getchar() {
+entry:
+  %byte_ptr = alloca i8, align 1
+  %iovec = alloca { i8*, i32 }, align 8
+  %buf_ptr = getelementptr inbounds { i8*, i32 }, { i8*, i32 }* %iovec, i32 0, i32 0
+  store i8* %byte_ptr, i8** %buf_ptr, align 8
+  %len_ptr = getelementptr inbounds { i8*, i32 }, { i8*, i32 }* %iovec, i32 0, i32 1
+  store i32 1, i32* %len_ptr, align 4
+  %nread_ptr = alloca i32, align 4
+  store i32 0, i32* %nread_ptr, align 4
+  %0 = call i32 @fd_read(i32 0, { i8*, i32 }* %iovec, i32 1, i32* %nread_ptr)
+  %nread = load i32, i32* %nread_ptr, align 4
+  %is_eof = icmp eq i32 %nread, 0
+  %byte = load i8, i8* %byte_ptr, align 1
+  %char_value = zext i8 %byte to i32
+  %result = select i1 %is_eof, i32 -1, i32 %char_value
+  ret i32 %result
+}
+
+define i32 @main() {
+init:
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  call void @flush_output()
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* getelementptr inbounds ([2 x i8], [2 x i8]* @cells, i32 0, i32 0), i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
+  %current_cell_ptr1 = getelementptr i8, i8* getelementptr inbounds ([2 x i8], [2 x i8]* @cells, i32 0, i32 0), i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr1, align 1
+  %output_len = load i32, i32* @output_len, align 4
+  %output_ptr = getelementptr [4096 x i8], [4096 x i8]* @output_buffer, i32 0, i32 %output_len
+  store i8 %cell_value, i8* %output_ptr, align 1
+  %new_output_len = add i32 %output_len, 1
+  store i32 %new_output_len, i32* @output_len, align 4
+  %output_is_full = icmp eq i32 %new_output_len, 4096
+  br i1 %output_is_full, label %flush_full_output, label %after_write
+
+flush_full_output:                                ; preds = %after_init
+  call void @flush_output()
+  br label %after_write
+
+after_write:                                      ; preds = %flush_full_output, %after_init
+  call void @flush_output()
+  ret i32 0
+}
+
+define void @_start() {
+entry:
+  %0 = call i32 @main()
+  ret void
+}
+
+define private void @flush_output() {
+entry:
+  %output_len = load i32, i32* @output_len, align 4
+  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([4096 x i8], [4096 x i8]* @output_buffer, i32 0, i32 0), i32 %output_len)
+  store i32 0, i32* @output_len, align 4
+  ret void
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+attributes #1 = { \"wasm-import-module\"=\"wasi_snapshot_preview1\" \"wasm-import-name\"=\"fd_write\" }
+attributes #2 = { \"wasm-import-module\"=\"wasi_snapshot_preview1\" \"wasm-import-name\"=\"fd_read\" }
+";
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_debug_info() {
+    let src = "+\n .";
+    let instrs = parse(src).unwrap();
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+        &CodegenOptions {
+            unbuffered_writes: true,
+            debug_info: Some(DebugSource::new("/src/foo.bf", src)),
+            ..CodegenOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() !dbg !4 {
+init:
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4, !dbg !7
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index, !dbg !7
+  %cell_value = load i8, i8* %current_cell_ptr, align 1, !dbg !7
+  %new_cell_value = add i8 %cell_value, 1, !dbg !7
+  store i8 %new_cell_value, i8* %current_cell_ptr, align 1, !dbg !7
+  %current_cell_ptr1 = getelementptr i8, i8* %cells, i32 %cell_index, !dbg !8
+  %cell_value2 = load i8, i8* %current_cell_ptr1, align 1, !dbg !8
+  %cell_val_as_char = zext i8 %cell_value2 to i32, !dbg !8
+  %0 = call i32 @putchar(i32 %cell_val_as_char), !dbg !8
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+
+!llvm.module.flags = !{!0, !1}
+!llvm.dbg.cu = !{!2}
+
+!0 = !{i32 2, !\"Debug Info Version\", i32 3}
+!1 = !{i32 2, !\"Dwarf Version\", i32 4}
+!2 = distinct !DICompileUnit(language: DW_LANG_C, file: !3, producer: \"bfc\", isOptimized: false, runtimeVersion: 0, emissionKind: LineTablesOnly, splitDebugInlining: false)
+!3 = !DIFile(filename: \"foo.bf\", directory: \"/src\")
+!4 = distinct !DISubprogram(name: \"main\", linkageName: \"main\", scope: null, file: !3, line: 1, type: !5, scopeLine: 1, spFlags: DISPFlagDefinition, unit: !2, retainedNodes: !6)
+!5 = !DISubroutineType(types: !6)
+!6 = !{}
+!7 = !DILocation(line: 1, column: 1, scope: !4)
+!8 = !DILocation(line: 2, column: 2, scope: !4)
 ";
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
 
 #[test]
-fn compile_start_instr_midway() {
+fn compile_stdout_buffering() {
+    let instrs = vec![Write {
+        offset: 0,
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+        &CodegenOptions {
+            stdout_buffering: Some(StdoutBuffering::Line),
+            ..CodegenOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@stdout = external global i8*
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define i32 @main() {
+init:
+  %stdout_ptr = load i8*, i8** @stdout, align 8
+  %0 = call i32 @setvbuf(i8* %stdout_ptr, i8* null, i32 1, i32 0)
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr, align 1
+  %cell_val_as_char = zext i8 %cell_value to i32
+  %1 = call i32 @putchar(i32 %cell_val_as_char)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_stdout_buffering_modes() {
+    let instrs = vec![];
+    let state = ExecutionState {
+        start_instr: None,
+        cells: vec![Wrapping(0)],
+        cell_ptr: 0,
+        outputs: vec![],
+    };
+
+    for (buffering, mode) in [
+        (StdoutBuffering::Full, 0),
+        (StdoutBuffering::Line, 1),
+        (StdoutBuffering::Unbuffered, 2),
+    ] {
+        let result = compile_to_module_with_options(
+            "foo",
+            Some("i686-pc-linux-gnu".to_owned()),
+            &instrs,
+            &state,
+            &CodegenOptions {
+                stdout_buffering: Some(buffering),
+                ..CodegenOptions::default()
+            },
+        );
+        let ir = result.to_cstring().into_string().unwrap();
+        let expected_call = format!(
+            "call i32 @setvbuf(i8* %stdout_ptr, i8* null, i32 {}, i32 0)",
+            mode
+        );
+        assert!(ir.contains(&expected_call), "{}", ir);
+    }
+
+    // By default, we don't call setvbuf.
+    let result = compile_to_module("foo", Some("i686-pc-linux-gnu".to_owned()), &instrs, &state);
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(!ir.contains("call i32 @setvbuf"));
+}
+
+#[test]
+fn compile_sanitize_address() {
+    let instrs = parse(",.").unwrap();
+    let state = ExecutionState {
+        start_instr: Some(&instrs[0]),
+        cells: vec![Wrapping(0)],
+        cell_ptr: 0,
+        outputs: vec![],
+    };
+    let options = CodegenOptions {
+        sanitize_address: true,
+        ..CodegenOptions::default()
+    };
+
+    let result = compile_to_module_with_options("foo", None, &instrs, &state, &options);
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("define i32 @main() #"));
+    assert!(ir.contains("define private void @flush_output() #"));
+    assert!(ir.contains("sanitize_address"));
+    assert!(verify_module(&result).is_ok());
+}
+
+#[test]
+fn compile_cells_init_calloc_or_memset() {
+    let instrs = vec![Write {
+        offset: 0,
+        position: None,
+    }];
+    let compile_cells = |cells| {
+        let state = ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells,
+            cell_ptr: 0,
+            outputs: vec![],
+        };
+        let result =
+            compile_to_module("foo", Some("i686-pc-linux-gnu".to_owned()), &instrs, &state);
+        result.to_cstring().into_string().unwrap()
+    };
+
+    // All zero cells only need calloc.
+    let ir = compile_cells(vec![Wrapping(0); 5]);
+    assert!(
+        ir.contains("%cells = call i8* @calloc(i32 5, i32 1)"),
+        "{}",
+        ir
+    );
+    assert!(!ir.contains("call i8* @malloc"), "{}", ir);
+    assert!(!ir.contains("call void @llvm.memset"), "{}", ir);
+
+    // Otherwise we initialise the cells with memset.
+    let ir = compile_cells(vec![Wrapping(0), Wrapping(3), Wrapping(0)]);
+    assert!(ir.contains("%cells = call i8* @malloc(i32 3)"), "{}", ir);
+    assert!(!ir.contains("call i8* @calloc"), "{}", ir);
+    assert!(ir.contains("i8 3, i32 1, i1 false)"), "{}", ir);
+}
+
+#[test]
+fn compile_custom_io() {
     let instrs = vec![
-        Set {
-            amount: Wrapping(1),
+        Read {
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: None,
+        },
+        Write {
+            offset: 0,
+            position: None,
+        },
+    ];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 1],
+            cell_ptr: 0,
+            outputs: vec![5],
+        },
+        &CodegenOptions {
+            unbuffered_writes: true,
+            custom_io: true,
+            ..CodegenOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@known_outputs = constant [1 x i8] c\"\\05\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @bf_putchar(i32)
+
+declare i32 @bf_getchar()
+
+define private i32 @write(i32 %0, i8* %1, i32 %2) {
+entry:
+  %index_ptr = alloca i32, align 4
+  store i32 0, i32* %index_ptr, align 4
+  br label %write_header
+
+write_header:                                     ; preds = %write_body, %entry
+  %index = load i32, i32* %index_ptr, align 4
+  %is_done = icmp sge i32 %index, %2
+  br i1 %is_done, label %write_after, label %write_body
+
+write_body:                                       ; preds = %write_header
+  %byte_ptr = getelementptr i8, i8* %1, i32 %index
+  %byte = load i8, i8* %byte_ptr, align 1
+  %char_value = zext i8 %byte to i32
+  %3 = call i32 @bf_putchar(i32 %char_value)
+  %next_index = add i32 %index, 1
+  store i32 %next_index, i32* %index_ptr, align 4
+  br label %write_header
+
+write_after:                                      ; preds = %write_header
+  ret i32 %2
+}
+
+define i32 @main() {
+init:
+  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([1 x i8], [1 x i8]* @known_outputs, i32 0, i32 0), i32 1)
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @bf_getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
+  %current_cell_ptr1 = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr1, align 1
+  %cell_val_as_char = zext i8 %cell_value to i32
+  %1 = call i32 @bf_putchar(i32 %cell_val_as_char)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_custom_entry() {
+    let instrs = vec![Increment {
+        amount: Wrapping(1),
+        offset: 0,
+        position: None,
+    }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 1],
+            cell_ptr: 0,
+            outputs: vec![],
+        },
+        &CodegenOptions {
+            entry_name: Some("run_bf".to_owned()),
+            ..CodegenOptions::default()
+        },
+    );
+    let expected = "; ModuleID = \'foo\'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nofree nounwind willreturn writeonly
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
+
+declare i8* @malloc(i32)
+
+declare i8* @calloc(i32, i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
+define void @run_bf() {
+init:
+  %cells = call i8* @calloc(i32 1, i32 1)
+  %cell_index_ptr = alloca i32, align 4
+  store i32 0, i32* %cell_index_ptr, align 4
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %cell_value = load i8, i8* %current_cell_ptr, align 1
+  %new_cell_value = add i8 %cell_value, 1
+  store i8 %new_cell_value, i8* %current_cell_ptr, align 1
+  call void @free(i8* %cells)
+  ret void
+}
+
+attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
+";
+    assert_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_set_run_as_memset() {
+    let instrs = vec![
+        Read {
+            offset: 0,
+            position: None,
         },
         Set {
-            amount: Wrapping(2),
+            amount: Wrapping(0),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: None,
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 1,
+            position: None,
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 2,
+            position: None,
         },
     ];
+
     let result = compile_to_module(
         "foo",
         Some("i686-pc-linux-gnu".to_owned()),
         &instrs,
         &ExecutionState {
-            start_instr: Some(&instrs[1]),
-            cells: vec![Wrapping(0)],
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0); 3],
             cell_ptr: 0,
             outputs: vec![],
         },
@@ -809,10 +2953,12 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nofree nounwind willreturn writeonly
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32 immarg, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1 immarg) #0
 
 declare i8* @malloc(i32)
 
+declare i8* @calloc(i32, i32)
+
 declare void @free(i8*)
 
 declare i32 @write(i32, i8*, i32)
@@ -821,33 +2967,124 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
+declare i32 @setvbuf(i8*, i8*, i32, i32)
+
 define i32 @main() {
 init:
-  %cells = call i8* @malloc(i32 1)
-  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  %cells = call i8* @calloc(i32 3, i32 1)
   %cell_index_ptr = alloca i32, align 4
   store i32 0, i32* %cell_index_ptr, align 4
   br label %after_init
 
 beginning:                                        ; No predecessors!
-  %cell_index = load i32, i32* %cell_index_ptr, align 4
-  %offset_cell_index = add i32 %cell_index, 0
-  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
-  store i8 1, i8* %current_cell_ptr, align 1
   br label %after_init
 
 after_init:                                       ; preds = %init, %beginning
-  %cell_index1 = load i32, i32* %cell_index_ptr, align 4
-  %offset_cell_index2 = add i32 %cell_index1, 0
-  %current_cell_ptr3 = getelementptr i8, i8* %cells, i32 %offset_cell_index2
-  store i8 2, i8* %current_cell_ptr3, align 1
+  %cell_index = load i32, i32* %cell_index_ptr, align 4
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr, align 1
+  %current_cell_ptr1 = getelementptr i8, i8* %cells, i32 %cell_index
+  call void @llvm.memset.p0i8.i32(i8* %current_cell_ptr1, i8 0, i32 3, i1 false)
   call void @free(i8* %cells)
   ret i32 0
 }
 
 attributes #0 = { argmemonly nofree nounwind willreturn writeonly }
 ";
+    assert_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
 
-    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+#[test]
+fn target_machine_uses_cpu_and_features() {
+    init_llvm();
+
+    let target_triple = CString::new("x86_64-pc-linux-gnu").unwrap();
+    let target_cpu = TargetCpu {
+        name: "skylake".to_owned(),
+        features: "+avx2,-sse4.1".to_owned(),
+    };
+    let target_machine = TargetMachine::new(
+        target_triple.as_ptr(),
+        &target_cpu,
+        RelocMode::default(),
+        CodeModel::default(),
+    )
+    .unwrap();
+
+    assert_eq!(target_machine.cpu(), "skylake");
+    assert_eq!(target_machine.features(), "+avx2,-sse4.1");
+}
+
+#[test]
+fn target_machine_native_cpu() {
+    init_llvm();
+
+    let target_triple = get_default_target_triple();
+    let target_cpu = TargetCpu {
+        name: "native".to_owned(),
+        features: "".to_owned(),
+    };
+    let target_machine = TargetMachine::new(
+        target_triple.as_ptr(),
+        &target_cpu,
+        RelocMode::default(),
+        CodeModel::default(),
+    )
+    .unwrap();
+
+    // We should have replaced "native" with the host CPU name.
+    assert_ne!(target_machine.cpu(), "native");
+}
+
+#[test]
+fn verify_module_accepts_valid_modules() {
+    let src = "+[>,.<-]>[>]";
+    let instrs = parse(src).unwrap();
+    // Cells that don't start at zero are initialised with memset.
+    let state = ExecutionState {
+        start_instr: Some(&instrs[0]),
+        cells: vec![Wrapping(0), Wrapping(3), Wrapping(0)],
+        cell_ptr: 0,
+        outputs: vec![],
+    };
+
+    for options in [
+        CodegenOptions::default(),
+        CodegenOptions {
+            count_loop_iterations: true,
+            debug_bounds: true,
+            ..CodegenOptions::default()
+        },
+        CodegenOptions {
+            debug_info: Some(DebugSource::new("/src/foo.bf", src)),
+            ..CodegenOptions::default()
+        },
+    ] {
+        let module = compile_to_module_with_options("foo", None, &instrs, &state, &options);
+        assert!(verify_module(&module).is_ok(), "{:?}", module.to_cstring());
+    }
+}
+
+#[test]
+fn optimise_ir_folds_increments() {
+    let instrs = parse("+++.").unwrap();
+    let state = ExecutionState {
+        start_instr: Some(&instrs[0]),
+        cells: vec![Wrapping(0)],
+        cell_ptr: 0,
+        outputs: vec![],
+    };
+    let mut module = compile_to_module("foo", None, &instrs, &state);
+    let unoptimised_ir = module.to_cstring().to_string_lossy().into_owned();
+    assert!(unoptimised_ir.contains("add i8"), "{}", unoptimised_ir);
+
+    optimise_ir(&mut module, 3);
+
+    // The three increments are folded into writing a constant.
+    let ir = module.to_cstring().to_string_lossy().into_owned();
+    assert!(!ir.contains("add i8"), "{}", ir);
+    assert!(ir.contains("store i8 3"), "{}", ir);
+    assert!(verify_module(&module).is_ok(), "{}", ir);
 }
@@ -2,52 +2,86 @@
 
 //! bfc is a highly optimising compiler for BF.
 
-use ariadne::{Label, Report, ReportKind, Source};
-use bfir::Position;
+use ariadne::{CharSet, Config, Label, Report, ReportKind, Source};
+use bfc::bfir::Position;
+use bfc::diagnostics::{Diagnostic, Severity};
+use bfc::{analysis, bfir, bounds, c_backend, diagnostics, execution, llvm, peephole, source_map};
+use bfc::{CompileError, CompileOptions, CompileTimeExec};
 use clap::builder::ValueParser;
 use clap::command;
+use clap::crate_version;
+use clap::error::ErrorKind;
 use clap::Arg;
 use clap::ArgAction;
 use clap::ArgMatches;
+use clap::Command;
 use clap::ValueHint;
+use is_terminal::IsTerminal;
+use llvm_sys::target_machine::LLVMCodeGenFileType;
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
 use std::env;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::prelude::Read;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use tempfile::NamedTempFile;
 
-mod bfir;
-mod bounds;
-mod diagnostics;
-mod execution;
-mod llvm;
-mod peephole;
 mod shell;
 
-#[cfg(test)]
-mod llvm_tests;
-
 /// Read the contents of the file at path, and return a string of its
-/// contents. Return a diagnostic if we can't open or read the file.
-fn slurp(path: &Path) -> Result<String, String> {
-    let mut file = match File::open(path) {
-        Ok(file) => file,
-        Err(message) => {
-            return Err(format!("{}: {}", path.display(), message));
-        }
+/// contents. Return an error if we can't open or read the file.
+fn slurp(path: &Path) -> Result<String, CompileError> {
+    let io_error = |error| CompileError::Io {
+        path: display_path(path),
+        error,
     };
 
     let mut contents = String::new();
+    if is_stdin(path) {
+        std::io::stdin()
+            .read_to_string(&mut contents)
+            .map_err(io_error)?;
+    } else {
+        let mut file = File::open(path).map_err(io_error)?;
+        file.read_to_string(&mut contents).map_err(io_error)?;
+    }
+    Ok(contents)
+}
+
+/// The SOURCE_FILE argument that means "read the program from stdin".
+const STDIN_PATH: &str = "-";
+
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new(STDIN_PATH)
+}
 
-    match file.read_to_string(&mut contents) {
-        Ok(_) => Ok(contents),
-        Err(message) => Err(format!("{} {}", path.display(), message)),
+/// The name of the source file to show in diagnostics.
+fn display_path(path: &Path) -> String {
+    if is_stdin(path) {
+        "<stdin>".to_owned()
+    } else {
+        path.display().to_string()
     }
 }
 
-/// Convert "foo.bf" to "foo".
+/// Write `contents` to the file at `path`.
+fn write_file<C: AsRef<[u8]>>(path: &str, contents: C) -> Result<(), CompileError> {
+    std::fs::write(path, contents).map_err(|error| CompileError::Io {
+        path: path.to_owned(),
+        error,
+    })
+}
+
+/// Convert "foo.bf" to "foo". Programs read from stdin are compiled
+/// to "a.out".
 fn executable_name(bf_path: &Path) -> String {
+    if is_stdin(bf_path) {
+        return "a.out".to_owned();
+    }
+
     let bf_file_name = bf_path.file_name().unwrap().to_str().unwrap();
 
     let mut name_parts: Vec<_> = bf_file_name.split('.').collect();
@@ -59,52 +93,826 @@ fn executable_name(bf_path: &Path) -> String {
     name_parts.join(".")
 }
 
+/// Split BF source at the first `!`, returning the program and the
+/// bytes after the `!`, which are the program's input. Any later `!`
+/// characters are part of the input.
+fn split_bang_input(src: &str) -> (&str, &[u8]) {
+    match src.split_once('!') {
+        Some((program, input)) => (program, input.as_bytes()),
+        None => (src, &[]),
+    }
+}
+
+/// Return the BF code from fenced code blocks tagged `bf` or
+/// `brainfuck` in a markdown document.
+///
+/// Everything else is replaced with spaces (keeping newlines), so
+/// positions in the result are positions in the original document.
+fn extract_markdown_bf(src: &str) -> String {
+    let mut result = String::with_capacity(src.len());
+    // The fence that opened the current code block, and whether the
+    // block contains BF.
+    let mut open_fence: Option<(char, usize, bool)> = None;
+
+    for line in src.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let fence_char = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        let fence_len = fence_char.map_or(0, |c| trimmed.chars().take_while(|&tc| tc == c).count());
+        // Fences may be indented by at most three spaces.
+        let is_fence = fence_len >= 3 && line.len() - trimmed.len() <= 3;
+
+        let keep_line = match open_fence {
+            Some((open_char, open_len, is_bf)) => {
+                let info = trimmed[fence_len..].trim();
+                if is_fence
+                    && fence_char == Some(open_char)
+                    && fence_len >= open_len
+                    && info.is_empty()
+                {
+                    open_fence = None;
+                    false
+                } else {
+                    is_bf
+                }
+            }
+            None => {
+                if is_fence {
+                    let info = trimmed[fence_len..].trim();
+                    let lang = info.split_whitespace().next().unwrap_or("");
+                    let is_bf =
+                        lang.eq_ignore_ascii_case("bf") || lang.eq_ignore_ascii_case("brainfuck");
+                    open_fence = Some((fence_char.unwrap(), fence_len, is_bf));
+                }
+                false
+            }
+        };
+
+        if keep_line {
+            result.push_str(line);
+        } else {
+            result.extend(line.chars().map(|c| if c == '\n' { '\n' } else { ' ' }));
+        }
+    }
+
+    result
+}
+
+/// Convert "foo.bf" to "foo.s" (given an extension of "s"). Programs
+/// read from stdin use "a.s".
+fn output_file_name(bf_path: &Path, extension: &str) -> String {
+    if is_stdin(bf_path) {
+        return format!("a.{}", extension);
+    }
+    format!("{}.{}", executable_name(bf_path), extension)
+}
+
+/// The path to write our output to. This is `output` if the user
+/// passed `--output`, otherwise we derive it from the BF file name:
+/// "foo.bf" becomes "foo", or "foo.s" given an extension of "s".
+fn output_path(output: Option<&PathBuf>, bf_path: &Path, extension: Option<&str>) -> String {
+    match (output, extension) {
+        (Some(output), _) => output.to_string_lossy().into_owned(),
+        (None, Some(extension)) => output_file_name(bf_path, extension),
+        (None, None) => executable_name(bf_path),
+    }
+}
+
+/// The path of the object file we keep with `--keep-object`:
+/// "foo" becomes "foo.o".
+fn object_path(executable_path: &str) -> String {
+    Path::new(executable_path)
+        .with_extension("o")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The kinds of output that bfc can generate with `--emit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmitKind {
+    Executable,
+    Object,
+    Assembly,
+    LlvmIr,
+    Bitcode,
+    C,
+    Wasm,
+    Bf,
+}
+
+impl EmitKind {
+    fn from_arg(arg: &str) -> Self {
+        match arg {
+            "obj" => EmitKind::Object,
+            "asm" => EmitKind::Assembly,
+            "ll" | "llvm-ir" => EmitKind::LlvmIr,
+            "bc" => EmitKind::Bitcode,
+            "c" => EmitKind::C,
+            "wasm" => EmitKind::Wasm,
+            "bf" => EmitKind::Bf,
+            _ => EmitKind::Executable,
+        }
+    }
+
+    /// The extension of the output file. Executables have no
+    /// extension.
+    fn extension(self) -> Option<&'static str> {
+        match self {
+            EmitKind::Executable => None,
+            EmitKind::Object => Some("o"),
+            EmitKind::Assembly => Some("s"),
+            EmitKind::LlvmIr => Some("ll"),
+            EmitKind::Bitcode => Some("bc"),
+            EmitKind::C => Some("c"),
+            EmitKind::Wasm => Some("wasm"),
+            // Don't overwrite the input file.
+            EmitKind::Bf => Some("out.bf"),
+        }
+    }
+}
+
+/// Write the output for `emit` to `output_path`. Executables are
+/// handled separately, as they require linking.
+#[allow(clippy::too_many_arguments)]
+fn emit_file(
+    emit: EmitKind,
+    llvm_module: &mut llvm::Module,
+    instrs: &[bfir::AstNode],
+    state: &execution::ExecutionState,
+    output_path: &str,
+    target_cpu: &llvm::TargetCpu,
+    reloc_mode: llvm::RelocMode,
+    code_model: llvm::CodeModel,
+) -> Result<(), CompileError> {
+    match emit {
+        EmitKind::Executable => unreachable!("Executables require linking"),
+        EmitKind::Bf => unreachable!("BF source is written before codegen"),
+        EmitKind::Object | EmitKind::Wasm => llvm::write_object_file(
+            llvm_module,
+            output_path,
+            LLVMCodeGenFileType::LLVMObjectFile,
+            target_cpu,
+            reloc_mode,
+            code_model,
+        ),
+        EmitKind::Assembly => llvm::write_object_file(
+            llvm_module,
+            output_path,
+            LLVMCodeGenFileType::LLVMAssemblyFile,
+            target_cpu,
+            reloc_mode,
+            code_model,
+        ),
+        EmitKind::LlvmIr => write_file(output_path, llvm_module.to_cstring().as_bytes()),
+        EmitKind::Bitcode => llvm::write_bitcode_file(llvm_module, output_path),
+        EmitKind::C => write_file(output_path, c_backend::compile_to_c(instrs, state)),
+    }
+}
+
+/// The CPU to generate code for, from `--mcpu` and `--mattr`.
+fn target_cpu_from_matches(matches: &ArgMatches) -> llvm::TargetCpu {
+    let default = llvm::TargetCpu::default();
+    llvm::TargetCpu {
+        name: matches
+            .get_one::<String>("mcpu")
+            .cloned()
+            .unwrap_or(default.name),
+        features: matches
+            .get_one::<String>("mattr")
+            .cloned()
+            .unwrap_or(default.features),
+    }
+}
+
+/// The relocation model, from `--reloc`.
+fn reloc_mode_from_matches(matches: &ArgMatches) -> llvm::RelocMode {
+    match matches.get_one::<String>("reloc").map(|s| s.as_str()) {
+        Some("static") => llvm::RelocMode::Static,
+        Some("dynamic-no-pic") => llvm::RelocMode::DynamicNoPic,
+        Some("pic") | None => llvm::RelocMode::Pic,
+        Some(other) => unreachable!("Unexpected --reloc value: {}", other),
+    }
+}
+
+/// The code model, from `--code-model`.
+fn code_model_from_matches(matches: &ArgMatches) -> llvm::CodeModel {
+    match matches.get_one::<String>("code-model").map(|s| s.as_str()) {
+        Some("small") => llvm::CodeModel::Small,
+        Some("medium") => llvm::CodeModel::Medium,
+        Some("large") => llvm::CodeModel::Large,
+        Some("kernel") => llvm::CodeModel::Kernel,
+        None => llvm::CodeModel::Default,
+        Some(other) => unreachable!("Unexpected --code-model value: {}", other),
+    }
+}
+
+/// Write `instrs` as plain BF source to `output_path`, with
+/// `comments` next to the instructions they were next to originally.
+fn emit_bf_file(
+    instrs: &[bfir::AstNode],
+    comments: &[bfir::Comment],
+    output_path: &str,
+) -> Result<(), String> {
+    let src = bfir::to_bf_source_with_comments(instrs, comments)?;
+    std::fs::write(output_path, src).map_err(|e| format!("{}: {}", output_path, e))
+}
+
+/// Run the program with the interpreter, reading `known_input` and
+/// then stdin, and writing to stdout.
+fn interpret(
+    instrs: &[bfir::AstNode],
+    known_input: &[u8],
+    tape: execution::Tape,
+    start_cell: usize,
+) -> Result<(), diagnostics::Warning> {
+    let mut stdin = std::io::stdin().lock().bytes();
+    // Compiled programs read EOF from getchar() as -1, so do the same.
+    let stdin_input = std::iter::from_fn(|| {
+        // Show any prompt before we wait for input.
+        let _ = std::io::stdout().flush();
+        Some(stdin.next().and_then(|byte| byte.ok()).unwrap_or(0xFF))
+    });
+    let mut input = known_input.iter().copied().chain(stdin_input);
+
+    let mut stdout = std::io::stdout();
+    let result = execution::interpret(instrs, tape, start_cell, &mut input, &mut stdout);
+    let _ = stdout.flush();
+    result
+}
+
+/// The result of optimising a program with two pass specifications.
+#[derive(Debug, PartialEq, Eq)]
+struct PassComparison {
+    instr_count_a: u64,
+    instr_count_b: u64,
+    /// Whether both optimised programs write the same output. This
+    /// is None if we couldn't run both programs to completion at
+    /// compile time, e.g. because they read input.
+    equivalent: Option<bool>,
+}
+
+/// Optimise `instrs` with both pass specifications, and compare the
+/// results.
+fn compare_passes(instrs: &[bfir::AstNode], spec_a: &str, spec_b: &str) -> PassComparison {
+    let (instrs_a, _) = peephole::optimize(
+        instrs.to_vec(),
+        peephole::OptimizeOptions {
+            passes: Some(spec_a.to_owned()),
+            ..peephole::OptimizeOptions::default()
+        },
+    );
+    let (instrs_b, _) = peephole::optimize(
+        instrs.to_vec(),
+        peephole::OptimizeOptions {
+            passes: Some(spec_b.to_owned()),
+            ..peephole::OptimizeOptions::default()
+        },
+    );
+
+    let max_steps = execution::max_steps_for(instrs);
+
+    // Start both programs with the same number of cells, as pass
+    // specifications may give different cell bounds.
+    let mut state_a = execution::ExecutionState::initial(instrs);
+    let outcome_a =
+        execution::execute_with_state(&instrs_a, &mut state_a, max_steps, &mut std::iter::empty());
+    let mut state_b = execution::ExecutionState::initial(instrs);
+    let outcome_b =
+        execution::execute_with_state(&instrs_b, &mut state_b, max_steps, &mut std::iter::empty());
+
+    let equivalent = match (outcome_a, outcome_b) {
+        (execution::Outcome::Completed(_), execution::Outcome::Completed(_)) => {
+            Some(state_a.outputs == state_b.outputs)
+        }
+        _ => None,
+    };
+
+    PassComparison {
+        instr_count_a: bfir::count_instrs(&instrs_a),
+        instr_count_b: bfir::count_instrs(&instrs_b),
+        equivalent,
+    }
+}
+
+fn print_pass_comparison(comparison: &PassComparison, spec_a: &str, spec_b: &str) {
+    println!("A ({}): {} instructions", spec_a, comparison.instr_count_a);
+    println!("B ({}): {} instructions", spec_b, comparison.instr_count_b);
+    println!(
+        "Difference (B - A): {:+} instructions",
+        comparison.instr_count_b as i64 - comparison.instr_count_a as i64
+    );
+    match comparison.equivalent {
+        Some(true) => println!("Outputs: identical"),
+        Some(false) => println!("Outputs: DIFFERENT"),
+        None => println!("Outputs: unknown (could not execute both programs at compile time)"),
+    }
+}
+
+/// Print the summary of the program for `--analyze` to stdout.
+fn print_analysis(analysis: &analysis::Analysis) {
+    println!("BF commands:             {}", analysis.bf_commands);
+    println!("Optimized instructions:  {}", analysis.optimized_instrs);
+    println!("Maximum loop depth:      {}", analysis.max_loop_depth);
+    println!("Highest cell index:      {}", analysis.highest_cell_index);
+}
+
+/// Print how long each optimisation pass took and how many
+/// instructions it removed, for `--stats`. We write to stderr, so
+/// this works with `--dump-ir`.
+fn print_optimize_stats(stats: &peephole::OptimizeStats) {
+    let name_width = stats
+        .passes
+        .iter()
+        .map(|pass| pass.name.len())
+        .chain(std::iter::once("Pass".len()))
+        .max()
+        .unwrap_or(0);
+
+    eprintln!(
+        "{:name_width$}  {:>12}  {:>12}",
+        "Pass",
+        "Removed",
+        "Time (ms)",
+        name_width = name_width
+    );
+    for pass in &stats.passes {
+        eprintln!(
+            "{:name_width$}  {:>12}  {:>12.3}",
+            pass.name,
+            pass.instrs_removed,
+            pass.duration.as_secs_f64() * 1000.0,
+            name_width = name_width
+        );
+    }
+
+    if stats.reached_fixed_point {
+        eprintln!(
+            "Reached a fixed point after {} iterations.",
+            stats.iterations
+        );
+    } else {
+        eprintln!(
+            "Did not reach a fixed point after {} iterations.",
+            stats.iterations
+        );
+    }
+}
+
+/// Print every optimisation pass with its description, for
+/// `--passes=list`.
+fn print_pass_list() {
+    let descriptions = peephole::pass_descriptions();
+    let name_width = descriptions
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, description) in descriptions {
+        println!("{:width$}  {}", name, description, width = name_width);
+    }
+}
+
+/// Parse a `--passes` argument, rejecting passes that don't exist.
+fn parse_pass_specification(pass_specification: &str) -> Result<String, String> {
+    if pass_specification != "list" {
+        peephole::check_pass_specification(pass_specification)
+            .map_err(|e| format!("{} (use --passes=list to see all passes)", e))?;
+    }
+    Ok(pass_specification.to_owned())
+}
+
+/// Should human readable diagnostics use colour? `choice` is the
+/// `--color` argument. `auto` only uses colour when writing to a
+/// terminal and the user hasn't set `NO_COLOR`.
+fn use_color(choice: &str, no_color: bool, is_terminal: bool) -> bool {
+    match choice {
+        "always" => true,
+        "never" => false,
+        _ => !no_color && is_terminal,
+    }
+}
+
+/// The ariadne configuration for diagnostics written to stderr. When
+/// we're not using colour, we also stick to ASCII, so the output is
+/// readable in CI logs.
+fn diagnostic_config(matches: &ArgMatches) -> Config {
+    let choice = matches
+        .get_one::<String>("color")
+        .map(String::as_str)
+        .unwrap_or("auto");
+    let no_color = env::var_os("NO_COLOR").map_or(false, |v| !v.is_empty());
+
+    let color = use_color(choice, no_color, std::io::stderr().is_terminal());
+    Config::default().with_color(color).with_char_set(if color {
+        CharSet::Unicode
+    } else {
+        CharSet::Ascii
+    })
+}
+
+/// Write `diagnostic` to stderr, in the format chosen with
+/// `--message-format` and `--color`.
+fn report_diagnostic(
+    matches: &ArgMatches,
+    path: &Path,
+    src: &dyn Fn() -> Result<String, CompileError>,
+    diagnostic: Diagnostic,
+) {
+    let path_str = display_path(path);
+    if matches
+        .get_one::<String>("message-format")
+        .map(String::as_str)
+        == Some("json")
+    {
+        eprintln!("{}", diagnostic.to_json(&path_str));
+        return;
+    }
+
+    // We read the source again to show the diagnostic, so say why if
+    // we can't, rather than pointing into an empty file.
+    let src = match src() {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}: {}", diagnostic.title, diagnostic.message);
+            return;
+        }
+    };
+
+    let kind = match diagnostic.severity {
+        Severity::Error => ReportKind::Error,
+        Severity::Warning => ReportKind::Warning,
+    };
+    let position = diagnostic.position.unwrap_or(Position { start: 0, end: 0 });
+
+    Report::build(kind, &path_str, position.start)
+        .with_config(diagnostic_config(matches))
+        .with_message(diagnostic.title)
+        .with_label(
+            Label::new((&path_str, position.start..position.end + 1))
+                .with_message(diagnostic.message),
+        )
+        .finish()
+        .eprint((&path_str, Source::from(src)))
+        .unwrap();
+}
+
+/// Convert a `--compile-time-exec` value.
+fn compile_time_exec_from_arg(arg: &str) -> CompileTimeExec {
+    match arg {
+        "off" => CompileTimeExec::Off,
+        "cells" => CompileTimeExec::Cells,
+        _ => CompileTimeExec::Full,
+    }
+}
+
+/// The library options for the flags in `matches`, so we compile
+/// programs the same way as `bfc::compile_to_object`.
+fn compile_options(matches: &ArgMatches, known_input: Vec<u8>) -> CompileOptions {
+    let path = matches
+        .get_one::<PathBuf>("path")
+        .expect("Required argument");
+    let emit = EmitKind::from_arg(
+        matches
+            .get_one::<String>("emit")
+            .expect("Required argument"),
+    );
+
+    CompileOptions {
+        opt_level: matches
+            .get_one::<String>("opt")
+            .expect("Required argument")
+            .parse()
+            .expect("Validated by clap"),
+        llvm_opt_level: matches
+            .get_one::<String>("llvm-opt")
+            .expect("Required argument")
+            .parse()
+            .expect("Validated by clap"),
+        target_triple: if emit == EmitKind::Wasm {
+            Some("wasm32-wasi".to_owned())
+        } else {
+            matches.get_one::<String>("target").cloned()
+        },
+        target_cpu: target_cpu_from_matches(matches),
+        reloc_mode: reloc_mode_from_matches(matches),
+        code_model: code_model_from_matches(matches),
+        passes: matches.get_one::<String>("passes").cloned(),
+        unroll_limit: *matches
+            .get_one::<u64>("unroll-limit")
+            .expect("Required argument"),
+        max_opt_iterations: *matches
+            .get_one::<u64>("max-opt-iterations")
+            .expect("Required argument"),
+        compile_time_exec: compile_time_exec_from_arg(
+            matches
+                .get_one::<String>("compile-time-exec")
+                .expect("Required argument"),
+        ),
+        max_steps: matches.get_one::<u64>("max-steps").copied(),
+        max_static_output: matches.get_one::<u64>("max-static-output").copied(),
+        keep_going: matches.get_flag("keep-going"),
+        stdout_buffering: matches
+            .get_one::<String>("stdout-buffering")
+            .map(|buffering| match buffering.as_str() {
+                "full" => llvm::StdoutBuffering::Full,
+                "line" => llvm::StdoutBuffering::Line,
+                _ => llvm::StdoutBuffering::Unbuffered,
+            }),
+        unbuffered: matches.get_flag("unbuffered"),
+        debug_bounds: matches.get_flag("debug-bounds"),
+        known_input,
+        count_loop_iterations: matches.get_flag("count-loops"),
+        debug_info: if matches.get_flag("debug-info") {
+            // Debuggers find the source relative to the directory
+            // they're run from, so use an absolute path.
+            let source_path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+            Some(source_path.display().to_string())
+        } else {
+            None
+        },
+        wrapping_tape: matches.get_flag("wrapping-tape"),
+        no_builtin_io: matches.get_flag("no-builtin-io"),
+        start_cell: *matches
+            .get_one::<u64>("start-cell")
+            .expect("Required argument") as usize,
+        entry_name: matches.get_one::<String>("entry").cloned(),
+        sanitize_address: matches.contains_id("sanitize"),
+    }
+}
+
+/// The flag that provides the program's input at compile time, if
+/// any.
+fn known_input_flag(matches: &ArgMatches) -> Option<&'static str> {
+    if matches.contains_id("stdin-file") {
+        Some("stdin-file")
+    } else if matches.get_flag("bang-input") {
+        Some("bang-input")
+    } else {
+        None
+    }
+}
+
 fn compile_file(matches: &ArgMatches) -> Result<(), ()> {
     let path = matches
         .get_one::<PathBuf>("path")
         .expect("Required argument");
 
-    let src = slurp(path).map_err(|e| {
-        eprintln!("{}", e);
-    })?;
+    // We can only read stdin once, so keep the source for
+    // diagnostics.
+    let stdin_src = if is_stdin(path) {
+        Some(slurp(path).map_err(|e| {
+            eprintln!("{}", e);
+        })?)
+    } else {
+        None
+    };
+    let read_src = || match &stdin_src {
+        Some(src) => Ok(src.clone()),
+        None => slurp(path),
+    };
+
+    let emit = EmitKind::from_arg(
+        matches
+            .get_one::<String>("emit")
+            .expect("Required argument"),
+    );
+
+    // Only compile time execution reads known input, so it would be
+    // silently ignored at lower optimisation levels.
+    if let Some(flag) = known_input_flag(matches) {
+        if !matches.get_flag("interpret")
+            && matches.get_one::<String>("opt").map(String::as_str) != Some("2")
+        {
+            eprintln!("--{} requires --opt=2.", flag);
+            return Err(());
+        }
+    }
+
+    // If we've compiled this source with the same options before,
+    // reuse the object file rather than compiling it again.
+    let cached_object = if use_object_cache(matches, emit) {
+        let src = read_src().map_err(|e| {
+            eprintln!("{}", e);
+        })?;
+        let input = match matches.get_one::<PathBuf>("stdin-file") {
+            Some(input_path) => std::fs::read(input_path).map_err(|e| {
+                eprintln!("{}: {}", input_path.display(), e);
+            })?,
+            None => vec![],
+        };
+        let cache_dir = matches
+            .get_one::<PathBuf>("cache-dir")
+            .expect("Checked by use_object_cache");
+        Some(cache_dir.join(format!("{}.o", cache_key(matches, src.as_bytes(), &input))))
+    } else {
+        None
+    };
+    if let Some(cached_object) = &cached_object {
+        if cached_object.exists() {
+            if matches.get_flag("stats") {
+                eprintln!("Cache hit: skipped parsing, optimisation and code generation.");
+            }
+            return write_cached_object(matches, path, emit, cached_object);
+        }
+    }
+
+    let strict = matches.get_flag("strict");
+    let keep_comments = matches.get_flag("keep-comments");
+    let mut comments = vec![];
+    let mut parse_src = |src: &str| {
+        if keep_comments {
+            bfir::parse_with_comments(src, strict).map(|(instrs, src_comments)| {
+                comments = src_comments;
+                instrs
+            })
+        } else if strict {
+            bfir::parse_strict(src)
+        } else {
+            bfir::parse(src)
+        }
+    };
 
-    let mut instrs = match bfir::parse(&src) {
+    let mut bang_input = vec![];
+    let parse_result = match matches
+        .get_one::<String>("extract-from")
+        .map(String::as_str)
+    {
+        Some("markdown") => {
+            let src = read_src().map_err(|e| {
+                eprintln!("{}", e);
+            })?;
+
+            // We report diagnostics against the original file, so
+            // extracted BF must keep the same positions.
+            let bf_src = extract_markdown_bf(&src);
+            parse_src(&bf_src)
+        }
+        _ if matches.get_flag("bang-input") => {
+            let src = read_src().map_err(|e| {
+                eprintln!("{}", e);
+            })?;
+
+            // The program comes first, so positions are unchanged.
+            let (bf_src, input) = split_bang_input(&src);
+            bang_input = input.to_vec();
+            parse_src(bf_src)
+        }
+        // We need the source text to keep comments, so only parse
+        // incrementally otherwise.
+        _ if stdin_src.is_some() || keep_comments => {
+            let src = read_src().map_err(|e| {
+                eprintln!("{}", e);
+            })?;
+            parse_src(&src)
+        }
+        _ => {
+            // Large generated programs can be many megabytes, so
+            // parse them without reading the whole file into memory.
+            let file = File::open(path).map_err(|e| {
+                eprintln!("{}: {}", path.display(), e);
+            })?;
+            bfir::parse_reader(file, strict).map_err(|e| {
+                eprintln!("{}: {}", path.display(), e);
+            })?
+        }
+    };
+
+    let mut instrs = match parse_result {
         Ok(instrs) => instrs,
         Err(bfir::ParseError { message, position }) => {
-            let path_str = path.display().to_string();
-            Report::build(ReportKind::Error, &path_str, position.start)
-                .with_message("Parse error")
-                .with_label(
-                    Label::new((&path_str, position.start..position.end + 1)).with_message(message),
-                )
-                .finish()
-                .eprint((&path_str, Source::from(src)))
-                .unwrap();
-
+            report_diagnostic(
+                matches,
+                path,
+                &read_src,
+                Diagnostic {
+                    severity: Severity::Error,
+                    title: "Parse error".to_owned(),
+                    message,
+                    position: Some(position),
+                },
+            );
             return Err(());
         }
     };
 
-    let opt_level = matches.get_one::<String>("opt").expect("Required argument");
-    if opt_level != "0" {
-        let pass_specification = matches.get_one::<String>("passes");
-        let (opt_instrs, warnings) = peephole::optimize(instrs, &pass_specification.cloned());
+    // We don't replay warnings when we use a cached object file, so
+    // only cache compiles that didn't report any.
+    let reported_warning = Cell::new(false);
+    let report_warning = |title: &str, diagnostics::Warning { message, position }| {
+        reported_warning.set(true);
+        report_diagnostic(
+            matches,
+            path,
+            &read_src,
+            Diagnostic {
+                severity: Severity::Warning,
+                title: title.to_owned(),
+                message,
+                position,
+            },
+        );
+    };
+
+    // We report these after optimisation, so we can skip loops that
+    // the optimiser warns about.
+    let mut loop_warnings = diagnostics::non_decreasing_loop_warnings(&instrs);
+
+    if let Some(specs) = matches.get_one::<String>("compare-passes") {
+        let (spec_a, spec_b) = match specs.split_once(':') {
+            Some(specs) => specs,
+            None => {
+                eprintln!("--compare-passes expects two pass specifications separated by `:`");
+                return Err(());
+            }
+        };
+        for spec in [spec_a, spec_b] {
+            peephole::check_pass_specification(spec).map_err(|e| {
+                eprintln!("{}", e);
+            })?;
+        }
+        let comparison = compare_passes(&instrs, spec_a, spec_b);
+        print_pass_comparison(&comparison, spec_a, spec_b);
+        return Ok(());
+    }
+
+    let dry_run = matches.get_flag("dry-run");
+    if dry_run && emit != EmitKind::Executable {
+        eprintln!("--dry-run can only be used when linking an executable.");
+        return Err(());
+    }
+
+    // Only keep the parsed program if we need to compare it with the
+    // optimised program.
+    let parsed_instrs = if matches.get_flag("analyze") {
+        Some(instrs.clone())
+    } else {
+        None
+    };
+
+    let input = match matches.get_one::<PathBuf>("stdin-file") {
+        Some(input_path) => std::fs::read(input_path).map_err(|e| {
+            eprintln!("{}: {}", input_path.display(), e);
+        })?,
+        None => bang_input,
+    };
+    let opts = compile_options(matches, input);
+
+    if opts.opt_level > 0 {
+        let mut pass_specification = opts.passes.clone();
+        if emit == EmitKind::Bf {
+            // Copying a cell requires a temporary cell in BF, so
+            // keep copy loops as they are.
+            let passes = pass_specification
+                .unwrap_or_else(|| peephole::DEFAULT_PASS_SPECIFICATION.to_owned());
+            pass_specification = Some(
+                passes
+                    .split(',')
+                    .filter(|pass| !matches!(*pass, "copy_loop" | "cell_copy"))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+        }
+
+        let mut stats = peephole::OptimizeStats::default();
+        let mut print_pass = |name: &str, instrs: &[bfir::AstNode]| {
+            eprintln!("*** IR after {} ***", name);
+            for instr in instrs {
+                eprintln!("{}", instr);
+            }
+        };
+        let optimize_opts = peephole::OptimizeOptions {
+            passes: pass_specification,
+            stats: if matches.get_flag("stats") {
+                Some(&mut stats)
+            } else {
+                None
+            },
+            after_pass: if matches.get_flag("print-passes") {
+                Some(&mut print_pass)
+            } else {
+                None
+            },
+            ..opts.optimize_options()
+        };
+        let (opt_instrs, warnings) = peephole::optimize(instrs, optimize_opts);
+        if matches.get_flag("stats") {
+            print_optimize_stats(&stats);
+        }
         instrs = opt_instrs;
 
-        for diagnostics::Warning { message, position } in warnings {
-            let path_str = path.display().to_string();
-            let position = position.unwrap_or(Position { start: 0, end: 0 });
-            Report::build(ReportKind::Warning, &path_str, position.start)
-                .with_message("Suspicious code found during optimization")
-                .with_label(
-                    Label::new((&path_str, position.start..position.end + 1))
-                        .with_message(message.clone()),
-                )
-                .finish()
-                .eprint((&path_str, Source::from(src.clone())))
-                .unwrap();
+        loop_warnings = diagnostics::without_reported(loop_warnings, &warnings);
+        for warning in warnings {
+            report_warning("Suspicious code found during optimization", warning);
         }
     }
+    for warning in loop_warnings {
+        report_warning("Suspicious loop", warning);
+    }
+
+    if let Some(parsed_instrs) = parsed_instrs {
+        print_analysis(&analysis::analyze(&parsed_instrs, &instrs));
+        return Ok(());
+    }
 
     if matches.get_flag("dump-ir") {
         for instr in &instrs {
@@ -113,37 +921,193 @@ fn compile_file(matches: &ArgMatches) -> Result<(), ()> {
         return Ok(());
     }
 
-    let (state, execution_warning) = if opt_level == "2" {
-        execution::execute(&instrs, execution::max_steps())
-    } else {
-        let mut init_state = execution::ExecutionState::initial(&instrs[..]);
-        init_state.start_instr = instrs.first();
-        (init_state, None)
+    if emit == EmitKind::Bf {
+        // Write the program itself, ignoring compile time execution.
+        let output_path = output_path(
+            matches.get_one::<PathBuf>("output"),
+            path,
+            Some(emit.extension().expect("BF has an extension")),
+        );
+        return emit_bf_file(&instrs, &comments, &output_path).map_err(|e| {
+            eprintln!("{}", e);
+        });
+    }
+
+    if opts.wrapping_tape {
+        if matches.get_flag("debug-bounds") {
+            eprintln!("--debug-bounds cannot be used with --wrapping-tape, as every cell access is in range.");
+            return Err(());
+        }
+        if emit == EmitKind::C {
+            eprintln!("--wrapping-tape is not supported with --emit=c.");
+            return Err(());
+        }
+    }
+    if matches.get_flag("no-builtin-io") {
+        if emit == EmitKind::C {
+            eprintln!("--no-builtin-io is not supported with --emit=c.");
+            return Err(());
+        }
+        // We can't provide bf_getchar and bf_putchar to the JIT, and
+        // they replace the stdout that setvbuf would configure.
+        if matches.get_flag("run") {
+            eprintln!("--run cannot be used with --no-builtin-io.");
+            return Err(());
+        }
+        if matches.contains_id("stdout-buffering") {
+            eprintln!("--stdout-buffering cannot be used with --no-builtin-io.");
+            return Err(());
+        }
+    }
+    if emit == EmitKind::C {
+        // The C backend only writes the program and its cells, so it
+        // can't add checks, counters, debug info or stdout setup.
+        for flag in ["debug-bounds", "count-loops", "debug-info"] {
+            if matches.get_flag(flag) {
+                eprintln!("--{} is not supported with --emit=c.", flag);
+                return Err(());
+            }
+        }
+        for flag in ["sanitize", "stdout-buffering"] {
+            if matches.contains_id(flag) {
+                eprintln!("--{} is not supported with --emit=c.", flag);
+                return Err(());
+            }
+        }
+    }
+    let entry_name = opts.entry_name.as_deref();
+    if let Some(name) = entry_name.filter(|name| llvm::is_reserved_symbol(name)) {
+        eprintln!(
+            "--entry={} clashes with a function or global that bfc generates.",
+            name
+        );
+        return Err(());
+    }
+    if entry_name.map_or(false, |name| name != "main") {
+        // Executables need a main function, and the JIT and the C
+        // backend always call or write main.
+        if matches!(emit, EmitKind::Executable | EmitKind::C | EmitKind::Wasm) {
+            eprintln!(
+                "--entry requires --emit=obj, asm, ll or bc, as executables need a main function."
+            );
+            return Err(());
+        }
+        if matches.get_flag("run") {
+            eprintln!("--run cannot be used with --entry.");
+            return Err(());
+        }
+    }
+    if matches.get_flag("interpret") {
+        return interpret(&instrs, &opts.known_input, opts.tape(), opts.start_cell).map_err(
+            |diagnostics::Warning { message, position }| {
+                report_diagnostic(
+                    matches,
+                    path,
+                    &read_src,
+                    Diagnostic {
+                        severity: Severity::Error,
+                        title: "Runtime error".to_owned(),
+                        message,
+                        position,
+                    },
+                );
+            },
+        );
+    }
+
+    // A program that always moves before the first cell can't run
+    // correctly, so don't compile it.
+    let bounds_warning = match bfc::check_cell_bounds(&instrs, &opts) {
+        Ok(warning) => warning,
+        Err(CompileError::CellOutOfRange(diagnostics::Warning { message, position })) => {
+            report_diagnostic(
+                matches,
+                path,
+                &read_src,
+                Diagnostic {
+                    severity: Severity::Error,
+                    title: "Out of range cell access".to_owned(),
+                    message,
+                    position,
+                },
+            );
+            return Err(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(());
+        }
     };
 
-    if let Some(diagnostics::Warning { message, position }) = execution_warning {
-        let path_str = path.display().to_string();
-        let position = position.unwrap_or(Position { start: 0, end: 0 });
+    let (state, execution_warning) = bfc::execute_at_compile_time(&instrs, &opts).map_err(|e| {
+        eprintln!("{}", e);
+    })?;
+
+    // Compile time execution reports the exact instruction when it
+    // moves out of range, so only report static bounds otherwise.
+    if let Some(warning) = bounds_warning.filter(|_| execution_warning.is_none()) {
+        report_warning("Out of range cell access", warning);
+    }
 
-        Report::build(ReportKind::Warning, &path_str, position.start)
-            .with_message("Invalid result during compiletime execution")
-            .with_label(
-                Label::new((&path_str, position.start..position.end + 1)).with_message(message),
-            )
-            .finish()
-            .eprint((&path_str, Source::from(src)))
-            .unwrap();
+    if let Some(warning) = execution_warning {
+        report_warning("Invalid result during compiletime execution", warning);
     }
 
     llvm::init_llvm();
-    let target_triple = matches.get_one::<String>("target");
-    let mut llvm_module = llvm::compile_to_module(
-        &path.display().to_string(),
-        target_triple.cloned(),
+    let target_triple = opts.target_triple.clone();
+    if target_triple.as_deref().map_or(false, is_wasm_target) {
+        // These options call libc functions at runtime, which we don't
+        // have on WebAssembly.
+        for flag in ["debug-bounds", "count-loops", "no-builtin-io"] {
+            if matches.get_flag(flag) {
+                eprintln!("--{} is not supported for WebAssembly targets.", flag);
+                return Err(());
+            }
+        }
+        // WASI runtimes start at _start, which calls main.
+        if entry_name.map_or(false, |name| name != "main") {
+            eprintln!("--entry is not supported for WebAssembly targets.");
+            return Err(());
+        }
+        if matches.contains_id("stdout-buffering") {
+            eprintln!("--stdout-buffering is not supported for WebAssembly targets.");
+            return Err(());
+        }
+    }
+    let sanitize_address = opts.sanitize_address;
+    if sanitize_address {
+        if target_triple.as_deref().map_or(false, is_wasm_target) {
+            eprintln!("--sanitize is not supported for WebAssembly targets.");
+            return Err(());
+        }
+        // The JIT doesn't load the AddressSanitizer runtime.
+        if matches.get_flag("run") {
+            eprintln!("--run cannot be used with --sanitize.");
+            return Err(());
+        }
+    }
+    let src = if opts.debug_info.is_some() {
+        read_src().map_err(|e| {
+            eprintln!("{}", e);
+        })?
+    } else {
+        String::new()
+    };
+    let codegen_options = opts.codegen_options(&src);
+    let mut llvm_module = llvm::compile_to_module_with_options(
+        &display_path(path),
+        target_triple.clone(),
         &instrs,
         &state,
+        &codegen_options,
     );
 
+    if let Some(source_map_path) = matches.get_one::<PathBuf>("source-map") {
+        std::fs::write(source_map_path, source_map::source_map(&instrs)).map_err(|e| {
+            eprintln!("{}: {}", source_map_path.display(), e);
+        })?;
+    }
+
     if matches.get_flag("dump-llvm") {
         let llvm_ir_cstr = llvm_module.to_cstring();
         let llvm_ir = String::from_utf8_lossy(llvm_ir_cstr.as_bytes());
@@ -151,63 +1115,472 @@ fn compile_file(matches: &ArgMatches) -> Result<(), ()> {
         return Ok(());
     }
 
-    let llvm_opt_raw = matches
-        .get_one::<String>("llvm-opt")
-        .expect("Required argument");
-    let llvm_opt = llvm_opt_raw.parse::<i64>().expect("Validated by clap");
-    llvm::optimise_ir(&mut llvm_module, llvm_opt);
-
-    // Compile the LLVM IR to a temporary object file.
-    let object_file = NamedTempFile::new().map_err(|e| {
+    llvm::verify_module(&llvm_module).map_err(|e| {
         eprintln!("{}", e);
     })?;
+    llvm::optimise_ir(&mut llvm_module, opts.llvm_opt_level);
 
-    let obj_file_path = object_file.path().to_str().expect("path not valid utf-8");
-    llvm::write_object_file(&mut llvm_module, obj_file_path).map_err(|e| {
-        eprintln!("{}", e);
-    })?;
+    if matches.get_flag("run") {
+        return llvm::run_jit(llvm_module).map(|_| ()).map_err(|e| {
+            eprintln!("{}", e);
+        });
+    }
+
+    let target_cpu = &opts.target_cpu;
+    let reloc_mode = opts.reloc_mode;
+    let code_model = opts.code_model;
+    if let Some(extension) = emit.extension() {
+        let output_path = output_path(matches.get_one::<PathBuf>("output"), path, Some(extension));
+        emit_file(
+            emit,
+            &mut llvm_module,
+            &instrs,
+            &state,
+            &output_path,
+            target_cpu,
+            reloc_mode,
+            code_model,
+        )
+        .map_err(|e| {
+            eprintln!("{}", e);
+        })?;
+        if let Some(cached_object) = cached_object.as_ref().filter(|_| !reported_warning.get()) {
+            store_cached_object(Path::new(&output_path), cached_object);
+        }
+        return Ok(());
+    }
+
+    link_executable(
+        matches,
+        path,
+        target_triple,
+        sanitize_address,
+        reloc_mode,
+        &mut |obj_file_path| {
+            llvm::write_object_file(
+                &mut llvm_module,
+                obj_file_path,
+                LLVMCodeGenFileType::LLVMObjectFile,
+                target_cpu,
+                reloc_mode,
+                code_model,
+            )
+            .map_err(|e| {
+                eprintln!("{}", e);
+            })?;
+            if let Some(cached_object) = cached_object.as_ref().filter(|_| !reported_warning.get())
+            {
+                store_cached_object(Path::new(obj_file_path), cached_object);
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Write an object file with `write_object`, then link it to produce
+/// the executable.
+fn link_executable(
+    matches: &ArgMatches,
+    path: &Path,
+    target_triple: Option<String>,
+    sanitize_address: bool,
+    reloc_mode: llvm::RelocMode,
+    write_object: &mut dyn FnMut(&str) -> Result<(), ()>,
+) -> Result<(), ()> {
+    let output_name = output_path(matches.get_one::<PathBuf>("output"), path, None);
+
+    // Unless the user wants to keep the object file, this is a
+    // temporary file that we delete after linking.
+    let object_file;
+    let obj_file_path = if matches.get_flag("keep-object") {
+        object_path(&output_name)
+    } else {
+        object_file = NamedTempFile::new().map_err(|e| {
+            eprintln!("{}", e);
+        })?;
+        object_file
+            .path()
+            .to_str()
+            .expect("path not valid utf-8")
+            .to_owned()
+    };
 
     let strip = matches.get_flag("strip");
-    let output_name = executable_name(path);
-    link_object_file(obj_file_path, &output_name, target_triple.cloned(), strip).map_err(|e| {
+    let linker = linker_from_matches(matches);
+    if matches.get_flag("dry-run") {
+        let command = link_command(
+            &linker,
+            &obj_file_path,
+            &output_name,
+            target_triple.as_deref(),
+            strip,
+            sanitize_address,
+            reloc_mode != llvm::RelocMode::Pic,
+        )
+        .map_err(|e| {
+            eprintln!("{}", e);
+        })?;
+        let target_triple = target_triple.unwrap_or_else(|| {
+            llvm::get_default_target_triple()
+                .to_string_lossy()
+                .into_owned()
+        });
+        print!(
+            "{}",
+            dry_run_summary(&obj_file_path, &command, &target_triple)
+        );
+        return Ok(());
+    }
+
+    write_object(&obj_file_path)?;
+
+    link_object_file(
+        &linker,
+        &obj_file_path,
+        &output_name,
+        target_triple,
+        strip,
+        sanitize_address,
+        reloc_mode != llvm::RelocMode::Pic,
+    )
+    .map_err(|e| {
         eprintln!("{}", e);
     })?;
 
     Ok(())
 }
 
-/// Link the object file.
-fn link_object_file(
-    object_file_path: &str,
-    executable_path: &str,
-    target_triple: Option<String>,
-    strip: bool,
-) -> Result<(), String> {
-    let mut clang_args = vec![object_file_path, "-o", executable_path];
+/// Arguments that don't change the object file we compile, so we
+/// ignore them in cache keys.
+const CACHE_IGNORED_ARGS: &[&str] = &[
+    "path",
+    "output",
+    "cache-dir",
+    "no-cache",
+    "keep-object",
+    "linker",
+    "strip",
+    "stats",
+    "color",
+    "message-format",
+];
 
-    if let Some(ref target_triple) = target_triple {
-        clang_args.push("-target");
-        clang_args.push(target_triple);
+/// Should we look for the object file in `--cache-dir`? We only cache
+/// object files, so we don't cache other kinds of output, or
+/// compilations that write something else.
+fn use_object_cache(matches: &ArgMatches, emit: EmitKind) -> bool {
+    if !matches.contains_id("cache-dir") || matches.get_flag("no-cache") {
+        return false;
+    }
+    if !matches!(emit, EmitKind::Executable | EmitKind::Object) {
+        return false;
+    }
+
+    let other_output = [
+        "analyze",
+        "dump-ir",
+        "dump-llvm",
+        "dry-run",
+        "interpret",
+        "print-passes",
+        "run",
+    ]
+    .iter()
+    .any(|flag| matches.get_flag(flag))
+        || matches.contains_id("compare-passes")
+        || matches.contains_id("source-map");
+    !other_output
+}
+
+/// A key for the object file compiled from `src` with these
+/// arguments, which reads `input` during compile time execution. We
+/// use two 64 bit hashes, so collisions are unlikely even in large
+/// caches.
+fn cache_key(matches: &ArgMatches, src: &[u8], input: &[u8]) -> String {
+    let mut hashers = [DefaultHasher::new(), DefaultHasher::new()];
+    for (seed, hasher) in hashers.iter_mut().enumerate() {
+        seed.hash(hasher);
+        crate_version!().hash(hasher);
+        llvm::get_default_target_triple().hash(hasher);
+        env::var_os("BFC_MAX_STEPS").hash(hasher);
+
+        // Debug info records the path of the source, so the same
+        // source at another path needs its own object file.
+        if matches.get_flag("debug-info") {
+            let path = matches
+                .get_one::<PathBuf>("path")
+                .expect("Required argument");
+            std::fs::canonicalize(path)
+                .unwrap_or_else(|_| path.clone())
+                .hash(hasher);
+        }
+
+        for id in matches.ids() {
+            if CACHE_IGNORED_ARGS.contains(&id.as_str()) {
+                continue;
+            }
+            id.as_str().hash(hasher);
+            if let Some(values) = matches.get_raw(id.as_str()) {
+                for value in values {
+                    value.hash(hasher);
+                }
+            }
+        }
+
+        src.hash(hasher);
+        input.hash(hasher);
+    }
+
+    format!("{:016x}{:016x}", hashers[0].finish(), hashers[1].finish())
+}
+
+/// Produce the output for `emit` from an object file in the cache.
+fn write_cached_object(
+    matches: &ArgMatches,
+    path: &Path,
+    emit: EmitKind,
+    cached_object: &Path,
+) -> Result<(), ()> {
+    let copy_object = |obj_file_path: &str| {
+        std::fs::copy(cached_object, obj_file_path)
+            .map(|_| ())
+            .map_err(|e| {
+                eprintln!("{}: {}", cached_object.display(), e);
+            })
+    };
+
+    if emit == EmitKind::Object {
+        return copy_object(&output_path(
+            matches.get_one::<PathBuf>("output"),
+            path,
+            Some("o"),
+        ));
+    }
+
+    link_executable(
+        matches,
+        path,
+        matches.get_one::<String>("target").cloned(),
+        matches.contains_id("sanitize"),
+        reloc_mode_from_matches(matches),
+        &mut |obj_file_path| copy_object(obj_file_path),
+    )
+}
+
+/// Copy the object file at `object_path` into the cache. We've still
+/// compiled the program if this fails, so we only warn.
+fn store_cached_object(object_path: &Path, cached_object: &Path) {
+    let cache_dir = cached_object
+        .parent()
+        .expect("Cached objects are in a directory");
+    let result = std::fs::create_dir_all(cache_dir).and_then(|_| {
+        // Write to a temporary file first, so concurrent compiles
+        // never see a partially written object file.
+        let temp_file = NamedTempFile::new_in(cache_dir)?;
+        std::fs::copy(object_path, temp_file.path())?;
+        temp_file
+            .persist(cached_object)
+            .map(|_| ())
+            .map_err(|e| e.error)
+    });
+    if let Err(e) = result {
+        eprintln!("Could not write {}: {}", cached_object.display(), e);
+    }
+}
+
+/// Describe what we would write and run, for `--dry-run`.
+fn dry_run_summary(obj_file_path: &str, link_command: &[String], target_triple: &str) -> String {
+    format!(
+        "Object file: {}\nLink command: {}\nTarget triple: {}\n",
+        obj_file_path,
+        link_command.join(" "),
+        target_triple
+    )
+}
+
+/// Does this target triple use WASI, rather than libc, for I/O?
+fn is_wasm_target(target_triple: &str) -> bool {
+    target_triple.starts_with("wasm32")
+}
+
+/// The different command line interfaces of linkers we support.
+#[derive(Debug, PartialEq, Eq)]
+enum LinkerKind {
+    /// clang, which can link for any target with `-target`.
+    Clang,
+    /// A C compiler driver such as gcc or cc, which only links for
+    /// the target it was built for.
+    CcDriver,
+    /// A linker that's invoked directly, such as ld or wasm-ld. These
+    /// don't add libc or C startup files.
+    Ld,
+}
+
+/// Guess the kind of linker from its name, e.g. `clang-14`,
+/// `/usr/bin/gcc` or `wasm-ld`.
+fn linker_kind(linker: &str) -> LinkerKind {
+    let name = Path::new(linker)
+        .file_name()
+        .map_or(linker.into(), |name| name.to_string_lossy());
+
+    if name.contains("clang") {
+        LinkerKind::Clang
+    } else if name == "ld" || name.starts_with("ld.") || name.ends_with("-ld") {
+        LinkerKind::Ld
+    } else {
+        LinkerKind::CcDriver
+    }
+}
+
+/// The linker to use, from `--linker`, then `$BFC_LINKER`, then
+/// `$CC`, defaulting to clang.
+fn linker_from_matches(matches: &ArgMatches) -> String {
+    let from_env = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+    matches
+        .get_one::<String>("linker")
+        .cloned()
+        .or_else(|| from_env("BFC_LINKER"))
+        .or_else(|| from_env("CC"))
+        .unwrap_or_else(|| "clang".to_owned())
+}
+
+/// The arguments to pass to `linker` to link the object file.
+fn link_args<'a>(
+    linker: &str,
+    object_file_path: &'a str,
+    executable_path: &'a str,
+    target_triple: Option<&'a str>,
+    strip: bool,
+    sanitize_address: bool,
+    no_pie: bool,
+) -> Result<Vec<&'a str>, String> {
+    let kind = linker_kind(linker);
+    let is_wasm = target_triple.map_or(false, is_wasm_target);
+
+    if kind == LinkerKind::Ld && !is_wasm {
+        return Err(format!(
+            "{} doesn't link libc, so can't link a native executable. Use a C compiler such as cc instead.",
+            linker
+        ));
+    }
+    if kind == LinkerKind::Ld && sanitize_address {
+        return Err(format!(
+            "{} can't link the AddressSanitizer runtime. Use a C compiler such as clang instead.",
+            linker
+        ));
+    }
+
+    let mut args = vec![object_file_path, "-o", executable_path];
+
+    if let Some(target_triple) = target_triple {
+        // Only clang can link for a different target, other linkers
+        // link for the target they were built for.
+        if kind == LinkerKind::Clang {
+            args.push("-target");
+            args.push(target_triple);
+        }
+
+        // We define _start ourselves on WebAssembly, and don't need
+        // libc. Linkers invoked directly don't add libc anyway.
+        if is_wasm && kind != LinkerKind::Ld {
+            args.push("-nostdlib");
+        }
     }
     if strip {
-        clang_args.push("-s");
+        args.push("-s");
+    }
+    if sanitize_address {
+        args.push("-fsanitize=address");
+    }
+    // Objects without position independent code can't be linked
+    // into a position independent executable.
+    if no_pie && kind != LinkerKind::Ld && !is_wasm {
+        args.push("-no-pie");
     }
 
-    shell::run_shell_command("clang", &clang_args[..])
+    Ok(args)
 }
 
-fn main() {
+/// The command that links the object file, starting with the linker
+/// itself.
+fn link_command(
+    linker: &str,
+    object_file_path: &str,
+    executable_path: &str,
+    target_triple: Option<&str>,
+    strip: bool,
+    sanitize_address: bool,
+    no_pie: bool,
+) -> Result<Vec<String>, CompileError> {
+    let args = link_args(
+        linker,
+        object_file_path,
+        executable_path,
+        target_triple,
+        strip,
+        sanitize_address,
+        no_pie,
+    )
+    .map_err(CompileError::UnsupportedLinker)?;
+
+    let mut command = vec![linker.to_owned()];
+    command.extend(args.into_iter().map(|arg| arg.to_owned()));
+    Ok(command)
+}
+
+/// Link the object file.
+fn link_object_file(
+    linker: &str,
+    object_file_path: &str,
+    executable_path: &str,
+    target_triple: Option<String>,
+    strip: bool,
+    sanitize_address: bool,
+    no_pie: bool,
+) -> Result<(), CompileError> {
+    let command = link_command(
+        linker,
+        object_file_path,
+        executable_path,
+        target_triple.as_deref(),
+        strip,
+        sanitize_address,
+        no_pie,
+    )?;
+    let args: Vec<&str> = command[1..].iter().map(|arg| arg.as_str()).collect();
+    shell::run_shell_command(&command[0], &args[..])
+}
+
+/// The version shown by `--version`, including the LLVM version, as
+/// codegen varies between LLVM releases.
+fn version() -> String {
+    format!("{} (LLVM {})", crate_version!(), llvm::LLVM_VERSION)
+}
+
+/// The command line interface of bfc.
+fn cli() -> Command {
     let default_triple_cstring = llvm::get_default_target_triple();
     let default_triple = default_triple_cstring.to_str().unwrap();
 
-    let matches = command!()
+    command!()
+        .version(version())
         .arg(
             Arg::new("path")
                 .value_name("SOURCE_FILE")
                 .value_hint(ValueHint::FilePath)
-                .help("The path to the brainfuck program to compile")
+                .help("The path to the brainfuck program to compile, or - to read from stdin")
                 .value_parser(ValueParser::path_buf())
-                .required(true),
+                .required_unless_present("passes"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("PATH")
+                .value_parser(ValueParser::path_buf())
+                .value_hint(ValueHint::FilePath)
+                .help("Write the output to PATH, rather than a name based on SOURCE_FILE"),
         )
         .arg(
             Arg::new("opt")
@@ -230,7 +1603,128 @@ fn main() {
             Arg::new("passes")
                 .long("passes")
                 .value_name("PASS-SPECIFICATION")
-                .help("Limit bfc optimizations to those specified"),
+                .value_parser(parse_pass_specification)
+                .help("Limit bfc optimizations to those specified, or `list` to show all passes"),
+        )
+        .arg(
+            Arg::new("unroll-limit")
+                .long("unroll-limit")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("8")
+                .help("Unroll loops that run at most N times (0 disables unrolling)"),
+        )
+        .arg(
+            Arg::new("max-opt-iterations")
+                .long("max-opt-iterations")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("40")
+                .help("Run the optimisation passes at most N times, warning if they haven't finished"),
+        )
+        .arg(
+            Arg::new("compare-passes")
+                .long("compare-passes")
+                .value_name("SPEC_A:SPEC_B")
+                .help("Optimize with both pass specifications and compare the results"),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .action(ArgAction::SetTrue)
+                .help("Reject characters that aren't BF commands or whitespace"),
+        )
+        .arg(
+            Arg::new("extract-from")
+                .long("extract-from")
+                .value_name("FORMAT")
+                .help("Compile the BF in fenced code blocks tagged `bf` or `brainfuck`")
+                .value_parser(["markdown"]),
+        )
+        .arg(
+            Arg::new("keep-going")
+                .long("keep-going")
+                .action(ArgAction::SetTrue)
+                .help("Don't warn when compile time execution reaches an error"),
+        )
+        .arg(
+            Arg::new("stdin-file")
+                .long("stdin-file")
+                .value_name("PATH")
+                .value_parser(ValueParser::path_buf())
+                .value_hint(ValueHint::FilePath)
+                .help(
+                    "Use the contents of PATH as the program's first input bytes during \
+                     compile time execution (requires --opt=2)",
+                ),
+        )
+        .arg(
+            Arg::new("bang-input")
+                .long("bang-input")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["stdin-file", "extract-from"])
+                .help(
+                    "Treat everything after the first `!` in the source as the program's \
+                     first input bytes during compile time execution (requires --opt=2)",
+                ),
+        )
+        .arg(
+            Arg::new("max-steps")
+                .long("max-steps")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64))
+                .help(
+                    "The maximum number of steps to execute at compile time, overriding \
+                     $BFC_MAX_STEPS (0 disables compile time execution)",
+                ),
+        )
+        .arg(
+            Arg::new("compile-time-exec")
+                .long("compile-time-exec")
+                .value_name("MODE")
+                .help(
+                    "How much of the program to run at compile time with --opt=2: nothing, \
+                     everything before the first write, or as much as possible",
+                )
+                .value_parser(["off", "cells", "full"])
+                .default_value("full"),
+        )
+        .arg(
+            Arg::new("max-static-output")
+                .long("max-static-output")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64))
+                .help(
+                    "If compile time execution writes more than N bytes, run the whole \
+                     program at runtime instead of embedding its output",
+                ),
+        )
+        .arg(
+            Arg::new("keep-object")
+                .long("keep-object")
+                .action(ArgAction::SetTrue)
+                .help("Keep the object file next to the executable, e.g. foo.o"),
+        )
+        .arg(
+            Arg::new("cache-dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .value_parser(ValueParser::path_buf())
+                .value_hint(ValueHint::DirPath)
+                .help("Reuse object files in DIR when the source and options are unchanged, and add new ones"),
+        )
+        .arg(
+            Arg::new("no-cache")
+                .long("no-cache")
+                .action(ArgAction::SetTrue)
+                .help("Always compile from scratch, even with --cache-dir"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["run", "interpret"])
+                .help("Generate code, then print the object file, link command and target triple instead of linking"),
         )
         .arg(
             Arg::new("strip")
@@ -238,6 +1732,19 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .help("Strip symbols from the binary"),
         )
+        .arg(
+            Arg::new("linker")
+                .long("linker")
+                .value_name("LINKER")
+                .help("The linker to use (default: $BFC_LINKER, then $CC, then clang)"),
+        )
+        .arg(
+            Arg::new("sanitize")
+                .long("sanitize")
+                .value_name("SANITIZER")
+                .value_parser(["address"])
+                .help("Link the executable with a sanitizer, e.g. AddressSanitizer"),
+        )
         .arg(
             Arg::new("target")
                 .long("target")
@@ -245,19 +1752,197 @@ fn main() {
                 .help("LLVM target triple")
                 .default_value(default_triple.to_string()),
         )
+        .arg(
+            Arg::new("mcpu")
+                .long("mcpu")
+                .value_name("CPU")
+                .help("Target CPU, e.g. skylake or native (default: generic)"),
+        )
+        .arg(
+            Arg::new("mattr")
+                .long("mattr")
+                .value_name("FEATURES")
+                .help("Target CPU features to enable or disable, e.g. +avx2,-sse4.1"),
+        )
+        .arg(
+            Arg::new("reloc")
+                .long("reloc")
+                .value_name("MODEL")
+                .help("Relocation model for generated code")
+                .value_parser(["pic", "static", "dynamic-no-pic"])
+                .default_value("pic"),
+        )
+        .arg(
+            Arg::new("code-model")
+                .long("code-model")
+                .value_name("MODEL")
+                .help("Code model for generated code (default: chosen by LLVM)")
+                .value_parser(["small", "medium", "large", "kernel"]),
+        )
+        .arg(
+            Arg::new("emit")
+                .long("emit")
+                .value_name("KIND")
+                .help("The kind of output to generate")
+                .value_parser([
+                    "exe", "obj", "asm", "ll", "llvm-ir", "bc", "c", "wasm", "bf",
+                ])
+                .default_value("exe"),
+        )
+        .arg(
+            Arg::new("print-passes")
+                .long("print-passes")
+                .action(ArgAction::SetTrue)
+                .help("Write the IR to stderr after each optimisation pass"),
+        )
+        .arg(
+            Arg::new("keep-comments")
+                .long("keep-comments")
+                .action(ArgAction::SetTrue)
+                .help("With --emit=bf, keep the comments from SOURCE_FILE"),
+        )
+        .arg(
+            Arg::new("stats")
+                .long("stats")
+                .action(ArgAction::SetTrue)
+                .help(
+                "Write the time taken and instructions removed by each optimisation pass to stderr",
+            ),
+        )
+        .arg(
+            Arg::new("message-format")
+                .long("message-format")
+                .value_name("FORMAT")
+                .help(
+                    "How to write errors and warnings: human readable, or one JSON object per line",
+                )
+                .value_parser(["human", "json"])
+                .default_value("human"),
+        )
+        .arg(
+            Arg::new("color")
+                .long("color")
+                .value_name("WHEN")
+                .help("When to use colour in errors and warnings (NO_COLOR disables it for auto)")
+                .value_parser(["auto", "always", "never"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::new("stdout-buffering")
+                .long("stdout-buffering")
+                .value_name("MODE")
+                .help("How the compiled program should buffer stdout (default: libc's default)")
+                .value_parser(["full", "line", "none"]),
+        )
+        .arg(
+            Arg::new("unbuffered")
+                .long("unbuffered")
+                .action(ArgAction::SetTrue)
+                .help("Write each output byte with putchar, rather than buffering output"),
+        )
+        .arg(
+            Arg::new("debug-info")
+                .short('g')
+                .long("debug-info")
+                .action(ArgAction::SetTrue)
+                .help("Emit DWARF line tables, so debuggers can step through the BF source"),
+        )
+        .arg(
+            Arg::new("debug-bounds")
+                .long("debug-bounds")
+                .action(ArgAction::SetTrue)
+                .help("Exit with an error if the program moves outside its cells at runtime"),
+        )
+        .arg(
+            Arg::new("wrapping-tape")
+                .long("wrapping-tape")
+                .action(ArgAction::SetTrue)
+                .help("Use a circular tape of 30,000 cells, so moving off one end wraps around to the other"),
+        )
+        .arg(
+            Arg::new("start-cell")
+                .long("start-cell")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(..=bounds::MAX_CELL_INDEX as u64))
+                .default_value("0")
+                .conflicts_with("wrapping-tape")
+                .help("Start the cell pointer at cell N, so the program can move up to N cells left"),
+        )
+        .arg(
+            Arg::new("no-builtin-io")
+                .long("no-builtin-io")
+                .action(ArgAction::SetTrue)
+                .help("Call bf_getchar and bf_putchar, provided at link time, rather than libc's getchar and putchar"),
+        )
+        .arg(
+            Arg::new("entry")
+                .long("entry")
+                .value_name("NAME")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help("Name the generated function NAME rather than main. Other names return void, so other code can call them"),
+        )
+        .arg(
+            Arg::new("count-loops")
+                .long("count-loops")
+                .action(ArgAction::SetTrue)
+                .help("Write the number of iterations of each loop to stderr on exit"),
+        )
+        .arg(
+            Arg::new("run")
+                .long("run")
+                .action(ArgAction::SetTrue)
+                .help("Run the program immediately, rather than writing an executable"),
+        )
+        .arg(
+            Arg::new("interpret")
+                .long("interpret")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("run")
+                .help("Run the program with bfc's interpreter, without using LLVM"),
+        )
+        .arg(
+            Arg::new("source-map")
+                .long("source-map")
+                .value_name("FILE")
+                .value_hint(ValueHint::FilePath)
+                .help("Write a mapping from generated instructions to source positions")
+                .value_parser(ValueParser::path_buf()),
+        )
         .arg(
             Arg::new("dump-llvm")
                 .long("dump-llvm")
                 .action(ArgAction::SetTrue)
                 .help("Print the LLVM IR generated"),
         )
+        .arg(
+            Arg::new("analyze")
+                .long("analyze")
+                .action(ArgAction::SetTrue)
+                .help("Print the size, loop depth and cells used by the program, then exit"),
+        )
         .arg(
             Arg::new("dump-ir")
                 .long("dump-ir")
                 .action(ArgAction::SetTrue)
                 .help("Print the BF IR generated"),
         )
-        .get_matches();
+}
+
+fn main() {
+    let mut cmd = cli();
+    let matches = cmd.get_matches_mut();
+
+    if matches.get_one::<String>("passes").map(String::as_str) == Some("list") {
+        print_pass_list();
+        return;
+    }
+    if !matches.contains_id("path") {
+        cmd.error(
+            ErrorKind::MissingRequiredArgument,
+            "SOURCE_FILE is required unless using --passes=list",
+        )
+        .exit();
+    }
 
     match compile_file(&matches) {
         Ok(_) => {}
@@ -272,6 +1957,491 @@ mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn version_includes_llvm() {
+        let version = cli().render_version();
+        assert!(version.starts_with(&format!("bfc {}", crate_version!())));
+        assert!(version.contains(&format!("(LLVM {})", llvm::LLVM_VERSION)));
+
+        // llvm-sys 140 binds LLVM 14, so llvm-config should report a
+        // 14.x release.
+        assert!(
+            llvm::LLVM_VERSION.starts_with("14."),
+            "{}",
+            llvm::LLVM_VERSION
+        );
+    }
+
+    #[test]
+    fn link_args_clang() {
+        let args = link_args(
+            "clang",
+            "foo.o",
+            "foo",
+            Some("x86_64-pc-linux-gnu"),
+            true,
+            false,
+            false,
+        );
+        assert_eq!(
+            args,
+            Ok(vec![
+                "foo.o",
+                "-o",
+                "foo",
+                "-target",
+                "x86_64-pc-linux-gnu",
+                "-s"
+            ])
+        );
+    }
+
+    #[test]
+    fn link_args_gcc_omits_target() {
+        let args = link_args(
+            "/usr/bin/gcc",
+            "foo.o",
+            "foo",
+            Some("x86_64-pc-linux-gnu"),
+            false,
+            false,
+            false,
+        );
+        assert_eq!(args, Ok(vec!["foo.o", "-o", "foo"]));
+    }
+
+    #[test]
+    fn link_args_wasm() {
+        let args = link_args(
+            "clang-14",
+            "foo.o",
+            "foo",
+            Some("wasm32-wasi"),
+            false,
+            false,
+            false,
+        );
+        assert_eq!(
+            args,
+            Ok(vec![
+                "foo.o",
+                "-o",
+                "foo",
+                "-target",
+                "wasm32-wasi",
+                "-nostdlib"
+            ])
+        );
+
+        let args = link_args(
+            "wasm-ld",
+            "foo.o",
+            "foo",
+            Some("wasm32-wasi"),
+            false,
+            false,
+            false,
+        );
+        assert_eq!(args, Ok(vec!["foo.o", "-o", "foo"]));
+    }
+
+    #[test]
+    fn link_args_ld_native() {
+        let args = link_args(
+            "ld",
+            "foo.o",
+            "foo",
+            Some("x86_64-pc-linux-gnu"),
+            false,
+            false,
+            false,
+        );
+        assert!(args.is_err());
+    }
+
+    #[test]
+    fn link_args_sanitize_address() {
+        let args = link_args("clang", "foo.o", "foo", None, false, true, false);
+        assert_eq!(args, Ok(vec!["foo.o", "-o", "foo", "-fsanitize=address"]));
+    }
+
+    #[test]
+    fn link_args_no_pie() {
+        let args = link_args("gcc", "foo.o", "foo", None, false, false, true);
+        assert_eq!(args, Ok(vec!["foo.o", "-o", "foo", "-no-pie"]));
+
+        let args = link_args(
+            "clang",
+            "foo.o",
+            "foo",
+            Some("wasm32-wasi"),
+            false,
+            false,
+            true,
+        );
+        assert!(!args.unwrap().contains(&"-no-pie"));
+    }
+
+    #[test]
+    fn link_args_sanitize_address_ld() {
+        let args = link_args(
+            "wasm-ld",
+            "foo.o",
+            "foo",
+            Some("wasm32-wasi"),
+            false,
+            true,
+            false,
+        );
+        assert!(args.is_err());
+    }
+
+    #[test]
+    fn link_command_starts_with_linker() {
+        let command = link_command("gcc", "foo.o", "foo", None, true, false, false).unwrap();
+        assert_eq!(command, vec!["gcc", "foo.o", "-o", "foo", "-s"]);
+    }
+
+    #[test]
+    fn dry_run_summary_lists_steps() {
+        let command = vec!["clang".to_owned(), "foo.o".to_owned()];
+        assert_eq!(
+            dry_run_summary("foo.o", &command, "x86_64-pc-linux-gnu"),
+            "Object file: foo.o\nLink command: clang foo.o\nTarget triple: x86_64-pc-linux-gnu\n"
+        );
+    }
+
+    #[test]
+    fn reloc_mode_from_flag() {
+        let cases = [
+            (None, llvm::RelocMode::Pic),
+            (Some("pic"), llvm::RelocMode::Pic),
+            (Some("static"), llvm::RelocMode::Static),
+            (Some("dynamic-no-pic"), llvm::RelocMode::DynamicNoPic),
+        ];
+        for (flag, expected) in cases {
+            let mut args = vec!["bfc".to_owned(), "foo.bf".to_owned()];
+            args.extend(flag.map(|flag| format!("--reloc={}", flag)));
+            let matches = cli().get_matches_from(args);
+            assert_eq!(reloc_mode_from_matches(&matches), expected);
+        }
+    }
+
+    #[test]
+    fn code_model_from_flag() {
+        let cases = [
+            (None, llvm::CodeModel::Default),
+            (Some("small"), llvm::CodeModel::Small),
+            (Some("medium"), llvm::CodeModel::Medium),
+            (Some("large"), llvm::CodeModel::Large),
+            (Some("kernel"), llvm::CodeModel::Kernel),
+        ];
+        for (flag, expected) in cases {
+            let mut args = vec!["bfc".to_owned(), "foo.bf".to_owned()];
+            args.extend(flag.map(|flag| format!("--code-model={}", flag)));
+            let matches = cli().get_matches_from(args);
+            assert_eq!(code_model_from_matches(&matches), expected);
+        }
+    }
+
+    #[test]
+    fn reloc_rejects_unknown_model() {
+        let result = cli().try_get_matches_from(["bfc", "foo.bf", "--reloc=ropi"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn compile_file_dry_run_does_not_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("foo");
+
+        let result = try_compile_file(
+            "+.",
+            &[
+                "--dry-run",
+                "--keep-object",
+                "--linker=bfc-no-such-linker",
+                "-o",
+                &output_path.display().to_string(),
+            ],
+        );
+        assert!(result.is_ok());
+        assert!(!output_path.exists());
+        assert!(!dir.path().join("foo.o").exists());
+    }
+
+    #[test]
+    fn compile_file_stdin_file_read_at_compile_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("in.txt");
+        std::fs::write(&input_path, "AB").unwrap();
+
+        let ir = compile_file_to_ll(
+            "+>+<,.>,.",
+            &[&format!("--stdin-file={}", input_path.display())],
+        );
+        assert!(!ir.contains("call i32 @getchar"), "{}", ir);
+    }
+
+    #[test]
+    fn compile_file_stdin_file_partly_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("in.txt");
+        std::fs::write(&input_path, "AB").unwrap();
+
+        // Compile time execution stops before the second read, so
+        // the `B` would be lost.
+        let result = try_compile_file(
+            "+>+<,.>,.",
+            &[
+                "--emit=ll",
+                "--max-steps=2",
+                &format!("--stdin-file={}", input_path.display()),
+            ],
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn compile_file_stdin_file_needs_opt_2() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("in.txt");
+        std::fs::write(&input_path, "A").unwrap();
+        let stdin_file = format!("--stdin-file={}", input_path.display());
+
+        for opt in ["--opt=0", "--opt=1"] {
+            let result = try_compile_file(",.", &["--emit=ll", opt, &stdin_file]);
+            assert!(result.is_err(), "{}", opt);
+        }
+    }
+
+    #[test]
+    fn compile_file_bang_input_partly_read() {
+        for args in [["--max-steps=2", "--opt=2"], ["--max-steps=100", "--opt=1"]] {
+            let result = try_compile_file(
+                "+>+<,.>,.!AB",
+                &["--emit=ll", "--bang-input", args[0], args[1]],
+            );
+            assert!(result.is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn compile_file_cells_mode_with_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("in.txt");
+        std::fs::write(&input_path, "AB").unwrap();
+        let stdin_file = format!("--stdin-file={}", input_path.display());
+
+        // Both reads happen before the first write, so the whole
+        // input is used at compile time.
+        let ir = compile_file_to_ll(",>,<.>.", &["--compile-time-exec=cells", &stdin_file]);
+        assert!(!ir.contains("call i32 @getchar"), "{}", ir);
+
+        // Cells mode stops at the first write, before reading `B`.
+        let result = try_compile_file(
+            ",.,.",
+            &["--emit=ll", "--compile-time-exec=cells", &stdin_file],
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn compile_file_max_static_output_with_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("in.txt");
+        std::fs::write(&input_path, "AB").unwrap();
+        let stdin_file = format!("--stdin-file={}", input_path.display());
+
+        let result = try_compile_file(",.,.", &["--emit=ll", "--max-static-output=0", &stdin_file]);
+        assert!(result.is_err());
+
+        let ir = compile_file_to_ll(",.,.", &["--max-static-output=2", &stdin_file]);
+        assert!(!ir.contains("call i32 @getchar"), "{}", ir);
+    }
+
+    #[test]
+    fn link_object_file_missing_linker() {
+        let result = link_object_file(
+            "bfc-no-such-linker",
+            "foo.o",
+            "foo",
+            None,
+            false,
+            false,
+            false,
+        );
+        assert!(matches!(result, Err(CompileError::Spawn { .. })));
+    }
+
+    #[test]
+    fn slurp_missing_file() {
+        let result = slurp(Path::new("no_such_file.bf"));
+        assert!(matches!(result, Err(CompileError::Io { .. })));
+    }
+
+    #[test]
+    fn linker_kinds() {
+        assert_eq!(linker_kind("clang"), LinkerKind::Clang);
+        assert_eq!(linker_kind("/usr/bin/clang-14"), LinkerKind::Clang);
+        assert_eq!(linker_kind("gcc"), LinkerKind::CcDriver);
+        assert_eq!(linker_kind("cc"), LinkerKind::CcDriver);
+        assert_eq!(linker_kind("ld.lld"), LinkerKind::Ld);
+        assert_eq!(linker_kind("wasm-ld"), LinkerKind::Ld);
+    }
+
+    #[test]
+    fn compare_default_passes_with_fewer_passes() {
+        let instrs = bfir::parse("++[->+++<]>.").unwrap();
+        let comparison = compare_passes(
+            &instrs,
+            peephole::DEFAULT_PASS_SPECIFICATION,
+            "combine_inc,combine_ptr",
+        );
+
+        assert_eq!(
+            comparison,
+            PassComparison {
+                instr_count_a: 3,
+                instr_count_b: 8,
+                equivalent: Some(true),
+            }
+        );
+    }
+
+    #[test]
+    fn compare_passes_with_input() {
+        let instrs = bfir::parse(",.").unwrap();
+        let comparison = compare_passes(&instrs, "combine_inc", "combine_ptr");
+        assert_eq!(comparison.equivalent, None);
+    }
+
+    #[test]
+    fn parse_pass_specification_unknown_pass() {
+        assert_eq!(
+            parse_pass_specification("combine_inc"),
+            Ok("combine_inc".to_owned())
+        );
+        assert_eq!(parse_pass_specification("list"), Ok("list".to_owned()));
+        assert!(parse_pass_specification("combine_incr").is_err());
+    }
+
+    #[test]
+    fn compile_options_default() {
+        // Without flags, we should compile like the library does by
+        // default.
+        // The CLI always passes a target, defaulting to the
+        // library's default.
+        let matches = cli().get_matches_from(["bfc", "foo.bf"]);
+        let default_triple = llvm::get_default_target_triple()
+            .to_string_lossy()
+            .into_owned();
+        assert_eq!(
+            compile_options(&matches, vec![]),
+            CompileOptions {
+                target_triple: Some(default_triple),
+                ..CompileOptions::default()
+            }
+        );
+    }
+
+    #[test]
+    fn compile_options_flags() {
+        let matches = cli().get_matches_from([
+            "bfc",
+            "foo.bf",
+            "--opt=1",
+            "--llvm-opt=1",
+            "--target=x86_64-pc-linux-gnu",
+            "--mcpu=skylake",
+            "--mattr=+avx2",
+            "--reloc=static",
+            "--code-model=large",
+            "--passes=combine_inc",
+            "--unroll-limit=3",
+            "--max-opt-iterations=5",
+            "--compile-time-exec=cells",
+            "--max-steps=100",
+            "--max-static-output=10",
+            "--keep-going",
+            "--stdout-buffering=line",
+            "--unbuffered",
+            "--debug-bounds",
+            "--count-loops",
+            "--debug-info",
+            "--no-builtin-io",
+            "--start-cell=4",
+            "--entry=run_bf",
+            "--sanitize=address",
+        ]);
+        assert_eq!(
+            compile_options(&matches, b"hi".to_vec()),
+            CompileOptions {
+                opt_level: 1,
+                llvm_opt_level: 1,
+                target_triple: Some("x86_64-pc-linux-gnu".to_owned()),
+                target_cpu: llvm::TargetCpu {
+                    name: "skylake".to_owned(),
+                    features: "+avx2".to_owned(),
+                },
+                reloc_mode: llvm::RelocMode::Static,
+                code_model: llvm::CodeModel::Large,
+                passes: Some("combine_inc".to_owned()),
+                unroll_limit: 3,
+                max_opt_iterations: 5,
+                compile_time_exec: CompileTimeExec::Cells,
+                max_steps: Some(100),
+                max_static_output: Some(10),
+                keep_going: true,
+                stdout_buffering: Some(llvm::StdoutBuffering::Line),
+                unbuffered: true,
+                debug_bounds: true,
+                known_input: b"hi".to_vec(),
+                count_loop_iterations: true,
+                // foo.bf doesn't exist, so we can't make it absolute.
+                debug_info: Some("foo.bf".to_owned()),
+                wrapping_tape: false,
+                no_builtin_io: true,
+                start_cell: 4,
+                entry_name: Some("run_bf".to_owned()),
+                sanitize_address: true,
+            }
+        );
+    }
+
+    #[test]
+    fn compile_options_wasm() {
+        let matches = cli().get_matches_from(["bfc", "foo.bf", "--emit=wasm"]);
+        assert_eq!(
+            compile_options(&matches, vec![]).target_triple,
+            Some("wasm32-wasi".to_owned())
+        );
+    }
+
+    #[test]
+    fn compile_options_wrapping_tape() {
+        let matches = cli().get_matches_from(["bfc", "foo.bf", "--wrapping-tape"]);
+        assert!(compile_options(&matches, vec![]).wrapping_tape);
+    }
+
+    #[test]
+    fn compile_time_exec_from_arg_modes() {
+        assert_eq!(compile_time_exec_from_arg("off"), CompileTimeExec::Off);
+        assert_eq!(compile_time_exec_from_arg("cells"), CompileTimeExec::Cells);
+        assert_eq!(compile_time_exec_from_arg("full"), CompileTimeExec::Full);
+    }
+
+    #[test]
+    fn compile_file_negative_cell_error() {
+        assert!(try_compile_file(",<.", &["--emit=ll"]).is_err());
+    }
+
+    #[test]
+    fn compile_file_start_cell() {
+        let ir = compile_file_to_ll(",<.", &["--opt=0", "--llvm-opt=0", "--start-cell=3"]);
+        assert!(ir.contains("store i32 3"), "{}", ir);
+    }
+
     #[test]
     fn executable_name_bf() {
         assert_eq!(executable_name(&PathBuf::from("foo.bf")), "foo");
@@ -286,4 +2456,479 @@ mod tests {
     fn executable_name_relative_path() {
         assert_eq!(executable_name(&PathBuf::from("bar/baz.bf")), "baz");
     }
+
+    /// Compile `src` with these arguments. Without `-o`, output files
+    /// are written to the current directory, so only use this
+    /// without `-o` when compilation fails.
+    fn try_compile_file(src: &str, args: &[&str]) -> Result<(), ()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo.bf");
+        std::fs::write(&path, src).unwrap();
+
+        let mut cli_args = vec!["bfc".to_owned(), path.display().to_string()];
+        cli_args.extend(args.iter().map(|arg| arg.to_string()));
+
+        let matches = cli().get_matches_from(cli_args);
+        compile_file(&matches)
+    }
+
+    /// Compile `src` with these extra arguments to LLVM IR, and
+    /// return the IR.
+    fn compile_file_to_ll(src: &str, args: &[&str]) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("foo.ll");
+        let output_arg = output_path.display().to_string();
+
+        let mut ll_args = vec!["--emit=ll", "-o", &output_arg];
+        ll_args.extend(args);
+
+        try_compile_file(src, &ll_args).unwrap();
+        std::fs::read_to_string(output_path).unwrap()
+    }
+
+    #[test]
+    fn compile_file_empty_program() {
+        for opt in ["--opt=0", "--opt=1", "--opt=2"] {
+            let ir = compile_file_to_ll("", &[opt]);
+            assert!(ir.contains("define i32 @main()"), "{}", ir);
+        }
+    }
+
+    #[test]
+    fn compile_file_comment_only_program() {
+        for opt in ["--opt=0", "--opt=1", "--opt=2"] {
+            let ir = compile_file_to_ll("a comment\n  \n", &[opt]);
+            assert!(ir.contains("define i32 @main()"), "{}", ir);
+        }
+    }
+
+    #[test]
+    fn compile_file_custom_entry() {
+        let ir = compile_file_to_ll("+.", &["--entry=run_bf"]);
+        assert!(ir.contains("define void @run_bf()"), "{}", ir);
+        assert!(!ir.contains("@main"), "{}", ir);
+    }
+
+    #[test]
+    fn compile_file_custom_entry_rejects_runtime_symbols() {
+        for name in ["putchar", "flush_output", "bf_getchar"] {
+            let result = try_compile_file("+.", &[&format!("--entry={}", name), "--emit=ll"]);
+            assert!(result.is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn compile_file_emit_c_rejects_unsupported_flags() {
+        for flag in [
+            "--debug-bounds",
+            "--count-loops",
+            "-g",
+            "--sanitize=address",
+            "--stdout-buffering=line",
+        ] {
+            let result = try_compile_file("+.", &[flag, "--emit=c"]);
+            assert!(result.is_err(), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn compile_file_custom_entry_needs_emit() {
+        let result = try_compile_file("+.", &["--entry=run_bf", "--dry-run"]);
+        assert!(result.is_err());
+    }
+
+    /// Compile foo.bf in `dir` to an object file, caching it in
+    /// `dir`/cache, and return the object file's contents.
+    fn compile_file_cached(dir: &Path, src: &str, args: &[&str]) -> Vec<u8> {
+        let path = dir.join("foo.bf");
+        std::fs::write(&path, src).unwrap();
+        let output_path = dir.join("foo.o");
+
+        let mut cli_args = vec![
+            "bfc".to_owned(),
+            path.display().to_string(),
+            "--emit=obj".to_owned(),
+            format!("--cache-dir={}", dir.join("cache").display()),
+            "-o".to_owned(),
+            output_path.display().to_string(),
+        ];
+        cli_args.extend(args.iter().map(|arg| arg.to_string()));
+
+        let matches = cli().get_matches_from(cli_args);
+        compile_file(&matches).unwrap();
+        std::fs::read(output_path).unwrap()
+    }
+
+    /// Replace every object file in `dir`/cache with `contents`, so we
+    /// can tell when compilation used the cache.
+    fn overwrite_cached_objects(dir: &Path, contents: &[u8]) -> usize {
+        let entries = std::fs::read_dir(dir.join("cache")).unwrap();
+        let mut count = 0;
+        for entry in entries {
+            std::fs::write(entry.unwrap().path(), contents).unwrap();
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn compile_file_cache_hit() {
+        let dir = tempfile::tempdir().unwrap();
+        let object = compile_file_cached(dir.path(), "+.", &[]);
+        assert!(!object.is_empty());
+        assert_eq!(overwrite_cached_objects(dir.path(), b"cached"), 1);
+
+        // An unchanged file skips code generation.
+        assert_eq!(compile_file_cached(dir.path(), "+.", &[]), b"cached");
+    }
+
+    #[test]
+    fn compile_file_cache_miss() {
+        let dir = tempfile::tempdir().unwrap();
+        compile_file_cached(dir.path(), "+.", &[]);
+        overwrite_cached_objects(dir.path(), b"cached");
+
+        // Changing the source or the options compiles again.
+        assert_ne!(compile_file_cached(dir.path(), "++.", &[]), b"cached");
+        assert_ne!(
+            compile_file_cached(dir.path(), "+.", &["--opt=1"]),
+            b"cached"
+        );
+        assert_eq!(overwrite_cached_objects(dir.path(), b"cached"), 3);
+    }
+
+    #[test]
+    fn compile_file_cache_skips_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        // This loop only increases its cell, so we warn about it.
+        compile_file_cached(dir.path(), ",[+].", &[]);
+        assert!(!dir.path().join("cache").exists());
+    }
+
+    #[test]
+    fn compile_file_cache_debug_info_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        for name in ["first", "second"] {
+            let path = dir.path().join(format!("{}.bf", name));
+            std::fs::write(&path, "+.").unwrap();
+            let matches = cli().get_matches_from([
+                "bfc".to_owned(),
+                path.display().to_string(),
+                "--emit=obj".to_owned(),
+                "-g".to_owned(),
+                format!("--cache-dir={}", cache_dir.display()),
+                "-o".to_owned(),
+                dir.path().join(format!("{}.o", name)).display().to_string(),
+            ]);
+            compile_file(&matches).unwrap();
+        }
+
+        // Each object file names its own source in its debug info.
+        assert_eq!(std::fs::read_dir(cache_dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn compile_file_no_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        compile_file_cached(dir.path(), "+.", &[]);
+        overwrite_cached_objects(dir.path(), b"cached");
+
+        assert_ne!(
+            compile_file_cached(dir.path(), "+.", &["--no-cache"]),
+            b"cached"
+        );
+    }
+
+    #[test]
+    fn compile_file_wrapping_tape() {
+        let ir = compile_file_to_ll(",<.", &["--opt=0", "--llvm-opt=0", "--wrapping-tape"]);
+        assert!(ir.contains("urem i32"), "{}", ir);
+        assert!(ir.contains("i32 30000"), "{}", ir);
+    }
+
+    /// Compile a small program and emit it as `emit`, returning the
+    /// contents of the output file.
+    fn emit_to_bytes(emit: EmitKind, target_triple: &str) -> Vec<u8> {
+        let instrs = bfir::parse(",[.,]").unwrap();
+        let mut state = execution::ExecutionState::initial(&instrs);
+        state.start_instr = instrs.first();
+
+        llvm::init_llvm();
+        let mut llvm_module =
+            llvm::compile_to_module("foo", Some(target_triple.to_owned()), &instrs, &state);
+
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join(output_file_name(
+            &PathBuf::from("foo.bf"),
+            emit.extension().unwrap(),
+        ));
+        let output_path = output_path.to_str().unwrap();
+
+        emit_file(
+            emit,
+            &mut llvm_module,
+            &instrs,
+            &state,
+            output_path,
+            &llvm::TargetCpu::default(),
+            llvm::RelocMode::default(),
+            llvm::CodeModel::default(),
+        )
+        .unwrap();
+        std::fs::read(output_path).unwrap()
+    }
+
+    #[test]
+    fn emit_kind_extensions() {
+        let extensions: Vec<_> = [
+            "exe", "obj", "asm", "ll", "llvm-ir", "bc", "c", "wasm", "bf",
+        ]
+        .iter()
+        .map(|arg| EmitKind::from_arg(arg).extension())
+        .collect();
+        assert_eq!(
+            extensions,
+            vec![
+                None,
+                Some("o"),
+                Some("s"),
+                Some("ll"),
+                Some("ll"),
+                Some("bc"),
+                Some("c"),
+                Some("wasm"),
+                Some("out.bf")
+            ]
+        );
+    }
+
+    #[test]
+    fn emit_obj() {
+        let output = emit_to_bytes(EmitKind::Object, "x86_64-pc-linux-gnu");
+        assert!(output.starts_with(b"\x7fELF"));
+    }
+
+    #[test]
+    fn emit_asm() {
+        let output = emit_to_bytes(EmitKind::Assembly, "x86_64-pc-linux-gnu");
+        assert!(String::from_utf8(output).unwrap().contains("main:"));
+    }
+
+    #[test]
+    fn emit_llvm_ir() {
+        let output = emit_to_bytes(EmitKind::LlvmIr, "x86_64-pc-linux-gnu");
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("define i32 @main()"));
+    }
+
+    #[test]
+    fn emit_bitcode() {
+        let output = emit_to_bytes(EmitKind::Bitcode, "x86_64-pc-linux-gnu");
+        assert!(output.starts_with(b"BC\xc0\xde"));
+    }
+
+    #[test]
+    fn emit_c() {
+        let output = emit_to_bytes(EmitKind::C, "x86_64-pc-linux-gnu");
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("int main(void)"));
+    }
+
+    #[test]
+    fn emit_bf() {
+        let instrs = bfir::parse(",[>+<-]>.").unwrap();
+        let (instrs, _) = peephole::optimize(instrs, peephole::OptimizeOptions::default());
+
+        let output_file = NamedTempFile::new().unwrap();
+        let output_path = output_file.path().to_str().unwrap();
+        emit_bf_file(&instrs, &[], output_path).unwrap();
+
+        let output = std::fs::read_to_string(output_path).unwrap();
+        assert_eq!(output, ",[->+<]>.");
+    }
+
+    #[test]
+    fn emit_bf_with_comments() {
+        let (instrs, comments) = bfir::parse_with_comments("++ two\n.", false).unwrap();
+        let (instrs, _) = peephole::optimize(instrs, peephole::OptimizeOptions::default());
+
+        let output_file = NamedTempFile::new().unwrap();
+        let output_path = output_file.path().to_str().unwrap();
+        emit_bf_file(&instrs, &comments, output_path).unwrap();
+
+        let output = std::fs::read_to_string(output_path).unwrap();
+        assert_eq!(output, "[-]++ two\n.");
+    }
+
+    #[test]
+    fn emit_wasm() {
+        let output = emit_to_bytes(EmitKind::Wasm, "wasm32-wasi");
+        assert!(output.starts_with(b"\0asm"));
+
+        // We import WASI functions directly, rather than needing libc.
+        let imports_fn = |name: &[u8]| output.windows(name.len()).any(|w| w == name);
+        assert!(imports_fn(b"fd_read"));
+        assert!(!imports_fn(b"malloc"));
+    }
+
+    #[test]
+    fn output_file_name_asm() {
+        assert_eq!(output_file_name(&PathBuf::from("foo.bf"), "s"), "foo.s");
+    }
+
+    #[test]
+    fn output_path_default() {
+        let bf_path = PathBuf::from("bar/baz.bf");
+        assert_eq!(output_path(None, &bf_path, None), "baz");
+        assert_eq!(output_path(None, &bf_path, Some("ll")), "baz.ll");
+    }
+
+    #[test]
+    fn output_path_explicit() {
+        let bf_path = PathBuf::from("bar/baz.bf");
+        let output = PathBuf::from("/tmp/out/hello");
+        assert_eq!(output_path(Some(&output), &bf_path, None), "/tmp/out/hello");
+        assert_eq!(
+            output_path(Some(&output), &bf_path, Some("ll")),
+            "/tmp/out/hello"
+        );
+    }
+
+    #[test]
+    fn object_path_next_to_executable() {
+        assert_eq!(object_path("foo"), "foo.o");
+        assert_eq!(object_path("/tmp/out/hello"), "/tmp/out/hello.o");
+    }
+
+    #[test]
+    fn compile_file_print_passes_with_stats() {
+        let ir = compile_file_to_ll("+++.", &["--print-passes", "--stats"]);
+        assert!(ir.contains("@known_outputs"), "{}", ir);
+    }
+
+    #[test]
+    fn compile_file_keep_object() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("foo");
+
+        let result = try_compile_file(
+            "+.",
+            &[
+                "--keep-object",
+                // We only want the object file, so don't actually link.
+                "--linker=true",
+                "-o",
+                &output_path.display().to_string(),
+            ],
+        );
+        assert!(result.is_ok());
+
+        let object = std::fs::read(dir.path().join("foo.o")).unwrap();
+        assert!(!object.is_empty());
+    }
+
+    #[test]
+    fn output_path_stdin() {
+        let bf_path = PathBuf::from("-");
+        assert_eq!(output_path(None, &bf_path, None), "a.out");
+        assert_eq!(output_path(None, &bf_path, Some("ll")), "a.ll");
+    }
+
+    #[test]
+    fn display_path_stdin() {
+        assert_eq!(display_path(Path::new("-")), "<stdin>");
+        assert_eq!(display_path(Path::new("foo.bf")), "foo.bf");
+    }
+
+    #[test]
+    fn output_file_name_relative_path() {
+        assert_eq!(output_file_name(&PathBuf::from("bar/baz.bf"), "o"), "baz.o");
+    }
+
+    #[test]
+    fn use_color_auto() {
+        assert!(use_color("auto", false, true));
+        assert!(!use_color("auto", false, false));
+        assert!(!use_color("auto", true, true));
+    }
+
+    #[test]
+    fn use_color_explicit() {
+        assert!(use_color("always", true, false));
+        assert!(!use_color("never", false, true));
+    }
+
+    #[test]
+    fn split_bang_input_without_bang() {
+        assert_eq!(split_bang_input(",[.,]"), (",[.,]", &b""[..]));
+    }
+
+    #[test]
+    fn split_bang_input_one_bang() {
+        assert_eq!(split_bang_input(",[.,]!hi\n"), (",[.,]", &b"hi\n"[..]));
+    }
+
+    #[test]
+    fn split_bang_input_many_bangs() {
+        // Only the first `!` separates the program from its input.
+        assert_eq!(split_bang_input(",.!a!b!"), (",.", &b"a!b!"[..]));
+    }
+
+    #[test]
+    fn compile_time_execute_bang_input() {
+        let (src, input) = split_bang_input(",.,.!hi");
+        let instrs = bfir::parse_strict(src).unwrap();
+        let opts = CompileOptions {
+            known_input: input.to_vec(),
+            ..CompileOptions::default()
+        };
+        let (state, warning) = bfc::execute_at_compile_time(&instrs, &opts).unwrap();
+        assert_eq!(warning, None);
+        assert_eq!(state.outputs, vec![b'h' as i8, b'i' as i8]);
+        assert_eq!(state.start_instr, None);
+    }
+
+    #[test]
+    fn extract_markdown_bf_blocks() {
+        let src = "# Example\n\n```bf\n+.\n```\n\nText with + and -.\n\n~~~ brainfuck\n>,\n~~~\n";
+        let bf_src = extract_markdown_bf(src);
+
+        assert_eq!(bf_src.len(), src.len());
+        let instrs = bfir::parse(&bf_src).unwrap();
+        assert_eq!(bfir::to_bf_source(&instrs).unwrap(), "+.>,");
+    }
+
+    #[test]
+    fn extract_markdown_bf_ignores_other_languages() {
+        let src = "```c\nx++;\n```\n```\ny--;\n```\n";
+        let bf_src = extract_markdown_bf(src);
+        assert!(bfir::parse(&bf_src).unwrap().is_empty());
+    }
+
+    #[test]
+    fn extract_markdown_bf_nested_fence() {
+        // A shorter fence inside a code block doesn't close it.
+        let src = "````bf\n+\n```\n-\n````\n";
+        let instrs = bfir::parse(&extract_markdown_bf(src)).unwrap();
+        assert_eq!(bfir::to_bf_source(&instrs).unwrap(), "+-");
+    }
+
+    #[test]
+    fn extract_markdown_bf_error_position() {
+        let src = "Some prose [with brackets].\n\n```bf\n+\n[>+\n```\n";
+        let err = bfir::parse(&extract_markdown_bf(src)).unwrap_err();
+
+        // The unmatched [ is on line 5 of the original file.
+        let line = src
+            .chars()
+            .take(err.position.start)
+            .filter(|c| *c == '\n')
+            .count()
+            + 1;
+        assert_eq!(line, 5);
+        assert_eq!(src.chars().nth(err.position.start), Some('['));
+    }
 }
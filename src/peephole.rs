@@ -1,118 +1,374 @@
 //! Optimisations that replace parts of the BF AST with faster
 //! equivalents.
 
-use std::collections::{HashMap, HashSet};
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 use std::num::Wrapping;
+use std::time::{Duration, Instant};
 
 use itertools::Itertools;
 
 use crate::diagnostics::Warning;
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{get_position, AstNode, BfValue, Combine, Position};
+use crate::bfir::{count_instrs, get_position, AstNode, BfValue, Combine, Position};
+
+/// Run our peephole optimisations at most this many times, unless the
+/// user passes `--max-opt-iterations`.
+pub const DEFAULT_MAX_ITERATIONS: u64 = 40;
+
+/// The `unroll` pass only unrolls loops that run at most this many
+/// times, unless the user passes `--unroll-limit`.
+pub const DEFAULT_UNROLL_LIMIT: u64 = 8;
+
+/// How much time a pass took and how many instructions it removed,
+/// summed over every iteration of `optimize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassStats {
+    pub name: &'static str,
+    /// Negative if the pass added instructions.
+    pub instrs_removed: i64,
+    pub duration: Duration,
+}
+
+/// Statistics about a call to `optimize`, for `--stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OptimizeStats {
+    /// The number of times we ran all the passes.
+    pub iterations: u64,
+    pub reached_fixed_point: bool,
+    /// The passes that ran, in the order they first ran.
+    pub passes: Vec<PassStats>,
+}
+
+impl OptimizeStats {
+    fn record(&mut self, name: &'static str, instrs_removed: i64, duration: Duration) {
+        match self.passes.iter_mut().find(|pass| pass.name == name) {
+            Some(pass) => {
+                pass.instrs_removed += instrs_removed;
+                pass.duration += duration;
+            }
+            None => self.passes.push(PassStats {
+                name,
+                instrs_removed,
+                duration,
+            }),
+        }
+    }
+}
 
-const MAX_OPT_ITERATIONS: u64 = 40;
+/// A callback given the name of a pass and the program after it ran.
+pub type AfterPass<'a> = dyn FnMut(&str, &[AstNode]) + 'a;
+
+/// Settings for `optimize`.
+pub struct OptimizeOptions<'a> {
+    /// A comma-separated list of passes to run, rather than
+    /// `DEFAULT_PASS_SPECIFICATION` (`--passes`).
+    pub passes: Option<String>,
+    /// Only unroll loops that run at most this many times
+    /// (`--unroll-limit`).
+    pub unroll_limit: u64,
+    /// Stop after this many runs of our passes, even if we haven't
+    /// reached a fixed point (`--max-opt-iterations`).
+    pub max_iterations: u64,
+    /// If set, record the time taken and instructions removed by each
+    /// pass (`--stats`).
+    pub stats: Option<&'a mut OptimizeStats>,
+    /// If set, call this with the name of each pass and the program
+    /// after it ran (`--print-passes`). This is useful for finding
+    /// which pass changed a program.
+    pub after_pass: Option<&'a mut AfterPass<'a>>,
+}
+
+impl Default for OptimizeOptions<'_> {
+    fn default() -> Self {
+        OptimizeOptions {
+            passes: None,
+            unroll_limit: DEFAULT_UNROLL_LIMIT,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            stats: None,
+            after_pass: None,
+        }
+    }
+}
 
 /// Given a sequence of BF instructions, apply peephole optimisations
 /// (repeatedly if necessary).
-pub fn optimize(
-    instrs: Vec<AstNode>,
-    pass_specification: &Option<String>,
-) -> (Vec<AstNode>, Vec<Warning>) {
+pub fn optimize(instrs: Vec<AstNode>, opts: OptimizeOptions) -> (Vec<AstNode>, Vec<Warning>) {
+    let OptimizeOptions {
+        passes: pass_specification,
+        unroll_limit,
+        max_iterations,
+        mut stats,
+        mut after_pass,
+    } = opts;
+    let pass_specification = &pass_specification;
+
     // Many of our individual peephole optimisations remove
     // instructions, creating new opportunities to combine. We run
     // until we've found a fixed-point where no further optimisations
     // can be made.
-    let mut prev = instrs.clone();
+    let mut result = instrs;
     let mut warnings = vec![];
 
-    let (mut result, warning) = optimize_once(instrs, pass_specification);
+    for _ in 0..max_iterations {
+        let prev = result.clone();
 
-    if let Some(warning) = warning {
-        warnings.push(warning);
-    }
+        let (new_result, new_warnings) = optimize_once(
+            result,
+            pass_specification,
+            unroll_limit,
+            stats.as_deref_mut(),
+            after_pass.as_deref_mut(),
+        );
+        extend_warnings(&mut warnings, new_warnings);
+        result = new_result;
 
-    for _ in 0..MAX_OPT_ITERATIONS {
         if prev == result {
+            if let Some(stats) = stats {
+                stats.reached_fixed_point = true;
+            }
             return (result, warnings);
-        } else {
-            prev = result.clone();
+        }
+    }
+
+    // Passes that undo each other never reach a fixed point, so this
+    // is usually a bug in bfc.
+    warnings.push(Warning {
+        message: format!(
+            "Ran peephole optimisations {} times but did not reach a fixed point.",
+            max_iterations
+        ),
+        position: None,
+    });
 
-            let (new_result, new_warning) = optimize_once(result, pass_specification);
+    (result, warnings)
+}
 
-            if let Some(warning) = new_warning {
-                warnings.push(warning);
-            }
-            result = new_result;
+/// Add `new_warnings` to `warnings`. Passes run until we reach a
+/// fixed point, so ignore warnings we've already seen.
+fn extend_warnings(warnings: &mut Vec<Warning>, new_warnings: Vec<Warning>) {
+    for warning in new_warnings {
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
         }
     }
+}
 
-    // TODO: use proper Info here.
-    eprintln!(
-        "Warning: ran peephole optimisations {} times but did not reach a fixed point!",
-        MAX_OPT_ITERATIONS
-    );
+/// The passes we run when the user doesn't specify `--passes`.
+pub const DEFAULT_PASS_SPECIFICATION: &str = "combine_inc,combine_ptr,infinite_loop,\
+     known_zero,multiply,copy_loop,merge_multiply,cell_copy,fold_multiply,zeroing_loop,scan_loop,\
+     combine_set,unroll,run_once,dead_loop,unreachable,redundant_set,read_clobber,multiply_clobber,dead_store,\
+     pure_removal,offset_sort,absorb_ptr,hoist_ptr";
+
+/// Every pass we support, with a short description, in the order we
+/// run them.
+const PASSES: &[(&str, &str)] = &[
+    ("combine_inc", "Combine consecutive increments, e.g. `+++`"),
+    (
+        "combine_ptr",
+        "Combine consecutive pointer increments, e.g. `>>>`",
+    ),
+    (
+        "infinite_loop",
+        "Mark loops that never terminate, such as `[]`",
+    ),
+    ("known_zero", "Mark cells that are known to be zero"),
+    (
+        "multiply",
+        "Convert multiply loops, e.g. `[->++<]`, to a single instruction",
+    ),
+    ("copy_loop", "Copy cells without using a temporary cell"),
+    (
+        "merge_multiply",
+        "Merge consecutive multiply loops from the same cell",
+    ),
+    (
+        "cell_copy",
+        "Convert multiply loops that copy a cell to a single copy",
+    ),
+    (
+        "fold_multiply",
+        "Replace multiply loops and copies of a known value with increments",
+    ),
+    ("zeroing_loop", "Convert `[-]` to setting the cell to zero"),
+    ("scan_loop", "Convert `[>]` and `[<]` to scan loops"),
+    ("combine_set", "Combine sets with other sets or increments"),
+    (
+        "unroll",
+        "Unroll loops that run a small, known number of times",
+    ),
+    (
+        "run_once",
+        "Remove the test of loops that clear their cell and always run once",
+    ),
+    ("dead_loop", "Remove loops whose cell is known to be zero"),
+    (
+        "unreachable",
+        "Remove code after a loop that never terminates",
+    ),
+    ("redundant_set", "Remove sets that don't change the cell"),
+    (
+        "read_clobber",
+        "Remove changes to a cell that is then read from stdin",
+    ),
+    (
+        "multiply_clobber",
+        "Remove multiply changes to cells that are set afterwards",
+    ),
+    (
+        "dead_store",
+        "Remove changes that are always overwritten before use",
+    ),
+    (
+        "pure_removal",
+        "Remove code at the end of the program with no side effects",
+    ),
+    (
+        "offset_sort",
+        "Use cell offsets rather than moving the pointer",
+    ),
+    (
+        "absorb_ptr",
+        "Move pointer increments after the loops that follow them",
+    ),
+    (
+        "hoist_ptr",
+        "Move pointer increments at the start of a loop outside it",
+    ),
+];
+
+/// The names of every pass we support, in the order we run them.
+pub fn pass_names() -> Vec<&'static str> {
+    PASSES.iter().map(|(name, _)| *name).collect()
+}
 
-    (result, warnings)
+/// The name and description of every pass we support, in the order
+/// we run them.
+pub fn pass_descriptions() -> &'static [(&'static str, &'static str)] {
+    PASSES
+}
+
+/// Check that every pass in this comma-separated specification
+/// exists. Empty names are ignored, so `--passes=` disables all
+/// passes.
+pub fn check_pass_specification(pass_specification: &str) -> Result<(), String> {
+    let names = pass_names();
+    for pass in pass_specification.split(',') {
+        if !pass.is_empty() && !names.contains(&pass) {
+            return Err(format!("Unknown optimisation pass `{}`", pass));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the passes enabled in a pass specification, recording
+/// statistics and calling `after_pass` if requested.
+struct PassRunner<'a, 'b, 'c> {
+    passes: Vec<&'a str>,
+    stats: Option<&'b mut OptimizeStats>,
+    after_pass: Option<&'b mut AfterPass<'c>>,
+}
+
+impl PassRunner<'_, '_, '_> {
+    fn run<F>(&mut self, name: &'static str, instrs: Vec<AstNode>, pass: F) -> Vec<AstNode>
+    where
+        F: FnOnce(Vec<AstNode>) -> Vec<AstNode>,
+    {
+        if !self.passes.contains(&name) {
+            return instrs;
+        }
+
+        let instrs = match self.stats {
+            Some(ref mut stats) => {
+                // Counting instructions walks the whole program, so
+                // only do it when we need stats.
+                let before = count_instrs(&instrs);
+                let start = Instant::now();
+                let instrs = pass(instrs);
+                let removed = before as i64 - count_instrs(&instrs) as i64;
+                stats.record(name, removed, start.elapsed());
+                instrs
+            }
+            None => pass(instrs),
+        };
+
+        if let Some(ref mut after_pass) = self.after_pass {
+            after_pass(name, &instrs);
+        }
+        instrs
+    }
 }
 
 /// Apply all our peephole optimisations once and return the result.
-fn optimize_once(
+fn optimize_once<'a>(
     instrs: Vec<AstNode>,
     pass_specification: &Option<String>,
-) -> (Vec<AstNode>, Option<Warning>) {
-    let pass_specification = pass_specification.clone().unwrap_or_else(|| {
-        "combine_inc,combine_ptr,known_zero,\
-         multiply,zeroing_loop,combine_set,\
-         dead_loop,redundant_set,read_clobber,\
-         pure_removal,offset_sort"
-            .to_owned()
-    });
-    let passes: Vec<_> = pass_specification.split(',').collect();
+    unroll_limit: u64,
+    stats: Option<&mut OptimizeStats>,
+    after_pass: Option<&mut AfterPass<'a>>,
+) -> (Vec<AstNode>, Vec<Warning>) {
+    let pass_specification = pass_specification
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PASS_SPECIFICATION.to_owned());
+    let mut runner = PassRunner {
+        passes: pass_specification.split(',').collect(),
+        stats,
+        after_pass,
+    };
+    if let Some(ref mut stats) = runner.stats {
+        stats.iterations += 1;
+    }
 
     let mut instrs = instrs;
+    let mut warnings = vec![];
 
-    if passes.contains(&"combine_inc") {
-        instrs = combine_increments(instrs);
-    }
-    if passes.contains(&"combine_ptr") {
-        instrs = combine_ptr_increments(instrs);
-    }
-    if passes.contains(&"known_zero") {
-        instrs = annotate_known_zero(instrs);
-    }
-    if passes.contains(&"multiply") {
-        instrs = extract_multiply(instrs);
-    }
-    if passes.contains(&"zeroing_loop") {
-        instrs = zeroing_loops(instrs);
-    }
-    if passes.contains(&"combine_set") {
-        instrs = combine_set_and_increments(instrs);
-    }
-    if passes.contains(&"dead_loop") {
-        instrs = remove_dead_loops(instrs);
-    }
-    if passes.contains(&"redundant_set") {
-        instrs = remove_redundant_sets(instrs);
-    }
-    if passes.contains(&"read_clobber") {
-        instrs = remove_read_clobber(instrs);
-    }
-    let warning = if passes.contains(&"pure_removal") {
+    instrs = runner.run("combine_inc", instrs, combine_increments);
+    instrs = runner.run("combine_ptr", instrs, combine_ptr_increments);
+    instrs = runner.run("infinite_loop", instrs, canonicalize_infinite_loops);
+    instrs = runner.run("known_zero", instrs, annotate_known_zero);
+    instrs = runner.run("multiply", instrs, extract_multiply);
+    instrs = runner.run("copy_loop", instrs, combine_copy_loops);
+    instrs = runner.run("merge_multiply", instrs, merge_multiply_moves);
+    instrs = runner.run("cell_copy", instrs, extract_cell_copies);
+    instrs = runner.run("fold_multiply", instrs, fold_known_multiply_moves);
+    instrs = runner.run("zeroing_loop", instrs, zeroing_loops);
+    instrs = runner.run("scan_loop", instrs, extract_scan_loops);
+    instrs = runner.run("combine_set", instrs, combine_set_and_increments);
+    instrs = runner.run("unroll", instrs, |instrs| {
+        unroll_constant_loops(instrs, unroll_limit)
+    });
+    instrs = runner.run("run_once", instrs, remove_run_once_loops);
+    instrs = runner.run("dead_loop", instrs, remove_dead_loops);
+    instrs = runner.run("unreachable", instrs, |instrs| {
+        let (reachable, unreachable_warnings) = remove_unreachable_code(instrs);
+        warnings.extend(unreachable_warnings);
+        reachable
+    });
+    instrs = runner.run("redundant_set", instrs, remove_redundant_sets);
+    instrs = runner.run("read_clobber", instrs, |instrs| {
+        let (unclobbered, clobber_warnings) = remove_read_clobber(instrs);
+        warnings.extend(clobber_warnings);
+        unclobbered
+    });
+    instrs = runner.run(
+        "multiply_clobber",
+        instrs,
+        remove_clobbered_multiply_changes,
+    );
+    instrs = runner.run("dead_store", instrs, remove_dead_stores);
+    instrs = runner.run("pure_removal", instrs, |instrs| {
         let (removed, pure_warning) = remove_pure_code(instrs);
-        instrs = removed;
-        pure_warning
-    } else {
-        None
-    };
+        warnings.extend(pure_warning);
+        removed
+    });
 
-    if passes.contains(&"offset_sort") {
-        instrs = sort_by_offset(instrs);
-    }
+    instrs = runner.run("offset_sort", instrs, sort_by_offset);
+    instrs = runner.run("absorb_ptr", instrs, absorb_ptr_increments);
+    instrs = runner.run("hoist_ptr", instrs, hoist_loop_ptr_increments);
 
-    (instrs, warning)
+    (instrs, warnings)
 }
 
 /// Defines a method on iterators to map a function over all loop bodies.
@@ -122,8 +378,13 @@ trait MapLoopsExt: Iterator<Item = AstNode> {
         F: Fn(Vec<AstNode>) -> Vec<AstNode>,
     {
         self.map(|instr| match instr {
-            Loop { body, position } => Loop {
+            Loop {
+                body,
+                offset,
+                position,
+            } => Loop {
                 body: f(body),
+                offset,
                 position,
             },
             other => other,
@@ -143,9 +404,17 @@ impl<I> MapLoopsExt for I where I: Iterator<Item = AstNode> {}
 /// Set {amount:100, offset: 1}, we're still considering previous instructions that
 /// modify the current cell, not the (cell_index + 1)th cell.
 fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
+    previous_offset_cell_change(instrs, index, 0)
+}
+
+/// Given an index into a vector of instructions, find the index of
+/// the previous instruction that modified the cell at `offset` from
+/// the current cell. If we're unsure, or there isn't one, return
+/// None.
+fn previous_offset_cell_change(instrs: &[AstNode], index: usize, offset: isize) -> Option<usize> {
     assert!(index < instrs.len());
 
-    let mut needed_offset = 0;
+    let mut needed_offset = offset;
     for i in (0..index).rev() {
         match instrs[i] {
             Increment { offset, .. } | Set { offset, .. } => {
@@ -166,27 +435,41 @@ fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                     return Some(i);
                 }
             }
+            CellCopy {
+                src_offset,
+                dst_offset,
+                ..
+            } => {
+                if dst_offset == needed_offset {
+                    return Some(i);
+                }
+                // The cell's value is used here, so we're unsure
+                // whether the change we'd find is still needed.
+                if src_offset == needed_offset {
+                    return None;
+                }
+            }
             // No cells changed, so just keep working backwards.
             Write { .. } => {}
             // These instructions may have modified the cell, so
             // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Read { .. } | Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. } => return None,
         }
     }
     None
 }
 
-/// Inverse of `previous_cell_change`.
+/// Inverse of `previous_offset_cell_change`.
 ///
-/// This is very similar to `previous_cell_change` and previous
+/// This is very similar to `previous_offset_cell_change` and previous
 /// implementations called `previous_cell_change` on the reversed
 /// vector. This proved extremely hard to reason about. Instead, we
-/// have copied the body of `previous_cell_change` and highlighted the
-/// differences.
-fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
+/// have copied the body of `previous_offset_cell_change` and
+/// highlighted the differences.
+fn next_offset_cell_change(instrs: &[AstNode], index: usize, offset: isize) -> Option<usize> {
     assert!(index < instrs.len());
 
-    let mut needed_offset = 0;
+    let mut needed_offset = offset;
     // Unlike previous_cell_change, we iterate forward.
     for (i, instr) in instrs.iter().enumerate().skip(index + 1) {
         match *instr {
@@ -209,11 +492,25 @@ fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                     return Some(i);
                 }
             }
+            CellCopy {
+                src_offset,
+                dst_offset,
+                ..
+            } => {
+                if dst_offset == needed_offset {
+                    return Some(i);
+                }
+                // The cell's value is used here, so we're unsure
+                // whether the change we'd find is still needed.
+                if src_offset == needed_offset {
+                    return None;
+                }
+            }
             // No cells changed, so just keep working backwards.
             Write { .. } => {}
             // These instructions may have modified the cell, so
             // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Read { .. } | Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. } => return None,
         }
     }
     None
@@ -293,17 +590,17 @@ fn combine_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
 }
 
 /// Don't bother updating cells if they're immediately overwritten
-/// by a value from stdin.
-// TODO: this should generate a warning too.
-fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
+/// by a value from stdin, and warn about the change we removed.
+fn remove_read_clobber(instrs: Vec<AstNode>) -> (Vec<AstNode>, Vec<Warning>) {
     let mut redundant_instr_positions = HashSet::new();
     let mut last_write_index = None;
 
     for (index, instr) in instrs.iter().enumerate() {
         match *instr {
-            Read { .. } => {
+            Read { offset, .. } => {
                 // If we can find the time this cell was modified:
-                if let Some(prev_modify_index) = previous_cell_change(&instrs, index) {
+                if let Some(prev_modify_index) = previous_offset_cell_change(&instrs, index, offset)
+                {
                     // This modify instruction is not redundant if we
                     // wrote anything afterwards.
                     if let Some(write_index) = last_write_index {
@@ -328,1196 +625,5408 @@ fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
         }
     }
 
-    instrs
-        .into_iter()
-        .enumerate()
-        .filter(|&(index, _)| !redundant_instr_positions.contains(&index))
-        .map(|(_, instr)| instr)
-        .map_loops(remove_read_clobber)
+    let mut warnings = vec![];
+    let mut result = Vec::with_capacity(instrs.len());
+    for (index, instr) in instrs.into_iter().enumerate() {
+        if redundant_instr_positions.contains(&index) {
+            // Don't warn about `Set 0`, as known_zero inserts those
+            // for cells the user never changed.
+            if !matches!(
+                instr,
+                Set {
+                    amount: Wrapping(0),
+                    ..
+                }
+            ) {
+                warnings.push(Warning {
+                    message:
+                        "This change has no effect, because the cell is read from stdin afterwards."
+                            .to_owned(),
+                    position: get_position(&instr),
+                });
+            }
+            continue;
+        }
+
+        match instr {
+            Loop {
+                body,
+                offset,
+                position,
+            } => {
+                let (body, body_warnings) = remove_read_clobber(body);
+                warnings.extend(body_warnings);
+                result.push(Loop {
+                    body,
+                    offset,
+                    position,
+                });
+            }
+            other => result.push(other),
+        }
+    }
+
+    (result, warnings)
 }
 
-/// Convert [-] to Set 0.
-fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    instrs
-        .into_iter()
-        .map(|instr| {
-            if let Loop { ref body, position } = instr {
-                // If the loop is [-]
-                if body.len() == 1 {
-                    if let Increment {
-                        amount: Wrapping(-1),
-                        offset: 0,
-                        ..
-                    } = body[0]
+/// Remove changes from a MultiplyMove when the destination cell is
+/// set afterwards anyway. For example, MultiplyMove {1: 2, 2: 1}
+/// followed by Set {amount: 0, offset: 1} doesn't need to modify
+/// cell #1.
+fn remove_clobbered_multiply_changes(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut clobbered_changes: HashMap<usize, Vec<isize>> = HashMap::new();
+
+    for (index, instr) in instrs.iter().enumerate() {
+        if let MultiplyMove { changes, .. } = instr {
+            for &dest_offset in changes.keys() {
+                if let Some(next_index) = next_offset_cell_change(&instrs, index, dest_offset) {
+                    // The destination cell may be written to stdout
+                    // before it's set.
+                    if instrs[index + 1..next_index]
+                        .iter()
+                        .any(|instr| matches!(instr, Write { .. }))
                     {
-                        return Set {
-                            amount: Wrapping(0),
-                            offset: 0,
-                            position,
-                        };
+                        continue;
+                    }
+
+                    if let Set { .. } = instrs[next_index] {
+                        clobbered_changes
+                            .entry(index)
+                            .or_default()
+                            .push(dest_offset);
                     }
                 }
             }
-            instr
-        })
-        .map_loops(zeroing_loops)
-}
+        }
+    }
 
-/// Remove any loops where we know the current cell is zero.
-fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
     instrs
-        .clone()
         .into_iter()
         .enumerate()
-        .filter(|&(index, ref instr)| {
-            if !matches!(instr, Loop { .. }) {
-                // Keep all instructions that aren't loops.
-                return true;
-            }
+        .map(|(index, instr)| match instr {
+            MultiplyMove {
+                mut changes,
+                position,
+            } => {
+                if let Some(dest_offsets) = clobbered_changes.get(&index) {
+                    for dest_offset in dest_offsets {
+                        changes.remove(dest_offset);
+                    }
+                }
 
-            // Find the previous change instruction:
-            if let Some(prev_change_index) = previous_cell_change(&instrs, index) {
-                let prev_instr = &instrs[prev_change_index];
-                // If the previous instruction set to zero, our loop is dead.
-                // TODO: MultiplyMove also zeroes the current cell.
-                // TODO: define an is_set_zero() helper.
-                if matches!(
-                    prev_instr,
+                if changes.is_empty() {
+                    // We're just zeroing the current cell.
                     Set {
                         amount: Wrapping(0),
                         offset: 0,
-                        ..
+                        position,
                     }
-                ) {
-                    return false;
+                } else {
+                    MultiplyMove { changes, position }
                 }
             }
-            true
+            other => other,
         })
-        .map(|(_, instr)| instr)
-        .map_loops(remove_dead_loops)
+        .map_loops(remove_clobbered_multiply_changes)
 }
 
-/// Reorder flat sequences of instructions so we use offsets and only
-/// have one pointer increment at the end. For example, given "+>+>+<"
-/// we return:
-/// Increment { amount: 1, offset: 0 }
-/// Increment { amount: 1, offset: 1 }
-/// Increment { amount: 2, offset: 2 }
-/// PointerIncrement(1)
-fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut sequence = vec![];
-    let mut result = vec![];
-
-    for instr in instrs {
-        if matches!(
-            instr,
-            Increment { .. } | Set { .. } | PointerIncrement { .. }
-        ) {
-            sequence.push(instr);
-        } else {
-            if !sequence.is_empty() {
-                result.extend(sort_sequence_by_offset(sequence));
-                sequence = vec![];
+/// Given an index into a vector of instructions, find the index of
+/// the next Set or overwriting CellCopy that overwrites the cell at
+/// `offset` from the current cell, before anything uses the cell's
+/// value. If we're unsure, or the value is used, return None.
+///
+/// Unlike `next_offset_cell_change`, this distinguishes overwriting
+/// a cell from adding to it. Increments and multiply targets add
+/// to the current value, so they use it.
+fn next_cell_overwrite(instrs: &[AstNode], index: usize, offset: isize) -> Option<usize> {
+    let mut needed_offset = offset;
+    for (i, instr) in instrs.iter().enumerate().skip(index + 1) {
+        match *instr {
+            Set { offset, .. } => {
+                if offset == needed_offset {
+                    return Some(i);
+                }
             }
-            if let Loop { body, position } = instr {
-                result.push(Loop {
-                    body: sort_by_offset(body),
-                    position,
-                });
-            } else {
-                result.push(instr);
+            Increment { offset, .. } => {
+                if offset == needed_offset {
+                    return None;
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                needed_offset -= amount;
+            }
+            MultiplyMove { ref changes, .. } => {
+                // The current cell is read, and the other cells are
+                // added to.
+                if needed_offset == 0 || changes.contains_key(&needed_offset) {
+                    return None;
+                }
+            }
+            CellCopy {
+                src_offset,
+                dst_offset,
+                overwrite,
+                ..
+            } => {
+                if src_offset == needed_offset {
+                    return None;
+                }
+                if dst_offset == needed_offset {
+                    return if overwrite { Some(i) } else { None };
+                }
+            }
+            Write { offset, .. } => {
+                if offset == needed_offset {
+                    return None;
+                }
+            }
+            // Reads of other cells don't use this cell. Reads of this
+            // cell are handled by remove_read_clobber.
+            Read { offset, .. } => {
+                if offset == needed_offset {
+                    return None;
+                }
             }
+            // Loops may use any cell.
+            Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. } => return None,
         }
     }
+    None
+}
 
-    if !sequence.is_empty() {
-        result.extend(sort_sequence_by_offset(sequence));
+/// Remove increments and sets whose value is always overwritten
+/// before it's used. For example, "+>-<[-]" doesn't need the "+".
+///
+/// We also look past reads of other cells, so "+>,<[-]" doesn't need
+/// the "+" either. Compile time execution stops at reads, so this
+/// changes the cells we see there, but not the program's output.
+fn remove_dead_stores(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut dead_instr_positions = HashSet::new();
+
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Increment { offset, .. } | Set { offset, .. } = *instr {
+            if next_cell_overwrite(&instrs, index, offset).is_some() {
+                dead_instr_positions.insert(index);
+            }
+        }
     }
 
-    result
+    instrs
+        .into_iter()
+        .enumerate()
+        .filter(|&(index, _)| !dead_instr_positions.contains(&index))
+        .map(|(_, instr)| instr)
+        .map_loops(remove_dead_stores)
 }
 
-/// Given a `HashMap` with orderable keys, return the values according to
-/// the key order.
-/// {2: 'foo': 1: 'bar'} => vec!['bar', 'foo']
-fn ordered_values<K: Ord + Hash + Eq, V>(map: HashMap<K, V>) -> Vec<V> {
-    let mut items: Vec<_> = map.into_iter().collect();
-    items.sort_by(|a, b| a.0.cmp(&b.0));
-    items.into_iter().map(|(_, v)| v).collect()
-}
+/// If there's a copy into a temporary cell at `index`, followed by
+/// moving the temporary cell back to the current cell, return a
+/// single MultiplyMove that copies without using the temporary cell.
+///
+/// Both MultiplyMoves add multiples of the current cell's original
+/// value, so the second MultiplyMove may also move the temporary cell
+/// to other cells, including cells the first MultiplyMove changed.
+fn as_copy(instrs: &[AstNode], index: usize) -> Option<AstNode> {
+    if let (
+        Some(MultiplyMove { changes, position }),
+        Some(PointerIncrement {
+            amount: temp_offset,
+            ..
+        }),
+        Some(MultiplyMove {
+            changes: restore_changes,
+            position: restore_position,
+        }),
+    ) = (
+        instrs.get(index),
+        instrs.get(index + 1),
+        instrs.get(index + 2),
+    ) {
+        // The first MultiplyMove must move the current cell to the
+        // temporary cell. With any other factor, the temporary cell
+        // may be zero when the current cell isn't, so the second
+        // MultiplyMove wouldn't run.
+        if changes.get(temp_offset) != Some(&Wrapping(1)) {
+            return None;
+        }
 
-/// Given a BF program, combine sets/increments using offsets so we
-/// have single `PointerIncrement` at the end.
-fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut instrs_by_offset: HashMap<isize, Vec<AstNode>> = HashMap::new();
-    let mut current_offset = 0;
-    let mut last_ptr_inc_pos = None;
+        // The second MultiplyMove must move the temporary cell back
+        // to the original cell, and leave the temporary cell zero.
+        if !restore_changes.contains_key(&-temp_offset) || restore_changes.contains_key(&0) {
+            return None;
+        }
 
-    for instr in instrs {
-        match instr {
-            Increment {
-                amount,
-                offset,
-                position,
-            } => {
-                let new_offset = offset + current_offset;
-                let same_offset_instrs = instrs_by_offset.entry(new_offset).or_default();
-                same_offset_instrs.push(Increment {
-                    amount,
-                    offset: new_offset,
-                    position,
-                });
-            }
-            Set {
-                amount,
-                offset,
-                position,
-            } => {
-                let new_offset = offset + current_offset;
-                let same_offset_instrs = instrs_by_offset.entry(new_offset).or_default();
-                same_offset_instrs.push(Set {
-                    amount,
-                    offset: new_offset,
-                    position,
-                });
-            }
-            PointerIncrement { amount, position } => {
-                current_offset += amount;
-                last_ptr_inc_pos = Some(position);
+        // The temporary cell must be zero beforehand, otherwise we
+        // restore its old value too.
+        match previous_offset_cell_change(instrs, index, *temp_offset) {
+            Some(prev_index) => {
+                if !matches!(
+                    instrs[prev_index],
+                    Set {
+                        amount: Wrapping(0),
+                        ..
+                    }
+                ) {
+                    return None;
+                }
             }
-            // We assume that we were only given a Vec of
-            // Increment/Set/PointerIncrement instructions. It's
-            // the job of this function to create instructions with
-            // offset.
-            _ => unreachable!(),
+            None => return None,
         }
+
+        let mut copy_changes = changes.clone();
+        copy_changes.remove(temp_offset);
+        for (offset, factor) in restore_changes {
+            let copy_factor = copy_changes
+                .entry(temp_offset + offset)
+                .or_insert(Wrapping(0));
+            *copy_factor += *factor;
+        }
+        copy_changes.retain(|_, factor| *factor != Wrapping(0));
+
+        return Some(MultiplyMove {
+            changes: copy_changes,
+            position: position.combine(*restore_position),
+        });
     }
+    None
+}
 
-    // Append the increment/set instructions, in offset order.
-    let mut results: Vec<AstNode> = vec![];
-    for same_offset_instrs in ordered_values(instrs_by_offset) {
-        results.extend(same_offset_instrs.into_iter());
+/// Combine a multiply move into a temporary cell and a multiply move
+/// back again into a single copy. For example, `[->+>+<<]>>[-<<+>>]`
+/// copies cell #0 to cell #1 using cell #2 as a temporary.
+fn combine_copy_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result = Vec::with_capacity(instrs.len());
+
+    let mut index = 0;
+    while index < instrs.len() {
+        if let Some(copy_instr) = as_copy(&instrs, index) {
+            result.push(copy_instr);
+            // We still need to move the pointer to the temporary
+            // cell, as the second MultiplyMove did.
+            result.push(instrs[index + 1].clone());
+            index += 3;
+        } else {
+            result.push(instrs[index].clone());
+            index += 1;
+        }
     }
 
-    // Add a single PointerIncrement at the end, reflecting the net
-    // pointer movement in this instruction sequence.
-    if current_offset != 0 {
-        results.push(PointerIncrement {
-            amount: current_offset,
-            position: last_ptr_inc_pos.unwrap(),
-        });
+    result.into_iter().map_loops(combine_copy_loops)
+}
+
+/// The changes of the MultiplyMove equivalent to `instr`. A CellCopy
+/// that adds the current cell to cell #n is {0: 1, n: 1}.
+fn multiply_changes(instr: &AstNode) -> Option<HashMap<isize, BfValue>> {
+    match *instr {
+        MultiplyMove { ref changes, .. } => Some(changes.clone()),
+        CellCopy {
+            src_offset: 0,
+            dst_offset,
+            overwrite: false,
+            ..
+        } => {
+            let mut changes = HashMap::new();
+            *changes.entry(0).or_insert(Wrapping(0)) += Wrapping(1);
+            *changes.entry(dst_offset).or_insert(Wrapping(0)) += Wrapping(1);
+            Some(changes)
+        }
+        _ => None,
     }
-    results
 }
 
-/// Combine set instructions with other set instructions or
-/// increments.
-fn combine_set_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    // It's sufficient to consider immediately adjacent instructions
-    // as sort_sequence_by_offset ensures that if the offset is the
-    // same, the instruction is adjacent.
+/// Combine consecutive MultiplyMoves from the same cell into a single
+/// MultiplyMove, so we only load the current cell once.
+///
+/// The second MultiplyMove reads whatever the first left in the
+/// current cell. For example, a copy {0: 1, 1: 1} followed by
+/// {3: 1} is equivalent to {1: 1, 3: 1}. A CellCopy that adds the
+/// current cell to another cell can be merged with a MultiplyMove
+/// too.
+fn merge_multiply_moves(instrs: Vec<AstNode>) -> Vec<AstNode> {
     instrs
         .into_iter()
         .coalesce(|prev_instr, instr| {
-            // TODO: Set, Write, Increment -> Set, Write, Set
-            // Inc x, Set y -> Set y
-            if let (
-                &Increment {
-                    offset: inc_offset,
-                    position: inc_pos,
-                    ..
-                },
-                &Set {
-                    amount: set_amount,
-                    offset: set_offset,
-                    position: set_pos,
-                },
-            ) = (&prev_instr, &instr)
-            {
-                if inc_offset == set_offset {
-                    return Ok(Set {
-                        amount: set_amount,
-                        offset: set_offset,
-                        // Whilst the Inc is dead here, by including
-                        // it in the position tracking we can show better warnings.
-                        position: set_pos.combine(inc_pos),
-                    });
-                }
+            // Two CellCopy instructions are cheaper than the
+            // MultiplyMove we'd merge them into.
+            if !matches!(prev_instr, MultiplyMove { .. }) && !matches!(instr, MultiplyMove { .. }) {
+                return Err((prev_instr, instr));
             }
-            Err((prev_instr, instr))
-        })
-        .coalesce(|prev_instr, instr| {
-            // Set x, Inc y -> Set x+y
-            if let Set {
-                amount: set_amount,
-                offset: set_offset,
-                position: set_pos,
-            } = prev_instr
+
+            if let (Some(prev_changes), Some(changes)) =
+                (multiply_changes(&prev_instr), multiply_changes(&instr))
             {
-                if let Increment {
-                    amount: inc_amount,
-                    offset: inc_offset,
-                    position: inc_pos,
-                } = instr
-                {
-                    if inc_offset == set_offset {
-                        return Ok(Set {
-                            amount: set_amount + inc_amount,
-                            offset: set_offset,
-                            position: set_pos.combine(inc_pos),
-                        });
-                    }
+                // The value of the current cell after the first
+                // MultiplyMove, as a multiple of its original value.
+                let remaining = prev_changes.get(&0).copied().unwrap_or(Wrapping(0));
+
+                let mut merged_changes = prev_changes;
+                merged_changes.remove(&0);
+                for (offset, factor) in changes {
+                    let merged_factor = merged_changes.entry(offset).or_insert(Wrapping(0));
+                    *merged_factor += remaining * factor;
                 }
-            }
-            Err((prev_instr, instr))
-        })
-        .coalesce(|prev_instr, instr| {
-            // Set x, Set y -> Set y
-            if let (
-                &Set {
-                    offset: offset1,
-                    position: position1,
-                    ..
-                },
-                &Set {
-                    amount,
-                    offset: offset2,
-                    position: position2,
-                },
-            ) = (&prev_instr, &instr)
-            {
-                if offset1 == offset2 {
+                merged_changes.retain(|_, factor| *factor != Wrapping(0));
+
+                let position = get_position(&prev_instr).combine(get_position(&instr));
+                if merged_changes.is_empty() {
+                    // We're just zeroing the current cell.
                     return Ok(Set {
-                        amount,
-                        offset: offset1,
-                        // Whilst the first Set is dead here, by including
-                        // it in the position tracking we can show better warnings.
-                        position: position1.combine(position2),
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position,
                     });
                 }
+                return Ok(MultiplyMove {
+                    changes: merged_changes,
+                    position,
+                });
             }
             Err((prev_instr, instr))
         })
-        .map_loops(combine_set_and_increments)
+        .map_loops(merge_multiply_moves)
 }
 
-fn remove_redundant_sets(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut reduced = remove_redundant_sets_inner(instrs);
+/// If the instruction at `index` adds one cell to another, return
+/// the equivalent CellCopy. If the destination is known to be zero,
+/// the copy overwrites it, so we don't need to load it.
+fn as_cell_copy(instrs: &[AstNode], index: usize) -> Option<AstNode> {
+    let (src_offset, dst_offset, position) = match instrs[index] {
+        // {0: 1, n: 1} preserves the current cell and adds it to
+        // cell #n.
+        MultiplyMove {
+            ref changes,
+            position,
+        } if changes.len() == 2 && changes.get(&0) == Some(&Wrapping(1)) => {
+            let (&dst_offset, &factor) = changes.iter().find(|(offset, _)| **offset != 0)?;
+            if factor != Wrapping(1) {
+                return None;
+            }
+            (0, dst_offset, position)
+        }
+        CellCopy {
+            src_offset,
+            dst_offset,
+            overwrite: false,
+            position,
+        } => (src_offset, dst_offset, position),
+        _ => return None,
+    };
 
-    // Remove a set zero at the beginning of the program, since cells
-    // are initialised to zero anyway.
-    if matches!(
-        reduced.first(),
-        Some(Set {
-            amount: Wrapping(0),
-            offset: 0,
-            ..
-        })
-    ) {
-        reduced.remove(0);
+    // Adding a cell to itself doubles it, so only overwrite distinct
+    // cells.
+    let overwrite = src_offset != dst_offset
+        && matches!(
+            previous_offset_cell_change(instrs, index, dst_offset).map(|i| &instrs[i]),
+            Some(Set {
+                amount: Wrapping(0),
+                ..
+            })
+        );
+    if !overwrite && matches!(instrs[index], CellCopy { .. }) {
+        return None;
     }
 
-    reduced
+    Some(CellCopy {
+        src_offset,
+        dst_offset,
+        overwrite,
+        position,
+    })
 }
 
-fn remove_redundant_sets_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut redundant_instr_positions = HashSet::new();
-
-    for (index, instr) in instrs.iter().enumerate() {
-        if matches!(instr, Loop { .. } | MultiplyMove { .. }) {
-            // There's no point setting to zero after a loop, as
-            // the cell is already zero.
-            if let Some(next_index) = next_cell_change(&instrs, index) {
-                if let Set {
-                    amount: Wrapping(0),
-                    offset: 0,
-                    ..
-                } = instrs[next_index]
-                {
-                    redundant_instr_positions.insert(next_index);
-                }
-            }
-        }
-    }
+/// Replace MultiplyMoves that copy the current cell to one other
+/// cell with a CellCopy, so we don't test the current cell or
+/// multiply. For example, `copy_loop` converts `[->+>+<<]>>[-<<+>>]`
+/// to {0: 1, 1: 1}, which is a copy from cell #0 to cell #1.
+fn extract_cell_copies(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let copies: Vec<_> = (0..instrs.len())
+        .map(|index| as_cell_copy(&instrs, index))
+        .collect();
 
     instrs
         .into_iter()
-        .enumerate()
-        .filter(|&(index, _)| !redundant_instr_positions.contains(&index))
-        .map(|(_, instr)| instr)
-        .map_loops(remove_redundant_sets_inner)
-}
-
-fn annotate_known_zero(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut result = vec![];
-
-    let position = if instrs.is_empty() {
-        None
-    } else {
-        get_position(&instrs[0]).map(|first_instr_pos| Position {
-            start: first_instr_pos.start,
-            end: first_instr_pos.start,
-        })
-    };
-
-    // Cells in BF are initialised to zero, so we know the current
-    // cell is zero at the start of execution.
-    let set_instr = Set {
-        amount: Wrapping(0),
-        offset: 0,
-        position,
-    };
-    // Insert the set instruction unless there is one already present.
-    if instrs.first() != Some(&set_instr) {
-        result.push(set_instr);
-    }
-
-    result.extend(annotate_known_zero_inner(&instrs));
-    result
+        .zip(copies)
+        .map(|(instr, copy)| copy.unwrap_or(instr))
+        .map_loops(extract_cell_copies)
 }
 
-fn annotate_known_zero_inner(instrs: &[AstNode]) -> Vec<AstNode> {
+/// Replace a MultiplyMove whose current cell has a known value with
+/// increments of the target cells, so we don't need to check the
+/// current cell at runtime. For example, `Set 3` followed by
+/// {1: 2} becomes `Set 0` and `Increment 6` at offset 1.
+///
+/// Likewise, a CellCopy from a cell with a known value becomes a Set
+/// or Increment of the destination cell.
+fn fold_known_multiply_moves(instrs: Vec<AstNode>) -> Vec<AstNode> {
     let mut result = Vec::with_capacity(instrs.len());
 
-    for (i, instr) in instrs.iter().enumerate() {
-        let instr = instr.clone();
-
-        match instr {
-            // After a loop, we know the cell is currently zero.
-            Loop { body, position } => {
-                result.push(Loop {
-                    body: annotate_known_zero_inner(&body),
-                    position,
-                });
-                // Treat this set as positioned at the ].
-                let set_pos = position.map(|loop_pos| Position {
-                    start: loop_pos.end,
-                    end: loop_pos.end,
+    for (index, instr) in instrs.iter().enumerate() {
+        if let CellCopy {
+            src_offset,
+            dst_offset,
+            overwrite,
+            position,
+        } = *instr
+        {
+            if let Some(&Set { amount: value, .. }) =
+                previous_offset_cell_change(&instrs, index, src_offset).map(|i| &instrs[i])
+            {
+                result.push(if overwrite {
+                    Set {
+                        amount: value,
+                        offset: dst_offset,
+                        position,
+                    }
+                } else {
+                    Increment {
+                        amount: value,
+                        offset: dst_offset,
+                        position,
+                    }
                 });
+                continue;
+            }
+        }
 
-                let set_instr = Set {
-                    amount: Wrapping(0),
-                    offset: 0,
-                    position: set_pos,
-                };
-                if instrs.get(i + 1) != Some(&set_instr) {
-                    result.push(set_instr.clone());
+        if let MultiplyMove { changes, position } = instr {
+            if let Some(Set { amount: value, .. }) =
+                previous_cell_change(&instrs, index).map(|prev_index| &instrs[prev_index])
+            {
+                // Apply changes in offset order, so output is
+                // deterministic.
+                let mut changes: Vec<_> = changes.iter().collect();
+                changes.sort();
+
+                let mut current_cell = Wrapping(0);
+                for (offset, factor) in changes {
+                    if *offset == 0 {
+                        current_cell = *value * *factor;
+                    } else if *value * *factor != Wrapping(0) {
+                        result.push(Increment {
+                            amount: *value * *factor,
+                            offset: *offset,
+                            position: *position,
+                        });
+                    }
                 }
-            }
-            _ => {
-                result.push(instr);
+                result.push(Set {
+                    amount: current_cell,
+                    offset: 0,
+                    position: *position,
+                });
+                continue;
             }
         }
+        result.push(instr.clone());
     }
 
-    result
+    result.into_iter().map_loops(fold_known_multiply_moves)
 }
 
-/// Remove code at the end of the program that has no side
-/// effects. This means we have no write commands afterwards, nor
-/// loops (which may not terminate so we should not remove).
-fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>) {
-    let mut pure_instrs = vec![];
-
-    while let Some(last_instr) = instrs.pop() {
-        match last_instr {
-            Read { .. } | Write { .. } | Loop { .. } => {
-                instrs.push(last_instr);
-                break;
-            }
-            _ => {
-                pure_instrs.push(last_instr);
+/// Convert [-] to Set 0.
+///
+/// Cells wrap around, so a loop that adds any odd amount to its cell,
+/// such as [+] or [---], always reaches zero too. Loops that add an
+/// even amount, such as [--], never terminate if the cell is odd, so
+/// we leave them alone.
+///
+/// If we know the cell is a multiple of the amount's largest power of
+/// two, such as 4 before [--], the loop does reach zero, so we
+/// convert it too.
+///
+/// Nested clears such as [[-]] are also Set 0: the outer loop only
+/// runs when the cell is non-zero, and its body always leaves the cell
+/// zero, so the outer loop runs at most once. We convert the inner
+/// loop first, and the outer loop on the next iteration of
+/// `optimize`.
+fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let known_zeroing_indexes: HashSet<usize> = instrs
+        .iter()
+        .enumerate()
+        .filter(|&(index, instr)| match instr {
+            Loop { body, offset, .. } => {
+                body.len() == 1 && is_known_zeroing_instr(&instrs, index, &body[0], *offset)
             }
-        }
-    }
-
-    let warning = if pure_instrs.is_empty() {
-        None
-    } else {
-        let position = pure_instrs
-            .into_iter()
-            .map(|instr| get_position(&instr))
-            .filter(|pos| pos.is_some())
-            .reduce(|pos1, pos2| pos1.combine(pos2))
-            .map(|pos| pos.unwrap());
-        Some(Warning {
-            message: "These instructions have no effect.".to_owned(),
-            position,
+            _ => false,
         })
-    };
+        .map(|(index, _)| index)
+        .collect();
 
-    (instrs, warning)
+    instrs
+        .into_iter()
+        .enumerate()
+        .map(|(index, instr)| {
+            if let Loop {
+                ref body,
+                offset,
+                position,
+            } = instr
+            {
+                if body.len() == 1
+                    && (is_zeroing_instr(&body[0], offset)
+                        || known_zeroing_indexes.contains(&index))
+                {
+                    return Set {
+                        amount: Wrapping(0),
+                        offset,
+                        position,
+                    };
+                }
+            }
+            instr
+        })
+        .map_loops(zeroing_loops)
 }
 
-/// Does this loop body represent a multiplication operation?
-/// E.g. "[->>>++<<<]" sets cell #3 to 2*cell #0.
-fn is_multiply_loop_body(body: &[AstNode]) -> bool {
-    // A multiply loop may only contain increments and pointer increments.
-    for body_instr in body {
-        match *body_instr {
-            Increment { .. } | PointerIncrement { .. } => {}
-            _ => return false,
-        }
-    }
-
-    // A multiply loop must have a net pointer movement of
-    // zero.
-    let mut net_movement = 0;
-    for body_instr in body {
-        if let PointerIncrement { amount, .. } = *body_instr {
-            net_movement += amount;
-        }
-    }
-    if net_movement != 0 {
-        return false;
-    }
-
-    let changes = cell_changes(body);
-    // A multiply loop must decrement cell #0.
-    if let Some(&Wrapping(-1)) = changes.get(&0) {
-    } else {
-        return false;
+/// Is `instr` the body of a loop at `offset` that always sets the
+/// loop's cell to zero?
+fn is_zeroing_instr(instr: &AstNode, offset: isize) -> bool {
+    match *instr {
+        // [-], or any other odd increment.
+        Increment {
+            amount,
+            offset: inc_offset,
+            ..
+        } => inc_offset == offset && amount.0 % 2 != 0,
+        // A loop body that we've already converted to Set 0.
+        Set {
+            amount: Wrapping(0),
+            offset: set_offset,
+            ..
+        } => set_offset == offset,
+        _ => false,
     }
-
-    changes.len() >= 2
 }
 
-/// Return a hashmap of all the cells that are affected by this
-/// sequence of instructions, and how much they change.
-/// E.g. "->>+++>+" -> {0: -1, 2: 3, 3: 1}
-fn cell_changes(instrs: &[AstNode]) -> HashMap<isize, BfValue> {
-    let mut changes = HashMap::new();
-    let mut cell_index: isize = 0;
-
-    for instr in instrs {
-        match *instr {
-            Increment { amount, offset, .. } => {
-                let current_amount = *changes.get(&(cell_index + offset)).unwrap_or(&Wrapping(0));
-                changes.insert(cell_index, current_amount + amount);
-            }
-            PointerIncrement { amount, .. } => {
-                cell_index += amount;
-            }
-            // We assume this is only called from is_multiply_loop.
-            _ => unreachable!(),
+/// Is `instr` the body of the loop at `index`, which tests the cell at
+/// `offset`, and does it always reach zero given what we know about
+/// the cell's value? An increment of 2^k * m, where m is odd, reaches
+/// zero exactly when the cell is a multiple of 2^k.
+fn is_known_zeroing_instr(
+    instrs: &[AstNode],
+    index: usize,
+    instr: &AstNode,
+    offset: isize,
+) -> bool {
+    match *instr {
+        Increment {
+            amount,
+            offset: inc_offset,
+            ..
+        } if inc_offset == offset && amount.0 != 0 => {
+            let divisor = 1 << (amount.0 as u8).trailing_zeros();
+            is_known_multiple(instrs, index, offset, divisor)
         }
+        _ => false,
     }
-
-    changes
 }
 
-fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
+/// Convert [>] and [<] to ScanLoop.
+fn extract_scan_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
     instrs
         .into_iter()
         .map(|instr| {
-            match instr {
-                Loop { body, position } => {
-                    if is_multiply_loop_body(&body) {
-                        let mut changes = cell_changes(&body);
-                        // MultiplyMove is for where we move to, so ignore
-                        // the cell we're moving from.
-                        changes.remove(&0);
-
-                        MultiplyMove { changes, position }
-                    } else {
-                        Loop {
-                            body: extract_multiply(body),
+            if let Loop {
+                ref body,
+                offset: 0,
+                position,
+            } = instr
+            {
+                // If the loop is [>] or [<]
+                if body.len() == 1 {
+                    if let PointerIncrement { amount, .. } = body[0] {
+                        return ScanLoop {
+                            step: amount,
                             position,
-                        }
+                        };
                     }
                 }
-                i => i,
             }
+            instr
         })
-        .collect()
+        .map_loops(extract_scan_loops)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use std::collections::HashMap;
-    use std::num::Wrapping;
+/// Does this loop body leave the cell at `offset` unchanged, without
+/// any other side effects? If so, the loop never terminates once
+/// entered.
+fn is_non_progressing_loop_body(body: &[AstNode], offset: isize) -> bool {
+    body.iter().all(|instr| match instr {
+        Increment {
+            offset: change_offset,
+            ..
+        }
+        | Set {
+            offset: change_offset,
+            ..
+        } => *change_offset != offset,
+        // A MultiplyMove zeroes the current cell.
+        MultiplyMove { changes, .. } => offset != 0 && !changes.contains_key(&offset),
+        CellCopy { dst_offset, .. } => *dst_offset != offset,
+        _ => false,
+    })
+}
 
-    use pretty_assertions::assert_eq;
-    use quickcheck::quickcheck;
-    use quickcheck::{Arbitrary, Gen, TestResult};
+/// Convert loops that can never terminate, such as [] or [>+<], to
+/// InfiniteLoop.
+fn canonicalize_infinite_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    instrs
+        .into_iter()
+        .map(|instr| {
+            if let Loop {
+                ref body,
+                offset,
+                position,
+            } = instr
+            {
+                if is_non_progressing_loop_body(body, offset) {
+                    return InfiniteLoop { offset, position };
+                }
+            }
+            instr
+        })
+        .map_loops(canonicalize_infinite_loops)
+}
 
-    use crate::bfir::parse;
-    use crate::bfir::{AstNode, Position};
-    use crate::diagnostics::Warning;
+/// Move pointer increments after the loops that follow them, so
+/// the loop tests and modifies cells at an offset instead. For
+/// example, ">[-<+>]" becomes a loop at offset 1, followed by ">".
+///
+/// This lets `sort_by_offset` combine the pointer increment with any
+/// instructions after the loop.
+fn absorb_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    // If nothing with side effects follows a loop at the top level,
+    // the moved pointer increment would be removed by
+    // `remove_pure_code`, and we'd warn about code that did have an
+    // effect.
+    let last_effect_index = instrs
+        .iter()
+        .rposition(|instr| {
+            matches!(
+                instr,
+                Read { .. } | Write { .. } | Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. }
+            )
+        })
+        .unwrap_or(0);
 
-    impl Arbitrary for AstNode {
-        fn arbitrary<G: Gen>(g: &mut G) -> AstNode {
-            arbitrary_instr(g, 5)
-        }
-    }
+    absorb_ptr_increments_inner(instrs, last_effect_index)
+}
 
-    // We define a separate function so we can recurse on max_depth.
-    // See https://github.com/BurntSushi/quickcheck/issues/23
-    fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
-        let modulus = if max_depth == 0 { 8 } else { 9 };
+fn absorb_ptr_increments_inner(instrs: Vec<AstNode>, limit: usize) -> Vec<AstNode> {
+    let mut result: Vec<AstNode> = Vec::with_capacity(instrs.len());
 
-        // If max_depth is zero, don't create loops.
-        match g.next_u32() % modulus {
-            // TODO: use arbitrary offsets.
-            0 => Increment {
-                amount: Wrapping(Arbitrary::arbitrary(g)),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            1 => PointerIncrement {
-                amount: Arbitrary::arbitrary(g),
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            // TODO: use arbitrary offsets.
-            2 => Set {
-                amount: Wrapping(Arbitrary::arbitrary(g)),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            3 => Read {
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            4 => Write {
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            5 => {
-                let mut changes = HashMap::new();
-                changes.insert(1, Wrapping(-1));
-                MultiplyMove {
-                    changes,
-                    position: Some(Position { start: 0, end: 0 }),
-                }
-            }
-            6 => {
-                let mut changes = HashMap::new();
-                changes.insert(1, Wrapping(2));
-                changes.insert(4, Wrapping(10));
-                MultiplyMove {
-                    changes,
-                    position: Some(Position { start: 0, end: 0 }),
-                }
-            }
-            7 => {
-                // A multiply by 2 loop that accesses a previous
-                // cell. Quickcheck doesn't seem to generate these by
-                // chance, but they often expose interesting bugs.
-                let body = vec![
-                    Increment {
-                        amount: Wrapping(-1),
-                        offset: 0,
-                        position: None,
-                    },
-                    PointerIncrement {
-                        amount: -1,
-                        position: None,
-                    },
-                    Increment {
-                        amount: Wrapping(2),
-                        offset: 0,
-                        position: None,
-                    },
-                    PointerIncrement {
-                        amount: 1,
-                        position: None,
-                    },
-                ];
+    for (index, instr) in instrs.into_iter().enumerate() {
+        match (result.pop(), instr) {
+            (
+                Some(PointerIncrement {
+                    amount,
+                    position: ptr_position,
+                }),
                 Loop {
                     body,
-                    position: None,
-                }
+                    offset,
+                    position,
+                },
+            ) if index < limit && is_shiftable_loop_body(&body) => {
+                result.push(Loop {
+                    body: shift_offsets(body, amount),
+                    offset: offset + amount,
+                    position,
+                });
+                result.push(PointerIncrement {
+                    amount,
+                    position: ptr_position,
+                });
             }
-            8 => {
-                assert!(max_depth > 0);
-                let loop_length = g.next_u32() % 10;
-                let mut body: Vec<_> = vec![];
-                for _ in 0..loop_length {
-                    body.push(arbitrary_instr(g, max_depth - 1));
-                }
-                Loop {
-                    body,
-                    position: Some(Position { start: 0, end: 0 }),
-                }
+            (prev_instr, instr) => {
+                result.extend(prev_instr);
+                result.push(instr);
             }
-            _ => unreachable!(),
         }
     }
 
-    #[test]
-    fn combine_increments_flat() {
-        let initial = parse("++").unwrap();
-        let expected = vec![Increment {
-            amount: Wrapping(2),
-            offset: 0,
-            position: Some(Position { start: 0, end: 1 }),
-        }];
-        assert_eq!(combine_increments(initial), expected);
-    }
+    result
+        .into_iter()
+        .map_loops(|body| absorb_ptr_increments_inner(body, usize::MAX))
+}
 
-    #[test]
-    fn combine_increments_unrelated() {
-        let initial = parse("+>+.").unwrap();
-        let expected = initial.clone();
-        assert_eq!(combine_increments(initial), expected);
+/// Can we move this loop body to a different offset? The body may
+/// only contain instructions with offsets (or loops that satisfy
+/// the same rules), and must have no net pointer movement.
+fn is_shiftable_loop_body(body: &[AstNode]) -> bool {
+    let mut net_movement = 0;
+    for instr in body {
+        match instr {
+            Increment { .. } | Set { .. } | Read { .. } | Write { .. } | CellCopy { .. } => {}
+            PointerIncrement { amount, .. } => net_movement += amount,
+            Loop { body, .. } => {
+                if !is_shiftable_loop_body(body) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
     }
+    net_movement == 0
+}
 
-    #[test]
-    fn combine_increments_nested() {
+/// Add `shift` to the offset of every instruction in this loop body.
+fn shift_offsets(instrs: Vec<AstNode>, shift: isize) -> Vec<AstNode> {
+    instrs
+        .into_iter()
+        .map(|instr| match instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => Increment {
+                amount,
+                offset: offset + shift,
+                position,
+            },
+            Set {
+                amount,
+                offset,
+                position,
+            } => Set {
+                amount,
+                offset: offset + shift,
+                position,
+            },
+            Read { offset, position } => Read {
+                offset: offset + shift,
+                position,
+            },
+            Write { offset, position } => Write {
+                offset: offset + shift,
+                position,
+            },
+            CellCopy {
+                src_offset,
+                dst_offset,
+                overwrite,
+                position,
+            } => CellCopy {
+                src_offset: src_offset + shift,
+                dst_offset: dst_offset + shift,
+                overwrite,
+                position,
+            },
+            Loop {
+                body,
+                offset,
+                position,
+            } => Loop {
+                body: shift_offsets(body, shift),
+                offset: offset + shift,
+                position,
+            },
+            other => other,
+        })
+        .collect()
+}
+
+/// Move a pointer increment at the start of a loop body outside the
+/// loop, by rotating the loop. For example, `[>[-<+>]<-]` is
+/// equivalent to `>`, a loop at offset -1 with a body of `[-<+>]<->`,
+/// then `<`.
+///
+/// This lets the pointer increments in the body cancel out, so the
+/// body can use offsets instead. We only do this for loops with no
+/// net pointer movement that `absorb_ptr_increments` can't handle.
+///
+/// The hoisted increment runs even if the loop body doesn't. We only
+/// hoist increments that move right, as moving left could move the
+/// pointer before cell #0.
+fn hoist_loop_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    // As with `absorb_ptr_increments`, don't add pointer increments
+    // after the last instruction with side effects.
+    let last_effect_index = instrs
+        .iter()
+        .rposition(|instr| {
+            matches!(
+                instr,
+                Read { .. } | Write { .. } | Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. }
+            )
+        })
+        .unwrap_or(0);
+
+    hoist_loop_ptr_increments_inner(instrs, last_effect_index)
+}
+
+fn hoist_loop_ptr_increments_inner(instrs: Vec<AstNode>, limit: usize) -> Vec<AstNode> {
+    let mut result: Vec<AstNode> = Vec::with_capacity(instrs.len());
+
+    for (index, instr) in instrs.into_iter().enumerate() {
+        match instr {
+            Loop {
+                mut body,
+                offset,
+                position,
+            } if index < limit
+                && matches!(body.first(), Some(PointerIncrement { amount, .. }) if *amount > 0)
+                && net_ptr_movement(&body) == Some(0)
+                && !is_shiftable_loop_body(&body) =>
+            {
+                let first_instr = body.remove(0);
+                let (amount, ptr_position) = match first_instr {
+                    PointerIncrement { amount, position } => (amount, position),
+                    _ => unreachable!(),
+                };
+
+                result.push(PointerIncrement {
+                    amount,
+                    position: ptr_position,
+                });
+                body.push(PointerIncrement {
+                    amount,
+                    position: ptr_position,
+                });
+                result.push(Loop {
+                    body,
+                    offset: offset - amount,
+                    position,
+                });
+                result.push(PointerIncrement {
+                    amount: -amount,
+                    position: ptr_position,
+                });
+            }
+            instr => result.push(instr),
+        }
+    }
+
+    result
+        .into_iter()
+        .map_loops(|body| hoist_loop_ptr_increments_inner(body, usize::MAX))
+}
+
+/// The net pointer movement of these instructions, or None if it
+/// depends on cell values (e.g. scan loops).
+fn net_ptr_movement(instrs: &[AstNode]) -> Option<isize> {
+    let mut net_movement = 0;
+    for instr in instrs {
+        match instr {
+            PointerIncrement { amount, .. } => net_movement += amount,
+            ScanLoop { .. } => return None,
+            // Loops that move the pointer move it an unknown amount.
+            Loop { body, .. } if net_ptr_movement(body) != Some(0) => return None,
+            _ => {}
+        }
+    }
+    Some(net_movement)
+}
+
+/// If `body` is a loop body we can safely repeat a fixed number of
+/// times, return how much each iteration changes the cell at
+/// `loop_offset`. Bodies must not read input, contain other loops,
+/// or overwrite the loop cell, and must leave the pointer where it
+/// started.
+fn unrollable_loop_delta(body: &[AstNode], loop_offset: isize) -> Option<BfValue> {
+    let mut ptr_offset = 0;
+    let mut delta = Wrapping(0);
+    for instr in body {
+        match instr {
+            Increment { amount, offset, .. } => {
+                if ptr_offset + offset == loop_offset {
+                    delta += *amount;
+                }
+            }
+            Set { offset, .. }
+            | CellCopy {
+                dst_offset: offset, ..
+            } => {
+                if ptr_offset + offset == loop_offset {
+                    return None;
+                }
+            }
+            PointerIncrement { amount, .. } => ptr_offset += amount,
+            Write { .. } => {}
+            Read { .. }
+            | Loop { .. }
+            | ScanLoop { .. }
+            | InfiniteLoop { .. }
+            | MultiplyMove { .. } => return None,
+        }
+    }
+
+    if ptr_offset == 0 {
+        Some(delta)
+    } else {
+        None
+    }
+}
+
+/// Replace loops that run a small, known number of times with
+/// copies of their body. For example, `Set 2` followed by `[.-]`
+/// becomes `Set 2 . - . -`, which `combine_set` can then simplify.
+///
+/// We only unroll loops where the loop cell was set just before the
+/// loop, and the body changes it by the same amount on every
+/// iteration. Loops that would run more than `limit` times are left
+/// alone.
+fn unroll_constant_loops(instrs: Vec<AstNode>, limit: u64) -> Vec<AstNode> {
+    let mut result = Vec::with_capacity(instrs.len());
+
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Loop { body, offset, .. } = instr {
+            if let Some(Set { amount: value, .. }) =
+                previous_offset_cell_change(&instrs, index, *offset).map(|i| &instrs[i])
+            {
+                if let Some(delta) = unrollable_loop_delta(body, *offset) {
+                    // Find the first iteration that leaves zero in
+                    // the loop cell.
+                    let mut cell = *value;
+                    let mut iterations = 0;
+                    while cell != Wrapping(0) && iterations < limit {
+                        cell += delta;
+                        iterations += 1;
+                    }
+
+                    // If the cell is already zero, `dead_loop` will
+                    // remove the loop.
+                    if cell == Wrapping(0) && iterations > 0 {
+                        for _ in 0..iterations {
+                            result.extend(body.iter().cloned());
+                        }
+                        continue;
+                    }
+                }
+            }
+        }
+        result.push(instr.clone());
+    }
+
+    result
+        .into_iter()
+        .map_loops(|body| unroll_constant_loops(body, limit))
+}
+
+/// If `body` starts by setting the loop cell to zero, and nothing
+/// afterwards in the body changes it, return true. Such a loop runs
+/// at most once, because the cell is always zero when we test it
+/// again.
+fn clears_loop_cell_on_entry(body: &[AstNode], loop_offset: isize) -> bool {
+    match body.first() {
+        Some(Set {
+            amount: Wrapping(0),
+            offset,
+            ..
+        }) if *offset == loop_offset => {}
+        _ => return false,
+    }
+
+    let mut ptr_offset = 0;
+    for instr in &body[1..] {
+        match instr {
+            Increment { offset, .. }
+            | Set { offset, .. }
+            | Read { offset, .. }
+            | CellCopy {
+                dst_offset: offset, ..
+            } => {
+                if ptr_offset + offset == loop_offset {
+                    return false;
+                }
+            }
+            // The current cell is zeroed, and the other cells are
+            // added to.
+            MultiplyMove { changes, .. } => {
+                if ptr_offset == loop_offset || changes.contains_key(&(loop_offset - ptr_offset)) {
+                    return false;
+                }
+            }
+            PointerIncrement { amount, .. } => ptr_offset += amount,
+            Write { .. } => {}
+            // Loops may change any cell.
+            Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. } => return false,
+        }
+    }
+
+    ptr_offset == 0
+}
+
+/// Replace loops that always run exactly once with their body.
+///
+/// A loop that starts by clearing its cell, such as `[[-]>+<]`, runs
+/// at most once. We can't remove the clear, as the rest of the body
+/// may observe the zero, but if we know the cell is non-zero when we
+/// reach the loop, the loop always runs once and we don't need to
+/// test the cell at all.
+fn remove_run_once_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result = Vec::with_capacity(instrs.len());
+
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Loop { body, offset, .. } = instr {
+            if let Some(Set { amount: value, .. }) =
+                previous_offset_cell_change(&instrs, index, *offset).map(|i| &instrs[i])
+            {
+                if *value != Wrapping(0) && clears_loop_cell_on_entry(body, *offset) {
+                    result.extend(body.iter().cloned());
+                    continue;
+                }
+            }
+        }
+        result.push(instr.clone());
+    }
+
+    result.into_iter().map_loops(remove_run_once_loops)
+}
+
+/// Remove any loops where we know the current cell is zero.
+fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    instrs
+        .clone()
+        .into_iter()
+        .enumerate()
+        .filter(|&(index, ref instr)| {
+            if !matches!(instr, Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. }) {
+                // Keep all instructions that aren't loops.
+                return true;
+            }
+
+            // The loop condition may test a cell other than the
+            // current cell.
+            let loop_offset = match instr {
+                Loop { offset, .. } | InfiniteLoop { offset, .. } => *offset,
+                _ => 0,
+            };
+
+            // A loop only exits when its cell is zero, so a loop on
+            // the same cell straight afterwards never runs, e.g.
+            // `[x][y]`. We don't need annotate_known_zero for this.
+            let prev_zeroed_offset = match index.checked_sub(1).map(|i| &instrs[i]) {
+                Some(Loop { offset, .. } | InfiniteLoop { offset, .. }) => Some(*offset),
+                Some(ScanLoop { .. }) => Some(0),
+                _ => None,
+            };
+            if prev_zeroed_offset == Some(loop_offset) {
+                return false;
+            }
+
+            // Find the previous change instruction:
+            if let Some(prev_change_index) =
+                previous_offset_cell_change(&instrs, index, loop_offset)
+            {
+                let prev_instr = &instrs[prev_change_index];
+                // If the previous instruction set to zero, our loop is dead.
+                // TODO: MultiplyMove also zeroes the current cell.
+                // TODO: define an is_set_zero() helper.
+                if let Set {
+                    amount: Wrapping(0),
+                    offset,
+                    ..
+                } = prev_instr
+                {
+                    if *offset == loop_offset {
+                        return false;
+                    }
+                }
+            }
+            true
+        })
+        .map(|(_, instr)| instr)
+        .map_loops(remove_dead_loops)
+}
+
+/// Remove instructions after an infinite loop whose cell we know is
+/// non-zero, as we never reach them.
+///
+/// We also warn about the loop itself, as the program provably never
+/// terminates.
+fn remove_unreachable_code(instrs: Vec<AstNode>) -> (Vec<AstNode>, Vec<Warning>) {
+    let mut warnings = vec![];
+
+    let entered_loop_index = instrs.iter().enumerate().position(|(index, instr)| {
+        if let InfiniteLoop { offset, .. } = *instr {
+            if let Some(prev_change_index) = previous_offset_cell_change(&instrs, index, offset) {
+                if let Set {
+                    amount,
+                    offset: set_offset,
+                    ..
+                } = instrs[prev_change_index]
+                {
+                    return set_offset == offset && amount.0 != 0;
+                }
+            }
+        }
+        false
+    });
+
+    if let Some(index) = entered_loop_index {
+        warnings.push(Warning {
+            message: "This loop never terminates, because its cell is never zero.".to_owned(),
+            position: get_position(&instrs[index]),
+        });
+    }
+
+    let reachable_len = entered_loop_index.map_or(instrs.len(), |index| index + 1);
+    let mut instrs = instrs;
+    let unreachable_instrs = instrs.split_off(reachable_len);
+    if !unreachable_instrs.is_empty() {
+        let position = unreachable_instrs
+            .iter()
+            .map(get_position)
+            .filter(|pos| pos.is_some())
+            .reduce(|pos1, pos2| pos1.combine(pos2))
+            .map(|pos| pos.unwrap());
+        warnings.push(Warning {
+            message: "This code is unreachable, because the loop before it never terminates."
+                .to_owned(),
+            position,
+        });
+    }
+
+    let instrs = instrs
+        .into_iter()
+        .map(|instr| match instr {
+            Loop {
+                body,
+                offset,
+                position,
+            } => {
+                let (body, body_warnings) = remove_unreachable_code(body);
+                warnings.extend(body_warnings);
+                Loop {
+                    body,
+                    offset,
+                    position,
+                }
+            }
+            other => other,
+        })
+        .collect();
+
+    (instrs, warnings)
+}
+
+/// Reorder flat sequences of instructions so we use offsets and only
+/// have one pointer increment at the end. For example, given "+>+>+<"
+/// we return:
+/// Increment { amount: 1, offset: 0 }
+/// Increment { amount: 1, offset: 1 }
+/// Increment { amount: 2, offset: 2 }
+/// PointerIncrement(1)
+///
+/// Reads and writes are included in sequences, so ">.>" becomes a
+/// write at offset 1 followed by a pointer increment of 2.
+fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    // If nothing but reads and writes follow a sequence at the top
+    // level, the pointer increments would end up after the last
+    // write and be removed by `remove_pure_code`, and we'd warn about
+    // code that did have an effect. Keep reads and writes as sequence
+    // boundaries there.
+    let io_limit = instrs
+        .iter()
+        .rposition(|instr| {
+            !matches!(
+                instr,
+                Increment { .. }
+                    | Set { .. }
+                    | PointerIncrement { .. }
+                    | Read { .. }
+                    | Write { .. }
+            )
+        })
+        .map_or(0, |index| index + 1);
+
+    sort_by_offset_inner(instrs, io_limit)
+}
+
+fn sort_by_offset_inner(instrs: Vec<AstNode>, io_limit: usize) -> Vec<AstNode> {
+    let mut sequence = vec![];
+    let mut result = vec![];
+
+    for (index, instr) in instrs.into_iter().enumerate() {
+        let is_sequence_instr = match instr {
+            Increment { .. } | Set { .. } | PointerIncrement { .. } => true,
+            Read { .. } | Write { .. } => index < io_limit,
+            _ => false,
+        };
+        if is_sequence_instr {
+            sequence.push(instr);
+        } else {
+            if !sequence.is_empty() {
+                result.extend(sort_sequence_by_offset(sequence));
+                sequence = vec![];
+            }
+            if let Loop {
+                body,
+                offset,
+                position,
+            } = instr
+            {
+                result.push(Loop {
+                    body: sort_by_offset_inner(body, usize::MAX),
+                    offset,
+                    position,
+                });
+            } else {
+                result.push(instr);
+            }
+        }
+    }
+
+    if !sequence.is_empty() {
+        result.extend(sort_sequence_by_offset(sequence));
+    }
+
+    hoist_past_multiply_moves(result)
+}
+
+/// A `MultiplyMove` ends a sequence, so sorting can't combine changes
+/// to the same cell either side of it. If the move doesn't use that
+/// cell, move the later changes before it. For example, `>[-]<` then
+/// a move from cell 0 to cell 2 then `>+<` becomes `>[-]+<` then the
+/// move, which `combine_set` can simplify to a set.
+///
+/// We only hoist increments and sets that appear before any other
+/// instruction after the move, and only when the sequence before the
+/// move changes the same cell.
+fn hoist_past_multiply_moves(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut remaining: VecDeque<AstNode> = instrs.into();
+    let mut result = vec![];
+
+    while let Some(instr) = remaining.pop_front() {
+        if let MultiplyMove { ref changes, .. } = instr {
+            let sequence_start = result
+                .iter()
+                .rposition(|instr| {
+                    !matches!(
+                        instr,
+                        Increment { .. }
+                            | Set { .. }
+                            | PointerIncrement { .. }
+                            | Read { .. }
+                            | Write { .. }
+                    )
+                })
+                .map_or(0, |index| index + 1);
+
+            // Cells changed by the preceding sequence, relative to the
+            // cell pointer at the move.
+            let mut changed_cells = HashSet::new();
+            let mut ptr_offset = 0;
+            for prev_instr in &result[sequence_start..] {
+                match prev_instr {
+                    Increment { offset, .. } | Set { offset, .. } => {
+                        changed_cells.insert(ptr_offset + offset);
+                    }
+                    PointerIncrement { amount, .. } => ptr_offset += amount,
+                    _ => {}
+                }
+            }
+            let changed_cells: HashSet<isize> = changed_cells
+                .into_iter()
+                .map(|cell| cell - ptr_offset)
+                .collect();
+
+            let mut hoisted = vec![];
+            let mut index = 0;
+            while index < remaining.len() {
+                let offset = match remaining[index] {
+                    Increment { offset, .. } | Set { offset, .. } => offset,
+                    _ => break,
+                };
+                if offset != 0 && !changes.contains_key(&offset) && changed_cells.contains(&offset)
+                {
+                    hoisted.push(remaining.remove(index).unwrap());
+                } else {
+                    index += 1;
+                }
+            }
+
+            if !hoisted.is_empty() {
+                let mut sequence = result.split_off(sequence_start);
+                sequence.extend(hoisted);
+                result.extend(sort_sequence_by_offset(sequence));
+            }
+        }
+        result.push(instr);
+    }
+
+    result
+}
+
+/// Given a `HashMap` with orderable keys, return the values according to
+/// the key order.
+/// {2: 'foo': 1: 'bar'} => vec!['bar', 'foo']
+fn ordered_values<K: Ord + Hash + Eq, V>(map: HashMap<K, V>) -> Vec<V> {
+    let mut items: Vec<_> = map.into_iter().collect();
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    items.into_iter().map(|(_, v)| v).collect()
+}
+
+/// Given a BF program, combine sets/increments using offsets so we
+/// have single `PointerIncrement` at the end.
+///
+/// Reads and writes stay in their original order. Changes to the
+/// cell a write uses stay on the same side of it, but changes to
+/// other cells may move past it. Compile time execution stops at
+/// reads, so no changes move past a read.
+fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut instrs_by_offset: HashMap<isize, Vec<AstNode>> = HashMap::new();
+    let mut current_offset = 0;
+    let mut last_ptr_inc_pos = None;
+    let mut results: Vec<AstNode> = vec![];
+
+    for instr in instrs {
+        match instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => {
+                let new_offset = offset + current_offset;
+                let same_offset_instrs = instrs_by_offset.entry(new_offset).or_default();
+                same_offset_instrs.push(Increment {
+                    amount,
+                    offset: new_offset,
+                    position,
+                });
+            }
+            Set {
+                amount,
+                offset,
+                position,
+            } => {
+                let new_offset = offset + current_offset;
+                let same_offset_instrs = instrs_by_offset.entry(new_offset).or_default();
+                same_offset_instrs.push(Set {
+                    amount,
+                    offset: new_offset,
+                    position,
+                });
+            }
+            PointerIncrement { amount, position } => {
+                current_offset += amount;
+                last_ptr_inc_pos = Some(position);
+            }
+            Read { offset, position } => {
+                let new_offset = offset + current_offset;
+                for same_offset_instrs in ordered_values(std::mem::take(&mut instrs_by_offset)) {
+                    results.extend(same_offset_instrs);
+                }
+                results.push(Read {
+                    offset: new_offset,
+                    position,
+                });
+            }
+            Write { offset, position } => {
+                let new_offset = offset + current_offset;
+                if let Some(same_offset_instrs) = instrs_by_offset.remove(&new_offset) {
+                    results.extend(same_offset_instrs);
+                }
+                results.push(Write {
+                    offset: new_offset,
+                    position,
+                });
+            }
+            // We assume that we were only given a Vec of
+            // Increment/Set/PointerIncrement/Read/Write
+            // instructions. It's the job of this function to create
+            // instructions with offset.
+            _ => unreachable!(),
+        }
+    }
+
+    // Append the remaining increment/set instructions, in offset order.
+    for same_offset_instrs in ordered_values(instrs_by_offset) {
+        results.extend(same_offset_instrs.into_iter());
+    }
+
+    // Add a single PointerIncrement at the end, reflecting the net
+    // pointer movement in this instruction sequence.
+    if current_offset != 0 {
+        results.push(PointerIncrement {
+            amount: current_offset,
+            position: last_ptr_inc_pos.unwrap(),
+        });
+    }
+    results
+}
+
+/// Combine set instructions with other set instructions or
+/// increments.
+fn combine_set_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    // It's sufficient to consider immediately adjacent instructions
+    // as sort_sequence_by_offset ensures that if the offset is the
+    // same, the instruction is adjacent.
+    let instrs: Vec<_> = instrs
+        .into_iter()
+        .coalesce(|prev_instr, instr| {
+            // Inc x, Set y -> Set y
+            if let (
+                &Increment {
+                    offset: inc_offset,
+                    position: inc_pos,
+                    ..
+                },
+                &Set {
+                    amount: set_amount,
+                    offset: set_offset,
+                    position: set_pos,
+                },
+            ) = (&prev_instr, &instr)
+            {
+                if inc_offset == set_offset {
+                    return Ok(Set {
+                        amount: set_amount,
+                        offset: set_offset,
+                        // Whilst the Inc is dead here, by including
+                        // it in the position tracking we can show better warnings.
+                        position: set_pos.combine(inc_pos),
+                    });
+                }
+            }
+            Err((prev_instr, instr))
+        })
+        .coalesce(|prev_instr, instr| {
+            // Set x, Inc y -> Set x+y
+            if let Set {
+                amount: set_amount,
+                offset: set_offset,
+                position: set_pos,
+            } = prev_instr
+            {
+                if let Increment {
+                    amount: inc_amount,
+                    offset: inc_offset,
+                    position: inc_pos,
+                } = instr
+                {
+                    if inc_offset == set_offset {
+                        return Ok(Set {
+                            amount: set_amount + inc_amount,
+                            offset: set_offset,
+                            position: set_pos.combine(inc_pos),
+                        });
+                    }
+                }
+            }
+            Err((prev_instr, instr))
+        })
+        .coalesce(|prev_instr, instr| {
+            // Set x, Set y -> Set y
+            if let (
+                &Set {
+                    offset: offset1,
+                    position: position1,
+                    ..
+                },
+                &Set {
+                    amount,
+                    offset: offset2,
+                    position: position2,
+                },
+            ) = (&prev_instr, &instr)
+            {
+                if offset1 == offset2 {
+                    return Ok(Set {
+                        amount,
+                        offset: offset1,
+                        // Whilst the first Set is dead here, by including
+                        // it in the position tracking we can show better warnings.
+                        position: position1.combine(position2),
+                    });
+                }
+            }
+            Err((prev_instr, instr))
+        })
+        .collect();
+
+    combine_known_cells_and_increments(instrs)
+        .into_iter()
+        .map_loops(combine_set_and_increments)
+}
+
+/// Set x, Write, Inc y -> Set x, Write, Set x+y
+///
+/// More generally, replace increments of any cell whose value we
+/// know from an earlier Set in this sequence, e.g. the known-zero
+/// Set that `annotate_known_zero` adds after a loop. Instructions
+/// that don't change the cell, such as Writes, pointer increments or
+/// changes to other cells, don't affect what we know.
+///
+/// We only look within a straight-line sequence, so this pass can
+/// still be applied to each segment separately.
+fn combine_known_cells_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result = Vec::with_capacity(instrs.len());
+
+    // The values of cells we know, keyed by their offset from the
+    // cell the pointer was on at `ptr_offset` 0.
+    let mut known_cells: HashMap<isize, BfValue> = HashMap::new();
+    let mut ptr_offset = 0;
+
+    for instr in instrs {
+        let instr = match instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => match known_cells.get(&(ptr_offset + offset)) {
+                Some(&known_amount) => Set {
+                    amount: known_amount + amount,
+                    offset,
+                    position,
+                },
+                None => Increment {
+                    amount,
+                    offset,
+                    position,
+                },
+            },
+            instr => instr,
+        };
+
+        match instr {
+            Set { amount, offset, .. } => {
+                known_cells.insert(ptr_offset + offset, amount);
+            }
+            PointerIncrement { amount, .. } => {
+                ptr_offset += amount;
+            }
+            Read { offset, .. }
+            | CellCopy {
+                dst_offset: offset, ..
+            } => {
+                known_cells.remove(&(ptr_offset + offset));
+            }
+            Increment { .. } | Write { .. } => {}
+            // This is the end of the sequence.
+            MultiplyMove { .. } | Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. } => {
+                known_cells.clear();
+                ptr_offset = 0;
+            }
+        }
+        result.push(instr);
+    }
+
+    result
+}
+
+fn remove_redundant_sets(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut reduced = remove_redundant_sets_inner(instrs);
+
+    // Remove a set zero at the beginning of the program, since cells
+    // are initialised to zero anyway.
+    if matches!(
+        reduced.first(),
+        Some(Set {
+            amount: Wrapping(0),
+            offset: 0,
+            ..
+        })
+    ) {
+        reduced.remove(0);
+    }
+
+    reduced
+}
+
+fn remove_redundant_sets_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut redundant_instr_positions = HashSet::new();
+
+    for (index, instr) in instrs.iter().enumerate() {
+        // Which cell (relative to the current cell) is zero after
+        // this instruction?
+        let zeroed_offset = match instr {
+            Loop { offset, .. } | InfiniteLoop { offset, .. } => Some(*offset),
+            ScanLoop { .. } => Some(0),
+            // A MultiplyMove with a change at offset 0 preserves
+            // the current cell.
+            MultiplyMove { changes, .. } if !changes.contains_key(&0) => Some(0),
+            _ => None,
+        };
+        if let Some(zeroed_offset) = zeroed_offset {
+            // There's no point setting to zero after a loop, as
+            // the cell is already zero.
+            if let Some(next_index) = next_offset_cell_change(&instrs, index, zeroed_offset) {
+                if let Set {
+                    amount: Wrapping(0),
+                    offset,
+                    ..
+                } = instrs[next_index]
+                {
+                    if offset == zeroed_offset {
+                        redundant_instr_positions.insert(next_index);
+                    }
+                }
+            }
+        }
+    }
+
+    instrs
+        .into_iter()
+        .enumerate()
+        .filter(|&(index, _)| !redundant_instr_positions.contains(&index))
+        .map(|(_, instr)| instr)
+        .map_loops(remove_redundant_sets_inner)
+}
+
+fn annotate_known_zero(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut result = vec![];
+
+    let position = if instrs.is_empty() {
+        None
+    } else {
+        get_position(&instrs[0]).map(|first_instr_pos| Position {
+            start: first_instr_pos.start,
+            end: first_instr_pos.start,
+        })
+    };
+
+    // Cells in BF are initialised to zero, so we know the current
+    // cell is zero at the start of execution.
+    let set_instr = Set {
+        amount: Wrapping(0),
+        offset: 0,
+        position,
+    };
+    // Insert the set instruction unless there is one already present.
+    if !is_set_zero(instrs.first(), 0) {
+        result.push(set_instr);
+    }
+
+    result.extend(annotate_known_zero_inner(&instrs));
+    result
+}
+
+/// Is `instr` a `Set 0` at `offset`? We ignore position, as the
+/// annotation may have a different position to an existing `Set 0`.
+fn is_set_zero(instr: Option<&AstNode>, offset: isize) -> bool {
+    matches!(
+        instr,
+        Some(Set {
+            amount: Wrapping(0),
+            offset: set_offset,
+            ..
+        }) if *set_offset == offset
+    )
+}
+
+fn annotate_known_zero_inner(instrs: &[AstNode]) -> Vec<AstNode> {
+    let mut result = Vec::with_capacity(instrs.len());
+
+    for (i, instr) in instrs.iter().enumerate() {
+        let instr = instr.clone();
+
+        match instr {
+            // After a loop, we know the cell is currently zero.
+            Loop {
+                body,
+                offset,
+                position,
+            } => {
+                result.push(Loop {
+                    body: annotate_known_zero_inner(&body),
+                    offset,
+                    position,
+                });
+                // Treat this set as positioned at the ].
+                let set_pos = position.map(|loop_pos| Position {
+                    start: loop_pos.end,
+                    end: loop_pos.end,
+                });
+
+                if !is_set_zero(instrs.get(i + 1), offset) {
+                    result.push(Set {
+                        amount: Wrapping(0),
+                        offset,
+                        position: set_pos,
+                    });
+                }
+            }
+            // After a scan loop, we know the cell is currently zero.
+            ScanLoop { position, .. } => {
+                result.push(instr);
+
+                let set_pos = position.map(|loop_pos| Position {
+                    start: loop_pos.end,
+                    end: loop_pos.end,
+                });
+
+                if !is_set_zero(instrs.get(i + 1), 0) {
+                    result.push(Set {
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position: set_pos,
+                    });
+                }
+            }
+            _ => {
+                result.push(instr);
+            }
+        }
+    }
+
+    result
+}
+
+/// Remove code at the end of the program that has no side
+/// effects. This means we have no write commands afterwards, nor
+/// loops (which may not terminate so we should not remove).
+///
+/// The warning position spans all the removed instructions, even if
+/// there are comments between them.
+fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>) {
+    let mut pure_instrs = vec![];
+
+    while let Some(last_instr) = instrs.pop() {
+        match last_instr {
+            Read { .. } | Write { .. } | Loop { .. } | ScanLoop { .. } | InfiniteLoop { .. } => {
+                instrs.push(last_instr);
+                break;
+            }
+            _ => {
+                pure_instrs.push(last_instr);
+            }
+        }
+    }
+
+    let warning = if pure_instrs.is_empty() {
+        None
+    } else {
+        let position = pure_instrs
+            .iter()
+            .filter_map(get_position)
+            .reduce(|pos1, pos2| Position {
+                start: min(pos1.start, pos2.start),
+                end: max(pos1.end, pos2.end),
+            });
+        Some(Warning {
+            message: "These instructions have no effect.".to_owned(),
+            position,
+        })
+    };
+
+    (instrs, warning)
+}
+
+/// If this loop body only changes cells by a constant amount on
+/// every iteration, and decrements cell #0, return how much it
+/// changes cell #0 by.
+/// E.g. "[--->>>++<<<]" -> Some(-3)
+fn multiply_loop_step(body: &[AstNode]) -> Option<BfValue> {
+    // A multiply loop may only contain increments and pointer increments.
+    for body_instr in body {
+        match *body_instr {
+            Increment { .. } | PointerIncrement { .. } => {}
+            _ => return None,
+        }
+    }
+
+    // A multiply loop must have a net pointer movement of
+    // zero.
+    let mut net_movement = 0;
+    for body_instr in body {
+        if let PointerIncrement { amount, .. } = *body_instr {
+            net_movement += amount;
+        }
+    }
+    if net_movement != 0 {
+        return None;
+    }
+
+    let changes = cell_changes(body);
+    if changes.len() < 2 {
+        return None;
+    }
+    // A multiply loop must decrement cell #0.
+    match changes.get(&0) {
+        Some(&step) if step.0 < 0 => Some(step),
+        _ => None,
+    }
+}
+
+/// The multiplicative inverse of an odd cell value, so
+/// `value * odd_inverse(value) == 1` with wrapping arithmetic.
+fn odd_inverse(value: BfValue) -> BfValue {
+    debug_assert!(value.0 % 2 != 0);
+    (1..=255u8)
+        .step_by(2)
+        .map(|candidate| Wrapping(candidate as i8))
+        .find(|&candidate| candidate * value == Wrapping(1))
+        .unwrap()
+}
+
+/// If the loop at `index` is a multiplication, return the changes
+/// for the equivalent MultiplyMove. E.g. "[->>>++<<<]" sets cell #3
+/// to 2*cell #0, and "[--->+<]" adds cell #0 / 3 to cell #1.
+///
+/// If cell #0 starts at n and changes by s each iteration, the loop
+/// runs t times where t * s == -n with wrapping arithmetic. Writing
+/// s as 2^v * k with k odd, this only has a solution when 2^v divides
+/// n, so we require that to be known. We also require every other
+/// change c to be a multiple of 2^v, so c * t equals
+/// -(c / 2^v) * n * inverse(k).
+fn multiply_loop_changes(instrs: &[AstNode], index: usize) -> Option<HashMap<isize, BfValue>> {
+    let body = match instrs[index] {
+        Loop {
+            ref body,
+            offset: 0,
+            ..
+        } => body,
+        // Multiply loops must test the current cell.
+        _ => return None,
+    };
+    let step = multiply_loop_step(body)?;
+
+    let divisor = 1_i32 << (step.0 as u8).trailing_zeros();
+    if divisor > 1 && !is_known_multiple(instrs, index, 0, divisor) {
+        return None;
+    }
+
+    let mut changes = cell_changes(body);
+    // MultiplyMove is for where we move to, so ignore the cell we're
+    // moving from.
+    changes.remove(&0);
+    if !changes
+        .values()
+        .all(|change| change.0 as i32 % divisor == 0)
+    {
+        return None;
+    }
+
+    let inverse = odd_inverse(Wrapping((step.0 as i32 / divisor) as i8));
+    for change in changes.values_mut() {
+        *change = -Wrapping((change.0 as i32 / divisor) as i8) * inverse;
+    }
+
+    Some(changes)
+}
+
+/// Do we know that the cell at `offset` is a multiple of `divisor`
+/// before the instruction at `index`? `divisor` must be a power of
+/// two no larger than 128, so wrapping doesn't change the answer.
+fn is_known_multiple(instrs: &[AstNode], index: usize, offset: isize, divisor: i32) -> bool {
+    match previous_offset_cell_change(instrs, index, offset) {
+        Some(prev_index) => match instrs[prev_index] {
+            Set { amount, .. } => amount.0 as i32 % divisor == 0,
+            // Adding a multiple preserves being a multiple.
+            Increment {
+                amount,
+                offset: prev_offset,
+                ..
+            } => {
+                amount.0 as i32 % divisor == 0
+                    && is_known_multiple(instrs, prev_index, prev_offset, divisor)
+            }
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// Return a hashmap of all the cells that are affected by this
+/// sequence of instructions, and how much they change.
+/// E.g. "->>+++>+" -> {0: -1, 2: 3, 3: 1}
+fn cell_changes(instrs: &[AstNode]) -> HashMap<isize, BfValue> {
+    let mut changes = HashMap::new();
+    let mut cell_index: isize = 0;
+
+    for instr in instrs {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                let current_amount = *changes.get(&(cell_index + offset)).unwrap_or(&Wrapping(0));
+                changes.insert(cell_index + offset, current_amount + amount);
+            }
+            PointerIncrement { amount, .. } => {
+                cell_index += amount;
+            }
+            // We assume this is only called from is_multiply_loop.
+            _ => unreachable!(),
+        }
+    }
+
+    changes
+}
+
+fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    let mut multiply_loops: HashMap<usize, HashMap<isize, BfValue>> = (0..instrs.len())
+        .filter_map(|index| multiply_loop_changes(&instrs, index).map(|changes| (index, changes)))
+        .collect();
+
+    instrs
+        .into_iter()
+        .enumerate()
+        .map(|(index, instr)| match multiply_loops.remove(&index) {
+            Some(changes) => MultiplyMove {
+                changes,
+                position: get_position(&instr),
+            },
+            None => match instr {
+                Loop {
+                    body,
+                    offset,
+                    position,
+                } => Loop {
+                    body: extract_multiply(body),
+                    offset,
+                    position,
+                },
+                i => i,
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::num::Wrapping;
+
+    use pretty_assertions::assert_eq;
+    use quickcheck::quickcheck;
+    use quickcheck::{Arbitrary, Gen, TestResult};
+
+    use crate::bfir::parse;
+    use crate::bfir::{count_instrs, AstNode, Position};
+    use crate::diagnostics::Warning;
+
+    impl Arbitrary for AstNode {
+        fn arbitrary<G: Gen>(g: &mut G) -> AstNode {
+            arbitrary_instr(g, 5)
+        }
+    }
+
+    // We define a separate function so we can recurse on max_depth.
+    // See https://github.com/BurntSushi/quickcheck/issues/23
+    fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
+        let modulus = if max_depth == 0 { 11 } else { 12 };
+
+        // If max_depth is zero, don't create loops.
+        match g.next_u32() % modulus {
+            // TODO: use arbitrary offsets.
+            0 => Increment {
+                amount: Wrapping(Arbitrary::arbitrary(g)),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            1 => PointerIncrement {
+                amount: Arbitrary::arbitrary(g),
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            // TODO: use arbitrary offsets.
+            2 => Set {
+                amount: Wrapping(Arbitrary::arbitrary(g)),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            3 => Read {
+                offset: (g.next_u32() % 3) as isize,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            4 => Write {
+                offset: (g.next_u32() % 3) as isize,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            5 => {
+                let mut changes = HashMap::new();
+                changes.insert(1, Wrapping(-1));
+                MultiplyMove {
+                    changes,
+                    position: Some(Position { start: 0, end: 0 }),
+                }
+            }
+            6 => {
+                let mut changes = HashMap::new();
+                changes.insert(1, Wrapping(2));
+                changes.insert(4, Wrapping(10));
+                MultiplyMove {
+                    changes,
+                    position: Some(Position { start: 0, end: 0 }),
+                }
+            }
+            7 => {
+                // A multiply by 2 loop that accesses a previous
+                // cell. Quickcheck doesn't seem to generate these by
+                // chance, but they often expose interesting bugs.
+                let body = vec![
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: 0,
+                        position: None,
+                    },
+                    PointerIncrement {
+                        amount: -1,
+                        position: None,
+                    },
+                    Increment {
+                        amount: Wrapping(2),
+                        offset: 0,
+                        position: None,
+                    },
+                    PointerIncrement {
+                        amount: 1,
+                        position: None,
+                    },
+                ];
+                Loop {
+                    body,
+                    offset: 0,
+                    position: None,
+                }
+            }
+            8 => {
+                // A scan loop such as [>] or [<<]. Like multiply
+                // loops, these are rarely generated by chance.
+                let amount = if g.next_u32() % 2 == 0 { 1 } else { -2 };
+                Loop {
+                    body: vec![PointerIncrement {
+                        amount,
+                        position: None,
+                    }],
+                    offset: 0,
+                    position: None,
+                }
+            }
+            9 => {
+                // A copy to the next cell that preserves the current
+                // cell.
+                let mut changes = HashMap::new();
+                changes.insert(0, Wrapping(1));
+                changes.insert(1, Wrapping(1));
+                MultiplyMove {
+                    changes,
+                    position: Some(Position { start: 0, end: 0 }),
+                }
+            }
+            10 => CellCopy {
+                // Sources and destinations may be the same cell.
+                src_offset: (g.next_u32() % 3) as isize,
+                dst_offset: (g.next_u32() % 3) as isize,
+                overwrite: Arbitrary::arbitrary(g),
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            11 => {
+                assert!(max_depth > 0);
+                let loop_length = g.next_u32() % 10;
+                let mut body: Vec<_> = vec![];
+                for _ in 0..loop_length {
+                    body.push(arbitrary_instr(g, max_depth - 1));
+                }
+                Loop {
+                    body,
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn combine_increments_flat() {
+        let initial = parse("++").unwrap();
+        let expected = vec![Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 1 }),
+        }];
+        assert_eq!(combine_increments(initial), expected);
+    }
+
+    #[test]
+    fn combine_increments_unrelated() {
+        let initial = parse("+>+.").unwrap();
+        let expected = initial.clone();
+        assert_eq!(combine_increments(initial), expected);
+    }
+
+    #[test]
+    fn optimize_warns_without_fixed_point() {
+        // We need a second iteration to see that `+++` has reached a
+        // fixed point.
+        let instrs = parse("+++.").unwrap();
+        let opts = OptimizeOptions {
+            max_iterations: 1,
+            ..OptimizeOptions::default()
+        };
+        let (_, warnings) = optimize(instrs, opts);
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                message: "Ran peephole optimisations 1 times but did not reach a fixed point."
+                    .to_owned(),
+                position: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn optimize_no_warning_at_fixed_point() {
+        let opts = OptimizeOptions {
+            max_iterations: 1,
+            ..OptimizeOptions::default()
+        };
+        let (_, warnings) = optimize(vec![], opts);
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn optimize_stats_counts_removed_instrs() {
+        let instrs = parse(",+++>>>.").unwrap();
+        let pass_specification = Some("combine_inc,combine_ptr".to_owned());
+        let mut stats = OptimizeStats::default();
+        let opts = OptimizeOptions {
+            passes: pass_specification.clone(),
+            stats: Some(&mut stats),
+            ..OptimizeOptions::default()
+        };
+        let (result, warnings) = optimize(instrs.clone(), opts);
+
+        let opts = OptimizeOptions {
+            passes: pass_specification,
+            ..OptimizeOptions::default()
+        };
+        assert_eq!((result, warnings), optimize(instrs, opts));
+        assert!(stats.reached_fixed_point);
+        assert_eq!(stats.iterations, 2);
+
+        let removed: Vec<_> = stats
+            .passes
+            .iter()
+            .map(|pass| (pass.name, pass.instrs_removed))
+            .collect();
+        assert_eq!(removed, vec![("combine_inc", 2), ("combine_ptr", 2)]);
+    }
+
+    #[test]
+    fn optimize_after_pass_called_after_each_pass() {
+        let instrs = parse(",+++>>>.").unwrap();
+        let pass_specification = Some("combine_inc,combine_ptr".to_owned());
+
+        let mut calls = vec![];
+        let opts = OptimizeOptions {
+            passes: pass_specification.clone(),
+            after_pass: Some(&mut |name, instrs| {
+                calls.push((name.to_owned(), count_instrs(instrs)))
+            }),
+            ..OptimizeOptions::default()
+        };
+        let (result, warnings) = optimize(instrs.clone(), opts);
+
+        let opts = OptimizeOptions {
+            passes: pass_specification,
+            ..OptimizeOptions::default()
+        };
+        assert_eq!((result, warnings), optimize(instrs, opts));
+        assert_eq!(
+            calls,
+            vec![
+                ("combine_inc".to_owned(), 6),
+                ("combine_ptr".to_owned(), 4),
+                ("combine_inc".to_owned(), 4),
+                ("combine_ptr".to_owned(), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn optimize_stats_with_after_pass() {
+        let instrs = parse(",+++>>>.").unwrap();
+        let mut stats = OptimizeStats::default();
+        let mut calls = 0;
+        let opts = OptimizeOptions {
+            passes: Some("combine_inc,combine_ptr".to_owned()),
+            stats: Some(&mut stats),
+            after_pass: Some(&mut |_, _| calls += 1),
+            ..OptimizeOptions::default()
+        };
+        optimize(instrs, opts);
+
+        assert_eq!(stats.iterations, 2);
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn combine_increments_nested() {
         let initial = parse("[++]").unwrap();
         let expected = vec![Loop {
-            body: vec![Increment {
+            body: vec![Increment {
+                amount: Wrapping(2),
+                offset: 0,
+                position: Some(Position { start: 1, end: 2 }),
+            }],
+            offset: 0,
+            position: Some(Position { start: 0, end: 3 }),
+        }];
+        assert_eq!(combine_increments(initial), expected);
+    }
+
+    #[test]
+    fn combine_increments_wraps_at_cell_width() {
+        // 128 increments wrap from 127 to -128.
+        let initial = parse(&"+".repeat(128)).unwrap();
+        let result = combine_increments(initial);
+        assert_eq!(result.len(), 1);
+        assert!(matches!(
+            result[0],
+            Increment {
+                amount: Wrapping(-128),
+                ..
+            }
+        ));
+
+        // 256 increments wrap back to zero, so we remove them.
+        let initial = parse(&"+".repeat(256)).unwrap();
+        assert_eq!(combine_increments(initial), vec![]);
+    }
+
+    #[test]
+    fn combine_set_and_increments_wraps_at_cell_width() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(127),
+                offset: 0,
+                position: None,
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+        ];
+        let expected = vec![Set {
+            amount: Wrapping(-128),
+            offset: 0,
+            position: None,
+        }];
+        assert_eq!(combine_set_and_increments(initial), expected);
+    }
+
+    #[test]
+    fn combine_increments_remove_redundant() {
+        let initial = parse("+-").unwrap();
+        assert_eq!(combine_increments(initial), vec![]);
+    }
+
+    #[test]
+    fn quickcheck_combine_increments_remove_zero_any_offset() {
+        fn combine_increments_remove_zero_any_offset(offset: isize) -> bool {
+            let initial = vec![Increment {
+                amount: Wrapping(0),
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            }];
+            combine_increments(initial) == vec![]
+        }
+        quickcheck(combine_increments_remove_zero_any_offset as fn(isize) -> bool);
+    }
+
+    #[test]
+    fn combine_increment_sum_to_zero() {
+        let initial = vec![
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(combine_increments(initial), vec![]);
+    }
+
+    #[test]
+    fn should_combine_ptr_increments() {
+        let initial = parse(">>").unwrap();
+        let expected = vec![PointerIncrement {
+            amount: 2,
+            position: Some(Position { start: 0, end: 1 }),
+        }];
+        assert_eq!(combine_ptr_increments(initial), expected);
+    }
+
+    #[test]
+    fn combine_set_sum_to_zero() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(
+            combine_set_and_increments(initial),
+            vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            }]
+        );
+    }
+
+    #[test]
+    fn should_combine_before_read() {
+        // The increment before the read is dead and can be removed.
+        let initial = parse("+,.").unwrap();
+        let expected = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        assert_eq!(optimize(initial, OptimizeOptions::default()).0, expected);
+    }
+
+    #[test]
+    fn dont_combine_before_read_different_offset() {
+        // The read does not affect the increment here.
+        let initial = vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 2,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(remove_read_clobber(initial.clone()).0, initial);
+    }
+
+    #[test]
+    fn combine_before_read_warns() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(5),
+                offset: 0,
+                position: Some(Position { start: 0, end: 4 }),
+            },
+            Read {
+                offset: 0,
+                position: Some(Position { start: 5, end: 5 }),
+            },
+        ];
+        let expected = vec![Read {
+            offset: 0,
+            position: Some(Position { start: 5, end: 5 }),
+        }];
+        assert_eq!(
+            remove_read_clobber(initial),
+            (
+                expected,
+                vec![Warning {
+                    message:
+                        "This change has no effect, because the cell is read from stdin afterwards."
+                            .to_owned(),
+                    position: Some(Position { start: 0, end: 4 }),
+                }]
+            )
+        );
+    }
+
+    #[test]
+    fn combine_before_read_no_warning_for_known_zero() {
+        // The cell is initially zero, but the user didn't write a Set.
+        let (_, warnings) = optimize(parse(",.").unwrap(), OptimizeOptions::default());
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn combine_before_read_warns_in_loop() {
+        let (_, warnings) = optimize(parse(",[+,.]").unwrap(), OptimizeOptions::default());
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                message:
+                    "This change has no effect, because the cell is read from stdin afterwards."
+                        .to_owned(),
+                position: Some(Position { start: 2, end: 2 }),
+            }]
+        );
+    }
+
+    #[test]
+    fn should_combine_before_read_nested() {
+        // The read clobbers the increment here.
+        let initial = parse("+[+,]").unwrap();
+        let expected = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![Read {
+                    offset: 0,
+                    position: Some(Position { start: 3, end: 3 }),
+                }],
+                offset: 0,
+                position: Some(Position { start: 1, end: 4 }),
+            },
+        ];
+        assert_eq!(optimize(initial, OptimizeOptions::default()).0, expected);
+    }
+
+    #[test]
+    fn combine_before_read_not_consecutive() {
+        // The increment before the read is dead and can be removed.
+        let initial = parse("+>-<,").unwrap();
+        let expected = vec![
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+            Read {
+                offset: 0,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+        ];
+        assert_eq!(remove_read_clobber(initial).0, expected);
+    }
+
+    #[test]
+    fn no_combine_before_read_after_write() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            Write {
+                offset: 0,
+                position: None,
+            },
+            Read {
+                offset: 0,
+                position: None,
+            },
+        ];
+        // TODO: write an assert_unchanged! macro.
+        let expected = initial.clone();
+        assert_eq!(remove_read_clobber(initial).0, expected);
+    }
+
+    #[test]
+    fn no_combine_before_read_after_multiply() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(-1));
+        let initial = vec![
+            MultiplyMove {
+                changes,
+                position: None,
+            },
+            Read {
+                offset: 0,
+                position: None,
+            },
+        ];
+        let expected = initial.clone();
+        assert_eq!(remove_read_clobber(initial).0, expected);
+    }
+
+    #[test]
+    fn simplify_zeroing_loop() {
+        let initial = parse("[-]").unwrap();
+        let expected = vec![Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 2 }),
+        }];
+        assert_eq!(zeroing_loops(initial), expected);
+    }
+
+    #[test]
+    fn simplify_nested_zeroing_loop() {
+        let initial = parse("[[-]]").unwrap();
+        let expected = vec![Loop {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 1, end: 3 }),
+            }],
+            offset: 0,
+            position: Some(Position { start: 0, end: 4 }),
+        }];
+        assert_eq!(zeroing_loops(initial), expected);
+    }
+
+    #[test]
+    fn optimize_nested_zeroing_loops() {
+        for src in [",[[-]].", ",[[[-]]].", ",[[+]]."] {
+            let initial = parse(src).unwrap();
+            let (result, _) = optimize(initial, OptimizeOptions::default());
+            assert_eq!(
+                result,
+                vec![
+                    Read {
+                        offset: 0,
+                        position: Some(Position { start: 0, end: 0 }),
+                    },
+                    Set {
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position: Some(Position {
+                            start: 1,
+                            end: src.len() - 2,
+                        }),
+                    },
+                    Write {
+                        offset: 0,
+                        position: Some(Position {
+                            start: src.len() - 1,
+                            end: src.len() - 1,
+                        }),
+                    },
+                ],
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn simplify_odd_increment_zeroing_loops() {
+        // Cells wrap around, so these loops reach zero too.
+        for src in ["[+]", "[---]", "[+++++]"] {
+            let initial = parse(src).unwrap();
+            let (combined, _) = optimize(
+                initial,
+                OptimizeOptions {
+                    passes: Some("combine_inc,zeroing_loop".to_owned()),
+                    ..OptimizeOptions::default()
+                },
+            );
+            assert_eq!(
+                combined,
+                vec![Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: Some(Position {
+                        start: 0,
+                        end: src.len() - 1,
+                    }),
+                }],
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn simplify_zeroing_loop_with_offset() {
+        let initial = vec![Loop {
+            body: vec![Increment {
+                amount: Wrapping(-1),
+                offset: 2,
+                position: None,
+            }],
+            offset: 2,
+            position: None,
+        }];
+        let expected = vec![Set {
+            amount: Wrapping(0),
+            offset: 2,
+            position: None,
+        }];
+        assert_eq!(zeroing_loops(initial), expected);
+    }
+
+    #[test]
+    fn simplify_loop_of_set_zero() {
+        let initial = vec![Loop {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 1, end: 3 }),
+            }],
+            offset: 0,
+            position: Some(Position { start: 0, end: 4 }),
+        }];
+        let expected = vec![Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 4 }),
+        }];
+        assert_eq!(zeroing_loops(initial), expected);
+    }
+
+    #[test]
+    fn dont_simplify_loop_of_set_other_cell() {
+        let initial = vec![Loop {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 1,
+                position: None,
+            }],
+            offset: 0,
+            position: None,
+        }];
+        assert_eq!(zeroing_loops(initial.clone()), initial);
+    }
+
+    #[test]
+    fn dont_simplify_multiple_decrement_loop() {
+        // A user who wrote this probably meant '[-]'. However, if the
+        // current cell has the value 3, we would actually wrap around
+        // (although BF does not specify this).
+        let initial = parse("[--]").unwrap();
+        assert_eq!(zeroing_loops(initial.clone()), initial);
+    }
+
+    #[test]
+    fn should_simplify_multiple_decrement_loop_on_known_multiple() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(4),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![Increment {
+                    amount: Wrapping(-2),
+                    offset: 0,
+                    position: Some(Position { start: 2, end: 3 }),
+                }],
+                offset: 0,
+                position: Some(Position { start: 1, end: 4 }),
+            },
+        ];
+        let expected = vec![
+            Set {
+                amount: Wrapping(4),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 1, end: 4 }),
+            },
+        ];
+        assert_eq!(zeroing_loops(initial), expected);
+    }
+
+    #[test]
+    fn should_simplify_multiple_decrement_loop_after_even_increment() {
+        // 4 + 2 is still even, and the loop is at an offset.
+        let initial = vec![
+            Set {
+                amount: Wrapping(4),
+                offset: 1,
+                position: None,
+            },
+            Increment {
+                amount: Wrapping(2),
+                offset: 1,
+                position: None,
+            },
+            Loop {
+                body: vec![Increment {
+                    amount: Wrapping(-2),
+                    offset: 1,
+                    position: None,
+                }],
+                offset: 1,
+                position: None,
+            },
+        ];
+        let result = zeroing_loops(initial);
+        assert!(matches!(
+            result[2],
+            Set {
+                amount: Wrapping(0),
+                offset: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn dont_simplify_multiple_decrement_loop_on_wrong_multiple() {
+        // 6 isn't a multiple of 4, so [----] never terminates.
+        let initial = vec![
+            Set {
+                amount: Wrapping(6),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body: vec![Increment {
+                    amount: Wrapping(-4),
+                    offset: 0,
+                    position: None,
+                }],
+                offset: 0,
+                position: None,
+            },
+        ];
+        assert_eq!(zeroing_loops(initial.clone()), initial);
+    }
+
+    #[test]
+    fn dont_simplify_multiple_decrement_loop_on_odd_value() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(3),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body: vec![Increment {
+                    amount: Wrapping(-2),
+                    offset: 0,
+                    position: None,
+                }],
+                offset: 0,
+                position: None,
+            },
+        ];
+        assert_eq!(zeroing_loops(initial.clone()), initial);
+    }
+
+    #[test]
+    fn should_extract_scan_loop() {
+        let initial = parse("[>][<]").unwrap();
+        let expected = vec![
+            ScanLoop {
+                step: 1,
+                position: Some(Position { start: 0, end: 2 }),
+            },
+            ScanLoop {
+                step: -1,
+                position: Some(Position { start: 3, end: 5 }),
+            },
+        ];
+        assert_eq!(extract_scan_loops(initial), expected);
+    }
+
+    #[test]
+    fn should_extract_nested_scan_loop() {
+        let initial = parse("[[>]]").unwrap();
+        let expected = vec![Loop {
+            body: vec![ScanLoop {
+                step: 1,
+                position: Some(Position { start: 1, end: 3 }),
+            }],
+            offset: 0,
+            position: Some(Position { start: 0, end: 4 }),
+        }];
+        assert_eq!(extract_scan_loops(initial), expected);
+    }
+
+    #[test]
+    fn should_canonicalize_empty_loop() {
+        let initial = parse(",[]").unwrap();
+        let expected = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            InfiniteLoop {
+                offset: 0,
+                position: Some(Position { start: 1, end: 2 }),
+            },
+        ];
+        assert_eq!(canonicalize_infinite_loops(initial), expected);
+    }
+
+    #[test]
+    fn should_canonicalize_loop_that_does_not_change_its_cell() {
+        let initial = vec![Loop {
+            body: vec![
+                Increment {
+                    amount: Wrapping(1),
+                    offset: 1,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(2),
+                    offset: -1,
+                    position: None,
+                },
+            ],
+            offset: 0,
+            position: None,
+        }];
+        let expected = vec![InfiniteLoop {
+            offset: 0,
+            position: None,
+        }];
+        assert_eq!(canonicalize_infinite_loops(initial), expected);
+    }
+
+    #[test]
+    fn should_not_canonicalize_progressing_loops() {
+        let initial = parse("[-][>][.][[-]]").unwrap();
+        assert_eq!(canonicalize_infinite_loops(initial.clone()), initial);
+    }
+
+    #[test]
+    fn should_flag_code_after_infinite_loop_unreachable() {
+        let initial = parse("+[]>.").unwrap();
+        let expected = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            InfiniteLoop {
+                offset: 0,
+                position: Some(Position { start: 1, end: 2 }),
+            },
+        ];
+
+        let (result, warnings) = optimize(initial, OptimizeOptions::default());
+
+        assert_eq!(result, expected);
+        assert_eq!(
+            warnings,
+            vec![
+                Warning {
+                    message: "This loop never terminates, because its cell is never zero."
+                        .to_owned(),
+                    position: Some(Position { start: 1, end: 2 }),
+                },
+                Warning {
+                    message:
+                        "This code is unreachable, because the loop before it never terminates."
+                            .to_owned(),
+                    position: Some(Position { start: 3, end: 4 }),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn should_warn_about_infinite_loop() {
+        let initial = parse("+[>+<]").unwrap();
+        let (_, warnings) = optimize(initial, OptimizeOptions::default());
+
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                message: "This loop never terminates, because its cell is never zero.".to_owned(),
+                position: Some(Position { start: 1, end: 5 }),
+            }]
+        );
+    }
+
+    #[test]
+    fn should_not_warn_about_terminating_loops() {
+        // The cell wraps around to zero eventually.
+        let initial = parse("+[+]").unwrap();
+        let (_, warnings) = optimize(initial, OptimizeOptions::default());
+        assert_eq!(warnings, vec![]);
+
+        // We don't know whether the input is zero.
+        let initial = parse(",[]").unwrap();
+        let (_, warnings) = optimize(initial, OptimizeOptions::default());
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn should_keep_code_after_infinite_loop_on_unknown_cell() {
+        // If the input is zero, we skip the loop.
+        let initial = parse(",[]>.").unwrap();
+        let expected = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            InfiniteLoop {
+                offset: 0,
+                position: Some(Position { start: 1, end: 2 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+        ];
+
+        let (result, warnings) = optimize(initial, OptimizeOptions::default());
+
+        assert_eq!(result, expected);
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn should_remove_unreachable_code_in_loop_body() {
+        let initial = vec![Loop {
+            body: vec![
+                Set {
+                    amount: Wrapping(3),
+                    offset: 1,
+                    position: None,
+                },
+                InfiniteLoop {
+                    offset: 1,
+                    position: None,
+                },
+                Write {
+                    offset: 0,
+                    position: None,
+                },
+            ],
+            offset: 0,
+            position: None,
+        }];
+        let expected = vec![Loop {
+            body: vec![
+                Set {
+                    amount: Wrapping(3),
+                    offset: 1,
+                    position: None,
+                },
+                InfiniteLoop {
+                    offset: 1,
+                    position: None,
+                },
+            ],
+            offset: 0,
+            position: None,
+        }];
+
+        let (result, warnings) = remove_unreachable_code(initial);
+        assert_eq!(result, expected);
+        // One warning for the infinite loop, one for the unreachable
+        // code.
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn dont_extract_scan_loop_with_other_instrs() {
+        let initial = parse("[>+]").unwrap();
+        assert_eq!(extract_scan_loops(initial.clone()), initial);
+    }
+
+    #[test]
+    fn remove_dead_scan_loop() {
+        // The scan loop here is dead, as the cell is zero after the
+        // first loop.
+        let initial = parse(",[>][<]").unwrap();
+        let expected = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            ScanLoop {
+                step: 1,
+                position: Some(Position { start: 1, end: 3 }),
+            },
+        ];
+        assert_eq!(optimize(initial, OptimizeOptions::default()).0, expected);
+    }
+
+    #[test]
+    fn should_absorb_ptr_increment() {
+        let initial = parse(">[-<+>].").unwrap();
+        let expected = vec![
+            Loop {
+                body: vec![
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: 1,
+                        position: Some(Position { start: 2, end: 2 }),
+                    },
+                    PointerIncrement {
+                        amount: -1,
+                        position: Some(Position { start: 3, end: 3 }),
+                    },
+                    Increment {
+                        amount: Wrapping(1),
+                        offset: 1,
+                        position: Some(Position { start: 4, end: 4 }),
+                    },
+                    PointerIncrement {
+                        amount: 1,
+                        position: Some(Position { start: 5, end: 5 }),
+                    },
+                ],
+                offset: 1,
+                position: Some(Position { start: 1, end: 6 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 7, end: 7 }),
+            },
+        ];
+        assert_eq!(absorb_ptr_increments(initial), expected);
+    }
+
+    #[test]
+    fn should_absorb_ptr_increment_nested_loop() {
+        let initial = parse(">[[-]].").unwrap();
+        let expected = vec![
+            Loop {
+                body: vec![Loop {
+                    body: vec![Increment {
+                        amount: Wrapping(-1),
+                        offset: 1,
+                        position: Some(Position { start: 3, end: 3 }),
+                    }],
+                    offset: 1,
+                    position: Some(Position { start: 2, end: 4 }),
+                }],
+                offset: 1,
+                position: Some(Position { start: 1, end: 5 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 6, end: 6 }),
+            },
+        ];
+        assert_eq!(absorb_ptr_increments(initial), expected);
+    }
+
+    #[test]
+    fn should_absorb_ptr_increment_in_loop_body() {
+        let initial = parse("[>[-]<]").unwrap();
+        let expected = vec![Loop {
+            body: vec![
+                Loop {
+                    body: vec![Increment {
+                        amount: Wrapping(-1),
+                        offset: 1,
+                        position: Some(Position { start: 3, end: 3 }),
+                    }],
+                    offset: 1,
+                    position: Some(Position { start: 2, end: 4 }),
+                },
+                PointerIncrement {
+                    amount: 1,
+                    position: Some(Position { start: 1, end: 1 }),
+                },
+                PointerIncrement {
+                    amount: -1,
+                    position: Some(Position { start: 5, end: 5 }),
+                },
+            ],
+            offset: 0,
+            position: Some(Position { start: 0, end: 6 }),
+        }];
+        assert_eq!(absorb_ptr_increments(initial), expected);
+    }
+
+    #[test]
+    fn dont_absorb_ptr_increment_with_net_movement() {
+        let initial = parse(">[>].").unwrap();
+        assert_eq!(absorb_ptr_increments(initial.clone()), initial);
+    }
+
+    #[test]
+    fn dont_absorb_ptr_increment_at_end() {
+        // The pointer increment would be pure code, and we'd warn
+        // about the user's '>'.
+        let initial = parse(">[-]").unwrap();
+        assert_eq!(absorb_ptr_increments(initial.clone()), initial);
+    }
+
+    #[test]
+    fn should_hoist_loop_ptr_increment() {
+        let initial = sort_by_offset(extract_multiply(parse(",[>[-<+>]<-].").unwrap()));
+        let mut changes = HashMap::new();
+        changes.insert(-1, Wrapping(1));
+        let expected = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Loop {
+                body: vec![
+                    MultiplyMove {
+                        changes,
+                        position: Some(Position { start: 3, end: 8 }),
+                    },
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: -1,
+                        position: Some(Position { start: 10, end: 10 }),
+                    },
+                    PointerIncrement {
+                        amount: -1,
+                        position: Some(Position { start: 9, end: 9 }),
+                    },
+                    PointerIncrement {
+                        amount: 1,
+                        position: Some(Position { start: 2, end: 2 }),
+                    },
+                ],
+                offset: -1,
+                position: Some(Position { start: 1, end: 11 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 12, end: 12 }),
+            },
+        ];
+        assert_eq!(hoist_loop_ptr_increments(initial), expected);
+    }
+
+    #[test]
+    fn should_hoist_loop_ptr_increment_from_source() {
+        let instrs = parse(",[>[-<+>]<-]>.").unwrap();
+        let result = optimize(instrs, OptimizeOptions::default()).0;
+
+        // The body shouldn't move the pointer at all.
+        let mut changes = HashMap::new();
+        changes.insert(-1, Wrapping(1));
+        let expected_body = vec![
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 3, end: 8 }),
+            },
+            Increment {
+                amount: Wrapping(-1),
+                offset: -1,
+                position: Some(Position { start: 10, end: 10 }),
+            },
+        ];
+        assert!(result.iter().any(|instr| matches!(
+            instr,
+            Loop { body, offset: -1, .. } if *body == expected_body
+        )));
+    }
+
+    #[test]
+    fn dont_hoist_loop_ptr_increment_with_net_movement() {
+        let initial = sort_by_offset(parse(",[>.].").unwrap());
+        assert_eq!(hoist_loop_ptr_increments(initial.clone()), initial);
+    }
+
+    #[test]
+    fn dont_hoist_loop_ptr_increment_with_scan_loop() {
+        let initial = vec![
+            Loop {
+                body: vec![
+                    PointerIncrement {
+                        amount: 1,
+                        position: Some(Position { start: 0, end: 0 }),
+                    },
+                    ScanLoop {
+                        step: 1,
+                        position: Some(Position { start: 0, end: 0 }),
+                    },
+                    PointerIncrement {
+                        amount: -1,
+                        position: Some(Position { start: 0, end: 0 }),
+                    },
+                ],
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(hoist_loop_ptr_increments(initial.clone()), initial);
+    }
+
+    #[test]
+    fn dont_hoist_loop_ptr_increment_left() {
+        // If the loop doesn't run, we'd move the pointer to cell -1.
+        let initial = sort_by_offset(extract_multiply(parse(",[<[->+<]>-].").unwrap()));
+        assert_eq!(hoist_loop_ptr_increments(initial.clone()), initial);
+    }
+
+    #[test]
+    fn dont_hoist_loop_ptr_increment_at_end() {
+        // The pointer increment after the loop would be pure code, and
+        // we'd warn about the user's '>'.
+        let initial = sort_by_offset(extract_multiply(parse(",[>[-<+>]<-]").unwrap()));
+        assert_eq!(hoist_loop_ptr_increments(initial.clone()), initial);
+    }
+
+    #[test]
+    fn remove_set_after_offset_loop() {
+        let initial = vec![
+            Loop {
+                body: vec![Increment {
+                    amount: Wrapping(-1),
+                    offset: 1,
+                    position: Some(Position { start: 0, end: 0 }),
+                }],
+                offset: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![initial[0].clone()];
+        assert_eq!(remove_redundant_sets(initial), expected);
+    }
+
+    #[test]
+    fn remove_repeated_loops() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            InfiniteLoop {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(optimize(initial, OptimizeOptions::default()).0, expected);
+    }
+
+    #[test]
+    fn remove_dead_loops_after_set() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        assert_eq!(remove_dead_loops(initial), expected);
+    }
+
+    #[test]
+    fn remove_dead_loops_after_loop() {
+        let initial = parse("[+][-]").unwrap();
+        let expected = parse("[+]").unwrap();
+        assert_eq!(remove_dead_loops(initial), expected);
+    }
+
+    #[test]
+    fn remove_dead_loops_after_loop_with_arbitrary_body() {
+        let initial = parse(",[>.<,][.>][<]").unwrap();
+        let expected = parse(",[>.<,]").unwrap();
+        assert_eq!(remove_dead_loops(initial), expected);
+    }
+
+    #[test]
+    fn remove_dead_loops_after_loop_on_other_cell() {
+        let initial = vec![
+            Loop {
+                body: vec![],
+                offset: 1,
+                position: None,
+            },
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: None,
+            },
+        ];
+        assert_eq!(remove_dead_loops(initial.clone()), initial);
+    }
+
+    #[test]
+    fn remove_dead_loops_nested() {
+        let initial = vec![Loop {
+            body: vec![
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Loop {
+                    body: vec![],
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+            ],
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        let expected = vec![Loop {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            }],
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        assert_eq!(remove_dead_loops(initial), expected);
+    }
+
+    #[test]
+    fn remove_dead_loops_not_adjacent() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(remove_dead_loops(initial), expected);
+    }
+
+    #[test]
+    fn quickcheck_should_combine_set_and_increment() {
+        fn should_combine_set_and_increment(
+            offset: isize,
+            set_amount: i8,
+            increment_amount: i8,
+        ) -> bool {
+            let set_amount = Wrapping(set_amount);
+            let increment_amount = Wrapping(increment_amount);
+
+            let initial = vec![
+                Set {
+                    amount: set_amount,
+                    offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Increment {
+                    amount: increment_amount,
+                    offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+            ];
+            let expected = vec![Set {
+                amount: set_amount + increment_amount,
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            }];
+            combine_set_and_increments(initial) == expected
+        }
+        quickcheck(should_combine_set_and_increment as fn(isize, i8, i8) -> bool);
+    }
+
+    #[test]
+    fn quickcheck_combine_set_and_increment_across_write() {
+        fn combine_set_and_increment_across_write(
+            offset: isize,
+            set_amount: i8,
+            increment_amount: i8,
+        ) -> bool {
+            let set_amount = Wrapping(set_amount);
+            let increment_amount = Wrapping(increment_amount);
+
+            let initial = vec![
+                Set {
+                    amount: set_amount,
+                    offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Write {
+                    offset: 0,
+                    position: Some(Position { start: 1, end: 1 }),
+                },
+                Increment {
+                    amount: increment_amount,
+                    offset,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+            ];
+            let expected = vec![
+                Set {
+                    amount: set_amount,
+                    offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Write {
+                    offset: 0,
+                    position: Some(Position { start: 1, end: 1 }),
+                },
+                Set {
+                    amount: set_amount + increment_amount,
+                    offset,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+            ];
+            combine_set_and_increments(initial) == expected
+        }
+        quickcheck(combine_set_and_increment_across_write as fn(isize, i8, i8) -> bool);
+    }
+
+    #[test]
+    fn quickcheck_combine_set_and_increment_across_write_different_offsets() {
+        fn combine_set_and_increment_across_write_different_offsets(
+            set_offset: isize,
+            set_amount: i8,
+            inc_offset: isize,
+            inc_amount: i8,
+        ) -> TestResult {
+            if set_offset == inc_offset {
+                return TestResult::discard();
+            }
+
+            let initial = vec![
+                Set {
+                    amount: Wrapping(set_amount),
+                    offset: set_offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Write {
+                    offset: 0,
+                    position: Some(Position { start: 1, end: 1 }),
+                },
+                Increment {
+                    amount: Wrapping(inc_amount),
+                    offset: inc_offset,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+            ];
+            let expected = initial.clone();
+
+            TestResult::from_bool(combine_set_and_increments(initial) == expected)
+        }
+        quickcheck(
+            combine_set_and_increment_across_write_different_offsets
+                as fn(isize, i8, isize, i8) -> TestResult,
+        );
+    }
+
+    #[test]
+    fn combine_set_and_increment_across_multiple_writes() {
+        let initial = parse("[-]+..+.-").unwrap();
+        let expected = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 3 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 5, end: 5 }),
+            },
+            Set {
+                amount: Wrapping(2),
+                offset: 0,
+                position: Some(Position { start: 6, end: 6 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 7, end: 7 }),
+            },
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 8, end: 8 }),
+            },
+        ];
+        assert_eq!(combine_set_and_increments(zeroing_loops(initial)), expected);
+    }
+
+    #[test]
+    fn dont_combine_set_and_increment_across_read() {
+        let initial = parse("[-]+,+").unwrap();
+        let initial = combine_set_and_increments(zeroing_loops(initial));
+        assert!(matches!(initial[2], Increment { .. }));
+    }
+
+    #[test]
+    fn combine_set_and_increment_across_read_elsewhere() {
+        // Reading into cell #1 doesn't change cell #0.
+        let initial = parse("[-]>,<+").unwrap();
+        let result = combine_set_and_increments(zeroing_loops(initial));
+        assert_eq!(
+            result[4],
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 6, end: 6 }),
+            }
+        );
+    }
+
+    #[test]
+    fn combine_set_and_increment_across_pointer_increments() {
+        let initial = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Increment {
                 amount: Wrapping(2),
                 offset: 0,
-                position: Some(Position { start: 1, end: 2 }),
+                position: Some(Position { start: 3, end: 3 }),
+            },
+        ];
+        let mut expected = initial.clone();
+        expected[3] = Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 3, end: 3 }),
+        };
+        assert_eq!(combine_set_and_increments(initial), expected);
+    }
+
+    #[test]
+    fn dont_combine_set_and_increment_across_loop() {
+        // The loop may change any cell, so we don't know cell #1
+        // afterwards.
+        let initial = parse(">[-]<[>+<-]>+").unwrap();
+        let result = combine_set_and_increments(zeroing_loops(initial));
+        assert!(matches!(result.last(), Some(Increment { .. })));
+    }
+
+    // TODO: rename our quickcheck property functions to something shorter.
+    #[test]
+    fn quickcheck_combine_set_and_increment_different_offsets() {
+        fn combine_set_and_increment_different_offsets(
+            set_offset: isize,
+            set_amount: i8,
+            inc_offset: isize,
+            inc_amount: i8,
+        ) -> TestResult {
+            if set_offset == inc_offset {
+                return TestResult::discard();
+            }
+
+            let initial = vec![
+                Set {
+                    amount: Wrapping(set_amount),
+                    offset: set_offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Increment {
+                    amount: Wrapping(inc_amount),
+                    offset: inc_offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+            ];
+            let expected = initial.clone();
+
+            TestResult::from_bool(combine_set_and_increments(initial) == expected)
+        }
+        quickcheck(
+            combine_set_and_increment_different_offsets as fn(isize, i8, isize, i8) -> TestResult,
+        );
+    }
+
+    #[test]
+    fn quickcheck_combine_increment_and_set_different_offsets() {
+        fn combine_increment_and_set_different_offsets(
+            set_offset: isize,
+            set_amount: i8,
+            inc_offset: isize,
+            inc_amount: i8,
+        ) -> TestResult {
+            if set_offset == inc_offset {
+                return TestResult::discard();
+            }
+
+            let initial = vec![
+                Increment {
+                    amount: Wrapping(inc_amount),
+                    offset: inc_offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Set {
+                    amount: Wrapping(set_amount),
+                    offset: set_offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+            ];
+            let expected = initial.clone();
+
+            TestResult::from_bool(combine_set_and_increments(initial) == expected)
+        }
+        quickcheck(
+            combine_increment_and_set_different_offsets as fn(isize, i8, isize, i8) -> TestResult,
+        );
+    }
+
+    #[test]
+    fn quickcheck_combine_set_and_set() {
+        fn combine_set_and_set(offset: isize, set_amount_before: i8, set_amount_after: i8) -> bool {
+            let initial = vec![
+                Set {
+                    amount: Wrapping(set_amount_before),
+                    offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Set {
+                    amount: Wrapping(set_amount_after),
+                    offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+            ];
+            let expected = vec![Set {
+                amount: Wrapping(set_amount_after),
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            }];
+            combine_set_and_increments(initial) == expected
+        }
+        quickcheck(combine_set_and_set as fn(isize, i8, i8) -> bool);
+    }
+
+    #[test]
+    fn quickcheck_combine_set_and_set_different_offsets() {
+        fn combine_set_and_set_different_offsets(
+            offset1: isize,
+            amount1: i8,
+            offset2: isize,
+            amount2: i8,
+        ) -> TestResult {
+            if offset1 == offset2 {
+                return TestResult::discard();
+            }
+
+            let initial = vec![
+                Set {
+                    amount: Wrapping(amount1),
+                    offset: offset1,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Set {
+                    amount: Wrapping(amount2),
+                    offset: offset2,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+            ];
+            let expected = initial.clone();
+
+            TestResult::from_bool(combine_set_and_increments(initial) == expected)
+        }
+        quickcheck(combine_set_and_set_different_offsets as fn(isize, i8, isize, i8) -> TestResult);
+    }
+
+    #[test]
+    fn should_combine_set_and_set_nested() {
+        let initial = vec![Loop {
+            body: vec![
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Set {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+            ],
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        let expected = vec![Loop {
+            body: vec![Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            }],
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        assert_eq!(combine_set_and_increments(initial), expected);
+    }
+
+    #[test]
+    fn quickcheck_should_combine_increment_and_set() {
+        fn should_combine_increment_and_set(offset: isize) -> bool {
+            let initial = vec![
+                Increment {
+                    amount: Wrapping(2),
+                    offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                Set {
+                    amount: Wrapping(3),
+                    offset,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+            ];
+            let expected = vec![Set {
+                amount: Wrapping(3),
+                offset,
+                position: Some(Position { start: 0, end: 0 }),
+            }];
+            combine_set_and_increments(initial) == expected
+        }
+        quickcheck(should_combine_increment_and_set as fn(isize) -> bool);
+    }
+
+    #[test]
+    fn should_remove_redundant_set() {
+        let initial = vec![
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(remove_redundant_sets(initial), expected);
+    }
+
+    #[test]
+    fn should_remove_redundant_set_multiply() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(1));
+
+        let initial = vec![
+            MultiplyMove {
+                changes: changes.clone(),
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let expected = vec![MultiplyMove {
+            changes,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        assert_eq!(remove_redundant_sets(initial), expected);
+    }
+
+    /// After a loop, if we set to a value other than zero, we shouldn't
+    /// remove it.
+    #[test]
+    fn not_redundant_set_when_nonzero() {
+        let instrs = vec![
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        assert_eq!(remove_redundant_sets(instrs.clone()), instrs);
+    }
+
+    fn is_pure(instrs: &[AstNode]) -> bool {
+        for instr in instrs {
+            match *instr {
+                Loop { .. } => {
+                    return false;
+                }
+                Read { .. } => {
+                    return false;
+                }
+                Write { .. } => {
+                    return false;
+                }
+                ScanLoop { .. } => {
+                    return false;
+                }
+                _ => (),
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn quickcheck_should_annotate_known_zero_at_start() {
+        fn should_annotate_known_zero_at_start(instrs: Vec<AstNode>) -> bool {
+            let annotated = annotate_known_zero(instrs);
+            matches!(
+                annotated[0],
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    ..
+                }
+            )
+        }
+        quickcheck(should_annotate_known_zero_at_start as fn(Vec<AstNode>) -> bool);
+    }
+
+    #[test]
+    fn annotate_known_zero_idempotent() {
+        fn is_idempotent(instrs: Vec<AstNode>) -> bool {
+            let annotated = annotate_known_zero(instrs);
+            let annotated_again = annotate_known_zero(annotated.clone());
+            if annotated == annotated_again {
+                true
+            } else {
+                println!("intermediate: {:?}", annotated);
+                println!("final: {:?}", annotated_again);
+                false
+            }
+        }
+        quickcheck(is_idempotent as fn(Vec<AstNode>) -> bool);
+    }
+
+    #[test]
+    fn should_annotate_known_zero() {
+        let initial = parse("+[]").unwrap();
+        let expected = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 1, end: 2 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        assert_eq!(annotate_known_zero(initial), expected);
+    }
+
+    #[test]
+    fn should_not_annotate_known_zero_twice() {
+        // The existing Set 0 instructions have different positions
+        // to the annotations we would insert.
+        let initial = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 3, end: 5 }),
+            },
+            Loop {
+                body: vec![],
+                offset: 0,
+                position: Some(Position { start: 6, end: 7 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 8, end: 10 }),
+            },
+            ScanLoop {
+                step: 1,
+                position: Some(Position { start: 11, end: 13 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: None,
+            },
+        ];
+        assert_eq!(annotate_known_zero(initial.clone()), initial);
+    }
+
+    #[test]
+    fn should_annotate_known_zero_nested() {
+        let initial = parse("[[]]").unwrap();
+        let expected = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![
+                    Loop {
+                        body: vec![],
+                        offset: 0,
+                        position: Some(Position { start: 1, end: 2 }),
+                    },
+                    Set {
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position: Some(Position { start: 2, end: 2 }),
+                    },
+                ],
+                offset: 0,
+                position: Some(Position { start: 0, end: 3 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+        ];
+        assert_eq!(annotate_known_zero(initial), expected);
+    }
+
+    /// When we annotate known zeroes, we have new opportunities for
+    /// combining instructions and loop removal. However, we should later
+    /// remove the Set 0 if we haven't combined it.
+    #[test]
+    fn should_annotate_known_zero_cleaned_up() {
+        let initial = vec![Write {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        assert_eq!(
+            optimize(initial.clone(), OptimizeOptions::default()).0,
+            initial
+        );
+    }
+
+    #[test]
+    fn should_preserve_set_0_in_loop() {
+        // Regression test. The loop always zeroes the cell, so it
+        // becomes a Set 0, but we must not drop the Set and leave an
+        // infinite loop.
+        let initial = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Loop {
+                body: vec![Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: Some(Position { start: 1, end: 1 }),
+                }],
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        let expected = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        assert_eq!(optimize(initial, OptimizeOptions::default()).0, expected);
+    }
+
+    #[test]
+    fn should_remove_pure_code() {
+        // The final increment here is side-effect free and can be
+        // removed.
+        let initial = parse("+.+").unwrap();
+        let expected = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+        ];
+
+        let (result, warnings) = optimize(initial, OptimizeOptions::default());
+
+        assert_eq!(result, expected);
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                message: "These instructions have no effect.".to_owned(),
+                position: Some(Position { start: 2, end: 2 }),
+            }]
+        );
+    }
+
+    #[test]
+    fn should_remove_pure_ptr_increments() {
+        let initial = parse(".>><<").unwrap();
+        let expected = vec![Write {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+
+        let (result, warning) = remove_pure_code(initial);
+
+        assert_eq!(result, expected);
+        assert_eq!(
+            warning,
+            Some(Warning {
+                message: "These instructions have no effect.".to_owned(),
+                position: Some(Position { start: 1, end: 4 }),
+            })
+        );
+    }
+
+    #[test]
+    fn should_remove_pure_sets_and_multiply_moves() {
+        let initial = parse(".[-] comment >[->+<]").unwrap();
+        let expected = vec![Write {
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+
+        let (result, warnings) = optimize(initial, OptimizeOptions::default());
+
+        assert_eq!(result, expected);
+        // The warning should cover all the removed instructions,
+        // including the comment between them.
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                message: "These instructions have no effect.".to_owned(),
+                position: Some(Position { start: 1, end: 19 }),
+            }]
+        );
+    }
+
+    #[test]
+    fn quickcheck_should_remove_dead_pure_code() {
+        fn should_remove_dead_pure_code(instrs: Vec<AstNode>) -> TestResult {
+            if !is_pure(&instrs) {
+                return TestResult::discard();
+            }
+            TestResult::from_bool(optimize(instrs, OptimizeOptions::default()).0 == vec![])
+        }
+        quickcheck(should_remove_dead_pure_code as fn(Vec<AstNode>) -> TestResult);
+    }
+
+    #[test]
+    fn quickcheck_optimize_should_be_idempotent() {
+        fn optimize_should_be_idempotent(instrs: Vec<AstNode>) -> bool {
+            // Once we've optimized once, running again shouldn't reduce the
+            // instructions further. If it does, we're probably running our
+            // optimisations in the wrong order.
+            let minimal = optimize(instrs, OptimizeOptions::default()).0;
+            optimize(minimal.clone(), OptimizeOptions::default()).0 == minimal
+        }
+        quickcheck(optimize_should_be_idempotent as fn(Vec<AstNode>) -> bool);
+    }
+
+    #[test]
+    fn pathological_optimisation_opportunity() {
+        let instrs = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        let expected = vec![
+            Read {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        assert_eq!(optimize(instrs, OptimizeOptions::default()).0, expected);
+    }
+
+    #[test]
+    fn quickcheck_optimize_should_decrease_size() {
+        fn optimize_should_decrease_size(instrs: Vec<AstNode>) -> bool {
+            // The result of optimize() should never increase the number of
+            // instructions.
+            let result = optimize(instrs.clone(), OptimizeOptions::default()).0;
+            count_instrs(&result) <= count_instrs(&instrs)
+        }
+        quickcheck(optimize_should_decrease_size as fn(Vec<AstNode>) -> bool);
+    }
+
+    /// Optimising the sample programs should never produce more
+    /// instructions than the baseline in `instr_counts.txt`.
+    #[test]
+    fn optimised_instr_counts_have_not_regressed() {
+        let baseline = include_str!("../sample_programs/instr_counts.txt");
+        let mut regressions = vec![];
+
+        for line in baseline.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (file_name, expected_count) = line.split_once(' ').unwrap();
+            let expected_count: u64 = expected_count.parse().unwrap();
+
+            let path = format!(
+                "{}/sample_programs/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                file_name
+            );
+            let src = std::fs::read_to_string(path).unwrap();
+            let (result, _) = optimize(parse(&src).unwrap(), OptimizeOptions::default());
+
+            let count = count_instrs(&result);
+            if count > expected_count {
+                regressions.push(format!(
+                    "{}: {} instructions, baseline is {}",
+                    file_name, count, expected_count
+                ));
+            }
+        }
+
+        assert!(
+            regressions.is_empty(),
+            "Optimised programs are larger than the baseline:\n{}",
+            regressions.join("\n")
+        );
+    }
+
+    #[test]
+    fn should_extract_multiply_simple() {
+        let instrs = parse("[->+++<]").unwrap();
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(3));
+        let expected = vec![MultiplyMove {
+            changes: dest_cells,
+            position: Some(Position { start: 0, end: 7 }),
+        }];
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_extract_multiply_nested() {
+        let instrs = parse("[[->+<]]").unwrap();
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(1));
+        let expected = vec![Loop {
+            body: vec![MultiplyMove {
+                changes: dest_cells,
+                position: Some(Position { start: 1, end: 6 }),
             }],
-            position: Some(Position { start: 0, end: 3 }),
+            offset: 0,
+            position: Some(Position { start: 0, end: 7 }),
+        }];
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_extract_multiply_negative_number() {
+        let instrs = parse("[->--<]").unwrap();
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(-2));
+        let expected = vec![MultiplyMove {
+            changes: dest_cells,
+            position: Some(Position { start: 0, end: 6 }),
+        }];
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_extract_multiply_multiple_cells() {
+        let instrs = parse("[->+++>>>+<<<<]").unwrap();
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(3));
+        dest_cells.insert(4, Wrapping(1));
+        let expected = vec![MultiplyMove {
+            changes: dest_cells,
+            position: Some(Position { start: 0, end: 14 }),
+        }];
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_extract_multiply_mixed_signs() {
+        let instrs = parse("[->+>-<<]").unwrap();
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(1));
+        dest_cells.insert(2, Wrapping(-1));
+        let expected = vec![MultiplyMove {
+            changes: dest_cells,
+            position: Some(Position { start: 0, end: 8 }),
         }];
-        assert_eq!(combine_increments(initial), expected);
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_not_extract_multiply_net_movement() {
+        let instrs = parse("[->+++<<]").unwrap();
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_extract_multiply_from_clear_loop() {
+        let instrs = parse("[-]").unwrap();
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_extract_multiply_with_inner_loop() {
+        let instrs = parse("[->+++<[]]").unwrap();
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    /// We need to decrement the initial cell in order for this to be a
+    /// multiply.
+    #[test]
+    fn should_not_extract_multiply_without_decrement() {
+        let instrs = parse("[+>++<]").unwrap();
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_extract_multiply_with_read() {
+        let instrs = parse("[+>++<,]").unwrap();
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_extract_multiply_with_write() {
+        let instrs = parse("[+>++<.]").unwrap();
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_extract_multiply_with_offsets() {
+        let instrs = vec![Loop {
+            body: vec![
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: None,
+                },
+                Increment {
+                    amount: Wrapping(2),
+                    offset: 1,
+                    position: None,
+                },
+            ],
+            offset: 0,
+            position: None,
+        }];
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(2));
+        let expected = vec![MultiplyMove {
+            changes: dest_cells,
+            position: None,
+        }];
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_extract_even_multiply() {
+        let instrs = combine_increments(annotate_known_zero(parse("++[-->++++<]").unwrap()));
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(2));
+        let expected = vec![
+            instrs[0].clone(),
+            instrs[1].clone(),
+            MultiplyMove {
+                changes: dest_cells,
+                position: Some(Position { start: 2, end: 11 }),
+            },
+            instrs[3].clone(),
+        ];
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_not_extract_even_multiply_from_unknown_cell() {
+        // If the input is odd, this loop never terminates.
+        let instrs = parse(",[-->++<]").unwrap();
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_extract_even_multiply_from_odd_cell() {
+        let instrs = combine_increments(annotate_known_zero(parse("+++[-->++<]").unwrap()));
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_extract_even_multiply_with_odd_change() {
+        // We can't halve 3.
+        let instrs = combine_increments(annotate_known_zero(parse("++[-->+++<]").unwrap()));
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_extract_odd_multiply() {
+        let instrs = parse("[--->+<]").unwrap();
+
+        // 171 * 3 == 1 with wrapping arithmetic, so adding 171 for
+        // every 3 in cell #0 adds cell #0 / 3.
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(171_u8 as i8));
+        let expected = vec![MultiplyMove {
+            changes: dest_cells,
+            position: Some(Position { start: 0, end: 7 }),
+        }];
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_extract_multiple_of_four_multiply() {
+        let instrs =
+            combine_increments(annotate_known_zero(parse("++++++++[---->++++<]").unwrap()));
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(1, Wrapping(1));
+        let expected = vec![
+            instrs[0].clone(),
+            instrs[1].clone(),
+            MultiplyMove {
+                changes: dest_cells,
+                position: Some(Position { start: 8, end: 19 }),
+            },
+            instrs[3].clone(),
+        ];
+
+        assert_eq!(extract_multiply(instrs), expected);
+    }
+
+    #[test]
+    fn should_not_extract_multiple_of_four_multiply_from_even_cell() {
+        // 6 is even, but this loop never terminates.
+        let instrs = combine_increments(annotate_known_zero(parse("++++++[---->++++<]").unwrap()));
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_extract_multiple_of_four_multiply_with_even_change() {
+        // We can't divide 6 by 4.
+        let instrs = combine_increments(annotate_known_zero(
+            parse("++++++++[---->++++++<]").unwrap(),
+        ));
+        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn odd_inverse_wraps() {
+        for value in (1..=255_u8).step_by(2) {
+            let value = Wrapping(value as i8);
+            assert_eq!(value * odd_inverse(value), Wrapping(1));
+        }
+    }
+
+    /// Build the instructions for `[->+>+<<]>>[-<<+>>]`, preceded by
+    /// a Set at `temp_amount` on the temporary cell.
+    fn copy_via_temp(temp_amount: i8, copy_factor: i8) -> Vec<AstNode> {
+        let mut copy_changes = HashMap::new();
+        copy_changes.insert(1, Wrapping(copy_factor));
+        copy_changes.insert(2, Wrapping(1));
+
+        let mut restore_changes = HashMap::new();
+        restore_changes.insert(-2, Wrapping(1));
+
+        vec![
+            Set {
+                amount: Wrapping(temp_amount),
+                offset: 2,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            MultiplyMove {
+                changes: copy_changes,
+                position: Some(Position { start: 1, end: 10 }),
+            },
+            PointerIncrement {
+                amount: 2,
+                position: Some(Position { start: 11, end: 12 }),
+            },
+            MultiplyMove {
+                changes: restore_changes,
+                position: Some(Position { start: 13, end: 20 }),
+            },
+        ]
+    }
+
+    #[test]
+    fn should_combine_copy_loops() {
+        let instrs = copy_via_temp(0, 1);
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(0, Wrapping(1));
+        dest_cells.insert(1, Wrapping(1));
+        let expected = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 2,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            MultiplyMove {
+                changes: dest_cells,
+                position: Some(Position { start: 13, end: 20 }),
+            },
+            PointerIncrement {
+                amount: 2,
+                position: Some(Position { start: 11, end: 12 }),
+            },
+        ];
+
+        assert_eq!(combine_copy_loops(instrs), expected);
+    }
+
+    #[test]
+    fn should_combine_copy_loops_multiply() {
+        let instrs = copy_via_temp(0, 3);
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(0, Wrapping(1));
+        dest_cells.insert(1, Wrapping(3));
+
+        let result = combine_copy_loops(instrs);
+        assert!(matches!(
+            result[1],
+            MultiplyMove { ref changes, .. } if *changes == dest_cells
+        ));
+    }
+
+    #[test]
+    fn should_combine_copy_loops_nested() {
+        let instrs = vec![Loop {
+            body: copy_via_temp(0, 1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+
+        let result = combine_copy_loops(instrs);
+        assert!(matches!(
+            result[0],
+            Loop { ref body, .. } if body.len() == 3
+        ));
+    }
+
+    /// If the temporary cell isn't zero, we'd restore its old value
+    /// too, so this isn't a copy.
+    #[test]
+    fn should_not_combine_copy_loops_nonzero_temp() {
+        let instrs = copy_via_temp(5, 1);
+        assert_eq!(combine_copy_loops(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_combine_copy_loops_unknown_temp() {
+        let instrs = copy_via_temp(0, 1)[1..].to_vec();
+        assert_eq!(combine_copy_loops(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_combine_copy_loops_restore_elsewhere() {
+        let mut instrs = copy_via_temp(0, 1);
+        instrs[2] = PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 11, end: 12 }),
+        };
+        assert_eq!(combine_copy_loops(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_combine_copy_loops_restore_to_other_cells() {
+        let mut instrs = copy_via_temp(0, 1);
+        let mut restore_changes = HashMap::new();
+        restore_changes.insert(-2, Wrapping(1));
+        restore_changes.insert(1, Wrapping(2));
+        instrs[3] = MultiplyMove {
+            changes: restore_changes,
+            position: Some(Position { start: 13, end: 20 }),
+        };
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(0, Wrapping(1));
+        dest_cells.insert(1, Wrapping(1));
+        dest_cells.insert(3, Wrapping(2));
+
+        let result = combine_copy_loops(instrs);
+        assert!(matches!(
+            result[1],
+            MultiplyMove { ref changes, .. } if *changes == dest_cells
+        ));
+    }
+
+    /// `[->+>+<<]>>[-<<+>+>]` adds the current cell to cell #1 twice.
+    #[test]
+    fn should_combine_copy_loops_restore_to_same_cell() {
+        let mut instrs = copy_via_temp(0, 1);
+        let mut restore_changes = HashMap::new();
+        restore_changes.insert(-2, Wrapping(1));
+        restore_changes.insert(-1, Wrapping(1));
+        instrs[3] = MultiplyMove {
+            changes: restore_changes,
+            position: Some(Position { start: 13, end: 20 }),
+        };
+
+        let mut dest_cells = HashMap::new();
+        dest_cells.insert(0, Wrapping(1));
+        dest_cells.insert(1, Wrapping(2));
+
+        let result = combine_copy_loops(instrs);
+        assert!(matches!(
+            result[1],
+            MultiplyMove { ref changes, .. } if *changes == dest_cells
+        ));
+    }
+
+    /// If the temporary cell gets twice the current cell, it's zero
+    /// when the current cell is 128.
+    #[test]
+    fn should_not_combine_copy_loops_temp_factor() {
+        let mut instrs = copy_via_temp(0, 1);
+        let mut copy_changes = HashMap::new();
+        copy_changes.insert(1, Wrapping(1));
+        copy_changes.insert(2, Wrapping(2));
+        instrs[1] = MultiplyMove {
+            changes: copy_changes,
+            position: Some(Position { start: 1, end: 10 }),
+        };
+        assert_eq!(combine_copy_loops(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_not_combine_copy_loops_restore_keeps_temp() {
+        let mut instrs = copy_via_temp(0, 1);
+        let mut restore_changes = HashMap::new();
+        restore_changes.insert(-2, Wrapping(1));
+        restore_changes.insert(0, Wrapping(1));
+        instrs[3] = MultiplyMove {
+            changes: restore_changes,
+            position: Some(Position { start: 13, end: 20 }),
+        };
+        assert_eq!(combine_copy_loops(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_combine_copy_loops_from_source() {
+        let instrs = parse(",>>[-]<<[->+>+<<]>>[-<<+>>]<<.").unwrap();
+        let result = optimize(instrs, OptimizeOptions::default()).0;
+
+        assert!(result.iter().any(|instr| matches!(
+            instr,
+            CellCopy {
+                src_offset: 0,
+                dst_offset: 1,
+                overwrite: false,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn should_merge_copy_and_move() {
+        let instrs = vec![
+            MultiplyMove {
+                changes: HashMap::from([(0, Wrapping(1)), (1, Wrapping(1))]),
+                position: Some(Position { start: 0, end: 10 }),
+            },
+            MultiplyMove {
+                changes: HashMap::from([(3, Wrapping(2))]),
+                position: Some(Position { start: 11, end: 20 }),
+            },
+        ];
+        let expected = vec![MultiplyMove {
+            changes: HashMap::from([(1, Wrapping(1)), (3, Wrapping(2))]),
+            position: Some(Position { start: 0, end: 20 }),
+        }];
+        assert_eq!(merge_multiply_moves(instrs), expected);
+    }
+
+    #[test]
+    fn should_merge_cell_copy_and_move() {
+        let instrs = vec![
+            CellCopy {
+                src_offset: 0,
+                dst_offset: 1,
+                overwrite: false,
+                position: Some(Position { start: 0, end: 10 }),
+            },
+            MultiplyMove {
+                changes: HashMap::from([(3, Wrapping(2))]),
+                position: Some(Position { start: 11, end: 20 }),
+            },
+        ];
+        let expected = vec![MultiplyMove {
+            changes: HashMap::from([(1, Wrapping(1)), (3, Wrapping(2))]),
+            position: Some(Position { start: 0, end: 20 }),
+        }];
+        assert_eq!(merge_multiply_moves(instrs), expected);
+    }
+
+    #[test]
+    fn should_not_merge_cell_copies() {
+        let instrs = vec![
+            CellCopy {
+                src_offset: 0,
+                dst_offset: 1,
+                overwrite: false,
+                position: None,
+            },
+            CellCopy {
+                src_offset: 0,
+                dst_offset: 2,
+                overwrite: false,
+                position: None,
+            },
+        ];
+        assert_eq!(merge_multiply_moves(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_extract_cell_copy() {
+        let instrs = vec![MultiplyMove {
+            changes: HashMap::from([(0, Wrapping(1)), (2, Wrapping(1))]),
+            position: Some(Position { start: 0, end: 10 }),
+        }];
+        let expected = vec![CellCopy {
+            src_offset: 0,
+            dst_offset: 2,
+            overwrite: false,
+            position: Some(Position { start: 0, end: 10 }),
+        }];
+        assert_eq!(extract_cell_copies(instrs), expected);
+    }
+
+    #[test]
+    fn should_extract_cell_copy_overwriting_known_zero() {
+        let instrs = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 2,
+                position: None,
+            },
+            Write {
+                offset: 2,
+                position: None,
+            },
+            MultiplyMove {
+                changes: HashMap::from([(0, Wrapping(1)), (2, Wrapping(1))]),
+                position: None,
+            },
+        ];
+        let mut expected = instrs.clone();
+        expected[2] = CellCopy {
+            src_offset: 0,
+            dst_offset: 2,
+            overwrite: true,
+            position: None,
+        };
+        assert_eq!(extract_cell_copies(instrs), expected);
     }
 
     #[test]
-    fn combine_increments_remove_redundant() {
-        let initial = parse("+-").unwrap();
-        assert_eq!(combine_increments(initial), vec![]);
+    fn should_not_extract_cell_copy_with_factor() {
+        let instrs = vec![MultiplyMove {
+            changes: HashMap::from([(0, Wrapping(1)), (2, Wrapping(3))]),
+            position: None,
+        }];
+        assert_eq!(extract_cell_copies(instrs.clone()), instrs);
+
+        let instrs = vec![MultiplyMove {
+            changes: HashMap::from([(0, Wrapping(1)), (1, Wrapping(1)), (2, Wrapping(1))]),
+            position: None,
+        }];
+        assert_eq!(extract_cell_copies(instrs.clone()), instrs);
     }
 
+    /// Adding a cell to itself doubles it, even if we know the cell
+    /// was zero beforehand.
     #[test]
-    fn quickcheck_combine_increments_remove_zero_any_offset() {
-        fn combine_increments_remove_zero_any_offset(offset: isize) -> bool {
-            let initial = vec![Increment {
+    fn should_not_overwrite_with_aliased_cell_copy() {
+        let instrs = vec![
+            Set {
                 amount: Wrapping(0),
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            }];
-            combine_increments(initial) == vec![]
-        }
-        quickcheck(combine_increments_remove_zero_any_offset as fn(isize) -> bool);
+                offset: 1,
+                position: None,
+            },
+            CellCopy {
+                src_offset: 1,
+                dst_offset: 1,
+                overwrite: false,
+                position: None,
+            },
+        ];
+        assert_eq!(extract_cell_copies(instrs.clone()), instrs);
     }
 
     #[test]
-    fn combine_increment_sum_to_zero() {
-        let initial = vec![
-            Increment {
-                amount: Wrapping(-1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+    fn should_fold_known_cell_copy() {
+        let set = Set {
+            amount: Wrapping(3),
+            offset: 1,
+            position: None,
+        };
+
+        let instrs = vec![
+            set.clone(),
+            CellCopy {
+                src_offset: 1,
+                dst_offset: 2,
+                overwrite: false,
+                position: None,
             },
+        ];
+        let expected = vec![
+            set.clone(),
             Increment {
-                amount: Wrapping(1),
+                amount: Wrapping(3),
+                offset: 2,
+                position: None,
+            },
+        ];
+        assert_eq!(fold_known_multiply_moves(instrs), expected);
+
+        let instrs = vec![
+            set.clone(),
+            CellCopy {
+                src_offset: 1,
+                dst_offset: 0,
+                overwrite: true,
+                position: None,
+            },
+        ];
+        let expected = vec![
+            set,
+            Set {
+                amount: Wrapping(3),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
         ];
-        assert_eq!(combine_increments(initial), vec![]);
+        assert_eq!(fold_known_multiply_moves(instrs), expected);
     }
 
     #[test]
-    fn should_combine_ptr_increments() {
-        let initial = parse(">>").unwrap();
-        let expected = vec![PointerIncrement {
-            amount: 2,
-            position: Some(Position { start: 0, end: 1 }),
-        }];
-        assert_eq!(combine_ptr_increments(initial), expected);
+    fn should_remove_dead_store_before_cell_copy_overwrite() {
+        let instrs = vec![
+            Set {
+                amount: Wrapping(5),
+                offset: 1,
+                position: None,
+            },
+            CellCopy {
+                src_offset: 0,
+                dst_offset: 1,
+                overwrite: true,
+                position: None,
+            },
+        ];
+        assert_eq!(remove_dead_stores(instrs.clone()), instrs[1..].to_vec());
     }
 
+    /// A CellCopy reads its source cell, so changes to the source
+    /// before it aren't clobbered by a later read.
     #[test]
-    fn combine_set_sum_to_zero() {
-        let initial = vec![
-            Set {
-                amount: Wrapping(-1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
+    fn should_not_remove_read_clobber_used_by_cell_copy() {
+        let instrs = vec![
             Increment {
                 amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                offset: 1,
+                position: None,
+            },
+            CellCopy {
+                src_offset: 1,
+                dst_offset: 2,
+                overwrite: false,
+                position: None,
+            },
+            Read {
+                offset: 1,
+                position: None,
             },
         ];
-        assert_eq!(
-            combine_set_and_increments(initial),
-            vec![Set {
-                amount: Wrapping(0),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            }]
-        );
+        assert_eq!(remove_read_clobber(instrs.clone()).0, instrs);
+        assert_eq!(remove_dead_stores(instrs.clone()), instrs);
     }
 
+    /// The second MultiplyMove reads the current cell after the first
+    /// has scaled it.
     #[test]
-    fn should_combine_before_read() {
-        // The increment before the read is dead and can be removed.
-        let initial = parse("+,.").unwrap();
-        let expected = vec![
-            Read {
-                position: Some(Position { start: 1, end: 1 }),
+    fn should_merge_scaled_multiply_moves() {
+        let instrs = vec![
+            MultiplyMove {
+                changes: HashMap::from([(0, Wrapping(2)), (1, Wrapping(1))]),
+                position: Some(Position { start: 0, end: 0 }),
             },
-            Write {
-                position: Some(Position { start: 2, end: 2 }),
+            MultiplyMove {
+                changes: HashMap::from([(0, Wrapping(3)), (1, Wrapping(1)), (2, Wrapping(1))]),
+                position: Some(Position { start: 1, end: 1 }),
             },
         ];
-        assert_eq!(optimize(initial, &None).0, expected);
+        let expected = vec![MultiplyMove {
+            changes: HashMap::from([(0, Wrapping(6)), (1, Wrapping(3)), (2, Wrapping(2))]),
+            position: Some(Position { start: 0, end: 1 }),
+        }];
+        assert_eq!(merge_multiply_moves(instrs), expected);
     }
 
+    /// A MultiplyMove that zeroes the current cell makes the next one
+    /// a no-op.
     #[test]
-    fn dont_combine_before_read_different_offset() {
-        // The read does not affect the increment here.
-        let initial = vec![
-            Increment {
-                amount: Wrapping(1),
-                offset: 2,
+    fn should_merge_multiply_move_after_zeroing() {
+        let instrs = parse("[->+<][->>+<<]").unwrap();
+        let instrs = extract_multiply(instrs);
+        let expected = vec![MultiplyMove {
+            changes: HashMap::from([(1, Wrapping(1))]),
+            position: Some(Position { start: 0, end: 13 }),
+        }];
+        assert_eq!(merge_multiply_moves(instrs), expected);
+    }
+
+    #[test]
+    fn should_merge_cancelling_multiply_moves_to_set() {
+        let instrs = vec![
+            MultiplyMove {
+                changes: HashMap::from([(0, Wrapping(1)), (1, Wrapping(1))]),
                 position: Some(Position { start: 0, end: 0 }),
             },
-            Read {
-                position: Some(Position { start: 0, end: 0 }),
+            MultiplyMove {
+                changes: HashMap::from([(1, Wrapping(-1))]),
+                position: Some(Position { start: 1, end: 1 }),
             },
         ];
-        assert_eq!(remove_read_clobber(initial.clone()), initial);
+        let expected = vec![Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 1 }),
+        }];
+        assert_eq!(merge_multiply_moves(instrs), expected);
     }
 
     #[test]
-    fn should_combine_before_read_nested() {
-        // The read clobbers the increment here.
-        let initial = parse("+[+,]").unwrap();
-        let expected = vec![
+    fn should_fold_multiply_move_with_known_cell() {
+        let instrs = vec![
             Set {
-                amount: Wrapping(1),
+                amount: Wrapping(3),
                 offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
-            Loop {
-                body: vec![Read {
-                    position: Some(Position { start: 3, end: 3 }),
-                }],
-                position: Some(Position { start: 1, end: 4 }),
+            MultiplyMove {
+                changes: HashMap::from([(1, Wrapping(2)), (-1, Wrapping(1))]),
+                position: Some(Position { start: 1, end: 8 }),
             },
         ];
-        assert_eq!(optimize(initial, &None).0, expected);
-    }
-
-    #[test]
-    fn combine_before_read_not_consecutive() {
-        // The increment before the read is dead and can be removed.
-        let initial = parse("+>-<,").unwrap();
         let expected = vec![
-            PointerIncrement {
-                amount: 1,
-                position: Some(Position { start: 1, end: 1 }),
+            Set {
+                amount: Wrapping(3),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
             },
             Increment {
-                amount: Wrapping(-1),
-                offset: 0,
-                position: Some(Position { start: 2, end: 2 }),
+                amount: Wrapping(3),
+                offset: -1,
+                position: Some(Position { start: 1, end: 8 }),
             },
-            PointerIncrement {
-                amount: -1,
-                position: Some(Position { start: 3, end: 3 }),
+            Increment {
+                amount: Wrapping(6),
+                offset: 1,
+                position: Some(Position { start: 1, end: 8 }),
             },
-            Read {
-                position: Some(Position { start: 4, end: 4 }),
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 1, end: 8 }),
             },
         ];
-        assert_eq!(remove_read_clobber(initial), expected);
+        assert_eq!(fold_known_multiply_moves(instrs), expected);
     }
 
     #[test]
-    fn no_combine_before_read_after_write() {
-        let initial = vec![
+    fn should_fold_multiply_move_preserving_cell() {
+        // A copy leaves the current cell unchanged.
+        let instrs = vec![
             Set {
-                amount: Wrapping(1),
+                amount: Wrapping(5),
                 offset: 0,
                 position: None,
             },
-            Write { position: None },
-            Read { position: None },
-        ];
-        // TODO: write an assert_unchanged! macro.
-        let expected = initial.clone();
-        assert_eq!(remove_read_clobber(initial), expected);
-    }
-
-    #[test]
-    fn no_combine_before_read_after_multiply() {
-        let mut changes = HashMap::new();
-        changes.insert(1, Wrapping(-1));
-        let initial = vec![
             MultiplyMove {
-                changes,
+                changes: HashMap::from([(0, Wrapping(1)), (2, Wrapping(1))]),
                 position: None,
             },
-            Read { position: None },
         ];
-        let expected = initial.clone();
-        assert_eq!(remove_read_clobber(initial), expected);
-    }
-
-    #[test]
-    fn simplify_zeroing_loop() {
-        let initial = parse("[-]").unwrap();
-        let expected = vec![Set {
-            amount: Wrapping(0),
-            offset: 0,
-            position: Some(Position { start: 0, end: 2 }),
-        }];
-        assert_eq!(zeroing_loops(initial), expected);
+        let result = fold_known_multiply_moves(instrs);
+        assert_eq!(
+            result[1..],
+            [
+                Increment {
+                    amount: Wrapping(5),
+                    offset: 2,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(5),
+                    offset: 0,
+                    position: None,
+                },
+            ]
+        );
     }
 
     #[test]
-    fn simplify_nested_zeroing_loop() {
-        let initial = parse("[[-]]").unwrap();
-        let expected = vec![Loop {
-            body: vec![Set {
-                amount: Wrapping(0),
-                offset: 0,
-                position: Some(Position { start: 1, end: 3 }),
-            }],
-            position: Some(Position { start: 0, end: 4 }),
-        }];
-        assert_eq!(zeroing_loops(initial), expected);
+    fn should_not_fold_multiply_move_with_unknown_cell() {
+        let instrs = parse(",[->+<]").unwrap();
+        let instrs = extract_multiply(instrs);
+        assert_eq!(fold_known_multiply_moves(instrs.clone()), instrs);
     }
 
     #[test]
-    fn dont_simplify_multiple_decrement_loop() {
-        // A user who wrote this probably meant '[-]'. However, if the
-        // current cell has the value 3, we would actually wrap around
-        // (although BF does not specify this).
-        let initial = parse("[--]").unwrap();
-        assert_eq!(zeroing_loops(initial.clone()), initial);
+    fn should_fold_multiply_move_from_source() {
+        let instrs = parse("+++[->++<]>.").unwrap();
+        let result = optimize(instrs, OptimizeOptions::default()).0;
+        assert!(!result
+            .iter()
+            .any(|instr| matches!(instr, MultiplyMove { .. })));
     }
 
     #[test]
-    fn remove_repeated_loops() {
-        let initial = vec![
+    fn should_unroll_loop_with_known_trip_count() {
+        let instrs = vec![
             Set {
-                amount: Wrapping(1),
+                amount: Wrapping(2),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body: vec![
+                    Write {
+                        offset: 0,
+                        position: None,
+                    },
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: 0,
+                        position: None,
+                    },
+                ],
+                offset: 0,
+                position: None,
+            },
+        ];
+        let expected = vec![
+            Set {
+                amount: Wrapping(2),
+                offset: 0,
+                position: None,
+            },
+            Write {
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
-            Loop {
-                body: vec![],
-                position: Some(Position { start: 0, end: 0 }),
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: None,
             },
-            Loop {
-                body: vec![],
-                position: Some(Position { start: 0, end: 0 }),
+            Write {
+                offset: 0,
+                position: None,
+            },
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: None,
             },
         ];
-        let expected = vec![
+        assert_eq!(unroll_constant_loops(instrs, 8), expected);
+    }
+
+    #[test]
+    fn should_unroll_loop_with_offset() {
+        // The loop tests the cell at offset 1.
+        let instrs = vec![
             Set {
                 amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                offset: 1,
+                position: None,
             },
             Loop {
-                body: vec![],
-                position: Some(Position { start: 0, end: 0 }),
+                body: vec![
+                    Write {
+                        offset: 0,
+                        position: None,
+                    },
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: 1,
+                        position: None,
+                    },
+                ],
+                offset: 1,
+                position: None,
             },
         ];
-        assert_eq!(optimize(initial, &None).0, expected);
+        let result = unroll_constant_loops(instrs, 8);
+        assert_eq!(
+            result[1..],
+            [
+                Write {
+                    offset: 0,
+                    position: None,
+                },
+                Increment {
+                    amount: Wrapping(-1),
+                    offset: 1,
+                    position: None,
+                },
+            ]
+        );
     }
 
     #[test]
-    fn remove_dead_loops_after_set() {
-        let initial = vec![
+    fn should_not_unroll_loop_over_limit() {
+        let instrs = vec![
             Set {
-                amount: Wrapping(0),
+                amount: Wrapping(3),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: None,
             },
             Loop {
-                body: vec![],
-                position: Some(Position { start: 0, end: 0 }),
+                body: vec![
+                    Write {
+                        offset: 0,
+                        position: None,
+                    },
+                    Increment {
+                        amount: Wrapping(-1),
+                        offset: 0,
+                        position: None,
+                    },
+                ],
+                offset: 0,
+                position: None,
             },
         ];
-        let expected = vec![Set {
-            amount: Wrapping(0),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        assert_eq!(remove_dead_loops(initial), expected);
+        assert_eq!(unroll_constant_loops(instrs.clone(), 2), instrs);
+        assert_eq!(unroll_constant_loops(instrs.clone(), 0), instrs);
+        assert_eq!(count_instrs(&unroll_constant_loops(instrs, 3)), 7);
     }
 
     #[test]
-    fn remove_dead_loops_nested() {
-        let initial = vec![Loop {
-            body: vec![
-                Set {
-                    amount: Wrapping(0),
-                    offset: 0,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Loop {
-                    body: vec![],
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-            ],
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        let expected = vec![Loop {
-            body: vec![Set {
-                amount: Wrapping(0),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            }],
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        assert_eq!(remove_dead_loops(initial), expected);
+    fn should_not_unroll_loop_with_unknown_cell() {
+        let instrs = parse(",[.-]").unwrap();
+        assert_eq!(unroll_constant_loops(instrs.clone(), 8), instrs);
     }
 
     #[test]
-    fn remove_dead_loops_not_adjacent() {
-        let initial = vec![
+    fn should_not_unroll_loop_with_read() {
+        let instrs = parse("[-]++[>,<-]").unwrap();
+        let instrs = combine_set_and_increments(zeroing_loops(instrs));
+        assert_eq!(unroll_constant_loops(instrs.clone(), 8), instrs);
+    }
+
+    #[test]
+    fn should_not_unroll_loop_moving_pointer() {
+        let instrs = parse("[-]++[.>-]").unwrap();
+        let instrs = combine_set_and_increments(zeroing_loops(instrs));
+        assert_eq!(unroll_constant_loops(instrs.clone(), 8), instrs);
+    }
+
+    #[test]
+    fn should_not_unroll_loop_that_never_terminates() {
+        // 1 + 2n is never zero.
+        let instrs = parse("[-]+[.++]").unwrap();
+        let instrs = combine_set_and_increments(zeroing_loops(instrs));
+        assert_eq!(unroll_constant_loops(instrs.clone(), 8), instrs);
+    }
+
+    #[test]
+    fn should_remove_run_once_loop() {
+        let instrs = parse("[-]+[[-]>.<]").unwrap();
+        let instrs = combine_set_and_increments(zeroing_loops(instrs));
+        let expected = vec![
             Set {
-                amount: Wrapping(0),
+                amount: Wrapping(1),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 0, end: 3 }),
             },
             Set {
-                amount: Wrapping(1),
-                offset: 1,
-                position: Some(Position { start: 0, end: 0 }),
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 5, end: 7 }),
             },
-            Loop {
-                body: vec![],
-                position: Some(Position { start: 0, end: 0 }),
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 8, end: 8 }),
             },
-        ];
-        let expected = vec![
-            Set {
-                amount: Wrapping(0),
+            Write {
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 9, end: 9 }),
             },
-            Set {
-                amount: Wrapping(1),
-                offset: 1,
-                position: Some(Position { start: 0, end: 0 }),
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 10, end: 10 }),
             },
         ];
-        assert_eq!(remove_dead_loops(initial), expected);
+        assert_eq!(remove_run_once_loops(instrs), expected);
     }
 
     #[test]
-    fn quickcheck_should_combine_set_and_increment() {
-        fn should_combine_set_and_increment(
-            offset: isize,
-            set_amount: i8,
-            increment_amount: i8,
-        ) -> bool {
-            let set_amount = Wrapping(set_amount);
-            let increment_amount = Wrapping(increment_amount);
-
-            let initial = vec![
-                Set {
-                    amount: set_amount,
-                    offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Increment {
-                    amount: increment_amount,
-                    offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-            ];
-            let expected = vec![Set {
-                amount: set_amount + increment_amount,
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            }];
-            combine_set_and_increments(initial) == expected
-        }
-        quickcheck(should_combine_set_and_increment as fn(isize, i8, i8) -> bool);
+    fn should_not_remove_run_once_loop_with_unknown_cell() {
+        // The loop runs at most once, but may not run at all.
+        let instrs = parse(",[[-]>.<]").unwrap();
+        let instrs = zeroing_loops(instrs);
+        assert_eq!(remove_run_once_loops(instrs.clone()), instrs);
     }
 
-    // TODO: rename our quickcheck property functions to something shorter.
     #[test]
-    fn quickcheck_combine_set_and_increment_different_offsets() {
-        fn combine_set_and_increment_different_offsets(
-            set_offset: isize,
-            set_amount: i8,
-            inc_offset: isize,
-            inc_amount: i8,
-        ) -> TestResult {
-            if set_offset == inc_offset {
-                return TestResult::discard();
-            }
-
-            let initial = vec![
-                Set {
-                    amount: Wrapping(set_amount),
-                    offset: set_offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Increment {
-                    amount: Wrapping(inc_amount),
-                    offset: inc_offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-            ];
-            let expected = initial.clone();
-
-            TestResult::from_bool(combine_set_and_increments(initial) == expected)
-        }
-        quickcheck(
-            combine_set_and_increment_different_offsets as fn(isize, i8, isize, i8) -> TestResult,
-        );
+    fn should_not_remove_loop_that_changes_cell_after_clear() {
+        let instrs = parse("[-]+[[-]>.<+]").unwrap();
+        let instrs = combine_set_and_increments(zeroing_loops(instrs));
+        assert_eq!(remove_run_once_loops(instrs.clone()), instrs);
+
+        let instrs = parse("[-]+[[-]>.<,]").unwrap();
+        let instrs = combine_set_and_increments(zeroing_loops(instrs));
+        assert_eq!(remove_run_once_loops(instrs.clone()), instrs);
     }
 
     #[test]
-    fn quickcheck_combine_increment_and_set_different_offsets() {
-        fn combine_increment_and_set_different_offsets(
-            set_offset: isize,
-            set_amount: i8,
-            inc_offset: isize,
-            inc_amount: i8,
-        ) -> TestResult {
-            if set_offset == inc_offset {
-                return TestResult::discard();
-            }
-
-            let initial = vec![
-                Increment {
-                    amount: Wrapping(inc_amount),
-                    offset: inc_offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Set {
-                    amount: Wrapping(set_amount),
-                    offset: set_offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-            ];
-            let expected = initial.clone();
-
-            TestResult::from_bool(combine_set_and_increments(initial) == expected)
-        }
-        quickcheck(
-            combine_increment_and_set_different_offsets as fn(isize, i8, isize, i8) -> TestResult,
-        );
+    fn should_not_remove_loop_with_multiply_into_cell() {
+        let instrs = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body: vec![
+                    Set {
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position: None,
+                    },
+                    PointerIncrement {
+                        amount: 1,
+                        position: None,
+                    },
+                    MultiplyMove {
+                        changes: HashMap::from([(-1, Wrapping(1))]),
+                        position: None,
+                    },
+                    PointerIncrement {
+                        amount: -1,
+                        position: None,
+                    },
+                ],
+                offset: 0,
+                position: None,
+            },
+        ];
+        assert_eq!(remove_run_once_loops(instrs.clone()), instrs);
     }
 
     #[test]
-    fn quickcheck_combine_set_and_set() {
-        fn combine_set_and_set(offset: isize, set_amount_before: i8, set_amount_after: i8) -> bool {
-            let initial = vec![
-                Set {
-                    amount: Wrapping(set_amount_before),
-                    offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Set {
-                    amount: Wrapping(set_amount_after),
-                    offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-            ];
-            let expected = vec![Set {
-                amount: Wrapping(set_amount_after),
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            }];
-            combine_set_and_increments(initial) == expected
-        }
-        quickcheck(combine_set_and_set as fn(isize, i8, i8) -> bool);
+    fn should_not_remove_run_once_loop_moving_pointer() {
+        let instrs = parse("[-]+[[-]>]").unwrap();
+        let instrs = combine_set_and_increments(zeroing_loops(instrs));
+        assert_eq!(remove_run_once_loops(instrs.clone()), instrs);
     }
 
     #[test]
-    fn quickcheck_combine_set_and_set_different_offsets() {
-        fn combine_set_and_set_different_offsets(
-            offset1: isize,
-            amount1: i8,
-            offset2: isize,
-            amount2: i8,
-        ) -> TestResult {
-            if offset1 == offset2 {
-                return TestResult::discard();
-            }
-
-            let initial = vec![
-                Set {
-                    amount: Wrapping(amount1),
-                    offset: offset1,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Set {
-                    amount: Wrapping(amount2),
-                    offset: offset2,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-            ];
-            let expected = initial.clone();
-
-            TestResult::from_bool(combine_set_and_increments(initial) == expected)
-        }
-        quickcheck(combine_set_and_set_different_offsets as fn(isize, i8, isize, i8) -> TestResult);
+    fn should_unroll_loop_from_source() {
+        let instrs = parse("+++[>.+<-]").unwrap();
+        let result = optimize(instrs, OptimizeOptions::default()).0;
+        assert!(!result.iter().any(|instr| matches!(instr, Loop { .. })));
     }
 
+    /// Multiply moves from different cells read different values.
     #[test]
-    fn should_combine_set_and_set_nested() {
-        let initial = vec![Loop {
-            body: vec![
-                Set {
-                    amount: Wrapping(0),
-                    offset: 0,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Set {
-                    amount: Wrapping(1),
-                    offset: 0,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-            ],
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        let expected = vec![Loop {
-            body: vec![Set {
-                amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            }],
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        assert_eq!(combine_set_and_increments(initial), expected);
+    fn should_not_merge_multiply_moves_from_different_cells() {
+        let instrs = parse("[->+<]>[->+<]").unwrap();
+        let instrs = extract_multiply(instrs);
+        assert_eq!(merge_multiply_moves(instrs.clone()), instrs);
     }
 
     #[test]
-    fn quickcheck_should_combine_increment_and_set() {
-        fn should_combine_increment_and_set(offset: isize) -> bool {
-            let initial = vec![
-                Increment {
-                    amount: Wrapping(2),
-                    offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-                Set {
-                    amount: Wrapping(3),
-                    offset,
-                    position: Some(Position { start: 0, end: 0 }),
-                },
-            ];
-            let expected = vec![Set {
-                amount: Wrapping(3),
-                offset,
-                position: Some(Position { start: 0, end: 0 }),
-            }];
-            combine_set_and_increments(initial) == expected
-        }
-        quickcheck(should_combine_increment_and_set as fn(isize) -> bool);
+    fn should_merge_copy_and_move_from_source() {
+        let instrs = parse(",>>[-]<<[->+>+<<]>>[-<<+>>]<<[->>>+<<<]>.>>.").unwrap();
+        let result = optimize(instrs, OptimizeOptions::default()).0;
+
+        // We should have a single MultiplyMove that loads cell #0 once.
+        let multiply_changes: Vec<_> = result
+            .iter()
+            .filter_map(|instr| match instr {
+                MultiplyMove { changes, .. } => Some(changes.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            multiply_changes,
+            vec![HashMap::from([(1, Wrapping(1)), (3, Wrapping(1))])]
+        );
     }
 
     #[test]
-    fn should_remove_redundant_set() {
-        let initial = vec![
-            Loop {
-                body: vec![],
+    fn should_not_remove_set_after_copy() {
+        let mut changes = HashMap::new();
+        changes.insert(0, Wrapping(1));
+        changes.insert(1, Wrapping(1));
+
+        let instrs = vec![
+            Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
-            Set {
-                amount: Wrapping(0),
-                offset: -1,
+            MultiplyMove {
+                changes,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Set {
@@ -1526,428 +6035,327 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
-        let expected = vec![
-            Loop {
-                body: vec![],
+        assert_eq!(remove_redundant_sets(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn should_remove_clobbered_multiply_change() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+        changes.insert(2, Wrapping(1));
+
+        let instrs = vec![
+            Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 1, end: 10 }),
+            },
             Set {
                 amount: Wrapping(0),
-                offset: -1,
+                offset: 1,
+                position: Some(Position { start: 11, end: 13 }),
+            },
+        ];
+
+        let mut expected_changes = HashMap::new();
+        expected_changes.insert(2, Wrapping(1));
+        let expected = vec![
+            Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
+            MultiplyMove {
+                changes: expected_changes,
+                position: Some(Position { start: 1, end: 10 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 1,
+                position: Some(Position { start: 11, end: 13 }),
+            },
         ];
-        assert_eq!(remove_redundant_sets(initial), expected);
+        assert_eq!(remove_clobbered_multiply_changes(instrs), expected);
     }
 
     #[test]
-    fn should_remove_redundant_set_multiply() {
+    fn should_replace_fully_clobbered_multiply() {
         let mut changes = HashMap::new();
-        changes.insert(1, Wrapping(1));
+        changes.insert(1, Wrapping(2));
 
-        let initial = vec![
+        let instrs = vec![
             MultiplyMove {
-                changes: changes.clone(),
-                position: Some(Position { start: 0, end: 0 }),
+                changes,
+                position: Some(Position { start: 0, end: 5 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 6, end: 6 }),
             },
             Set {
-                amount: Wrapping(0),
+                amount: Wrapping(3),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 7, end: 9 }),
             },
         ];
-        let expected = vec![MultiplyMove {
-            changes,
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        assert_eq!(remove_redundant_sets(initial), expected);
-    }
 
-    /// After a loop, if we set to a value other than zero, we shouldn't
-    /// remove it.
-    #[test]
-    fn not_redundant_set_when_nonzero() {
-        let instrs = vec![
-            Loop {
-                body: vec![],
-                position: Some(Position { start: 0, end: 0 }),
+        let expected = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 5 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 6, end: 6 }),
             },
             Set {
-                amount: Wrapping(1),
+                amount: Wrapping(3),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 7, end: 9 }),
             },
         ];
-        assert_eq!(remove_redundant_sets(instrs.clone()), instrs);
-    }
-
-    fn is_pure(instrs: &[AstNode]) -> bool {
-        for instr in instrs {
-            match *instr {
-                Loop { .. } => {
-                    return false;
-                }
-                Read { .. } => {
-                    return false;
-                }
-                Write { .. } => {
-                    return false;
-                }
-                _ => (),
-            }
-        }
-        true
-    }
-
-    #[test]
-    fn quickcheck_should_annotate_known_zero_at_start() {
-        fn should_annotate_known_zero_at_start(instrs: Vec<AstNode>) -> bool {
-            let annotated = annotate_known_zero(instrs);
-            matches!(
-                annotated[0],
-                Set {
-                    amount: Wrapping(0),
-                    offset: 0,
-                    ..
-                }
-            )
-        }
-        quickcheck(should_annotate_known_zero_at_start as fn(Vec<AstNode>) -> bool);
+        assert_eq!(remove_clobbered_multiply_changes(instrs), expected);
     }
 
     #[test]
-    fn annotate_known_zero_idempotent() {
-        fn is_idempotent(instrs: Vec<AstNode>) -> bool {
-            let annotated = annotate_known_zero(instrs);
-            let annotated_again = annotate_known_zero(annotated.clone());
-            if annotated == annotated_again {
-                true
-            } else {
-                println!("intermediate: {:?}", annotated);
-                println!("final: {:?}", annotated_again);
-                false
-            }
-        }
-        quickcheck(is_idempotent as fn(Vec<AstNode>) -> bool);
-    }
+    fn should_not_remove_multiply_change_before_write() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
 
-    #[test]
-    fn should_annotate_known_zero() {
-        let initial = parse("+[]").unwrap();
-        let expected = vec![
-            Set {
-                amount: Wrapping(0),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+        let instrs = vec![
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 0, end: 5 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 6, end: 6 }),
             },
-            Increment {
-                amount: Wrapping(1),
+            Write {
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Loop {
-                body: vec![],
-                position: Some(Position { start: 1, end: 2 }),
+                position: Some(Position { start: 7, end: 7 }),
             },
             Set {
                 amount: Wrapping(0),
                 offset: 0,
-                position: Some(Position { start: 2, end: 2 }),
+                position: Some(Position { start: 8, end: 10 }),
             },
         ];
-        assert_eq!(annotate_known_zero(initial), expected);
+        assert_eq!(remove_clobbered_multiply_changes(instrs.clone()), instrs);
     }
 
     #[test]
-    fn should_annotate_known_zero_nested() {
-        let initial = parse("[[]]").unwrap();
-        let expected = vec![
-            Set {
-                amount: Wrapping(0),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+    fn should_not_remove_multiply_change_before_increment() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+
+        let instrs = vec![
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 0, end: 5 }),
             },
-            Loop {
-                body: vec![
-                    Loop {
-                        body: vec![],
-                        position: Some(Position { start: 1, end: 2 }),
-                    },
-                    Set {
-                        amount: Wrapping(0),
-                        offset: 0,
-                        position: Some(Position { start: 2, end: 2 }),
-                    },
-                ],
-                position: Some(Position { start: 0, end: 3 }),
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 6, end: 7 }),
             },
             Set {
                 amount: Wrapping(0),
-                offset: 0,
-                position: Some(Position { start: 3, end: 3 }),
+                offset: 1,
+                position: Some(Position { start: 8, end: 10 }),
             },
         ];
-        assert_eq!(annotate_known_zero(initial), expected);
-    }
-
-    /// When we annotate known zeroes, we have new opportunities for
-    /// combining instructions and loop removal. However, we should later
-    /// remove the Set 0 if we haven't combined it.
-    #[test]
-    fn should_annotate_known_zero_cleaned_up() {
-        let initial = vec![Write {
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        assert_eq!(optimize(initial.clone(), &None).0, initial);
+        assert_eq!(remove_clobbered_multiply_changes(instrs.clone()), instrs);
     }
 
     #[test]
-    fn should_preserve_set_0_in_loop() {
-        // Regression test.
-        let initial = vec![
-            Read {
+    fn should_remove_dead_store() {
+        // The increment is overwritten after moving the pointer.
+        let instrs = vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
                 position: Some(Position { start: 0, end: 0 }),
             },
-            Loop {
-                body: vec![Set {
-                    amount: Wrapping(0),
-                    offset: 0,
-                    position: Some(Position { start: 0, end: 0 }),
-                }],
-                position: Some(Position { start: 0, end: 0 }),
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Set {
+                amount: Wrapping(2),
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
             },
         ];
-        assert_eq!(optimize(initial.clone(), &None).0, initial);
+        let expected = instrs[1..].to_vec();
+        assert_eq!(remove_dead_stores(instrs), expected);
     }
 
     #[test]
-    fn should_remove_pure_code() {
-        // The final increment here is side-effect free and can be
-        // removed.
-        let initial = parse("+.+").unwrap();
-        let expected = vec![
+    fn should_remove_dead_store_despite_write_elsewhere() {
+        let instrs = vec![
             Set {
                 amount: Wrapping(1),
-                offset: 0,
+                offset: 1,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Write {
+                offset: 0,
                 position: Some(Position { start: 1, end: 1 }),
             },
-        ];
-
-        let (result, warnings) = optimize(initial, &None);
-
-        assert_eq!(result, expected);
-        assert_eq!(
-            warnings,
-            vec![Warning {
-                message: "These instructions have no effect.".to_owned(),
+            Set {
+                amount: Wrapping(2),
+                offset: 1,
                 position: Some(Position { start: 2, end: 2 }),
-            }]
-        );
-    }
-
-    #[test]
-    fn quickcheck_should_remove_dead_pure_code() {
-        fn should_remove_dead_pure_code(instrs: Vec<AstNode>) -> TestResult {
-            if !is_pure(&instrs) {
-                return TestResult::discard();
-            }
-            TestResult::from_bool(optimize(instrs, &None).0 == vec![])
-        }
-        quickcheck(should_remove_dead_pure_code as fn(Vec<AstNode>) -> TestResult);
-    }
-
-    #[test]
-    fn quickcheck_optimize_should_be_idempotent() {
-        fn optimize_should_be_idempotent(instrs: Vec<AstNode>) -> bool {
-            // Once we've optimized once, running again shouldn't reduce the
-            // instructions further. If it does, we're probably running our
-            // optimisations in the wrong order.
-            let minimal = optimize(instrs, &None).0;
-            optimize(minimal.clone(), &None).0 == minimal
-        }
-        quickcheck(optimize_should_be_idempotent as fn(Vec<AstNode>) -> bool);
+            },
+        ];
+        let expected = instrs[1..].to_vec();
+        assert_eq!(remove_dead_stores(instrs), expected);
     }
 
     #[test]
-    fn pathological_optimisation_opportunity() {
+    fn should_remove_dead_store_despite_read_elsewhere() {
         let instrs = vec![
-            Read {
-                position: Some(Position { start: 0, end: 0 }),
-            },
             Increment {
                 amount: Wrapping(1),
                 offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
-            PointerIncrement {
-                amount: 1,
-                position: Some(Position { start: 0, end: 0 }),
+            Read {
+                offset: 1,
+                position: Some(Position { start: 2, end: 2 }),
             },
-            Increment {
-                amount: Wrapping(1),
+            Set {
+                amount: Wrapping(0),
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            PointerIncrement {
-                amount: 1,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            PointerIncrement {
-                amount: -1,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 4, end: 6 }),
             },
+        ];
+        let expected = instrs[1..].to_vec();
+        assert_eq!(remove_dead_stores(instrs), expected);
+    }
+
+    #[test]
+    fn should_not_remove_store_before_read() {
+        // Changes before a read of the same cell are removed by
+        // remove_read_clobber instead.
+        let instrs = vec![
             Increment {
-                amount: Wrapping(-1),
+                amount: Wrapping(1),
                 offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
-            PointerIncrement {
-                amount: -1,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Increment {
-                amount: Wrapping(-1),
+            Read {
                 offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 1, end: 1 }),
             },
-            Write {
-                position: Some(Position { start: 0, end: 0 }),
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 2, end: 4 }),
             },
         ];
+        assert_eq!(remove_dead_stores(instrs.clone()), instrs);
+    }
 
-        let expected = vec![
-            Read {
+    #[test]
+    fn should_not_remove_store_before_write() {
+        let instrs = vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Write {
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-
-        assert_eq!(optimize(instrs, &None).0, expected);
-    }
-
-    fn count_instrs(instrs: &[AstNode]) -> u64 {
-        let mut count = 0;
-        for instr in instrs {
-            if let Loop { ref body, .. } = *instr {
-                count += count_instrs(body);
-            }
-            count += 1;
-        }
-        count
-    }
-
-    #[test]
-    fn quickcheck_optimize_should_decrease_size() {
-        fn optimize_should_decrease_size(instrs: Vec<AstNode>) -> bool {
-            // The result of optimize() should never increase the number of
-            // instructions.
-            let result = optimize(instrs.clone(), &None).0;
-            count_instrs(&result) <= count_instrs(&instrs)
-        }
-        quickcheck(optimize_should_decrease_size as fn(Vec<AstNode>) -> bool);
-    }
-
-    #[test]
-    fn should_extract_multiply_simple() {
-        let instrs = parse("[->+++<]").unwrap();
-
-        let mut dest_cells = HashMap::new();
-        dest_cells.insert(1, Wrapping(3));
-        let expected = vec![MultiplyMove {
-            changes: dest_cells,
-            position: Some(Position { start: 0, end: 7 }),
-        }];
-
-        assert_eq!(extract_multiply(instrs), expected);
-    }
-
-    #[test]
-    fn should_extract_multiply_nested() {
-        let instrs = parse("[[->+<]]").unwrap();
-
-        let mut dest_cells = HashMap::new();
-        dest_cells.insert(1, Wrapping(1));
-        let expected = vec![Loop {
-            body: vec![MultiplyMove {
-                changes: dest_cells,
-                position: Some(Position { start: 1, end: 6 }),
-            }],
-            position: Some(Position { start: 0, end: 7 }),
-        }];
-
-        assert_eq!(extract_multiply(instrs), expected);
-    }
-
-    #[test]
-    fn should_extract_multiply_negative_number() {
-        let instrs = parse("[->--<]").unwrap();
-
-        let mut dest_cells = HashMap::new();
-        dest_cells.insert(1, Wrapping(-2));
-        let expected = vec![MultiplyMove {
-            changes: dest_cells,
-            position: Some(Position { start: 0, end: 6 }),
-        }];
-
-        assert_eq!(extract_multiply(instrs), expected);
-    }
-
-    #[test]
-    fn should_extract_multiply_multiple_cells() {
-        let instrs = parse("[->+++>>>+<<<<]").unwrap();
-
-        let mut dest_cells = HashMap::new();
-        dest_cells.insert(1, Wrapping(3));
-        dest_cells.insert(4, Wrapping(1));
-        let expected = vec![MultiplyMove {
-            changes: dest_cells,
-            position: Some(Position { start: 0, end: 14 }),
-        }];
-
-        assert_eq!(extract_multiply(instrs), expected);
-    }
-
-    #[test]
-    fn should_not_extract_multiply_net_movement() {
-        let instrs = parse("[->+++<<]").unwrap();
-        assert_eq!(extract_multiply(instrs.clone()), instrs);
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Set {
+                amount: Wrapping(2),
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        assert_eq!(remove_dead_stores(instrs.clone()), instrs);
     }
 
     #[test]
-    fn should_not_extract_multiply_from_clear_loop() {
-        let instrs = parse("[-]").unwrap();
-        assert_eq!(extract_multiply(instrs.clone()), instrs);
+    fn should_not_remove_store_before_increment() {
+        // The later increment adds to our value, so it isn't dead.
+        let instrs = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        assert_eq!(remove_dead_stores(instrs.clone()), instrs);
     }
 
     #[test]
-    fn should_not_extract_multiply_with_inner_loop() {
-        let instrs = parse("[->+++<[]]").unwrap();
-        assert_eq!(extract_multiply(instrs.clone()), instrs);
-    }
+    fn should_not_remove_store_to_multiply_source() {
+        // The multiply reads the current cell before it's set.
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(1));
 
-    /// We need to decrement the initial cell in order for this to be a
-    /// multiply.
-    #[test]
-    fn should_not_extract_multiply_without_decrement() {
-        let instrs = parse("[+>++<]").unwrap();
-        assert_eq!(extract_multiply(instrs.clone()), instrs);
+        let instrs = vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 1, end: 6 }),
+            },
+            Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 7, end: 7 }),
+            },
+        ];
+        assert_eq!(remove_dead_stores(instrs.clone()), instrs);
     }
 
     #[test]
-    fn should_not_extract_multiply_with_read() {
-        let instrs = parse("[+>++<,]").unwrap();
-        assert_eq!(extract_multiply(instrs.clone()), instrs);
-    }
+    fn should_not_remove_store_to_multiply_target() {
+        // The multiply adds to cell #1, so the increment still
+        // affects its value.
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(1));
 
-    #[test]
-    fn should_not_extract_multiply_with_write() {
-        let instrs = parse("[+>++<.]").unwrap();
-        assert_eq!(extract_multiply(instrs.clone()), instrs);
+        let instrs = vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 1, end: 6 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 7, end: 7 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 8, end: 8 }),
+            },
+        ];
+        assert_eq!(remove_dead_stores(instrs.clone()), instrs);
     }
 
     #[test]
@@ -1992,6 +6400,7 @@ mod tests {
                     position: Some(Position { start: 4, end: 4 }),
                 },
             ]),
+            offset: 0,
             position: Some(Position { start: 0, end: 5 }),
         }];
         assert_eq!(sort_by_offset(instrs), expected);
@@ -2003,8 +6412,102 @@ mod tests {
         assert_eq!(sort_by_offset(initial), vec![]);
     }
 
-    // If there's a read instruction, we should only combine before and
-    // after.
+    #[test]
+    fn sort_by_offset_hoists_past_multiply_move() {
+        let mut changes = HashMap::new();
+        changes.insert(-1, Wrapping(1));
+        let instrs = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 2,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            MultiplyMove {
+                changes: changes.clone(),
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Increment {
+                amount: Wrapping(3),
+                offset: 1,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+        ];
+        let expected = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 2,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(3),
+                offset: 2,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        assert_eq!(sort_by_offset(instrs), expected);
+    }
+
+    #[test]
+    fn sort_by_offset_no_hoist_past_multiply_move_target() {
+        // The move changes cell 1, so the increment must stay after it.
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(1));
+        let instrs = vec![
+            Set {
+                amount: Wrapping(0),
+                offset: 1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            MultiplyMove {
+                changes,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+        ];
+        assert_eq!(sort_by_offset(instrs.clone()), instrs);
+    }
+
+    #[test]
+    fn optimize_combines_set_across_multiply_move() {
+        // The second `>+<` can join the `>[-]<` before the multiply
+        // loop, giving a single set.
+        let instrs = parse(",>[-]<[->>+<<]>+<>>.").unwrap();
+        let (optimized, _) = optimize(instrs, OptimizeOptions::default());
+        let sets: Vec<_> = optimized
+            .iter()
+            .filter(|instr| matches!(instr, Set { offset: 1, .. }))
+            .collect();
+        assert_eq!(sets.len(), 1);
+        assert!(matches!(
+            sets[0],
+            Set {
+                amount: Wrapping(1),
+                ..
+            }
+        ));
+        assert!(!optimized
+            .iter()
+            .any(|instr| matches!(instr, Increment { offset: 1, .. })));
+    }
+
+    // If there's a read instruction at the end of the program, we
+    // should only combine before and after.
     #[test]
     fn sort_by_offset_read() {
         let instrs = parse(">>,>>").unwrap();
@@ -2014,6 +6517,7 @@ mod tests {
                 position: Some(Position { start: 1, end: 1 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 2, end: 2 }),
             },
             PointerIncrement {
@@ -2024,6 +6528,106 @@ mod tests {
         assert_eq!(sort_by_offset(instrs), expected);
     }
 
+    #[test]
+    fn sort_by_offset_read_and_write_offsets() {
+        let instrs = parse(">>,>>.[-]").unwrap();
+        let expected = vec![
+            Read {
+                offset: 2,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Write {
+                offset: 4,
+                position: Some(Position { start: 5, end: 5 }),
+            },
+            PointerIncrement {
+                amount: 4,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+            Loop {
+                body: vec![Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: Some(Position { start: 7, end: 7 }),
+                }],
+                offset: 0,
+                position: Some(Position { start: 6, end: 8 }),
+            },
+        ];
+        assert_eq!(sort_by_offset(instrs), expected);
+    }
+
+    #[test]
+    fn sort_by_offset_moves_changes_past_writes() {
+        // The + stays before the write of the same cell, but moves
+        // after the write of cell #1.
+        let instrs = parse("+>.<.[-]").unwrap();
+        let expected = vec![
+            Write {
+                offset: 1,
+                position: Some(Position { start: 2, end: 2 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Write {
+                offset: 0,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+            Loop {
+                body: vec![Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: Some(Position { start: 6, end: 6 }),
+                }],
+                offset: 0,
+                position: Some(Position { start: 5, end: 7 }),
+            },
+        ];
+        assert_eq!(sort_by_offset(instrs), expected);
+    }
+
+    #[test]
+    fn sort_by_offset_no_changes_past_reads() {
+        let instrs = parse(">+<,>-.[-]").unwrap();
+        let expected = vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 1,
+                position: Some(Position { start: 1, end: 1 }),
+            },
+            Read {
+                offset: 0,
+                position: Some(Position { start: 3, end: 3 }),
+            },
+            Increment {
+                amount: Wrapping(-1),
+                offset: 1,
+                position: Some(Position { start: 5, end: 5 }),
+            },
+            Write {
+                offset: 1,
+                position: Some(Position { start: 6, end: 6 }),
+            },
+            PointerIncrement {
+                amount: 1,
+                position: Some(Position { start: 4, end: 4 }),
+            },
+            Loop {
+                body: vec![Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                    position: Some(Position { start: 8, end: 8 }),
+                }],
+                offset: 0,
+                position: Some(Position { start: 7, end: 9 }),
+            },
+        ];
+        assert_eq!(sort_by_offset(instrs), expected);
+    }
+
     #[test]
     fn quickcheck_sort_by_offset_set() {
         fn sort_by_offset_set(amount1: i8, amount2: i8) -> bool {
@@ -2153,6 +6757,7 @@ mod tests {
         let instrs = parse(",+>+<+.").unwrap();
         let expected = vec![
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Increment {
@@ -2166,10 +6771,11 @@ mod tests {
                 position: Some(Position { start: 3, end: 3 }),
             },
             Write {
+                offset: 0,
                 position: Some(Position { start: 6, end: 6 }),
             },
         ];
-        assert_eq!(optimize(instrs, &None).0, expected);
+        assert_eq!(optimize(instrs, OptimizeOptions::default()).0, expected);
     }
 
     #[test]
@@ -2179,9 +6785,11 @@ mod tests {
         let instrs = vec![
             Loop {
                 body: vec![],
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2197,6 +6805,7 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2236,6 +6845,7 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2257,6 +6867,7 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2276,6 +6887,7 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2285,6 +6897,7 @@ mod tests {
     #[test]
     fn prev_mutate_no_predecessors() {
         let instrs = vec![Read {
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         }];
         assert_eq!(previous_cell_change(&instrs, 0), None);
@@ -2304,6 +6917,7 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2319,9 +6933,11 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
             Write {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2341,6 +6957,7 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2356,6 +6973,7 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -2368,20 +6986,23 @@ mod tests {
         // mutated.
         let instrs = vec![
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Loop {
                 body: vec![],
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
-        assert_eq!(next_cell_change(&instrs, 0), None);
+        assert_eq!(next_offset_cell_change(&instrs, 0, 0), None);
     }
 
     #[test]
     fn next_mutate_increment() {
         let instrs = vec![
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             Increment {
@@ -2395,13 +7016,14 @@ mod tests {
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
-        assert_eq!(next_cell_change(&instrs, 0), Some(2));
+        assert_eq!(next_offset_cell_change(&instrs, 0, 0), Some(2));
     }
 
     #[test]
     fn next_mutate_consider_pointer_increment() {
         let instrs = vec![
             Read {
+                offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             PointerIncrement {
@@ -2420,7 +7042,22 @@ mod tests {
             },
         ];
 
-        assert_eq!(next_cell_change(&instrs, 0), Some(3));
+        assert_eq!(next_offset_cell_change(&instrs, 0, 0), Some(3));
+    }
+
+    #[test]
+    fn default_passes_are_all_passes() {
+        assert_eq!(DEFAULT_PASS_SPECIFICATION, pass_names().join(","));
+    }
+
+    #[test]
+    fn check_pass_specification_unknown_pass() {
+        assert_eq!(check_pass_specification("combine_inc,combine_ptr"), Ok(()));
+        assert_eq!(check_pass_specification(""), Ok(()));
+        assert_eq!(
+            check_pass_specification("combine_incr,combine_ptr"),
+            Err("Unknown optimisation pass `combine_incr`".to_owned())
+        );
     }
 }
 
@@ -2440,14 +7077,37 @@ mod soundness_tests {
         check_cells: bool,
         dummy_read_value: Option<i8>,
     ) -> TestResult
+    where
+        F: Fn(Vec<AstNode>) -> Vec<AstNode>,
+    {
+        transform_is_sound_with_extra_steps(instrs, transform, check_cells, dummy_read_value, 0)
+    }
+
+    /// As `transform_is_sound`, but give the transformed program
+    /// `extra_steps` more steps, for transforms that replace one
+    /// instruction with several.
+    fn transform_is_sound_with_extra_steps<F>(
+        instrs: Vec<AstNode>,
+        transform: F,
+        check_cells: bool,
+        dummy_read_value: Option<i8>,
+        extra_steps: u64,
+    ) -> TestResult
     where
         F: Fn(Vec<AstNode>) -> Vec<AstNode>,
     {
         let max_steps = 1000;
+        // Every read gets the dummy value, if we have one.
+        let dummy_input = || {
+            dummy_read_value
+                .map(|value| value as u8)
+                .into_iter()
+                .cycle()
+        };
 
         // First, we execute the program given.
         let mut state = ExecutionState::initial(&instrs[..]);
-        let result = execute_with_state(&instrs[..], &mut state, max_steps, dummy_read_value);
+        let result = execute_with_state(&instrs[..], &mut state, max_steps, &mut dummy_input());
 
         // Optimisations may change malformed programs to well-formed
         // programs, so we ignore programs that don't terminate nicely.
@@ -2466,121 +7126,814 @@ mod soundness_tests {
         let result2 = execute_with_state(
             &optimised_instrs[..],
             &mut state2,
-            max_steps,
-            dummy_read_value,
+            max_steps + extra_steps,
+            &mut dummy_input(),
         );
 
-        // Compare the outcomes: they should be the same.
-        match (result, result2) {
-            // If the first result completed, the second should have
-            // completed too. We allow them to take a different amount of
-            // steps.
-            (Completed(_), Completed(_)) => (),
-            (ReachedRuntimeValue, ReachedRuntimeValue) => (),
-            // Any other situation means that the first program terminated
-            // but the optimised program did not.
-            (_, _) => {
-                println!("Optimised program did not terminate properly!");
-                return TestResult::failed();
-            }
+        // Compare the outcomes: they should be the same.
+        match (result, result2) {
+            // If the first result completed, the second should have
+            // completed too. We allow them to take a different amount of
+            // steps.
+            (Completed(_), Completed(_)) => (),
+            (ReachedRuntimeValue, ReachedRuntimeValue) => (),
+            // Any other situation means that the first program terminated
+            // but the optimised program did not.
+            (_, _) => {
+                println!("Optimised program did not terminate properly!");
+                return TestResult::failed();
+            }
+        }
+
+        // Likewise we should have written the same outputs.
+        if state.outputs != state2.outputs {
+            println!(
+                "Different outputs! Original outputs: {:?} Optimised: {:?}",
+                state.outputs, state2.outputs
+            );
+            return TestResult::failed();
+        }
+
+        // If requested, compare that the cells at the end are the same
+        // too. This is true of most, but not all, of our optimisations.
+        if check_cells && state.cells != state2.cells {
+            println!(
+                "Different cell states! Optimised state: {:?} Optimised: {:?}",
+                state.cells, state2.cells
+            );
+            return TestResult::failed();
+        }
+
+        TestResult::passed()
+    }
+
+    #[test]
+    fn combine_increments_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, combine_increments, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    fn discard_positions(instrs: Vec<AstNode>) -> Vec<AstNode> {
+        instrs
+            .into_iter()
+            .map(|instr| match instr {
+                Increment { amount, offset, .. } => Increment {
+                    amount,
+                    offset,
+                    position: None,
+                },
+                PointerIncrement { amount, .. } => PointerIncrement {
+                    amount,
+                    position: None,
+                },
+                Read { offset, .. } => Read {
+                    offset,
+                    position: None,
+                },
+                Write { offset, .. } => Write {
+                    offset,
+                    position: None,
+                },
+                Loop { body, .. } => Loop {
+                    body,
+                    offset: 0,
+                    position: None,
+                },
+                Set { amount, offset, .. } => Set {
+                    amount,
+                    offset,
+                    position: None,
+                },
+                MultiplyMove { changes, .. } => MultiplyMove {
+                    changes,
+                    position: None,
+                },
+                CellCopy {
+                    src_offset,
+                    dst_offset,
+                    overwrite,
+                    ..
+                } => CellCopy {
+                    src_offset,
+                    dst_offset,
+                    overwrite,
+                    position: None,
+                },
+                ScanLoop { step, .. } => ScanLoop {
+                    step,
+                    position: None,
+                },
+                InfiniteLoop { offset, .. } => InfiniteLoop {
+                    offset,
+                    position: None,
+                },
+            })
+            .map_loops(discard_positions)
+    }
+
+    /// Optimisations should not be affected by the presence or
+    /// absence of position data.
+    #[test]
+    fn discard_positions_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, discard_positions, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    #[test]
+    fn combine_ptr_increments_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, combine_ptr_increments, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    #[test]
+    fn annotate_known_zero_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, annotate_known_zero, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    #[test]
+    fn extract_multiply_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, extract_multiply, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    /// Random programs rarely contain loops that decrement by two, so
+    /// build a known cell value followed by such a loop.
+    #[test]
+    fn extract_even_multiply_is_sound() {
+        fn is_sound(source: i8, change: i8, offset: i8) -> TestResult {
+            // The loop never terminates if the source is odd.
+            if source % 2 != 0 || offset == 0 {
+                return TestResult::discard();
+            }
+            let offset = offset as isize;
+
+            let instrs = vec![
+                PointerIncrement {
+                    amount: 128,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(source),
+                    offset: 0,
+                    position: None,
+                },
+                Loop {
+                    body: vec![
+                        Increment {
+                            amount: Wrapping(-2),
+                            offset: 0,
+                            position: None,
+                        },
+                        PointerIncrement {
+                            amount: offset,
+                            position: None,
+                        },
+                        Increment {
+                            amount: Wrapping(change.wrapping_mul(2)),
+                            offset: 0,
+                            position: None,
+                        },
+                        PointerIncrement {
+                            amount: -offset,
+                            position: None,
+                        },
+                    ],
+                    offset: 0,
+                    position: None,
+                },
+                PointerIncrement {
+                    amount: offset,
+                    position: None,
+                },
+                Write {
+                    offset: 0,
+                    position: None,
+                },
+            ];
+            transform_is_sound(instrs, extract_multiply, true, None)
+        }
+        quickcheck(is_sound as fn(i8, i8, i8) -> TestResult)
+    }
+
+    /// A loop that decrements the source by `step` and adds `change`
+    /// to the cell at `offset`, after setting the source to `source`.
+    fn divisible_multiply_loop(source: i8, step: i8, change: i8, offset: isize) -> Vec<AstNode> {
+        vec![
+            PointerIncrement {
+                amount: 128,
+                position: None,
+            },
+            Set {
+                amount: Wrapping(source),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body: vec![
+                    Increment {
+                        amount: Wrapping(step),
+                        offset: 0,
+                        position: None,
+                    },
+                    Increment {
+                        amount: Wrapping(change),
+                        offset,
+                        position: None,
+                    },
+                ],
+                offset: 0,
+                position: None,
+            },
+        ]
+    }
+
+    /// Random programs rarely contain loops that decrement by more
+    /// than one, so build a known cell value followed by such a loop.
+    #[test]
+    fn extract_divisible_multiply_is_sound() {
+        fn is_sound(source: i8, step: i8, change: i8, offset: i8) -> TestResult {
+            if step == 0 || offset == 0 {
+                return TestResult::discard();
+            }
+            let step = -(step as i16).abs().min(128) as i8;
+            // The loop only terminates if the source is a multiple of
+            // this, and we can only divide changes by it.
+            let divisor = 1_i8.wrapping_shl((step as u8).trailing_zeros());
+
+            let mut instrs = divisible_multiply_loop(
+                source.wrapping_mul(divisor),
+                step,
+                change.wrapping_mul(divisor),
+                offset as isize,
+            );
+            instrs.push(PointerIncrement {
+                amount: offset as isize,
+                position: None,
+            });
+            instrs.push(Write {
+                offset: 0,
+                position: None,
+            });
+            transform_is_sound(instrs, extract_multiply, true, None)
+        }
+        quickcheck(is_sound as fn(i8, i8, i8, i8) -> TestResult)
+    }
+
+    /// Check every decrement and every source value that terminates,
+    /// since there are few enough to try them all.
+    #[test]
+    fn extract_divisible_multiply_is_sound_for_all_sources() {
+        for step in -128..=-1_i8 {
+            let divisor = 1_i32 << (step as u8).trailing_zeros();
+            for source in i8::MIN..=i8::MAX {
+                if source as i32 % divisor != 0 {
+                    continue;
+                }
+                let change = (3 * divisor) as i8;
+                let instrs = divisible_multiply_loop(source, step, change, 1);
+
+                let optimised_instrs = extract_multiply(instrs.clone());
+                assert!(
+                    matches!(optimised_instrs[2], MultiplyMove { .. }),
+                    "step {} source {}",
+                    step,
+                    source
+                );
+
+                let mut state = ExecutionState::initial(&instrs[..]);
+                let result =
+                    execute_with_state(&instrs[..], &mut state, 10000, &mut std::iter::empty());
+                assert!(matches!(result, Completed(_)));
+
+                let mut state2 = ExecutionState::initial(&instrs[..]);
+                execute_with_state(
+                    &optimised_instrs[..],
+                    &mut state2,
+                    10000,
+                    &mut std::iter::empty(),
+                );
+                assert_eq!(state.cells, state2.cells, "step {} source {}", step, source);
+            }
+        }
+    }
+
+    #[test]
+    fn extract_divisible_multiply_ignores_unknown_multiple() {
+        for step in -128..=-1_i8 {
+            if step % 2 != 0 {
+                continue;
+            }
+            // The source is odd, so the loop never terminates.
+            let instrs = divisible_multiply_loop(1, step, 0, 1);
+            assert_eq!(extract_multiply(instrs.clone()), instrs);
+        }
+    }
+
+    #[test]
+    fn extract_scan_loops_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, extract_scan_loops, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    #[test]
+    fn absorb_ptr_increments_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, absorb_ptr_increments, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    #[test]
+    fn hoist_loop_ptr_increments_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, hoist_loop_ptr_increments, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    /// Random loop bodies rarely have net zero movement, so build
+    /// loops that start by moving the pointer and end by moving it
+    /// back, with reads and writes at the shifted position.
+    #[test]
+    fn hoist_loop_ptr_increments_shifted_io_is_sound() {
+        fn is_sound(
+            before: Vec<AstNode>,
+            body: Vec<AstNode>,
+            amount: i8,
+            read_value: i8,
+        ) -> TestResult {
+            let amount = (amount % 3) as isize;
+            let body_movement = match net_ptr_movement(&body) {
+                Some(movement) => movement,
+                None => return TestResult::discard(),
+            };
+            let position = Some(Position { start: 0, end: 0 });
+
+            let mut loop_body = vec![PointerIncrement { amount, position }];
+            loop_body.extend(body);
+            loop_body.push(Read {
+                offset: 0,
+                position,
+            });
+            loop_body.push(Write {
+                offset: 0,
+                position,
+            });
+            loop_body.push(PointerIncrement {
+                amount: -amount - body_movement,
+                position,
+            });
+
+            let mut instrs = before;
+            instrs.push(Loop {
+                body: loop_body,
+                offset: 0,
+                position,
+            });
+            instrs.push(Write {
+                offset: 0,
+                position,
+            });
+
+            transform_is_sound(instrs, hoist_loop_ptr_increments, true, Some(read_value))
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>, Vec<AstNode>, i8, i8) -> TestResult)
+    }
+
+    #[test]
+    fn remove_clobbered_multiply_changes_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, remove_clobbered_multiply_changes, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    #[test]
+    fn canonicalize_infinite_loops_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, canonicalize_infinite_loops, true, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
+
+    #[test]
+    fn remove_unreachable_code_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(
+                instrs,
+                |instrs| remove_unreachable_code(canonicalize_infinite_loops(instrs)).0,
+                true,
+                None,
+            )
         }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
 
-        // Likewise we should have written the same outputs.
-        if state.outputs != state2.outputs {
-            println!(
-                "Different outputs! Original outputs: {:?} Optimised: {:?}",
-                state.outputs, state2.outputs
-            );
-            return TestResult::failed();
+    #[test]
+    fn merge_multiply_moves_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            transform_is_sound(instrs, merge_multiply_moves, true, None)
         }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+    }
 
-        // If requested, compare that the cells at the end are the same
-        // too. This is true of most, but not all, of our optimisations.
-        if check_cells && state.cells != state2.cells {
-            println!(
-                "Different cell states! Optimised state: {:?} Optimised: {:?}",
-                state.cells, state2.cells
-            );
-            return TestResult::failed();
+    #[test]
+    fn remove_dead_stores_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>, read_value: i8) -> TestResult {
+            // When every read succeeds, we end with the same cells.
+            transform_is_sound(instrs, remove_dead_stores, true, Some(read_value))
         }
+        quickcheck(is_sound as fn(Vec<AstNode>, i8) -> TestResult)
+    }
 
-        TestResult::passed()
+    #[test]
+    fn remove_dead_stores_before_unknown_read_is_sound() {
+        fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+            // Execution stops at the first read, where we may have
+            // removed a store that is overwritten afterwards. Only
+            // compare outputs.
+            transform_is_sound(instrs, remove_dead_stores, false, None)
+        }
+        quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
     }
 
     #[test]
-    fn combine_increments_is_sound() {
+    fn combine_copy_loops_is_sound() {
         fn is_sound(instrs: Vec<AstNode>) -> TestResult {
-            transform_is_sound(instrs, combine_increments, true, None)
+            transform_is_sound(instrs, combine_copy_loops, true, None)
         }
         quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
     }
 
-    fn discard_positions(instrs: Vec<AstNode>) -> Vec<AstNode> {
-        instrs
-            .into_iter()
-            .map(|instr| match instr {
-                Increment { amount, offset, .. } => Increment {
-                    amount,
-                    offset,
+    /// Random programs rarely copy via a temporary cell, so build the
+    /// copy idiom `[->+>+<<]>>[-<<+>>]` with arbitrary offsets.
+    #[test]
+    fn combine_copy_loops_idiom_is_sound() {
+        fn is_sound(source: i8, factor: i8, copy_offset: i8, temp_offset: i8) -> TestResult {
+            if copy_offset == 0 || temp_offset == 0 || copy_offset == temp_offset {
+                return TestResult::discard();
+            }
+            let copy_offset = copy_offset as isize;
+            let temp_offset = temp_offset as isize;
+
+            let mut copy_changes = HashMap::new();
+            copy_changes.insert(copy_offset, Wrapping(factor));
+            copy_changes.insert(temp_offset, Wrapping(1));
+
+            let mut restore_changes = HashMap::new();
+            restore_changes.insert(-temp_offset, Wrapping(1));
+
+            let instrs = vec![
+                PointerIncrement {
+                    amount: 128,
                     position: None,
                 },
-                PointerIncrement { amount, .. } => PointerIncrement {
-                    amount,
+                Set {
+                    amount: Wrapping(source),
+                    offset: 0,
                     position: None,
                 },
-                Read { .. } => Read { position: None },
-                Write { .. } => Write { position: None },
-                Loop { body, .. } => Loop {
-                    body,
+                Set {
+                    amount: Wrapping(0),
+                    offset: temp_offset,
                     position: None,
                 },
-                Set { amount, offset, .. } => Set {
-                    amount,
-                    offset,
+                MultiplyMove {
+                    changes: copy_changes,
                     position: None,
                 },
-                MultiplyMove { changes, .. } => MultiplyMove {
-                    changes,
+                PointerIncrement {
+                    amount: temp_offset,
                     position: None,
                 },
-            })
-            .map_loops(discard_positions)
+                MultiplyMove {
+                    changes: restore_changes,
+                    position: None,
+                },
+                PointerIncrement {
+                    amount: -temp_offset,
+                    position: None,
+                },
+                Write {
+                    offset: 0,
+                    position: None,
+                },
+                PointerIncrement {
+                    amount: copy_offset,
+                    position: None,
+                },
+                Write {
+                    offset: 0,
+                    position: None,
+                },
+            ];
+
+            // We should recognise the idiom, not just leave it alone.
+            if combine_copy_loops(instrs.clone()) == instrs {
+                return TestResult::failed();
+            }
+
+            transform_is_sound(instrs, combine_copy_loops, true, None)
+        }
+        quickcheck(is_sound as fn(i8, i8, i8, i8) -> TestResult)
     }
 
-    /// Optimisations should not be affected by the presence or
-    /// absence of position data.
+    /// Build a copy via a temporary cell where both MultiplyMoves may
+    /// change other cells too. Offsets are small, so the cells often
+    /// overlap.
     #[test]
-    fn discard_positions_is_sound() {
+    fn combine_copy_loops_overlapping_cells_is_sound() {
+        fn is_sound(
+            source: i8,
+            copy_offset: u8,
+            copy_factor: i8,
+            temp_offset: u8,
+            restore_offset: u8,
+            restore_factor: i8,
+        ) -> TestResult {
+            let copy_offset = (copy_offset % 5) as isize - 2;
+            let temp_offset = (temp_offset % 5) as isize - 2;
+            let restore_offset = (restore_offset % 5) as isize - 2;
+            // A restore offset of zero would leave the temporary cell
+            // non-zero, so we wouldn't combine.
+            if temp_offset == 0 || copy_offset == temp_offset || restore_offset == 0 {
+                return TestResult::discard();
+            }
+
+            let mut copy_changes = HashMap::new();
+            copy_changes.insert(copy_offset, Wrapping(copy_factor));
+            copy_changes.insert(temp_offset, Wrapping(1));
+
+            let mut restore_changes = HashMap::new();
+            restore_changes.insert(restore_offset, Wrapping(restore_factor));
+            restore_changes.insert(-temp_offset, Wrapping(1));
+
+            let mut instrs = vec![
+                PointerIncrement {
+                    amount: 8,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(source),
+                    offset: 0,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(0),
+                    offset: temp_offset,
+                    position: None,
+                },
+                MultiplyMove {
+                    changes: copy_changes,
+                    position: None,
+                },
+                PointerIncrement {
+                    amount: temp_offset,
+                    position: None,
+                },
+                MultiplyMove {
+                    changes: restore_changes,
+                    position: None,
+                },
+                PointerIncrement {
+                    amount: -temp_offset - 4,
+                    position: None,
+                },
+            ];
+            for _ in -4..=4 {
+                instrs.push(Write {
+                    offset: 0,
+                    position: None,
+                });
+                instrs.push(PointerIncrement {
+                    amount: 1,
+                    position: None,
+                });
+            }
+
+            // We should recognise the idiom, not just leave it alone.
+            if combine_copy_loops(instrs.clone()) == instrs {
+                return TestResult::failed();
+            }
+
+            transform_is_sound(instrs, combine_copy_loops, true, None)
+        }
+        quickcheck(is_sound as fn(i8, u8, i8, u8, u8, i8) -> TestResult)
+    }
+
+    #[test]
+    fn fold_known_multiply_moves_is_sound() {
         fn is_sound(instrs: Vec<AstNode>) -> TestResult {
-            transform_is_sound(instrs, discard_positions, true, None)
+            // Each multiply move becomes an increment per target cell.
+            transform_is_sound_with_extra_steps(instrs, fold_known_multiply_moves, true, None, 1000)
         }
         quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
     }
 
+    /// Random programs rarely have a multiply move straight after a
+    /// set, so build one explicitly.
     #[test]
-    fn combine_ptr_increments_is_sound() {
+    fn fold_known_multiply_move_is_sound() {
+        fn is_sound(value: i8, changes: Vec<(i8, i8)>) -> TestResult {
+            let instrs = vec![
+                PointerIncrement {
+                    amount: 128,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(value),
+                    offset: 0,
+                    position: None,
+                },
+                MultiplyMove {
+                    changes: changes
+                        .into_iter()
+                        .map(|(offset, factor)| (offset as isize, Wrapping(factor)))
+                        .collect(),
+                    position: None,
+                },
+            ];
+            transform_is_sound(instrs, fold_known_multiply_moves, true, None)
+        }
+        quickcheck(is_sound as fn(i8, Vec<(i8, i8)>) -> TestResult)
+    }
+
+    #[test]
+    fn extract_cell_copies_is_sound() {
         fn is_sound(instrs: Vec<AstNode>) -> TestResult {
-            transform_is_sound(instrs, combine_ptr_increments, true, None)
+            transform_is_sound(instrs, extract_cell_copies, true, None)
         }
         quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
     }
 
+    /// Build copies between nearby cells, including copies of a cell
+    /// to itself. The destination is set beforehand, so some copies
+    /// overwrite a known zero, and the source is either set or read,
+    /// so some copies can be folded.
     #[test]
-    fn annotate_known_zero_is_sound() {
+    fn extract_cell_copy_is_sound() {
+        fn is_sound(
+            src_value: i8,
+            read_value: Option<i8>,
+            dst_value: i8,
+            src_offset: u8,
+            dst_offset: u8,
+        ) -> TestResult {
+            let src_offset = (src_offset % 3) as isize;
+            let dst_offset = (dst_offset % 3) as isize;
+
+            let mut copies = vec![CellCopy {
+                src_offset,
+                dst_offset,
+                overwrite: false,
+                position: None,
+            }];
+            if src_offset == 0 && dst_offset != 0 {
+                copies.push(MultiplyMove {
+                    changes: HashMap::from([(0, Wrapping(1)), (dst_offset, Wrapping(1))]),
+                    position: None,
+                });
+            }
+
+            for copy in copies {
+                let mut instrs = vec![Set {
+                    amount: Wrapping(src_value),
+                    offset: src_offset,
+                    position: None,
+                }];
+                if read_value.is_some() {
+                    instrs.push(Read {
+                        offset: src_offset,
+                        position: None,
+                    });
+                }
+                instrs.extend([
+                    Set {
+                        amount: Wrapping(dst_value),
+                        offset: dst_offset,
+                        position: None,
+                    },
+                    copy,
+                    Write {
+                        offset: dst_offset,
+                        position: None,
+                    },
+                ]);
+
+                let result = transform_is_sound(
+                    instrs,
+                    |instrs| fold_known_multiply_moves(extract_cell_copies(instrs)),
+                    true,
+                    read_value,
+                );
+                if result.is_failure() {
+                    return result;
+                }
+            }
+            TestResult::passed()
+        }
+        quickcheck(is_sound as fn(i8, Option<i8>, i8, u8, u8) -> TestResult)
+    }
+
+    #[test]
+    fn unroll_constant_loops_is_sound() {
         fn is_sound(instrs: Vec<AstNode>) -> TestResult {
-            transform_is_sound(instrs, annotate_known_zero, true, None)
+            transform_is_sound(
+                instrs,
+                |instrs| unroll_constant_loops(instrs, 8),
+                true,
+                None,
+            )
         }
         quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
     }
 
     #[test]
-    fn extract_multiply_is_sound() {
+    fn remove_run_once_loops_is_sound() {
         fn is_sound(instrs: Vec<AstNode>) -> TestResult {
-            transform_is_sound(instrs, extract_multiply, true, None)
+            transform_is_sound(instrs, remove_run_once_loops, true, None)
         }
         quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
     }
 
+    /// Random loop bodies rarely start by clearing the loop cell, so
+    /// build one explicitly.
+    #[test]
+    fn remove_run_once_loop_is_sound() {
+        fn is_sound(value: i8, loop_offset: i8, body: Vec<AstNode>) -> TestResult {
+            let loop_offset = isize::from(loop_offset % 4);
+            let mut loop_body = vec![Set {
+                amount: Wrapping(0),
+                offset: loop_offset,
+                position: None,
+            }];
+            loop_body.extend(body);
+
+            let instrs = vec![
+                PointerIncrement {
+                    amount: 128,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(value),
+                    offset: loop_offset,
+                    position: None,
+                },
+                Loop {
+                    body: loop_body,
+                    offset: loop_offset,
+                    position: None,
+                },
+            ];
+            transform_is_sound(instrs, remove_run_once_loops, true, None)
+        }
+        quickcheck(is_sound as fn(i8, i8, Vec<AstNode>) -> TestResult)
+    }
+
+    /// Random programs rarely have a loop straight after a set, so
+    /// build one explicitly.
+    #[test]
+    fn unroll_constant_loop_is_sound() {
+        fn is_sound(value: i8, body: Vec<AstNode>, limit: u8) -> TestResult {
+            let instrs = vec![
+                PointerIncrement {
+                    amount: 128,
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(value),
+                    offset: 0,
+                    position: None,
+                },
+                Loop {
+                    body,
+                    offset: 0,
+                    position: None,
+                },
+            ];
+            transform_is_sound(
+                instrs,
+                |instrs| unroll_constant_loops(instrs, u64::from(limit)),
+                true,
+                None,
+            )
+        }
+        quickcheck(is_sound as fn(i8, Vec<AstNode>, u8) -> TestResult)
+    }
+
     #[test]
     fn simplify_loops_is_sound() {
         fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -2597,6 +7950,34 @@ mod soundness_tests {
         quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
     }
 
+    #[test]
+    fn combine_set_and_increments_across_write_is_sound() {
+        fn is_sound(set_offset: u8, set_amount: i8, inc_offset: u8, inc_amount: i8) -> TestResult {
+            let instrs = vec![
+                Set {
+                    amount: Wrapping(set_amount),
+                    offset: (set_offset % 3) as isize,
+                    position: None,
+                },
+                Write {
+                    offset: 0,
+                    position: None,
+                },
+                Increment {
+                    amount: Wrapping(inc_amount),
+                    offset: (inc_offset % 3) as isize,
+                    position: None,
+                },
+                Write {
+                    offset: 0,
+                    position: None,
+                },
+            ];
+            transform_is_sound(instrs, combine_set_and_increments, true, None)
+        }
+        quickcheck(is_sound as fn(u8, i8, u8, i8) -> TestResult)
+    }
+
     #[test]
     fn remove_dead_loops_is_sound() {
         fn is_sound(instrs: Vec<AstNode>) -> TestResult {
@@ -2620,7 +8001,12 @@ mod soundness_tests {
             // reach a runtime value. Consider `+,` to `,` -- the `,`
             // overwrites the cell, but when we reach the runtime value
             // the cells are different.
-            transform_is_sound(instrs, remove_read_clobber, false, read_value)
+            transform_is_sound(
+                instrs,
+                |instrs| remove_read_clobber(instrs).0,
+                false,
+                read_value,
+            )
         }
         quickcheck(is_sound as fn(Vec<AstNode>, Option<i8>) -> TestResult)
     }
@@ -2647,7 +8033,7 @@ mod soundness_tests {
     #[test]
     fn test_overall_optimize_is_sound() {
         fn optimize_ignore_warnings(instrs: Vec<AstNode>) -> Vec<AstNode> {
-            optimize(instrs, &None).0
+            optimize(instrs, OptimizeOptions::default()).0
         }
 
         fn optimizations_sound_together(
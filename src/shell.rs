@@ -3,14 +3,17 @@
 
 use std::process::Command;
 
-/// Execute the CLI command specified.
+use bfc::CompileError;
+
+/// Execute the linker command specified.
 ///
 /// # Failures
 ///
-/// If the command isn't on $PATH, returns Err with a helpful
-/// message. If the command returns a non-zero exit code, returns Err
-/// with stderr.
-pub fn run_shell_command(command: &str, args: &[&str]) -> Result<(), String> {
+/// If the command can't be started, e.g. it isn't on $PATH, returns
+/// Err with the underlying error. If the command returns a non-zero
+/// exit code or is killed by a signal, returns Err with its exit code
+/// and everything it wrote to stdout and stderr.
+pub fn run_shell_command(command: &str, args: &[&str]) -> Result<(), CompileError> {
     let mut c = Command::new(command);
     for arg in args {
         c.arg(arg);
@@ -21,10 +24,49 @@ pub fn run_shell_command(command: &str, args: &[&str]) -> Result<(), String> {
             if result.status.success() {
                 Ok(())
             } else {
-                let stderr = String::from_utf8_lossy(&result.stderr);
-                Err((*stderr).to_owned())
+                Err(CompileError::Link {
+                    status: result.status.code(),
+                    stdout: String::from_utf8_lossy(&result.stdout).into_owned(),
+                    stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+                })
             }
         }
-        Err(_) => Err(format!("Could not execute '{}'. Is it on $PATH?", command)),
+        Err(error) => Err(CompileError::Spawn {
+            command: command.to_owned(),
+            error,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_shell_command_success() {
+        assert!(run_shell_command("sh", &["-c", "exit 0"]).is_ok());
+    }
+
+    #[test]
+    fn run_shell_command_captures_output() {
+        let error = run_shell_command("sh", &["-c", "echo out; echo err >&2; exit 3"]).unwrap_err();
+        match error {
+            CompileError::Link {
+                status,
+                stdout,
+                stderr,
+            } => {
+                assert_eq!(status, Some(3));
+                assert_eq!(stdout, "out\n");
+                assert_eq!(stderr, "err\n");
+            }
+            other => panic!("Expected a link error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn run_shell_command_killed_by_signal() {
+        let error = run_shell_command("sh", &["-c", "kill -9 $$"]).unwrap_err();
+        assert!(matches!(error, CompileError::Link { status: None, .. }));
     }
 }
@@ -0,0 +1,84 @@
+//! A plain text mapping from generated instructions to BF source
+//! positions, for tools that don't read DWARF.
+
+use crate::bfir::AstNode;
+use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, Position};
+
+/// Return a source map for these instructions, with one line per
+/// instruction in the order we generate code for them. Loops are
+/// listed before their bodies.
+///
+/// Each line has the instruction index, the start and end offsets
+/// (inclusive) in the BF source, and the instruction kind, separated
+/// by tabs. Instructions without a position use `-` for the offsets.
+pub fn source_map(instrs: &[AstNode]) -> String {
+    let mut lines = vec![];
+    add_entries(instrs, &mut lines);
+
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(index, line)| format!("{}\t{}\n", index, line))
+        .collect()
+}
+
+fn add_entries(instrs: &[AstNode], lines: &mut Vec<String>) {
+    for instr in instrs {
+        let offsets = match get_position(instr) {
+            Some(Position { start, end }) => format!("{}\t{}", start, end),
+            None => "-\t-".to_owned(),
+        };
+        lines.push(format!("{}\t{}", offsets, instr_kind(instr)));
+
+        if let Loop { body, .. } = instr {
+            add_entries(body, lines);
+        }
+    }
+}
+
+fn instr_kind(instr: &AstNode) -> &'static str {
+    match instr {
+        Increment { .. } => "increment",
+        PointerIncrement { .. } => "pointer_increment",
+        Read { .. } => "read",
+        Write { .. } => "write",
+        Loop { .. } => "loop",
+        Set { .. } => "set",
+        MultiplyMove { .. } => "multiply_move",
+        CellCopy { .. } => "cell_copy",
+        ScanLoop { .. } => "scan_loop",
+        InfiniteLoop { .. } => "infinite_loop",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::num::Wrapping;
+
+    use crate::bfir::parse;
+
+    #[test]
+    fn source_map_entry_per_instr() {
+        let instrs = parse("+[>.]").unwrap();
+        assert_eq!(
+            source_map(&instrs),
+            "0\t0\t0\tincrement\n\
+             1\t1\t4\tloop\n\
+             2\t2\t2\tpointer_increment\n\
+             3\t3\t3\twrite\n"
+        );
+    }
+
+    #[test]
+    fn source_map_without_position() {
+        let instrs = vec![Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: None,
+        }];
+        assert_eq!(source_map(&instrs), "0\t-\t-\tset\n");
+    }
+}